@@ -0,0 +1,55 @@
+//! Heap allocations per request answered by the server, counted by a wrapping
+//! global allocator: `cargo bench --bench allocations`.
+//!
+//! Replies used to be marshalled into a fresh `Vec`, one allocation per
+//! request; answering from reused buffers brought that down to zero.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::net::UdpSocket;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use simple_ntp::server::NtpServer;
+use simple_ntp::testing::HostilePacket;
+
+const REQUESTS: usize = 100_000;
+
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+fn main() {
+    let server = NtpServer::builder().bind("127.0.0.1:0").build().unwrap().spawn().unwrap();
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.connect(server.local_addr()).unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    let request = HostilePacket::client().build();
+    let mut buf = [0u8; 1024];
+    let mut exchange = || {
+        socket.send(&request).unwrap();
+        socket.recv(&mut buf).unwrap();
+    };
+
+    // Warm up, so one-off allocations of the server thread are not counted.
+    (0..100).for_each(|_| exchange());
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    (0..REQUESTS).for_each(|_| exchange());
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+
+    println!("server: {:.3} allocations per request", allocations as f64 / REQUESTS as f64);
+    server.stop();
+}
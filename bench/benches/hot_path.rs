@@ -0,0 +1,78 @@
+use std::hint::black_box;
+use std::net::UdpSocket;
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use simple_ntp::protocol::{duration_to_ntp_timestamp, ntp_timestamp_to_duration, NtpMsg};
+use simple_ntp::server::NtpServer;
+use simple_ntp::simulation::{Simulation, VirtualServer};
+use simple_ntp::testing::{Distribution, HostilePacket};
+
+fn packet(c: &mut Criterion) {
+    let data = HostilePacket::server().build();
+    let msg = NtpMsg::parse(&data).unwrap();
+
+    let mut group = c.benchmark_group("packet");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("marshal", |b| b.iter(|| black_box(&msg).marshal()));
+    group.bench_function("marshal_into", |b| {
+        let mut buf = [0u8; 48];
+        b.iter(|| black_box(&msg).marshal_into(black_box(&mut buf)))
+    });
+    group.bench_function("parse", |b| b.iter(|| NtpMsg::parse(black_box(&data))));
+    group.finish();
+}
+
+fn timestamp(c: &mut Criterion) {
+    let now = Duration::new(1_700_000_000, 123_456_789);
+    let timestamp = duration_to_ntp_timestamp(&now);
+
+    let mut group = c.benchmark_group("timestamp");
+    group.bench_function("from_duration", |b| b.iter(|| duration_to_ntp_timestamp(black_box(&now))));
+    group.bench_function("to_duration", |b| b.iter(|| ntp_timestamp_to_duration(black_box(timestamp))));
+    group.finish();
+}
+
+/// Poll rounds through the synchronizer's filter and selection, over simulated paths.
+fn filter(c: &mut Criterion) {
+    let simulation = || {
+        Simulation::builder()
+            .server(VirtualServer::new("a").delay(Distribution::Uniform(Duration::from_millis(1), Duration::from_millis(9))))
+            .server(VirtualServer::new("b").delay(Distribution::Exponential(Duration::from_millis(20))).loss(0.05))
+            .server(VirtualServer::new("c").offset_nanos(2_000_000).delay(Distribution::Fixed(Duration::from_millis(5))))
+            .drift_ppm(12.0)
+            .build()
+            .unwrap()
+    };
+
+    let mut group = c.benchmark_group("filter");
+    group.throughput(Throughput::Elements(100));
+    group.bench_function("poll_rounds", |b| {
+        b.iter_batched(simulation, |mut sim| sim.run(Duration::from_secs(64 * 100)), BatchSize::SmallInput)
+    });
+    group.finish();
+}
+
+/// Requests answered by a server over loopback, one at a time.
+fn server(c: &mut Criterion) {
+    let server = NtpServer::builder().bind("127.0.0.1:0").build().unwrap().spawn().unwrap();
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.connect(server.local_addr()).unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+    let request = HostilePacket::client().build();
+    let mut buf = [0u8; 1024];
+
+    let mut group = c.benchmark_group("server");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("loopback_exchange", |b| {
+        b.iter(|| {
+            socket.send(&request).unwrap();
+            socket.recv(&mut buf).unwrap()
+        })
+    });
+    group.finish();
+    server.stop();
+}
+
+criterion_group!(benches, packet, timestamp, filter, server);
+criterion_main!(benches);
@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use simple_ntp::protocol::NtpPacket;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(packet) = NtpPacket::parse(data) {
+        assert_eq!(packet.marshal(), data);
+        for field in &packet.extensions {
+            assert!(field.value.len() >= 12 && field.value.len() % 4 == 0);
+        }
+    }
+});
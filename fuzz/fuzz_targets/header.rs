@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use simple_ntp::protocol::NtpMsg;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(msg) = NtpMsg::parse(data) {
+        assert_eq!(msg.marshal(), data);
+    }
+});
@@ -0,0 +1,25 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use simple_ntp::protocol::NtpPacket;
+
+/// A valid version 4 server header, so every input reaches the trailer.
+const HEADER: [u8; 48] = {
+    let mut header = [0u8; 48];
+    header[0] = 0x24;
+    header[1] = 2;
+    header
+};
+
+fuzz_target!(|tail: &[u8]| {
+    let data = [&HEADER[..], tail].concat();
+    match NtpPacket::parse(&data) {
+        Ok(packet) => {
+            assert_eq!(packet.marshal(), data);
+            if let Some(mac) = &packet.mac {
+                assert!(matches!(mac.digest.len(), 0 | 16 | 20));
+            }
+        }
+        Err(_) => assert!(!tail.is_empty()),
+    }
+});
@@ -0,0 +1,111 @@
+#ifndef SIMPLE_NTP_H
+#define SIMPLE_NTP_H
+
+/* Generated by cbindgen from src/ffi.rs, do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Success.
+ */
+#define SNTP_OK 0
+
+/**
+ * No response, or the socket could not be set up.
+ */
+#define SNTP_SERVICE_UNAVAILABLE 1
+
+/**
+ * The server name does not resolve.
+ */
+#define SNTP_BAD_SERVER_ADDR 2
+
+/**
+ * Any other failure, including a panic.
+ */
+#define SNTP_UNEXPECTED 3
+
+/**
+ * The response was too short.
+ */
+#define SNTP_TRUNCATED 4
+
+/**
+ * The response failed validation.
+ */
+#define SNTP_UNTRUSTED 5
+
+/**
+ * Invalid settings.
+ */
+#define SNTP_BAD_CONFIG 6
+
+/**
+ * A null pointer or a server name that is not UTF-8.
+ */
+#define SNTP_INVALID_ARGUMENT 7
+
+/**
+ * The server name did not resolve in time.
+ */
+#define SNTP_RESOLVE_TIMEOUT 8
+
+/**
+ * The server answered with a Kiss-o'-Death: poll less often, or stop.
+ */
+#define SNTP_KISS_OF_DEATH 9
+
+/**
+ * The answer of a server, see [`NtpResult`].
+ */
+typedef struct SntpResult {
+  uint8_t stratum;
+  uint32_t reference_id;
+  /**
+   * NTP short format, 16.16 fixed point seconds.
+   */
+  uint32_t root_delay;
+  /**
+   * NTP short format, 16.16 fixed point seconds.
+   */
+  uint32_t root_dispersion;
+  int64_t offset_nanos;
+  int64_t delay_nanos;
+} SntpResult;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Query `server`, `host` or `host:port`, waiting up to `timeout_ms`
+ * milliseconds, 0 for the default of 5 seconds.
+ *
+ * # Safety
+ *
+ * `server` must be a NUL terminated string and `result` valid for writes.
+ */
+int32_t sntp_query(const char *server, uint32_t timeout_ms, struct SntpResult *result);
+
+/**
+ * Offset of the local clock from `server` in nanoseconds, see [`sntp_query`].
+ *
+ * # Safety
+ *
+ * `server` must be a NUL terminated string and `offset_nanos` valid for writes.
+ */
+int32_t sntp_clock_offset_nanos(const char *server, uint32_t timeout_ms, int64_t *offset_nanos);
+
+/**
+ * A static description of `status`.
+ */
+const char *sntp_strerror(int32_t status);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* SIMPLE_NTP_H */
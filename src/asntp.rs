@@ -0,0 +1,155 @@
+//! [`unix_timestamp`], [`clock_offset_nanos`] and [`ntp`] as async functions on
+//! tokio's `UdpSocket`, for async services that would otherwise need
+//! `spawn_blocking`. They query like [`default_client`](crate::client::default_client):
+//! the same timeout, checks on the response and [`NtpError`]s, but only over NTP,
+//! not the `time://` and `daytime://` fallbacks.
+//!
+//! Example
+//! ```rust,no_run
+//! # use simple_ntp::asntp;
+//!
+//! #[tokio::main(flavor = "current_thread")]
+//! async fn main() {
+//!     match asntp::clock_offset_nanos("ntp.aliyun.com").await {
+//!         Ok(offset) => println!("{:?}", offset as f64 / 1e9),
+//!         Err(err) => println!("{:?}", err),
+//!     }
+//! }
+//! ```
+
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use tokio::net::{self, UdpSocket};
+use tokio::time;
+
+use crate::client::{
+    check_response, corrected_time, getaddr, note_answered, observe, offset_nanos_of, pick_responsive, sys_time,
+    AuditRecord, Exchange, IpFamily, ResponsePolicy, Timestamps, DEFAULT_TIMEOUT, NTP_DEFAULT_PORT,
+};
+use crate::diag;
+use crate::otel;
+use crate::protocol::{duration_to_ntp_timestamp, NtpError, NtpMsg, NTP_VERSION_4};
+
+/// Retrieve current unix timestamp, see [`client::unix_timestamp`](crate::client::unix_timestamp).
+pub async fn unix_timestamp(ntp_server: &str) -> Result<Duration, NtpError> {
+    Ok(corrected_time(ntp(ntp_server).await?))
+}
+
+/// Get system clock offset in nano seconds, see
+/// [`client::clock_offset_nanos`](crate::client::clock_offset_nanos).
+pub async fn clock_offset_nanos(ntp_server: &str) -> Result<i64, NtpError> {
+    Ok(offset_nanos_of(ntp(ntp_server).await?))
+}
+
+/// Retrieve t1, t2, t3 and t4 from ntp server, see [`client::ntp`](crate::client::ntp).
+pub async fn ntp(ntp_server: &str) -> Result<Timestamps, NtpError> {
+    let exchange = exchange(ntp_server).await?;
+
+    Ok((exchange.t1, exchange.t2, exchange.t3, exchange.t4))
+}
+
+async fn exchange(ntp_server: &str) -> Result<Exchange, NtpError> {
+    let span = otel::ExchangeSpan::start(ntp_server);
+    let result = match resolve(ntp_server).await {
+        Ok(addr) => exchange_with(ntp_server, addr).await,
+        Err(err) => Err(err),
+    };
+    span.end(ntp_server, &result);
+
+    result
+}
+
+async fn exchange_with(ntp_server: &str, addr: SocketAddr) -> Result<Exchange, NtpError> {
+    let local = if addr.is_ipv4() {
+        SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+    } else {
+        SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+    };
+    let socket = UdpSocket::bind(local).await.map_err(|err| {
+        NtpError::ServiceUnavailable(err.to_string())
+    })?;
+    socket.connect(addr).await.map_err(|err| {
+        NtpError::UnexpectedErr(err.to_string())
+    })?;
+
+    let mut record = AuditRecord {
+        server: ntp_server.to_string(),
+        addr: Some(addr),
+        ..AuditRecord::default()
+    };
+    let policy = ResponsePolicy::default();
+    let timestamp = duration_to_ntp_timestamp(&sys_time());
+    let request = NtpMsg::new_for_client(NTP_VERSION_4, timestamp).marshal();
+    debug!("sending ntp request to {} ({})", ntp_server, addr);
+    diag::count(diag::QUERIES, ntp_server);
+    let transmit_time = sys_time();
+    let started = Instant::now();
+    socket.send(&request).await.map_err(|err| {
+        NtpError::ServiceUnavailable(err.to_string())
+    })?;
+    let mut buf = policy.limits.receive_buffer();
+    let received = match time::timeout(DEFAULT_TIMEOUT, socket.recv(&mut buf)).await {
+        Ok(received) => received,
+        Err(_) => {
+            diag::count(diag::TIMEOUTS, ntp_server);
+            Err(io::ErrorKind::TimedOut.into())
+        }
+    };
+    record.timing.round_trip = started.elapsed();
+    note_answered(addr, received.is_ok());
+    let n = received.map_err(|err| {
+        warn!("no response from {}: {:?}", addr, err);
+        NtpError::ServiceUnavailable(err.to_string())
+    })?;
+    let receive_time = sys_time();
+    debug!("received {} bytes from {}", n, addr);
+
+    let result = check_response(addr, timestamp, transmit_time, receive_time, &buf[..n], &policy, &mut record);
+    observe(ntp_server, &result);
+
+    result
+}
+
+async fn resolve(ntp_server: &str) -> Result<SocketAddr, NtpError> {
+    let host = getaddr(ntp_server, NTP_DEFAULT_PORT);
+    let addrs: Vec<_> = match host.parse::<SocketAddr>() {
+        Ok(addr) => vec![addr],
+        Err(_) => match time::timeout(DEFAULT_TIMEOUT, net::lookup_host(&host)).await {
+            Ok(addrs) => addrs.map_err(|err| NtpError::BadNtpServerAddr(err.to_string()))?.collect(),
+            Err(_) => {
+                warn!("resolving {} timed out after {:?}", host, DEFAULT_TIMEOUT);
+                return Err(NtpError::ResolveTimeout(format!("{}: no answer from the resolver in {:?}", host, DEFAULT_TIMEOUT)));
+            }
+        },
+    };
+
+    pick_responsive(&addrs).ok_or_else(|| {
+        NtpError::BadNtpServerAddr(format!("{}: no {:?} address", ntp_server, IpFamily::Any))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asntp::*;
+    use crate::testing::{MockServer, Response};
+
+    #[tokio::test]
+    async fn test_async_query() {
+        let server = MockServer::builder().offset_nanos(2_000_000_000).start().unwrap();
+        let offset = clock_offset_nanos(&server.addr()).await.unwrap();
+        assert_eq!((offset as f64 / 1e9).round(), 2.0);
+        let timestamp = unix_timestamp(&server.addr()).await.unwrap();
+        assert_eq!((timestamp.as_secs_f64() - sys_time().as_secs_f64()).round(), 2.0);
+        let (t1, t2, t3, t4) = ntp(&server.addr()).await.unwrap();
+        assert!(t1 <= t4 && t2 <= t3);
+
+        // The same checks, and errors, as the blocking client.
+        let server = MockServer::builder().script([Response::BadOrigin, Response::KissOfDeath(*b"RATE")]).start().unwrap();
+        assert!(matches!(ntp(&server.addr()).await, Err(NtpError::UntrustedMessage)));
+        assert!(matches!(ntp(&server.addr()).await, Err(NtpError::KissOfDeath(code)) if code == "RATE"));
+        let closed = std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        assert!(matches!(ntp(&closed.to_string()).await, Err(NtpError::ServiceUnavailable(_))));
+    }
+}
@@ -0,0 +1,141 @@
+//! Classic unix daemonization for `sntp serve --daemon`, for init systems
+//! without service supervision (BSD rc scripts, containers with a minimal init),
+//! and the signals `serve` handles: SIGTERM/SIGINT to stop, SIGHUP to reload.
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set by SIGTERM and SIGINT once [`stop_on_signals`] is called.
+static STOP: AtomicBool = AtomicBool::new(false);
+
+/// Set by SIGHUP once [`reload_on_sighup`] is called.
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+/// Turn SIGTERM and SIGINT into a clean shutdown, so the pidfile is removed.
+pub fn stop_on_signals() -> &'static AtomicBool {
+    extern "C" fn handle(_signal: libc::c_int) {
+        STOP.store(true, Ordering::Relaxed);
+    }
+    unsafe {
+        libc::signal(libc::SIGTERM, handle as *const () as libc::sighandler_t);
+        libc::signal(libc::SIGINT, handle as *const () as libc::sighandler_t);
+    }
+    &STOP
+}
+
+/// Turn SIGHUP into a configuration reload instead of termination.
+pub fn reload_on_sighup() -> &'static AtomicBool {
+    extern "C" fn handle(_signal: libc::c_int) {
+        RELOAD.store(true, Ordering::Relaxed);
+    }
+    unsafe {
+        libc::signal(libc::SIGHUP, handle as *const () as libc::sighandler_t);
+    }
+    &RELOAD
+}
+
+/// A pidfile holding our pid, removed again on drop.
+#[derive(Debug)]
+pub struct Pidfile {
+    path: PathBuf,
+}
+
+impl Pidfile {
+    /// Fail if `path` names a process that is still running. Stale pidfiles are ignored.
+    pub fn check(path: &Path) -> Result<(), String> {
+        let Ok(content) = fs::read_to_string(path) else {
+            return Ok(());
+        };
+        match content.trim().parse::<libc::pid_t>() {
+            Ok(pid) if pid > 0 && pid as u32 != process::id() && is_running(pid) => {
+                Err(format!("already running as pid {} ({})", pid, path.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Write the current pid to `path`.
+    pub fn create(path: &Path) -> Result<Self, String> {
+        Pidfile::check(path)?;
+        fs::write(path, format!("{}\n", process::id()))
+            .map_err(|err| format!("failed to write pidfile {}: {}", path.display(), err))?;
+
+        Ok(Pidfile { path: path.to_path_buf() })
+    }
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Detach from the terminal: fork, `setsid`, fork again, `chdir /`, and point
+/// stdin at `/dev/null` and stdout/stderr at `log_file` (or `/dev/null`).
+///
+/// Must be called before any thread is spawned. Relative paths used afterwards
+/// resolve against `/`, so make them absolute first.
+pub fn daemonize(log_file: Option<&Path>) -> Result<(), String> {
+    // Opened before forking so a bad path is still reported on the terminal.
+    let output = match log_file {
+        Some(path) => OpenOptions::new().create(true).append(true).open(path),
+        None => OpenOptions::new().write(true).open("/dev/null"),
+    }
+    .map_err(|err| format!("failed to open log file: {}", err))?;
+    let null = File::open("/dev/null").map_err(|err| err.to_string())?;
+
+    fork_and_exit_parent()?;
+    if unsafe { libc::setsid() } == -1 {
+        return Err(format!("setsid failed: {}", io::Error::last_os_error()));
+    }
+    // The session leader exits so the daemon can never reacquire a controlling terminal.
+    fork_and_exit_parent()?;
+
+    unsafe {
+        libc::umask(0o022);
+        libc::dup2(null.as_raw_fd(), libc::STDIN_FILENO);
+        libc::dup2(output.as_raw_fd(), libc::STDOUT_FILENO);
+        libc::dup2(output.as_raw_fd(), libc::STDERR_FILENO);
+    }
+    std::env::set_current_dir("/").map_err(|err| err.to_string())
+}
+
+/// Signal 0 only checks for existence; EPERM means it exists but belongs to someone else.
+fn is_running(pid: libc::pid_t) -> bool {
+    let alive = unsafe { libc::kill(pid, 0) } == 0;
+    alive || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+fn fork_and_exit_parent() -> Result<(), String> {
+    match unsafe { libc::fork() } {
+        -1 => Err(format!("fork failed: {}", io::Error::last_os_error())),
+        0 => Ok(()),
+        _ => unsafe { libc::_exit(0) },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pidfile() {
+        let path = std::env::temp_dir().join(format!("sntp-test-{}.pid", process::id()));
+
+        let pidfile = Pidfile::create(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}\n", process::id()));
+        drop(pidfile);
+        assert!(!path.exists());
+
+        // pid 1 is always running, an unused pid is a stale file.
+        fs::write(&path, "1\n").unwrap();
+        assert!(Pidfile::check(&path).unwrap_err().contains("pid 1"));
+        fs::write(&path, format!("{}\n", libc::pid_t::MAX)).unwrap();
+        assert!(Pidfile::check(&path).is_ok());
+        fs::remove_file(&path).unwrap();
+    }
+}
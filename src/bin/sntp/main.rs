@@ -0,0 +1,1445 @@
+//! `sntp` command line tool.
+
+use std::collections::VecDeque;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{self, ExitCode};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
+use serde_json::{json, Value};
+use simple_ntp::client::{AuditRecord, IpFamily, NtpResult, ParseMode, SntpClient, DEFAULT_MIN_INTERVAL};
+use simple_ntp::clock;
+use simple_ntp::config::{self, Config, ServerConfig};
+#[cfg(unix)]
+use simple_ntp::control;
+use simple_ntp::pcap::{self, PcapWriter};
+use simple_ntp::protocol::{self, NtpError, NtpPacket, ShortFormat};
+use simple_ntp::server::{IpNet, ServerHandle};
+use simple_ntp::status;
+use simple_ntp::synchronizer::{self, SyncHandle};
+#[cfg(unix)]
+use simple_ntp::systemd;
+
+#[cfg(unix)]
+mod daemon;
+#[cfg(all(windows, feature = "windows-service"))]
+mod service;
+
+#[derive(Parser)]
+#[command(name = "sntp", version, about = "Query SNTP servers")]
+struct Cli {
+    /// Output format.
+    #[arg(long, global = true, value_enum, default_value_t = Output::Text)]
+    output: Output,
+    /// Trace exchanges on stderr: `-v` timestamps and checks, `-vv` also annotated hexdumps of the packets.
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    verbose: u8,
+    /// Write every NTP datagram sent and received to this pcap file, for Wireshark.
+    #[arg(long, global = true, value_name = "FILE")]
+    pcap: Option<PathBuf>,
+    /// Also reject responses with malformed extension fields or trailing data.
+    #[arg(long, global = true)]
+    strict: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+/// The tool invoked as `ntpdate`, e.g. through a symlink, takes only `ntpdate` flags.
+#[derive(Parser)]
+#[command(name = "ntpdate", version, about = "Set the clock from SNTP servers (ntpdate compatible)")]
+struct NtpdateCli {
+    #[command(flatten)]
+    args: NtpdateArgs,
+}
+
+/// The commonly used subset of `ntpdate` flags.
+#[derive(Args)]
+struct NtpdateArgs {
+    /// Query only, don't set the clock.
+    #[arg(short = 'q')]
+    query_only: bool,
+    /// Step the clock; it is always stepped, accepted for compatibility.
+    #[arg(short = 'b')]
+    step: bool,
+    /// Use an unprivileged source port; it always is, accepted for compatibility.
+    #[arg(short = 'u')]
+    unprivileged: bool,
+    /// Seconds to wait for a server response.
+    #[arg(short = 't', default_value_t = 2.0)]
+    timeout: f64,
+    /// Only use IPv4 addresses.
+    #[arg(short = '4', conflicts_with = "ipv6")]
+    ipv4: bool,
+    /// Only use IPv6 addresses.
+    #[arg(short = '6')]
+    ipv6: bool,
+    /// Servers, `host` or `host:port`.
+    #[arg(required = true)]
+    servers: Vec<String>,
+}
+
+/// `-v` count, read by every query.
+static VERBOSE: AtomicU8 = AtomicU8::new(0);
+
+/// Client of every query, with the `SNTP_TIMEOUT` and `SNTP_FAMILY` environment applied.
+static CLIENT: OnceLock<SntpClient> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Output {
+    /// Human readable text.
+    Text,
+    /// A JSON document per result.
+    Json,
+    /// One compact JSON object per line.
+    Jsonl,
+    /// A header and then one row per measurement (watch and drift only).
+    Csv,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Query a server and print offset, delay, stratum, refid and root dispersion.
+    Query {
+        /// Server, `host` or `host:port`.
+        server: String,
+    },
+    /// Measure the offset to a server and step the system clock by it.
+    Set {
+        /// Server, `host` or `host:port`.
+        server: String,
+        /// Only print the offset that would be applied.
+        #[arg(long)]
+        dry_run: bool,
+        /// Refuse to step the clock by more than this, e.g. `500ms` or `10s` [env: SNTP_MAX_OFFSET].
+        #[arg(long, value_parser = parse_duration)]
+        max_offset: Option<Duration>,
+        /// Step the clock whatever the offset, like `ntpd -gq`, for a boot script
+        /// on a machine whose RTC may be far off; ignores --max-offset.
+        #[arg(short = 'g', long)]
+        trust_first_sync: bool,
+    },
+    /// Query a server repeatedly, printing offset, delay and jitter for each sample.
+    Watch {
+        /// Server, `host` or `host:port`.
+        server: String,
+        /// Time between queries.
+        #[arg(long, value_parser = parse_duration, default_value = "10s")]
+        interval: Duration,
+        /// Stop after this many queries.
+        #[arg(long)]
+        count: Option<u64>,
+        /// Append a sparkline of recent offsets to each line.
+        #[arg(long)]
+        sparkline: bool,
+    },
+    /// Monitoring plugin: exit 0/1/2/3 for OK/WARNING/CRITICAL/UNKNOWN with a perfdata line.
+    Check {
+        /// Server, `host` or `host:port`.
+        server: String,
+        /// Absolute offset above which the check is WARNING.
+        #[arg(long, value_parser = parse_duration, default_value = "60s")]
+        warn: Duration,
+        /// Absolute offset above which the check is CRITICAL.
+        #[arg(long, value_parser = parse_duration, default_value = "120s")]
+        crit: Duration,
+    },
+    /// Query several servers concurrently and rank them by disagreement with the median offset.
+    Compare {
+        /// Servers, `host` or `host:port`.
+        #[arg(required = true, num_args = 2..)]
+        servers: Vec<String>,
+        /// Flag servers whose offset differs from the median by more than this as falsetickers.
+        #[arg(long, value_parser = parse_duration, default_value = "100ms")]
+        threshold: Duration,
+        /// Measure the second of two servers against the first over this many rounds instead.
+        #[arg(long)]
+        samples: Option<usize>,
+    },
+    /// Measure a server over a long series and estimate the local clock's frequency error in ppm.
+    ///
+    /// Stop any daemon disciplining the clock first, or this measures what is left after it.
+    Drift {
+        /// Server, `host` or `host:port`.
+        server: String,
+        /// How long to measure; more time narrows the confidence interval.
+        #[arg(long, value_parser = parse_duration, default_value = "1h")]
+        duration: Duration,
+        /// Time between queries.
+        #[arg(long, value_parser = parse_duration, default_value = "64s")]
+        interval: Duration,
+    },
+    /// Recompute the offsets of the NTP exchanges in a pcap capture, as the client would have.
+    Replay {
+        /// The capture, e.g. from `--pcap` or `tcpdump -w` on the client host.
+        file: PathBuf,
+    },
+    /// Print every field of a packet, including extension fields and MAC.
+    Decode {
+        /// Hex bytes, e.g. `24 02 03 e7 ...` or a Wireshark hex stream, `-` for stdin, or a
+        /// pcap capture, decoding its datagrams to or from port 123.
+        #[arg(required = true)]
+        input: Vec<String>,
+    },
+    /// Set the clock like `ntpdate`, accepting its flags.
+    Ntpdate(NtpdateArgs),
+    /// Convert an ntpd `ntp.conf` or a chrony `chrony.conf` to a `serve --config` file on stdout.
+    Import {
+        /// The ntpd or chrony configuration file.
+        file: PathBuf,
+    },
+    /// Talk to a running `serve --control`, like `chronyc`.
+    #[cfg(unix)]
+    Ctl {
+        /// The control socket.
+        #[arg(long, default_value = control::DEFAULT_SOCKET)]
+        socket: PathBuf,
+        #[command(subcommand)]
+        request: CtlRequest,
+    },
+    /// Run an SNTP server relaying the time of upstream servers.
+    Serve {
+        #[command(flatten)]
+        serve: Box<ServeArgs>,
+        #[cfg(unix)]
+        #[command(flatten)]
+        daemon: DaemonArgs,
+    },
+    /// Install, remove or run `serve` as a Windows service.
+    #[cfg(all(windows, feature = "windows-service"))]
+    #[command(subcommand)]
+    Service(ServiceCommand),
+}
+
+/// Requests of `ctl`, see the library's `control` module.
+#[cfg(unix)]
+#[derive(Subcommand)]
+enum CtlRequest {
+    /// Print the selected server and the current offset.
+    Status {
+        /// `tracking` prints the fields of `chronyc tracking` in its layout.
+        #[arg(long, value_enum, default_value_t = StatusFormat::Summary)]
+        format: StatusFormat,
+    },
+    /// List the servers with their reachability, offset and jitter.
+    Sources,
+    /// Poll all servers now.
+    Poll,
+    /// Start polling another server.
+    Add {
+        /// Server, `host` or `host:port`.
+        server: String,
+    },
+    /// Stop polling a server.
+    Remove {
+        /// Server, `host` or `host:port`.
+        server: String,
+    },
+}
+
+#[cfg(unix)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum StatusFormat {
+    /// The selected server and its sample.
+    Summary,
+    /// Like `chronyc tracking`.
+    Tracking,
+}
+
+/// Options of `serve` for running without a service manager.
+#[cfg(unix)]
+#[derive(Args)]
+struct DaemonArgs {
+    /// Detach from the terminal and run in the background.
+    #[arg(long)]
+    daemon: bool,
+    /// Write the server's pid to this file, refusing to start if it names a running process.
+    #[arg(long)]
+    pidfile: Option<PathBuf>,
+    /// Append stdout and stderr to this file when running as a daemon, instead of discarding them.
+    #[arg(long, requires = "daemon")]
+    log_file: Option<PathBuf>,
+}
+
+#[cfg(all(windows, feature = "windows-service"))]
+#[derive(Subcommand)]
+enum ServiceCommand {
+    /// Register an automatically started service running `serve` with these options.
+    Install(ServeArgs),
+    /// Stop and remove the service.
+    Uninstall,
+    /// Run as the service, this is what the service control manager starts.
+    #[command(hide = true)]
+    Run(ServeArgs),
+}
+
+/// Options of `serve`.
+#[derive(Args, Clone, Debug)]
+struct ServeArgs {
+    /// TOML configuration file, see the library's `config` module. `SNTP_*` environment
+    /// variables override it and the flags below override both. Re-read on SIGHUP.
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Upstream server to relay, may be repeated. Without one the local clock is served.
+    #[arg(long)]
+    upstream: Vec<String>,
+    /// Also relay the NTP servers offered by DHCP (option 42), read again on SIGHUP.
+    #[cfg(target_os = "linux")]
+    #[arg(long)]
+    dhcp: bool,
+    /// Address to listen on [default: 0.0.0.0:123].
+    #[arg(long)]
+    bind: Option<String>,
+    /// Upstream poll interval [default: 64s].
+    #[arg(long, value_parser = parse_duration)]
+    poll: Option<Duration>,
+    /// Stratum to serve the local clock at when no upstream is given [default: 10].
+    #[arg(long)]
+    local_stratum: Option<u8>,
+    /// Minimum interval between requests of one client, `0` disables rate limiting [default: 2s].
+    #[arg(long, value_parser = parse_duration)]
+    rate_limit: Option<Duration>,
+    /// Answer rate limited clients with a RATE Kiss-o'-Death instead of dropping them.
+    #[arg(long)]
+    kod: bool,
+    /// Network allowed to query, e.g. `10.0.0.0/8`, may be repeated.
+    #[arg(long, value_parser = parse_net)]
+    allow: Vec<IpNet>,
+    /// Network denied from querying, may be repeated.
+    #[arg(long, value_parser = parse_net)]
+    deny: Vec<IpNet>,
+    /// Deny clients not matched by an --allow network.
+    #[arg(long)]
+    deny_by_default: bool,
+    /// Accept `sntp ctl` requests on this unix socket [default: /run/sntp.sock].
+    #[cfg(unix)]
+    #[arg(long, num_args = 0..=1, default_missing_value = control::DEFAULT_SOCKET)]
+    control: Option<PathBuf>,
+    /// Serve the JSON status on `http://ADDR/status`, 503 while unsynchronized.
+    #[arg(long, value_name = "ADDR")]
+    http: Option<String>,
+    /// Accept the first upstream offset however large, like `ntpd -g`; the configured
+    /// `clock.max_offset` applies after that.
+    #[arg(short = 'g', long)]
+    trust_first_sync: bool,
+}
+
+impl ServeArgs {
+    /// The configuration file, if any, with the flags applied on top.
+    fn config(&self) -> Result<Config, String> {
+        let mut config = match &self.config {
+            Some(path) => Config::load(path).map_err(|err| format!("{:?}", err))?,
+            None => Config::default(),
+        };
+        config.apply_env().map_err(|err| format!("{:?}", err))?;
+        if !self.upstream.is_empty() {
+            config.servers = self.upstream.iter().map(|server| ServerConfig::new(server)).collect();
+        }
+        #[cfg(target_os = "linux")]
+        if self.dhcp {
+            config.servers.extend(simple_ntp::dhcp::ntp_servers().iter().map(|server| ServerConfig::new(server)));
+        }
+        if let Some(bind) = &self.bind {
+            config.serve.bind = bind.clone();
+        }
+        config.poll.interval = self.poll.unwrap_or(config.poll.interval);
+        config.serve.local_stratum = self.local_stratum.unwrap_or(config.serve.local_stratum);
+        config.serve.rate_limit = self.rate_limit.unwrap_or(config.serve.rate_limit);
+        config.serve.kod |= self.kod;
+        config.serve.allow.extend(&self.allow);
+        config.serve.deny.extend(&self.deny);
+        config.serve.deny_by_default |= self.deny_by_default;
+        config.clock.trust_first_sync |= self.trust_first_sync;
+
+        Ok(config)
+    }
+}
+
+fn main() -> ExitCode {
+    let invoked_as = std::env::args_os().next().map(PathBuf::from);
+    if invoked_as.as_deref().and_then(Path::file_stem).is_some_and(|name| name == "ntpdate") {
+        return exit(ntpdate(&NtpdateCli::parse().args));
+    }
+
+    let cli = Cli::parse();
+    let output = cli.output;
+    VERBOSE.store(cli.verbose, Ordering::Relaxed);
+    let env = match Config::from_env() {
+        Ok(env) => env,
+        Err(err) => {
+            eprintln!("sntp: {:?}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    let client = match &cli.pcap {
+        Some(path) => match PcapWriter::create(path) {
+            Ok(pcap) => env.client().to_builder().pcap(pcap).build(),
+            Err(err) => {
+                eprintln!("sntp: {:?}", err);
+                return ExitCode::FAILURE;
+            }
+        },
+        None => env.client(),
+    };
+    let client = if cli.strict {
+        client.to_builder().parse_mode(ParseMode::Strict).build()
+    } else {
+        client
+    };
+    // watch spaces its queries itself, possibly closer than the rate limiter's default.
+    let client = match &cli.command {
+        Command::Watch { interval, .. } if *interval < DEFAULT_MIN_INTERVAL => {
+            client.to_builder().min_interval(*interval).build()
+        }
+        _ => client,
+    };
+    let _ = CLIENT.set(client);
+    if output == Output::Csv && !matches!(cli.command, Command::Watch { .. } | Command::Drift { .. }) {
+        eprintln!("sntp: --output csv is only supported by watch and drift");
+        return ExitCode::FAILURE;
+    }
+    if output != Output::Text && matches!(cli.command, Command::Ntpdate(_) | Command::Import { .. }) {
+        eprintln!("sntp: ntpdate and import only print text");
+        return ExitCode::FAILURE;
+    }
+
+    let result = match cli.command {
+        Command::Query { server } => query(&server, output),
+        Command::Set { server, dry_run, max_offset, trust_first_sync } => {
+            let max_offset = max_offset.or(env.clock.max_offset).filter(|_| !trust_first_sync);
+            set(&server, dry_run, max_offset, output)
+        }
+        Command::Watch { server, interval, count, sparkline } => watch(&server, interval, count, sparkline, output),
+        Command::Check { server, warn, crit } => return check(&server, warn, crit, output),
+        Command::Compare { servers, samples: Some(samples), .. } => compare_pair(&servers, samples, output),
+        Command::Compare { servers, threshold, samples: None } => compare(&servers, threshold, output),
+        Command::Drift { server, duration, interval } => drift(&server, duration, interval, output),
+        Command::Replay { file } => replay(&file, output),
+        Command::Decode { input } => decode(&input, output),
+        Command::Ntpdate(args) => ntpdate(&args),
+        Command::Import { file } => import(&file),
+        #[cfg(unix)]
+        Command::Ctl { socket, request } => ctl(&socket, &request, output),
+        #[cfg(unix)]
+        Command::Serve { serve: args, daemon } => serve_daemon(&args, &daemon, output),
+        #[cfg(not(unix))]
+        Command::Serve { serve: args } => serve(&args, output, &AtomicBool::new(false), &AtomicBool::new(false)),
+        #[cfg(all(windows, feature = "windows-service"))]
+        Command::Service(ServiceCommand::Install(_)) => {
+            // Validated above, stored verbatim as the service's `service run` options.
+            service::install(std::env::args_os().skip_while(|arg| arg != "install").skip(1).collect())
+        }
+        #[cfg(all(windows, feature = "windows-service"))]
+        Command::Service(ServiceCommand::Uninstall) => service::uninstall(),
+        #[cfg(all(windows, feature = "windows-service"))]
+        Command::Service(ServiceCommand::Run(args)) => service::run(args),
+    };
+    exit(result)
+}
+
+fn exit(result: Result<(), String>) -> ExitCode {
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("sntp: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn query(server: &str, output: Output) -> Result<(), String> {
+    let (timing, result) = timed_query(server);
+    let result = result.map_err(|err| format!("{}: {:?}", server, err))?;
+    match output {
+        Output::Text => print_result(server, &result),
+        _ => print_json(&result_json(server, &result, &timing), output),
+    }
+
+    Ok(())
+}
+
+fn set(server: &str, dry_run: bool, max_offset: Option<Duration>, output: Output) -> Result<(), String> {
+    let (timing, result) = timed_query(server);
+    let result = result.map_err(|err| format!("{}: {:?}", server, err))?;
+    let offset = result.offset_nanos as f64 / 1e9;
+
+    if let Some(max_offset) = max_offset {
+        if result.offset_nanos.unsigned_abs() as u128 > max_offset.as_nanos() {
+            return Err(format!(
+                "offset {:+.6} s from {} exceeds --max-offset {:?}, not stepping the clock",
+                offset, server, max_offset
+            ));
+        }
+    }
+    if !dry_run {
+        clock::step(result.offset_nanos).map_err(|err| format!("failed to step clock: {:?}", err))?;
+    }
+
+    match output {
+        Output::Text => println!(
+            "{} clock by {:+.6} s (server {}, stratum {})",
+            if dry_run { "would step" } else { "stepped" },
+            offset,
+            result.addr,
+            result.stratum
+        ),
+        _ => {
+            let mut value = result_json(server, &result, &timing);
+            value["stepped"] = json!(!dry_run);
+            print_json(&value, output);
+        }
+    }
+
+    Ok(())
+}
+
+/// Samples kept for jitter and the sparkline.
+const WATCH_WINDOW: usize = 32;
+
+/// Columns of `watch --output csv`, offsets and delays in seconds.
+const CSV_HEADER: [&str; 11] = [
+    "time", "server", "addr", "stratum", "refid", "offset", "delay", "jitter", "root_delay", "root_dispersion", "error",
+];
+
+fn watch(server: &str, interval: Duration, count: Option<u64>, sparkline: bool, output: Output) -> Result<(), String> {
+    let mut offsets: VecDeque<f64> = VecDeque::with_capacity(WATCH_WINDOW);
+    if output == Output::Csv {
+        println!("{}", csv_row(&CSV_HEADER));
+    }
+    let mut n = 0;
+    loop {
+        let (timing, result) = timed_query(server);
+        match result {
+            Ok(result) => {
+                if offsets.len() == WATCH_WINDOW {
+                    offsets.pop_front();
+                }
+                offsets.push_back(result.offset_nanos as f64 / 1e9);
+                match output {
+                    Output::Text => {
+                        let mut line = format!(
+                            "{} offset {:+.6} s  delay {:.6} s  jitter {:.6} s",
+                            time_of_day(timing.started),
+                            result.offset_nanos as f64 / 1e9,
+                            result.delay_nanos as f64 / 1e9,
+                            jitter(&offsets)
+                        );
+                        if sparkline {
+                            line = line + "  " + &spark(&offsets);
+                        }
+                        println!("{}", line);
+                    }
+                    Output::Csv => println!(
+                        "{}",
+                        csv_row(&[
+                            &timing.started.as_secs_f64().to_string(),
+                            server,
+                            &result.addr.to_string(),
+                            &result.stratum.to_string(),
+                            &result.refid(),
+                            &(result.offset_nanos as f64 / 1e9).to_string(),
+                            &(result.delay_nanos as f64 / 1e9).to_string(),
+                            &jitter(&offsets).to_string(),
+                            &result.root_delay.as_secs_f64().to_string(),
+                            &result.root_dispersion.as_secs_f64().to_string(),
+                            "",
+                        ])
+                    ),
+                    Output::Json | Output::Jsonl => {
+                        let mut value = result_json(server, &result, &timing);
+                        value["jitter"] = json!(jitter(&offsets));
+                        print_json(&value, output);
+                    }
+                }
+            }
+            Err(err) => match output {
+                Output::Text => println!("{} {}: {:?}", time_of_day(timing.started), server, err),
+                Output::Csv => println!(
+                    "{}",
+                    csv_row(&[
+                        &timing.started.as_secs_f64().to_string(),
+                        server,
+                        "", "", "", "", "", "", "", "",
+                        &format!("{:?}", err),
+                    ])
+                ),
+                Output::Json | Output::Jsonl => print_json(&error_json(server, &err, &timing), output),
+            },
+        }
+
+        n += 1;
+        if count.is_some_and(|count| n >= count) {
+            return Ok(());
+        }
+        thread::sleep(interval);
+    }
+}
+
+/// `HH:MM:SS` UTC.
+fn time_of_day(now: Duration) -> String {
+    let secs = now.as_secs() % 86400;
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// RMS of the differences between successive offsets.
+fn jitter(offsets: &VecDeque<f64>) -> f64 {
+    if offsets.len() < 2 {
+        return 0.0;
+    }
+    let sum: f64 = offsets.iter()
+        .zip(offsets.iter().skip(1))
+        .map(|(a, b)| (b - a).powi(2))
+        .sum();
+    (sum / (offsets.len() - 1) as f64).sqrt()
+}
+
+/// Offsets as a unicode sparkline scaled to the window's range.
+fn spark(offsets: &VecDeque<f64>) -> String {
+    const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+    let min = offsets.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = offsets.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    offsets.iter()
+        .map(|offset| {
+            if max > min {
+                BARS[((offset - min) / (max - min) * 7.0).round() as usize]
+            } else {
+                BARS[3]
+            }
+        })
+        .collect()
+}
+
+/// Plugin exit code and label for an absolute offset of `offset_nanos`.
+fn check_status(offset_nanos: i64, warn: Duration, crit: Duration) -> (u8, &'static str) {
+    let offset = offset_nanos.unsigned_abs() as u128;
+    if offset > crit.as_nanos() {
+        (2, "CRITICAL")
+    } else if offset > warn.as_nanos() {
+        (1, "WARNING")
+    } else {
+        (0, "OK")
+    }
+}
+
+fn check(server: &str, warn: Duration, crit: Duration, output: Output) -> ExitCode {
+    let (timing, result) = timed_query(server);
+    let (code, label) = match &result {
+        Ok(result) => check_status(result.offset_nanos, warn, crit),
+        Err(_) => (3, "UNKNOWN"),
+    };
+
+    match (output, result) {
+        (Output::Text, Ok(result)) => println!(
+            "NTP {}: offset {:+.6} s from {} | offset={:.6}s;{:.6};{:.6} delay={:.6}s stratum={}",
+            label,
+            result.offset_nanos as f64 / 1e9,
+            server,
+            result.offset_nanos as f64 / 1e9,
+            warn.as_secs_f64(),
+            crit.as_secs_f64(),
+            result.delay_nanos as f64 / 1e9,
+            result.stratum
+        ),
+        (Output::Text, Err(err)) => println!("NTP {}: {}: {:?}", label, server, err),
+        (_, result) => {
+            let mut value = match result {
+                Ok(result) => result_json(server, &result, &timing),
+                Err(err) => error_json(server, &err, &timing),
+            };
+            value["status"] = json!(label);
+            print_json(&value, output);
+        }
+    }
+    ExitCode::from(code)
+}
+
+/// Offset of the second server's clock from the first's, with its uncertainty.
+fn compare_pair(servers: &[String], samples: usize, output: Output) -> Result<(), String> {
+    let [reference, server] = servers else {
+        return Err("--samples compares exactly two servers".to_string());
+    };
+    let client = CLIENT.get_or_init(SntpClient::default);
+    let comparison = client.compare(reference, server, samples).map_err(|err| format!("{:?}", err))?;
+
+    match output {
+        Output::Text => println!("{} is {} from {}", server, comparison, reference),
+        _ => print_json(
+            &json!({
+                "reference": reference,
+                "server": server,
+                "offset": comparison.offset_nanos as f64 / 1e9,
+                "uncertainty": comparison.uncertainty_nanos as f64 / 1e9,
+                "jitter": comparison.jitter_nanos as f64 / 1e9,
+                "samples": comparison.samples,
+            }),
+            output,
+        ),
+    }
+
+    Ok(())
+}
+
+/// Columns of `drift --output csv`, the estimate so far in ppm, empty until 3 answers.
+const DRIFT_CSV_HEADER: [&str; 8] = ["time", "server", "addr", "offset", "delay", "frequency_ppm", "confidence_ppm", "error"];
+
+fn drift(server: &str, duration: Duration, interval: Duration, output: Output) -> Result<(), String> {
+    let start = Instant::now();
+    let mut samples = Vec::new();
+    if output == Output::Csv {
+        println!("{}", csv_row(&DRIFT_CSV_HEADER));
+    }
+    loop {
+        let (timing, result) = timed_query(server);
+        match result {
+            Ok(result) => {
+                samples.push((timing.started + timing.elapsed / 2, result.offset_nanos));
+                let estimate = synchronizer::drift(&samples);
+                match output {
+                    Output::Text => println!(
+                        "{} offset {:+.6} s  delay {:.6} s  {}",
+                        time_of_day(timing.started),
+                        result.offset_nanos as f64 / 1e9,
+                        result.delay_nanos as f64 / 1e9,
+                        estimate
+                            .map(|drift| format!("{:+.3} ppm ± {:.3}", drift.frequency_ppm, drift.confidence_ppm))
+                            .unwrap_or_default()
+                    ),
+                    Output::Csv => println!(
+                        "{}",
+                        csv_row(&[
+                            &timing.started.as_secs_f64().to_string(),
+                            server,
+                            &result.addr.to_string(),
+                            &(result.offset_nanos as f64 / 1e9).to_string(),
+                            &(result.delay_nanos as f64 / 1e9).to_string(),
+                            &estimate.map(|drift| drift.frequency_ppm.to_string()).unwrap_or_default(),
+                            &estimate.map(|drift| drift.confidence_ppm.to_string()).unwrap_or_default(),
+                            "",
+                        ])
+                    ),
+                    Output::Json | Output::Jsonl => {
+                        let mut value = result_json(server, &result, &timing);
+                        value["frequency_ppm"] = json!(estimate.map(|drift| drift.frequency_ppm));
+                        value["confidence_ppm"] = json!(estimate.map(|drift| drift.confidence_ppm));
+                        print_json(&value, output);
+                    }
+                }
+            }
+            Err(err) => match output {
+                Output::Text => eprintln!("{} {}: {:?}", time_of_day(timing.started), server, err),
+                Output::Csv => println!(
+                    "{}",
+                    csv_row(&[&timing.started.as_secs_f64().to_string(), server, "", "", "", "", "", &format!("{:?}", err)])
+                ),
+                Output::Json | Output::Jsonl => print_json(&error_json(server, &err, &timing), output),
+            },
+        }
+        if start.elapsed() + interval > duration {
+            break;
+        }
+        thread::sleep(interval.saturating_sub(timing.elapsed));
+    }
+
+    let drift = synchronizer::drift(&samples)
+        .ok_or_else(|| format!("only {} answers from {}, need at least 3", samples.len(), server))?;
+    match output {
+        Output::Text => println!("{}: local clock {}", server, drift),
+        // The last row already has the final estimate.
+        Output::Csv => {}
+        Output::Json | Output::Jsonl => print_json(
+            &json!({
+                "server": server,
+                "frequency_ppm": drift.frequency_ppm,
+                "skew_ppm": drift.skew_ppm,
+                "confidence_ppm": drift.confidence_ppm,
+                "samples": drift.samples,
+                "span": drift.span.as_secs_f64(),
+            }),
+            output,
+        ),
+    }
+
+    Ok(())
+}
+
+fn compare(servers: &[String], threshold: Duration, output: Output) -> Result<(), String> {
+    let results: Vec<_> = thread::scope(|scope| {
+        let handles: Vec<_> = servers.iter()
+            .map(|server| scope.spawn(move || timed_query(server)))
+            .collect();
+        handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+    });
+
+    let mut answered: Vec<(&String, (Timing, NtpResult))> = Vec::new();
+    let mut failed = Vec::new();
+    for (server, (timing, result)) in servers.iter().zip(results) {
+        match result {
+            Ok(result) => answered.push((server, (timing, result))),
+            Err(err) => failed.push((server, timing, err)),
+        }
+    }
+
+    let median = rank(&mut answered);
+    let falseticker = |result: &NtpResult| {
+        (result.offset_nanos - median).unsigned_abs() as u128 > threshold.as_nanos()
+    };
+
+    match output {
+        Output::Text => {
+            for (server, _, err) in &failed {
+                eprintln!("{}: {:?}", server, err);
+            }
+        }
+        _ => {
+            let ranked: Vec<Value> = answered.iter()
+                .enumerate()
+                .map(|(rank, (server, (timing, result)))| {
+                    let mut value = result_json(server, result, timing);
+                    value["rank"] = json!(rank + 1);
+                    value["disagreement"] = json!((result.offset_nanos - median) as f64 / 1e9);
+                    value["falseticker"] = json!(falseticker(result));
+                    value
+                })
+                .collect();
+            let errors: Vec<Value> = failed.iter()
+                .map(|(server, timing, err)| error_json(server, err, timing))
+                .collect();
+            print_json(
+                &json!({
+                    "median_offset": (!answered.is_empty()).then(|| median as f64 / 1e9),
+                    "servers": ranked,
+                    "errors": errors,
+                }),
+                output,
+            );
+        }
+    }
+    if answered.is_empty() {
+        return Err("no server answered".to_string());
+    }
+    if output != Output::Text {
+        return Ok(());
+    }
+
+    println!(
+        "{:<4} {:<24} {:<40} {:>7} {:>12} {:>10} {:>12}  status",
+        "rank", "server", "address", "stratum", "offset", "delay", "vs median"
+    );
+    for (rank, (server, (_, result))) in answered.iter().enumerate() {
+        let disagreement = result.offset_nanos - median;
+        let status = if falseticker(result) { "falseticker" } else { "ok" };
+        println!(
+            "{:<4} {:<24} {:<40} {:>7} {:>+12.6} {:>10.6} {:>+12.6}  {}",
+            rank + 1,
+            server,
+            result.addr.to_string(),
+            result.stratum,
+            result.offset_nanos as f64 / 1e9,
+            result.delay_nanos as f64 / 1e9,
+            disagreement as f64 / 1e9,
+            status
+        );
+    }
+
+    Ok(())
+}
+
+fn replay(file: &Path, output: Output) -> Result<(), String> {
+    let exchanges = pcap::replay_file(file).map_err(|err| format!("{:?}", err))?;
+    let verbose = VERBOSE.load(Ordering::Relaxed);
+    for (result, record) in exchanges {
+        if verbose > 0 {
+            eprint!("{}", trace(&record, verbose));
+        }
+        let started = record.t1.unwrap_or_default();
+        let timing = Timing { started, elapsed: record.t4.map(|t4| t4.saturating_sub(started)).unwrap_or_default() };
+        match (result, output) {
+            (Ok(result), Output::Text) => println!(
+                "{} {} offset {:+.6} s  delay {:.6} s  stratum {}",
+                time_of_day(started),
+                record.server,
+                result.offset_nanos as f64 / 1e9,
+                result.delay_nanos as f64 / 1e9,
+                result.stratum
+            ),
+            (Err(err), Output::Text) => println!("{} {}: {:?}", time_of_day(started), record.server, err),
+            (Ok(result), _) => print_json(&result_json(&record.server, &result, &timing), output),
+            (Err(err), _) => print_json(&error_json(&record.server, &err, &timing), output),
+        }
+    }
+
+    Ok(())
+}
+
+fn decode(input: &[String], output: Output) -> Result<(), String> {
+    let text = match input {
+        [arg] if arg == "-" => io::read_to_string(io::stdin()).map_err(|err| format!("stdin: {}", err))?,
+        [arg] if Path::new(arg).is_file() => {
+            let data = std::fs::read(arg).map_err(|err| format!("{}: {}", arg, err))?;
+            if let Ok(datagrams) = pcap::read(&data[..]) {
+                let ntp: Vec<_> = datagrams.iter().filter(|d| d.from.port() == 123 || d.to.port() == 123).collect();
+                if ntp.is_empty() {
+                    return Err(format!("{}: no datagrams to or from port 123", arg));
+                }
+                if output != Output::Text {
+                    let packets = ntp.iter().map(|datagram| {
+                        let mut value = packet_json(&datagram.payload);
+                        value["time"] = json!(datagram.time.as_secs_f64());
+                        value["from"] = json!(datagram.from.to_string());
+                        value["to"] = json!(datagram.to.to_string());
+                        value
+                    });
+                    match output {
+                        Output::Jsonl => packets.for_each(|value| print_json(&value, output)),
+                        _ => print_json(&Value::Array(packets.collect()), output),
+                    }
+                    return Ok(());
+                }
+                for (i, datagram) in ntp.iter().enumerate() {
+                    if i > 0 {
+                        println!();
+                    }
+                    println!("{} {} -> {} ({} bytes)", time_of_day(datagram.time), datagram.from, datagram.to, datagram.payload.len());
+                    print_packet(&datagram.payload);
+                }
+                return Ok(());
+            }
+            String::from_utf8(data).map_err(|_| format!("{}: neither a pcap capture nor hex", arg))?
+        }
+        _ => input.join(" "),
+    };
+    let packet = parse_hex(&text)?;
+    match output {
+        Output::Text => print_packet(&packet),
+        _ => print_json(&packet_json(&packet), output),
+    }
+    Ok(())
+}
+
+/// Every field of `packet`, delays in seconds and timestamps in seconds since
+/// the unix epoch, or the reason it does not parse.
+fn packet_json(packet: &[u8]) -> Value {
+    let hex = |bytes: &[u8]| bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    let time = |t: u64| protocol::ntp_timestamp_to_duration(t).as_secs_f64();
+    match NtpPacket::parse(packet) {
+        Ok(parsed) => {
+            let header = &parsed.header;
+            json!({
+                "length": packet.len(),
+                "leap": header.leap_indicator,
+                "version": header.version_number,
+                "mode": header.mode,
+                "stratum": header.stratum,
+                "poll": header.poll as i8,
+                "precision": header.precision as i8,
+                "root_delay": ShortFormat(header.root_delay).as_secs_f64(),
+                "root_dispersion": ShortFormat(header.root_dispersion).as_secs_f64(),
+                "reference_id": header.reference_identifier,
+                "reference": time(header.reference_timestamp),
+                "originate": time(header.originate_timestamp),
+                "receive": time(header.receiver_timestamp),
+                "transmit": time(header.transmit_timestamp),
+                "extensions": parsed.extensions.iter()
+                    .map(|extension| json!({ "type": extension.field_type, "value": hex(&extension.value) }))
+                    .collect::<Vec<_>>(),
+                "mac": parsed.mac.map(|mac| json!({ "key_id": mac.key_id, "digest": hex(&mac.digest) })),
+            })
+        }
+        Err(err) => json!({ "length": packet.len(), "error": format!("{:?}", err) }),
+    }
+}
+
+/// The annotated hexdump of `packet`, and why a strict parse rejects it, if it does.
+fn print_packet(packet: &[u8]) {
+    print!("{}", protocol::hexdump(packet));
+    if let Err(err) = NtpPacket::parse(packet) {
+        println!("invalid: {:?}", err);
+    }
+}
+
+/// Bytes of hex digits, ignoring whitespace, `:` separators and `0x` prefixes.
+fn parse_hex(text: &str) -> Result<Vec<u8>, String> {
+    let digits: Vec<u8> = text.split_whitespace()
+        .flat_map(|word| word.split(':'))
+        .map(|word| word.strip_prefix("0x").unwrap_or(word))
+        .flat_map(|word| word.bytes())
+        .collect();
+    if digits.is_empty() {
+        return Err("no bytes to decode".to_string());
+    }
+    if !digits.len().is_multiple_of(2) {
+        return Err(format!("odd number of hex digits ({})", digits.len()));
+    }
+    digits.chunks(2)
+        .map(|pair| {
+            let pair = std::str::from_utf8(pair).unwrap_or("");
+            u8::from_str_radix(pair, 16).map_err(|_| format!("not hex: `{}`", pair))
+        })
+        .collect()
+}
+
+/// Sort by distance from the median offset, closest (and then fastest) first.
+/// Returns the median offset in nanoseconds, 0 if `answered` is empty.
+fn rank<T, U>(answered: &mut [(T, (U, NtpResult))]) -> i64 {
+    let mut offsets: Vec<i64> = answered.iter().map(|(_, (_, result))| result.offset_nanos).collect();
+    offsets.sort_unstable();
+    let median = offsets.get(offsets.len() / 2).copied().unwrap_or_default();
+    answered.sort_by_key(|(_, (_, result))| ((result.offset_nanos - median).abs(), result.delay_nanos));
+    median
+}
+
+fn ntpdate(args: &NtpdateArgs) -> Result<(), String> {
+    let timeout = Duration::try_from_secs_f64(args.timeout)
+        .ok()
+        .filter(|timeout| !timeout.is_zero())
+        .ok_or_else(|| format!("invalid timeout `{}`", args.timeout))?;
+    let family = match (args.ipv4, args.ipv6) {
+        (true, _) => IpFamily::V4,
+        (_, true) => IpFamily::V6,
+        _ => IpFamily::Any,
+    };
+    let client = SntpClient::builder().timeout(timeout).family(family).build();
+
+    let results: Vec<_> = thread::scope(|scope| {
+        let handles: Vec<_> = args.servers.iter()
+            .map(|server| {
+                let client = &client;
+                scope.spawn(move || client.query(server))
+            })
+            .collect();
+        handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+    });
+
+    // Like ntpdate, print every server and pick the synchronized one with the lowest delay.
+    let mut best: Option<NtpResult> = None;
+    for (server, result) in args.servers.iter().zip(results) {
+        match result {
+            Ok(result) => {
+                println!(
+                    "server {}, stratum {}, offset {:+.6}, delay {:.5}",
+                    result.addr.ip(),
+                    result.stratum,
+                    result.offset_nanos as f64 / 1e9,
+                    result.delay_nanos as f64 / 1e9
+                );
+                if result.stratum != 0 && best.as_ref().is_none_or(|best| result.delay_nanos < best.delay_nanos) {
+                    best = Some(result);
+                }
+            }
+            Err(err) => eprintln!("{}: {:?}", server, err),
+        }
+    }
+    let best = best.ok_or_else(|| "no server suitable for synchronization found".to_string())?;
+
+    if !args.query_only {
+        clock::step(best.offset_nanos).map_err(|err| format!("failed to step clock: {:?}", err))?;
+    }
+    println!(
+        "ntpdate[{}]: {} time server {} offset {:+.6} sec",
+        process::id(),
+        if args.query_only { "adjust" } else { "step" },
+        best.addr.ip(),
+        best.offset_nanos as f64 / 1e9
+    );
+
+    Ok(())
+}
+
+/// `serve`, optionally in the background and with a pidfile.
+#[cfg(unix)]
+fn serve_daemon(args: &ServeArgs, daemon: &DaemonArgs, output: Output) -> Result<(), String> {
+    // Resolved up front, daemonizing changes the working directory to `/`.
+    let absolute = |path: &PathBuf| std::path::absolute(path).map_err(|err| format!("{}: {}", path.display(), err));
+    let pidfile = daemon.pidfile.as_ref().map(absolute).transpose()?;
+    let log_file = daemon.log_file.as_ref().map(absolute).transpose()?;
+    let mut args = args.clone();
+    args.config = args.config.as_ref().map(absolute).transpose()?;
+    args.control = args.control.as_ref().map(absolute).transpose()?;
+
+    if let Some(pidfile) = &pidfile {
+        daemon::Pidfile::check(pidfile)?;
+    }
+    if daemon.daemon {
+        daemon::daemonize(log_file.as_deref())?;
+    }
+    let _pidfile = pidfile.as_deref().map(daemon::Pidfile::create).transpose()?;
+
+    serve(&args, output, daemon::stop_on_signals(), daemon::reload_on_sighup())
+}
+
+/// Run the server until it fails or `stop` is set, reloading `--config` when `reload` is set.
+fn serve(args: &ServeArgs, output: Output, stop: &AtomicBool, reload: &AtomicBool) -> Result<(), String> {
+    let mut running = args.config()?;
+
+    let mut builder = running.server_builder();
+    let mut upstream = None;
+    if !running.servers.is_empty() {
+        let sync = running.synchronizer_builder().start().map_err(|err| format!("{:?}", err))?;
+        upstream = Some(sync.handle());
+        builder = builder.upstream(sync);
+    }
+
+    let server = builder.build().map_err(|err| format!("{}: {:?}", running.serve.bind, err))?;
+    let local_addr = server.local_addr().map_err(|err| format!("{:?}", err))?;
+    match output {
+        Output::Text => println!("serving on {}", local_addr),
+        _ => print_json(&json!({ "serving": local_addr.to_string() }), output),
+    }
+    let server = server.spawn().map_err(|err| format!("{:?}", err))?;
+    #[cfg(unix)]
+    let _control = match (&args.control, &upstream) {
+        (Some(path), Some(sync)) => Some(control::spawn(path, sync.clone()).map_err(|err| format!("{:?}", err))?),
+        (Some(_), None) => return Err("--control needs upstream servers".to_string()),
+        (None, _) => None,
+    };
+    match (&args.http, &upstream) {
+        (Some(addr), Some(sync)) => {
+            status::serve_http(addr.as_str(), sync.clone()).map_err(|err| format!("{}: {:?}", addr, err))?;
+        }
+        (Some(_), None) => return Err("--http needs upstream servers".to_string()),
+        (None, _) => {}
+    }
+
+    // Report readiness, status and watchdog keepalives to systemd while the server runs.
+    let watchdog = watchdog_interval();
+    let tick = watchdog.map_or(SUPERVISE_INTERVAL, |watchdog| (watchdog / 2).min(SUPERVISE_INTERVAL));
+    let mut ready = false;
+    let mut status = String::new();
+    while server.is_running() {
+        if stop.load(Ordering::Relaxed) {
+            server.stop();
+            return Ok(());
+        }
+        if reload.swap(false, Ordering::Relaxed) {
+            match reload_config(args, &running, &server, upstream.as_ref()) {
+                Ok(reloaded) => running = reloaded,
+                Err(err) => eprintln!("sntp: reload failed, keeping the running configuration: {}", err),
+            }
+        }
+        let (synchronized, current) = match upstream.as_ref().map(SyncHandle::selected) {
+            None => (true, format!("serving the local clock at stratum {}", running.serve.local_stratum)),
+            Some(None) => (false, "waiting for the first upstream sync".to_string()),
+            Some(Some((result, _))) => (
+                true,
+                format!("offset {:+.6} s to {}, stratum {}", result.offset_nanos as f64 / 1e9, result.addr, result.stratum),
+            ),
+        };
+
+        let mut state = Vec::new();
+        if synchronized && !ready {
+            state.push("READY=1".to_string());
+            ready = true;
+        }
+        if current != status {
+            state.push(format!("STATUS={}", current));
+            status = current;
+        }
+        if watchdog.is_some() {
+            state.push("WATCHDOG=1".to_string());
+        }
+        if !state.is_empty() {
+            if let Err(err) = sd_notify(&state.join("\n")) {
+                eprintln!("sntp: sd_notify failed: {}", err);
+            }
+        }
+        thread::sleep(tick);
+    }
+
+    Err("server stopped".to_string())
+}
+
+#[cfg(unix)]
+fn ctl(socket: &Path, request: &CtlRequest, output: Output) -> Result<(), String> {
+    let mut request = match request {
+        CtlRequest::Status { format: StatusFormat::Summary } => "status".to_string(),
+        CtlRequest::Status { format: StatusFormat::Tracking } => "tracking".to_string(),
+        CtlRequest::Sources => "sources".to_string(),
+        CtlRequest::Poll => "poll".to_string(),
+        CtlRequest::Add { server } => format!("add {}", server),
+        CtlRequest::Remove { server } => format!("remove {}", server),
+    };
+    let structured = matches!(request.as_str(), "status" | "tracking" | "sources");
+    if output != Output::Text && structured {
+        request += " json";
+    }
+    let reply = control::request(socket, &request).map_err(|err| match err {
+        NtpError::UnexpectedErr(reason) => reason,
+        err => format!("{:?}", err),
+    })?;
+    match output {
+        Output::Text => print!("{}", reply),
+        _ if structured => {
+            let value: Value = serde_json::from_str(&reply).map_err(|err| format!("malformed reply: {}", err))?;
+            print_json(&value, output);
+        }
+        _ => print_json(&json!({ "ok": true }), output),
+    }
+    Ok(())
+}
+
+fn import(path: &Path) -> Result<(), String> {
+    let content = std::fs::read_to_string(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    let (config, skipped) = Config::import(&content);
+    for line in skipped {
+        eprintln!("sntp: skipped {}", line);
+    }
+    print!("{}", config.to_toml());
+    Ok(())
+}
+
+/// Re-read `--config` and apply it to the running server and synchronizer.
+fn reload_config(
+    args: &ServeArgs,
+    running: &Config,
+    server: &ServerHandle,
+    upstream: Option<&SyncHandle>,
+) -> Result<Config, String> {
+    let config = args.config()?;
+    if config.serve.bind != running.serve.bind
+        || config.servers.is_empty() != running.servers.is_empty()
+        || (config.servers.is_empty() && config.serve.local_stratum != running.serve.local_stratum)
+    {
+        return Err("bind, local stratum or switching between upstream and local clock need a restart".to_string());
+    }
+
+    config.reload(server, upstream).map_err(|err| format!("{:?}", err))?;
+    println!("reloaded {}", args.config.as_deref().map_or("configuration".into(), Path::to_string_lossy));
+
+    Ok(config)
+}
+
+/// How often `serve` checks the server and upstream state.
+const SUPERVISE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[cfg(unix)]
+fn sd_notify(state: &str) -> Result<bool, String> {
+    systemd::notify(state).map_err(|err| format!("{:?}", err))
+}
+
+#[cfg(not(unix))]
+fn sd_notify(_state: &str) -> Result<bool, String> {
+    Ok(false)
+}
+
+#[cfg(unix)]
+fn watchdog_interval() -> Option<Duration> {
+    systemd::watchdog_interval()
+}
+
+#[cfg(not(unix))]
+fn watchdog_interval() -> Option<Duration> {
+    None
+}
+
+/// When a query was started, since the unix epoch, and how long it took.
+struct Timing {
+    started: Duration,
+    elapsed: Duration,
+}
+
+fn timed_query(server: &str) -> (Timing, Result<NtpResult, NtpError>) {
+    let started = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let instant = Instant::now();
+    let verbose = VERBOSE.load(Ordering::Relaxed);
+    let client = CLIENT.get_or_init(SntpClient::default);
+    let result = if verbose == 0 {
+        client.query(server)
+    } else {
+        let (result, record) = client.query_audited(server);
+        eprint!("{}", trace(&record, verbose));
+        result
+    };
+    (Timing { started, elapsed: instant.elapsed() }, result)
+}
+
+/// The `-v` trace of one exchange; timestamps are local times since the unix epoch.
+fn trace(record: &AuditRecord, verbose: u8) -> String {
+    let mut out = format!(
+        "{} ({})\n",
+        record.server,
+        record.addr.map(|addr| addr.to_string()).unwrap_or_else(|| "unresolved".to_string())
+    );
+    for (name, t) in [("t1", record.t1), ("t2", record.t2), ("t3", record.t3), ("t4", record.t4)] {
+        if let Some(t) = t {
+            out += &format!("  {} {}.{:09}\n", name, t.as_secs(), t.subsec_nanos());
+        }
+    }
+    for verdict in &record.verdicts {
+        out += &format!("  {:?} {}\n", verdict.check, if verdict.passed { "passed" } else { "FAILED" });
+    }
+    let timing = &record.timing;
+    out += &format!(
+        "  resolve {:?}, socket {:?}, round trip {:?}, validate {:?}\n",
+        timing.resolve, timing.socket, timing.round_trip, timing.validate
+    );
+    if verbose >= 2 {
+        for (name, packet) in [("request", &record.request), ("response", &record.response)] {
+            if packet.is_empty() {
+                continue;
+            }
+            out += &format!("  {} ({} bytes)\n", name, packet.len());
+            for line in protocol::hexdump(packet).lines() {
+                out += &format!("    {}\n", line);
+            }
+        }
+    }
+    out
+}
+
+/// Every field of `result`, offset and delays in seconds, plus the query timing.
+/// Every field of `result` and the timing, in seconds; times since the unix epoch,
+/// a reference time of a server never synchronized is null.
+fn result_json(server: &str, result: &NtpResult, timing: &Timing) -> Value {
+    let (t1, t2, t3, t4) = result.timestamps;
+    json!({
+        "time": timing.started.as_secs_f64(),
+        "elapsed": timing.elapsed.as_secs_f64(),
+        "server": server,
+        "addr": result.addr.to_string(),
+        "leap": result.leap_indicator,
+        "version": result.version,
+        "mode": result.mode,
+        "stratum": result.stratum,
+        "poll": result.poll.exponent(),
+        "precision": result.precision,
+        "refid": result.refid(),
+        "reference_id": result.reference_id,
+        "root_delay": result.root_delay.as_secs_f64(),
+        "root_dispersion": result.root_dispersion.as_secs_f64(),
+        "reference_time": (result.reference_time != Duration::ZERO).then_some(result.reference_time.as_secs_f64()),
+        "t1": t1.as_secs_f64(),
+        "t2": t2.as_secs_f64(),
+        "t3": t3.as_secs_f64(),
+        "t4": t4.as_secs_f64(),
+        "offset": result.offset_nanos as f64 / 1e9,
+        "delay": result.delay_nanos as f64 / 1e9,
+    })
+}
+
+fn error_json(server: &str, err: &NtpError, timing: &Timing) -> Value {
+    json!({
+        "time": timing.started.as_secs_f64(),
+        "elapsed": timing.elapsed.as_secs_f64(),
+        "server": server,
+        "error": format!("{:?}", err),
+    })
+}
+
+/// Pretty-printed for `--output json`, a single line for `--output jsonl`.
+fn print_json(value: &Value, output: Output) {
+    if output == Output::Jsonl {
+        println!("{}", value);
+    } else {
+        println!("{}", serde_json::to_string_pretty(value).unwrap());
+    }
+}
+
+/// Join `fields` with commas, quoting those that contain a comma, quote or newline.
+fn csv_row(fields: &[&str]) -> String {
+    fields.iter()
+        .map(|field| {
+            if field.contains([',', '"', '\n', '\r']) {
+                format!("\"{}\"", field.replace('"', "\"\""))
+            } else {
+                field.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn print_result(server: &str, result: &NtpResult) {
+    println!("{:<16}{} ({})", "server", server, result.addr);
+    println!("{:<16}{:+.6} s", "offset", result.offset_nanos as f64 / 1e9);
+    println!("{:<16}{:.6} s", "delay", result.delay_nanos as f64 / 1e9);
+    println!("{:<16}{}", "stratum", result.stratum);
+    println!("{:<16}{}", "refid", result.refid());
+    println!("{:<16}{:.6} s", "root delay", result.root_delay.as_secs_f64());
+    println!("{:<16}{:.6} s", "root dispersion", result.root_dispersion.as_secs_f64());
+}
+
+/// Parse `250ms`, `10s`, `5m`, `1h` or plain seconds like `1.5`.
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    config::parse_duration(s).map_err(|_| format!("invalid duration `{}`", s))
+}
+
+fn parse_net(s: &str) -> Result<IpNet, String> {
+    s.parse().map_err(|err| format!("{:?}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spark() {
+        assert_eq!(spark(&VecDeque::from(vec![0.0, 0.5, 1.0])), "▁▅█");
+        assert_eq!(spark(&VecDeque::from(vec![2.0, 2.0])), "▄▄");
+        assert_eq!(jitter(&VecDeque::from(vec![0.0, 3.0, 0.0])), 3.0);
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_rank() {
+        let sample = simple_ntp::testing::answer;
+        let mut answered = [
+            ("far", ((), sample(5_000_000_000, 1_000))),
+            ("slow", ((), sample(1_000, 9_000))),
+            ("fast", ((), sample(-1_000, 2_000))),
+            ("median", ((), sample(0, 5_000))),
+        ];
+        assert_eq!(rank(&mut answered), 1_000);
+        let names: Vec<_> = answered.iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, ["slow", "median", "fast", "far"]);
+        assert_eq!(rank::<(), ()>(&mut []), 0);
+    }
+
+    #[test]
+    fn test_check_status() {
+        let warn = Duration::from_millis(50);
+        let crit = Duration::from_millis(250);
+        assert_eq!(check_status(50_000_000, warn, crit), (0, "OK"));
+        assert_eq!(check_status(-60_000_000, warn, crit), (1, "WARNING"));
+        assert_eq!(check_status(300_000_000, warn, crit), (2, "CRITICAL"));
+    }
+
+    #[test]
+    fn test_parse_hex() {
+        assert_eq!(parse_hex("24 02 0x03 e7\n"), Ok(vec![0x24, 0x02, 0x03, 0xe7]));
+        assert_eq!(parse_hex("24:02:03:E7"), Ok(vec![0x24, 0x02, 0x03, 0xe7]));
+        assert_eq!(parse_hex("240203e7"), Ok(vec![0x24, 0x02, 0x03, 0xe7]));
+        assert!(parse_hex("240").unwrap_err().contains("odd"));
+        assert!(parse_hex("2g").unwrap_err().contains("`2g`"));
+        assert!(parse_hex(" ").is_err());
+    }
+
+    #[test]
+    fn test_csv_row() {
+        assert_eq!(csv_row(&CSV_HEADER).split(',').count(), CSV_HEADER.len());
+        assert_eq!(csv_row(&["a", "", "1.5"]), "a,,1.5");
+        assert_eq!(csv_row(&["Err(\"a, b\")", "x"]), "\"Err(\"\"a, b\"\")\",x");
+    }
+
+    #[test]
+    fn test_serve_config() {
+        let path = std::env::temp_dir().join(format!("sntp-test-{}.toml", std::process::id()));
+        let cli = Cli::try_parse_from(["sntp", "serve", "--config", path.to_str().unwrap(), "--kod", "--poll", "2m", "-g"]).unwrap();
+        let Command::Serve { serve: args, .. } = cli.command else {
+            panic!("not serve");
+        };
+
+        std::fs::write(&path, "[[server]]\naddress = \"a.example\"\n[poll]\ninterval = \"16s\"\n[serve]\ndeny = [\"10.0.0.0/8\"]\n").unwrap();
+        let config = args.config().unwrap();
+        assert_eq!(config.servers, [ServerConfig::new("a.example")]);
+        assert_eq!(config.poll.interval, Duration::from_secs(120));
+        assert_eq!(config.serve.deny.len(), 1);
+        assert!(config.serve.kod && config.clock.trust_first_sync);
+
+        std::fs::write(&path, "pool = 2\n").unwrap();
+        assert!(args.config().unwrap_err().contains("unknown field"));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,197 @@
+//! Running `sntp serve` as a Windows service.
+//!
+//! `sntp service install [serve options]` registers an automatically started
+//! service whose command line is `sntp service run [serve options]`. The service
+//! control manager then starts it, stops it through the control handler, and
+//! everything logged (including the library's diagnostics) goes to the
+//! Application event log under the `sntp` source.
+
+use std::ffi::OsString;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use windows_service::service::{
+    ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode, ServiceInfo,
+    ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+};
+use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+use windows_service::{define_windows_service, service_dispatcher};
+use windows_sys::Win32::Foundation::HANDLE;
+use windows_sys::Win32::System::EventLog::{
+    RegisterEventSourceW, ReportEventW, EVENTLOG_ERROR_TYPE, EVENTLOG_INFORMATION_TYPE, EVENTLOG_WARNING_TYPE,
+};
+
+use crate::{serve, Output, ServeArgs};
+
+const SERVICE_NAME: &str = "sntp";
+const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
+
+/// Options of the `run` command line, read by the service thread.
+static SERVE_ARGS: OnceLock<ServeArgs> = OnceLock::new();
+
+define_windows_service!(ffi_service_main, service_main);
+
+/// Register the service, passing `serve_args` through to `sntp serve`.
+pub fn install(serve_args: Vec<OsString>) -> Result<(), String> {
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )
+    .map_err(|err| format!("failed to open the service manager: {}", err))?;
+
+    let mut launch_arguments = vec![OsString::from("service"), OsString::from("run")];
+    launch_arguments.extend(serve_args);
+    let info = ServiceInfo {
+        name: SERVICE_NAME.into(),
+        display_name: "SNTP time service".into(),
+        service_type: SERVICE_TYPE,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: std::env::current_exe().map_err(|err| err.to_string())?,
+        launch_arguments,
+        dependencies: Vec::new(),
+        account_name: None,
+        account_password: None,
+    };
+    let service = manager
+        .create_service(&info, ServiceAccess::CHANGE_CONFIG)
+        .map_err(|err| format!("failed to create service {}: {}", SERVICE_NAME, err))?;
+    service
+        .set_description("Synchronizes with upstream SNTP servers and serves time to the network.")
+        .map_err(|err| err.to_string())?;
+
+    Ok(())
+}
+
+/// Stop the service if it is running and remove it.
+pub fn uninstall() -> Result<(), String> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+        .map_err(|err| format!("failed to open the service manager: {}", err))?;
+    let service = manager
+        .open_service(SERVICE_NAME, ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE)
+        .map_err(|err| format!("failed to open service {}: {}", SERVICE_NAME, err))?;
+
+    let status = service.query_status().map_err(|err| err.to_string())?;
+    if status.current_state != ServiceState::Stopped {
+        service.stop().map_err(|err| err.to_string())?;
+    }
+    service.delete().map_err(|err| err.to_string())
+}
+
+/// Entry point of `sntp service run`, blocks until the service is stopped.
+pub fn run(args: ServeArgs) -> Result<(), String> {
+    let _ = SERVE_ARGS.set(args);
+    service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+        .map_err(|err| format!("not started by the service control manager: {}", err))
+}
+
+fn service_main(_arguments: Vec<OsString>) {
+    if let Some(logger) = EventLog::open(SERVICE_NAME) {
+        if log::set_logger(Box::leak(Box::new(logger))).is_ok() {
+            log::set_max_level(log::LevelFilter::Info);
+        }
+    }
+    if let Err(err) = run_service() {
+        log::error!("service failed: {}", err);
+    }
+}
+
+fn run_service() -> Result<(), String> {
+    let args = SERVE_ARGS.get().ok_or("missing serve options")?;
+    let stop = Arc::new(AtomicBool::new(false));
+    let reload = Arc::new(AtomicBool::new(false));
+    let (stopping, reloading) = (stop.clone(), reload.clone());
+    let status_handle = service_control_handler::register(SERVICE_NAME, move |control| match control {
+        ServiceControl::Stop | ServiceControl::Shutdown => {
+            stopping.store(true, Ordering::Relaxed);
+            ServiceControlHandlerResult::NoError
+        }
+        // `sc control sntp paramchange` re-reads the --config file.
+        ServiceControl::ParamChange => {
+            reloading.store(true, Ordering::Relaxed);
+            ServiceControlHandlerResult::NoError
+        }
+        ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+        _ => ServiceControlHandlerResult::NotImplemented,
+    })
+    .map_err(|err| err.to_string())?;
+
+    let status = |current_state, controls_accepted, exit_code| ServiceStatus {
+        service_type: SERVICE_TYPE,
+        current_state,
+        controls_accepted,
+        exit_code: ServiceExitCode::Win32(exit_code),
+        checkpoint: 0,
+        wait_hint: Duration::ZERO,
+        process_id: None,
+    };
+    status_handle
+        .set_service_status(status(
+            ServiceState::Running,
+            ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN | ServiceControlAccept::PARAM_CHANGE,
+            0,
+        ))
+        .map_err(|err| err.to_string())?;
+
+    log::info!("service started");
+    let result = serve(args, Output::Text, &stop, &reload);
+    if let Err(err) = &result {
+        log::error!("{}", err);
+    }
+    status_handle
+        .set_service_status(status(
+            ServiceState::Stopped,
+            ServiceControlAccept::empty(),
+            if result.is_ok() { 0 } else { 1 },
+        ))
+        .map_err(|err| err.to_string())
+}
+
+/// `log` backend writing to the Application event log.
+struct EventLog {
+    source: HANDLE,
+}
+
+// The event source handle may be used from any thread.
+unsafe impl Send for EventLog {}
+unsafe impl Sync for EventLog {}
+
+impl EventLog {
+    fn open(source: &str) -> Option<Self> {
+        let source = wide(source);
+        let handle = unsafe { RegisterEventSourceW(ptr::null(), source.as_ptr()) };
+        (!handle.is_null()).then_some(EventLog { source: handle })
+    }
+}
+
+impl log::Log for EventLog {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Info
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let kind = match record.level() {
+            log::Level::Error => EVENTLOG_ERROR_TYPE,
+            log::Level::Warn => EVENTLOG_WARNING_TYPE,
+            _ => EVENTLOG_INFORMATION_TYPE,
+        };
+        let message = wide(&record.args().to_string());
+        let strings = [message.as_ptr()];
+        unsafe {
+            ReportEventW(self.source, kind, 0, 0, ptr::null_mut(), 1, 0, strings.as_ptr(), ptr::null());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// NUL-terminated UTF-16.
+fn wide(s: &str) -> Vec<u16> {
+    s.encode_utf16().chain(Some(0)).collect()
+}
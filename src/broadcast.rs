@@ -0,0 +1,483 @@
+//! Broadcast and multicast NTP client.
+//!
+//! A [`BroadcastClient`] listens for the mode 5 messages a server sends to a
+//! LAN's broadcast address or a multicast group, 224.0.1.1 for NTP, every poll
+//! interval. Those carry only the server's transmit time, so the delay from
+//! server to client is unknown. As RFC 5905 section 8 prescribes, the first
+//! broadcast of a server starts a calibration: a few ordinary client/server
+//! exchanges with it, the shortest round trip of which is kept. Each later
+//! broadcast then yields an offset of transmit time plus half that delay
+//! minus arrival time.
+//!
+//! Anyone on the LAN can broadcast, so a client that should follow known
+//! servers only names them with [`BroadcastClientBuilder::server`]. A server
+//! whose calibration failed is not retried before a backoff, and at most
+//! [`MAX_BROADCAST_SERVERS`] servers are tracked at once.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::client::{SntpClient, DEFAULT_MIN_INTERVAL};
+use crate::protocol::{ntp_timestamp_to_duration, NtpError, NtpMsg, NTP_VERSION_3, NTP_VERSION_4};
+
+/// NTP's IPv4 multicast group.
+pub const NTP_MULTICAST_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 1, 1);
+
+const NTP_MODE_BROADCAST: u8 = 5;
+
+/// How often the listening thread checks whether the client was stopped.
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Servers calibrated or waiting to be retried at once; broadcasts of others are ignored.
+pub const MAX_BROADCAST_SERVERS: usize = 64;
+
+/// Wait after a failed calibration before the next, doubled on each failure.
+const CALIBRATION_BACKOFF: Duration = Duration::from_secs(64);
+const MAX_CALIBRATION_BACKOFF: Duration = Duration::from_secs(4096);
+
+/// The latest offset derived from a broadcast.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BroadcastSample {
+    /// Address the broadcast came from.
+    pub server: SocketAddr,
+    pub stratum: u8,
+    /// System clock offset in nano seconds, server time sub local time.
+    pub offset_nanos: i64,
+    /// Round-trip delay measured when calibrating, half of it is assumed one way.
+    pub delay_nanos: i64,
+    /// Local time the broadcast was received, since the Unix epoch.
+    pub received: Duration,
+}
+
+/// Configure a [`BroadcastClient`].
+pub struct BroadcastClientBuilder {
+    bind: String,
+    multicast: Option<(Ipv4Addr, Ipv4Addr)>,
+    calibration: usize,
+    client: SntpClient,
+    servers: Vec<IpAddr>,
+}
+
+impl BroadcastClientBuilder {
+    /// Address to receive broadcasts on, `0.0.0.0:123` by default.
+    pub fn bind(mut self, addr: &str) -> Self {
+        self.bind = addr.to_string();
+        self
+    }
+
+    /// Join the multicast `group`, e.g. [`NTP_MULTICAST_GROUP`], on the
+    /// interface with address `interface`, `0.0.0.0` for the kernel's choice.
+    /// Only broadcasts are received by default.
+    pub fn multicast(mut self, group: Ipv4Addr, interface: Ipv4Addr) -> Self {
+        self.multicast = Some((group, interface));
+        self
+    }
+
+    /// Exchanges with a newly heard server to measure the delay to it, 3 by
+    /// default, [`DEFAULT_MIN_INTERVAL`] apart. With 0 the delay is taken to
+    /// be zero.
+    pub fn calibration(mut self, exchanges: usize) -> Self {
+        self.calibration = exchanges;
+        self
+    }
+
+    /// Client for the calibration exchanges, [`SntpClient::default`] by default.
+    pub fn client(mut self, client: SntpClient) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Only accept broadcasts from `addr`, may be called again for more
+    /// servers. Broadcasts from any address are accepted by default.
+    pub fn server(mut self, addr: IpAddr) -> Self {
+        self.servers.push(addr);
+        self
+    }
+
+    /// Bind the socket and start listening on a background thread.
+    pub fn start(self) -> Result<BroadcastClient, NtpError> {
+        let socket = UdpSocket::bind(&self.bind).map_err(|err| {
+            NtpError::ServiceUnavailable(format!("bind {}: {}", self.bind, err))
+        })?;
+        if let Some((group, interface)) = self.multicast {
+            socket.join_multicast_v4(&group, &interface).map_err(|err| {
+                NtpError::ServiceUnavailable(format!("join {} on {}: {}", group, interface, err))
+            })?;
+        }
+        socket.set_read_timeout(Some(STOP_POLL_INTERVAL)).map_err(|err| {
+            NtpError::UnexpectedErr(err.to_string())
+        })?;
+        let local_addr = socket.local_addr().map_err(|err| {
+            NtpError::UnexpectedErr(err.to_string())
+        })?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let state = Arc::new(Mutex::new(State::default()));
+        let listener = Listener {
+            socket,
+            calibration: self.calibration,
+            client: self.client,
+            servers: self.servers,
+            state: state.clone(),
+            stop: stop.clone(),
+        };
+        let worker = thread::Builder::new()
+            .name("sntp-broadcast".to_string())
+            .spawn(move || listener.listen())
+            .map_err(|err| {
+                NtpError::UnexpectedErr(err.to_string())
+            })?;
+
+        Ok(BroadcastClient {
+            local_addr,
+            state,
+            stop,
+            worker: Some(worker),
+        })
+    }
+}
+
+/// Listens for broadcast servers on a background thread until stopped or dropped.
+///
+/// Example
+/// ```rust,no_run
+/// # use std::net::Ipv4Addr;
+/// # use std::time::Duration;
+/// # use simple_ntp::broadcast::{BroadcastClient, NTP_MULTICAST_GROUP};
+///
+/// fn main() {
+///     let client = BroadcastClient::builder()
+///         .multicast(NTP_MULTICAST_GROUP, Ipv4Addr::UNSPECIFIED)
+///         .start()
+///         .unwrap();
+///     std::thread::sleep(Duration::from_secs(300));
+///     if let Some(sample) = client.sample() {
+///         println!("{} says we are {}ns off", sample.server, sample.offset_nanos);
+///     }
+/// }
+/// ```
+#[derive(Debug)]
+pub struct BroadcastClient {
+    local_addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    stop: Arc<AtomicBool>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl BroadcastClient {
+    pub fn builder() -> BroadcastClientBuilder {
+        BroadcastClientBuilder {
+            bind: "0.0.0.0:123".to_string(),
+            multicast: None,
+            calibration: 3,
+            client: SntpClient::default(),
+            servers: Vec::new(),
+        }
+    }
+
+    /// Address the socket is bound to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// The latest sample of any server, `None` until a calibrated server broadcast.
+    pub fn sample(&self) -> Option<BroadcastSample> {
+        self.state.lock().unwrap().sample
+    }
+
+    /// Round-trip delay to `server` measured when calibrating, `None` if not (yet) calibrated.
+    pub fn delay_nanos(&self, server: SocketAddr) -> Option<i64> {
+        self.state.lock().unwrap().delays.get(&server).copied()
+    }
+
+    /// Stop listening and wait for the thread to exit.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Drop for BroadcastClient {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Calibrated round-trip delay of each server heard.
+    delays: HashMap<SocketAddr, i64>,
+    /// When servers whose calibration failed may be tried again, and the backoff after that.
+    failed: HashMap<SocketAddr, (Instant, Duration)>,
+    sample: Option<BroadcastSample>,
+}
+
+impl State {
+    /// Whether `server`, not calibrated yet, may be calibrated `now`.
+    fn may_calibrate(&self, server: SocketAddr, now: Instant) -> bool {
+        match self.failed.get(&server) {
+            Some((retry, _)) => now >= *retry,
+            None => self.delays.len() + self.failed.len() < MAX_BROADCAST_SERVERS,
+        }
+    }
+
+    /// Remember the outcome of calibrating `server`, backing off if it failed.
+    fn calibrated(&mut self, server: SocketAddr, delay: Option<i64>, now: Instant) {
+        match delay {
+            Some(delay) => {
+                self.failed.remove(&server);
+                self.delays.insert(server, delay);
+            }
+            None => {
+                let backoff = self.failed.get(&server).map_or(CALIBRATION_BACKOFF, |(_, backoff)| *backoff);
+                self.failed.insert(server, (now + backoff, (backoff * 2).min(MAX_CALIBRATION_BACKOFF)));
+            }
+        }
+    }
+}
+
+struct Listener {
+    socket: UdpSocket,
+    calibration: usize,
+    client: SntpClient,
+    servers: Vec<IpAddr>,
+    state: Arc<Mutex<State>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl Listener {
+    fn listen(&self) {
+        let mut buf = [0u8; 1500];
+        while !self.stop.load(Ordering::Relaxed) {
+            let (n, from) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => continue,
+                Err(err) => {
+                    debug!("broadcast recv failed: {}", err);
+                    continue;
+                }
+            };
+            let received = self.client.time.now();
+            let Some(msg) = broadcast(&buf[..n]) else {
+                debug!("ignored datagram from {}", from);
+                continue;
+            };
+            if !self.servers.is_empty() && !self.servers.contains(&from.ip()) {
+                debug!("ignored broadcast from unlisted {}", from);
+                continue;
+            }
+
+            let delay = self.state.lock().unwrap().delays.get(&from).copied();
+            let Some(delay) = delay.or_else(|| self.calibrate(from)) else {
+                continue;
+            };
+            let transmit = ntp_timestamp_to_duration(msg.transmit_timestamp);
+            let sample = BroadcastSample {
+                server: from,
+                stratum: msg.stratum,
+                offset_nanos: nanos(transmit) + delay / 2 - nanos(received),
+                delay_nanos: delay,
+                received,
+            };
+            debug!("broadcast from {}: offset {}ns", from, sample.offset_nanos);
+            self.state.lock().unwrap().sample = Some(sample);
+        }
+    }
+
+    /// Measure the round-trip delay to `server`, the shortest of the
+    /// calibration exchanges, and remember it. `None` if none succeeded, or
+    /// if `server` is backing off or too many servers are known.
+    fn calibrate(&self, server: SocketAddr) -> Option<i64> {
+        if !self.state.lock().unwrap().may_calibrate(server, Instant::now()) {
+            debug!("not calibrating broadcast server {} now", server);
+            return None;
+        }
+        let delay = self.measure(server);
+        self.state.lock().unwrap().calibrated(server, delay, Instant::now());
+        delay
+    }
+
+    fn measure(&self, server: SocketAddr) -> Option<i64> {
+        let mut delays = Vec::with_capacity(self.calibration);
+        for i in 0..self.calibration {
+            if i > 0 && !self.sleep(DEFAULT_MIN_INTERVAL) {
+                return None;
+            }
+            match self.client.query(&server.to_string()) {
+                Ok(result) => delays.push(result.delay_nanos.max(0)),
+                Err(err) => warn!("calibrating broadcast server {} failed: {:?}", server, err),
+            }
+        }
+        let delay = if self.calibration == 0 { 0 } else { delays.into_iter().min()? };
+        info!("calibrated broadcast server {}: delay {}ns", server, delay);
+        Some(delay)
+    }
+
+    /// Sleep for `duration`, `false` if stopped in the meantime.
+    fn sleep(&self, duration: Duration) -> bool {
+        let deadline = Instant::now() + duration;
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            if self.stop.load(Ordering::Relaxed) {
+                return false;
+            }
+            thread::sleep(left.min(STOP_POLL_INTERVAL));
+        }
+        !self.stop.load(Ordering::Relaxed)
+    }
+}
+
+/// The header of a well formed broadcast from a synchronized server.
+fn broadcast(data: &[u8]) -> Option<NtpMsg> {
+    let msg = NtpMsg::parse(data.get(..48)?).ok()?;
+    let usable = msg.mode == NTP_MODE_BROADCAST
+        && matches!(msg.version_number, NTP_VERSION_3 | NTP_VERSION_4)
+        && (1..16).contains(&msg.stratum)
+        && msg.transmit_timestamp != 0;
+    usable.then_some(msg)
+}
+
+fn nanos(d: Duration) -> i64 {
+    d.as_nanos() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::broadcast::*;
+    use crate::client::sys_time;
+    use crate::protocol::{duration_to_ntp_timestamp, NTP_MODE_CLIENT};
+    use crate::testing;
+
+    const OFFSET: Duration = Duration::from_secs(5);
+
+    /// A server 5 seconds ahead answering client requests on `socket`, until the
+    /// socket is shut or `stop` set.
+    fn serve(socket: UdpSocket, stop: Arc<AtomicBool>) -> JoinHandle<usize> {
+        thread::spawn(move || {
+            let mut answered = 0;
+            let mut buf = [0u8; 48];
+            socket.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+            while !stop.load(Ordering::Relaxed) {
+                let Ok((48, from)) = socket.recv_from(&mut buf) else { continue };
+                let request = NtpMsg::parse(&buf).unwrap();
+                if request.mode == NTP_MODE_CLIENT {
+                    let reply = testing::reply(&request, sys_time() + OFFSET, 2, 0);
+                    socket.send_to(&reply.marshal(), from).unwrap();
+                    answered += 1;
+                }
+            }
+            answered
+        })
+    }
+
+    fn announcement(stratum: u8) -> Vec<u8> {
+        let mut msg = NtpMsg::new();
+        msg.version_number = NTP_VERSION_4;
+        msg.mode = NTP_MODE_BROADCAST;
+        msg.stratum = stratum;
+        msg.transmit_timestamp = duration_to_ntp_timestamp(&(sys_time() + OFFSET));
+        msg.marshal()
+    }
+
+    #[test]
+    fn test_broadcast() {
+        let client = BroadcastClient::builder().bind("127.0.0.1:0").calibration(1).start().unwrap();
+        let broadcaster = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = broadcaster.local_addr().unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let responder = serve(broadcaster.try_clone().unwrap(), stop.clone());
+
+        broadcaster.send_to(&announcement(16), client.local_addr()).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(client.sample(), None);
+        assert_eq!(client.delay_nanos(server), None);
+
+        for _ in 0..2 {
+            broadcaster.send_to(&announcement(2), client.local_addr()).unwrap();
+            thread::sleep(Duration::from_millis(200));
+        }
+        stop.store(true, Ordering::Relaxed);
+        assert_eq!(responder.join().unwrap(), 1);
+
+        let sample = client.sample().unwrap();
+        assert_eq!(sample.server, server);
+        assert_eq!(sample.stratum, 2);
+        assert_eq!((sample.offset_nanos as f64 / 1e9).round(), 5.0);
+        assert_eq!(client.delay_nanos(server), Some(sample.delay_nanos));
+        assert!((0..1_000_000_000).contains(&sample.delay_nanos));
+        client.stop();
+    }
+
+    #[test]
+    fn test_server() {
+        let client = BroadcastClient::builder()
+            .bind("127.0.0.1:0")
+            .calibration(1)
+            .server("127.0.0.2".parse().unwrap())
+            .start()
+            .unwrap();
+        let broadcaster = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let responder = serve(broadcaster.try_clone().unwrap(), stop.clone());
+
+        broadcaster.send_to(&announcement(2), client.local_addr()).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        stop.store(true, Ordering::Relaxed);
+        assert_eq!(responder.join().unwrap(), 0);
+        assert_eq!(client.sample(), None);
+    }
+
+    #[test]
+    fn test_calibration_backoff() {
+        let quick = SntpClient::builder().timeout(Duration::from_millis(50)).build();
+        let client = BroadcastClient::builder().bind("127.0.0.1:0").calibration(1).client(quick).start().unwrap();
+        let broadcaster = UdpSocket::bind("127.0.0.1:0").unwrap();
+        broadcaster.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+
+        // Nobody answers the first calibration, the second broadcast waits for the backoff.
+        for _ in 0..2 {
+            broadcaster.send_to(&announcement(2), client.local_addr()).unwrap();
+            thread::sleep(Duration::from_millis(200));
+        }
+        let mut requests = 0;
+        let mut buf = [0u8; 48];
+        while broadcaster.recv_from(&mut buf).is_ok() {
+            requests += 1;
+        }
+        assert_eq!(requests, 1);
+        assert_eq!(client.sample(), None);
+    }
+
+    #[test]
+    fn test_state() {
+        let now = Instant::now();
+        let server = |port| SocketAddr::from(([127, 0, 0, 1], port));
+        let mut state = State::default();
+        for port in 0..MAX_BROADCAST_SERVERS as u16 {
+            assert!(state.may_calibrate(server(port), now));
+            state.calibrated(server(port), (port % 2 == 0).then_some(1000), now);
+        }
+        assert!(!state.may_calibrate(server(9999), now));
+
+        // Failed servers back off, twice as long after each failure.
+        assert!(!state.may_calibrate(server(1), now + CALIBRATION_BACKOFF - Duration::from_secs(1)));
+        let later = now + CALIBRATION_BACKOFF;
+        assert!(state.may_calibrate(server(1), later));
+        state.calibrated(server(1), None, later);
+        assert!(!state.may_calibrate(server(1), later + CALIBRATION_BACKOFF));
+        assert!(state.may_calibrate(server(1), later + CALIBRATION_BACKOFF * 2));
+        state.calibrated(server(1), Some(1000), later);
+        assert_eq!(state.delays.len(), MAX_BROADCAST_SERVERS / 2 + 1);
+        assert!(!state.failed.contains_key(&server(1)));
+    }
+}
@@ -0,0 +1,2947 @@
+//! The SNTP client: one-shot queries, a configurable [`SntpClient`], and the
+//! [`Transport`] and [`NtpClient`] seams to test code using it.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::diag;
+use crate::legacy;
+use crate::otel;
+use crate::pcap::PcapWriter;
+use crate::protocol::{
+    duration_to_ntp_timestamp, ntp_timestamp_to_duration, refid, stratum_name, Limits, NtpError, NtpMsg, NtpPacket,
+    ParseError, PollInterval, ShortFormat, NTP_MODE_SERVER, NTP_VERSION_3, NTP_VERSION_4,
+};
+use crate::servers;
+use crate::timesource::{self, SystemClock, TimeSource};
+
+pub(crate) const NTP_DEFAULT_PORT: &str = "123";
+
+/// Leap indicator: clock unsynchronized.
+const LEAP_ALARM: u8 = 3;
+
+/// Read and write timeout of the default client.
+pub(crate) const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Least time between queries to one server, see [`SntpClientBuilder::min_interval`].
+pub const DEFAULT_MIN_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The largest UDP payload, bounding the client's receive buffer whatever [`Limits::max_packet`].
+const MAX_DATAGRAM: usize = 65_507;
+
+/// How often a cancellable exchange checks whether it was cancelled while waiting for the response.
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Server addresses tracked by the client-side rate limit before old entries are dropped.
+const MAX_RATE_LIMITED_SERVERS: usize = 10_000;
+
+/// When each server address was last queried, by any client of this process.
+static LAST_QUERIES: OnceLock<Mutex<HashMap<SocketAddr, Instant>>> = OnceLock::new();
+
+/// Unanswered exchanges in a row after which resolving a name skips the address.
+const UNRESPONSIVE_AFTER: u32 = 2;
+
+/// How long an unresponsive address is skipped, unless it is the only one.
+const UNRESPONSIVE_FOR: Duration = Duration::from_secs(600);
+
+/// Unanswered exchanges in a row and the latest, by server address, for any
+/// client of this process. Pools and anycast services rotate addresses, a
+/// dead one must not wedge a name.
+static MISSES: OnceLock<Mutex<HashMap<SocketAddr, (u32, Instant)>>> = OnceLock::new();
+
+/// Most servers sharing one socket in [`SntpClient::query_multiplexed`], so a
+/// burst of responses fits in the socket's receive buffer.
+pub const MULTIPLEX_BATCH: usize = 64;
+
+/// Threads resolving names in [`SntpClient::query_many`].
+const RESOLVER_THREADS: usize = 16;
+
+/// Median absolute deviations from the median offset beyond which
+/// [`SntpClient::query_multi`] discards an answer.
+const OUTLIER_MADS: u64 = 3;
+
+/// Least distance from the median offset at which an answer is an outlier,
+/// so servers agreeing to a few milliseconds are not thrown out over the rest.
+pub(crate) const MIN_OUTLIER_DISTANCE: Duration = Duration::from_millis(10);
+
+/// Retrieve current unix timestamp.
+///
+/// Example
+/// ```rust
+/// # use simple_ntp::client::unix_timestamp;
+///
+/// fn main() {
+///     match unix_timestamp("ntp.aliyun.com:123") {
+///         Ok(msg) => {
+///             println!("{:?}", msg);
+///         }
+///         Err(err) => println!("{:?}", err)
+///     }
+/// }
+/// ```
+pub fn unix_timestamp(ntp_server: &str) -> Result<Duration, NtpError> {
+    default_client().unix_timestamp(ntp_server)
+}
+
+/// Get system clock offset in nano seconds. remote timestamp sub local timestamp.
+///
+/// Example
+/// ```rust
+/// # use simple_ntp::client::clock_offset_nanos;
+///
+/// fn main() {
+///     match clock_offset_nanos("ntp.aliyun.com") {
+///         Ok(msg) => { println!("{:?}", msg as f64 / 1e9); }
+///         Err(err) => println!("{:?}", err)
+///     }
+/// }
+///
+/// ```
+pub fn clock_offset_nanos(ntp_server: &str) -> Result<i64, NtpError> {
+    default_client().clock_offset(ntp_server)
+}
+
+/// t1 corrected by the offset of an exchange's timestamps.
+pub(crate) fn corrected_time((t1, t2, t3, t4): Timestamps) -> Duration {
+    (t1 * 2 + t2 + t3 - t1 - t4) / 2
+}
+
+/// ((t2 - t1) + (t3 - t4)) / 2 of an exchange's timestamps.
+pub(crate) fn offset_nanos_of((t1, t2, t3, t4): Timestamps) -> i64 {
+    let mut diff = (t2.as_secs() as i64 - t1.as_secs() as i64 + t3.as_secs() as i64 - t4.as_secs() as i64) * 1_000_000_000 / 2;
+    diff += (t2.subsec_nanos() as i64 - t1.subsec_nanos() as i64 + t3.subsec_nanos() as i64 - t4.subsec_nanos() as i64) / 2;
+    diff
+}
+
+
+/// The four timestamps of an exchange, see [`ntp`].
+pub type Timestamps = (Duration, Duration, Duration, Duration);
+
+/// An exchange with the record of it.
+pub(crate) type AuditedExchange = (Result<Exchange, NtpError>, AuditRecord);
+
+/// Retrieve four time from ntp server: t1, t2, t3 and t4.
+///
+/// t1: client transmit time
+///
+/// t2: server received time
+///
+/// t3: server transmit time
+///
+/// t4: client received time
+///
+/// So, system clock offset = ((t2 - t1) + (t3 - t4)) / 2,
+/// and round-trip time = ((t4 - t1) - (t3 - t2)) / 2.
+///
+/// [`query`] also returns the rest of the response.
+pub fn ntp(ntp_server: &str) -> Result<Timestamps, NtpError> {
+    let exchange = exchange(ntp_server)?;
+
+    Ok((exchange.t1, exchange.t2, exchange.t3, exchange.t4))
+}
+
+/// What a server answered, and the offset and delay computed from it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NtpResult {
+    /// Address the request was sent to.
+    pub addr: SocketAddr,
+    /// Leap second warning, 1 or 2 for one inserted or deleted at the end of
+    /// the day, 3 when the server is unsynchronized.
+    pub leap_indicator: u8,
+    /// NTP version of the response.
+    pub version: u8,
+    /// 4 for a server, 5 for a broadcast server.
+    pub mode: u8,
+    /// Stratum of the server, 1 for primary servers.
+    pub stratum: u8,
+    /// How often the server would like to be polled.
+    pub poll: PollInterval,
+    /// Precision of the server's clock, log2 seconds, e.g. -20 for about a microsecond.
+    pub precision: i8,
+    /// Reference ID, see [`NtpResult::refid`].
+    pub reference_id: u32,
+    /// Round-trip delay to the primary reference source.
+    pub root_delay: ShortFormat,
+    /// Dispersion to the primary reference source.
+    pub root_dispersion: ShortFormat,
+    /// When the server's clock was last set, unix time, zero if never.
+    pub reference_time: Duration,
+    /// t1, t2, t3 and t4, see [`ntp`].
+    pub timestamps: Timestamps,
+    /// System clock offset in nano seconds, remote timestamp sub local timestamp.
+    pub offset_nanos: i64,
+    /// Round-trip delay in nano seconds.
+    pub delay_nanos: i64,
+}
+
+impl NtpResult {
+    /// Reference ID as ntpq prints it: the ASCII code for stratum 0 and 1, an IPv4 address otherwise.
+    pub fn refid(&self) -> String {
+        refid(self.stratum, self.reference_id)
+    }
+
+    /// Time since the server's clock was last set, as of its transmit
+    /// timestamp, or `None` if it never was.
+    pub fn reference_age(&self) -> Option<Duration> {
+        (self.reference_time != Duration::ZERO).then(|| self.timestamps.2.saturating_sub(self.reference_time))
+    }
+
+    /// Root distance in nano seconds, half the round trip to the primary
+    /// reference source plus its dispersion: how far off the server's time can be.
+    pub fn root_distance_nanos(&self) -> i64 {
+        let short = |value: ShortFormat| nanos(value.to_duration());
+        (self.delay_nanos.max(0) + short(self.root_delay)) / 2 + short(self.root_dispersion)
+    }
+}
+
+impl fmt::Display for NtpResult {
+    /// One line, e.g. `192.0.2.1:123 stratum 2 (secondary) refid 10.0.0.1 offset +0.001234567s delay 0.012000000s`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} stratum {} ({}) refid {} offset {:+.9}s delay {:.9}s root delay {} root dispersion {}",
+            self.addr,
+            self.stratum,
+            stratum_name(self.stratum),
+            self.refid(),
+            self.offset_nanos as f64 / 1e9,
+            self.delay_nanos as f64 / 1e9,
+            self.root_delay,
+            self.root_dispersion
+        )
+    }
+}
+
+/// How the clock of one server relates to another's, see [`SntpClient::compare`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Comparison {
+    /// Rounds in which both servers answered.
+    pub samples: usize,
+    /// The second server's clock sub the first's, in nano seconds, from the
+    /// round with the least combined delay.
+    pub offset_nanos: i64,
+    /// How far the true mutual offset can be from `offset_nanos`: half the
+    /// combined delay of that round, as each offset is within half its delay.
+    pub uncertainty_nanos: i64,
+    /// RMS difference of the other rounds' mutual offsets from `offset_nanos`.
+    pub jitter_nanos: i64,
+}
+
+/// How [`SntpClient::query_multi`] settles on one answer from several servers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Strategy {
+    /// Query all servers at once and take the answer with the median offset,
+    /// once outliers are discarded.
+    #[default]
+    Median,
+    /// Query all servers at once and take the answer with the lowest delay,
+    /// the least skewed by asymmetric paths, once outliers are discarded.
+    LowestDelay,
+    /// Query the servers one after another and take the first answer, for no
+    /// more traffic than one server's while it is up.
+    Fallback,
+}
+
+/// The answer [`SntpClient::query_multi`] settled on, and what each server said.
+#[derive(Debug)]
+pub struct MultiResult {
+    /// The selected answer.
+    pub best: NtpResult,
+    /// Results in the order of the servers; with [`Strategy::Fallback`], only
+    /// of those queried.
+    pub results: Vec<Result<NtpResult, NtpError>>,
+    /// Indices into `results` of the answers discarded as outliers: further
+    /// from the median offset than 3 median absolute deviations, or 10 ms.
+    pub outliers: Vec<usize>,
+}
+
+impl fmt::Display for Comparison {
+    /// E.g. `+0.001234567s ± 0.000250000s, jitter 0.000012000s over 8 samples`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:+.9}s ± {:.9}s, jitter {:.9}s over {} samples",
+            self.offset_nanos as f64 / 1e9,
+            self.uncertainty_nanos as f64 / 1e9,
+            self.jitter_nanos as f64 / 1e9,
+            self.samples
+        )
+    }
+}
+
+
+impl From<&Exchange> for NtpResult {
+    fn from(exchange: &Exchange) -> Self {
+        NtpResult {
+            addr: exchange.peer,
+            leap_indicator: exchange.msg.leap_indicator,
+            version: exchange.msg.version_number,
+            mode: exchange.msg.mode,
+            stratum: exchange.msg.stratum,
+            poll: PollInterval::from_exponent(exchange.msg.poll as i8),
+            precision: exchange.msg.precision as i8,
+            reference_id: exchange.msg.reference_identifier,
+            root_delay: ShortFormat(exchange.msg.root_delay),
+            root_dispersion: ShortFormat(exchange.msg.root_dispersion),
+            reference_time: ntp_timestamp_to_duration(exchange.msg.reference_timestamp),
+            timestamps: (exchange.t1, exchange.t2, exchange.t3, exchange.t4),
+            offset_nanos: exchange.offset_nanos(),
+            delay_nanos: exchange.delay_nanos(),
+        }
+    }
+}
+
+/// Query a server and return its answer along with the computed offset and delay.
+///
+/// Example
+/// ```rust
+/// # use simple_ntp::client::query;
+///
+/// fn main() {
+///     match query("ntp.aliyun.com") {
+///         Ok(result) => println!("stratum {} refid {} offset {}ns", result.stratum, result.refid(), result.offset_nanos),
+///         Err(err) => println!("{:?}", err)
+///     }
+/// }
+/// ```
+pub fn query(ntp_server: &str) -> Result<NtpResult, NtpError> {
+    let exchange = exchange(ntp_server)?;
+
+    Ok(NtpResult::from(&exchange))
+}
+
+/// Like [`ntp`], also returning an audit record of the exchange whether it succeeded or not.
+///
+/// Example
+/// ```rust
+/// # use simple_ntp::client::ntp_audited;
+///
+/// fn main() {
+///     let (result, record) = ntp_audited("ntp.aliyun.com");
+///     println!("{:?} {:?}", result, record);
+/// }
+/// ```
+pub fn ntp_audited(ntp_server: &str) -> (Result<Timestamps, NtpError>, AuditRecord) {
+    let (result, record) = exchange_audited(ntp_server);
+
+    (result.map(|exchange| (exchange.t1, exchange.t2, exchange.t3, exchange.t4)), record)
+}
+
+/// A client with the default settings, shared by the whole process.
+///
+/// Example
+/// ```rust,no_run
+/// # use simple_ntp::client::default_client;
+///
+/// fn main() {
+///     // The median answer of a redundant set of public servers.
+///     let result = default_client().query_default().unwrap();
+///     println!("offset {}ns", result.offset_nanos);
+/// }
+/// ```
+pub fn default_client() -> &'static SntpClient {
+    static CLIENT: OnceLock<SntpClient> = OnceLock::new();
+    CLIENT.get_or_init(SntpClient::default)
+}
+
+/// Query several servers and settle on one answer, see [`SntpClient::query_multi`].
+///
+/// Example
+/// ```rust,no_run
+/// # use simple_ntp::client::{query_multi, Strategy};
+/// # use simple_ntp::servers;
+///
+/// fn main() {
+///     // Do not mix leap smearing servers such as time.google.com with others.
+///     match query_multi(&servers::DEFAULT, Strategy::Median) {
+///         Ok(multi) => println!("{} ({} outliers)", multi.best, multi.outliers.len()),
+///         Err(err) => println!("{:?}", err),
+///     }
+/// }
+/// ```
+pub fn query_multi<S: AsRef<str> + Sync>(servers: &[S], strategy: Strategy) -> Result<MultiResult, NtpError> {
+    default_client().query_multi(servers, strategy)
+}
+
+/// Query many servers at once, see [`SntpClient::query_many`].
+pub fn query_many<S: AsRef<str> + Sync>(servers: &[S]) -> Vec<Result<NtpResult, NtpError>> {
+    default_client().query_many(servers)
+}
+
+/// Like [`query`], also returning an audit record of the exchange whether it succeeded or not.
+pub fn query_audited(ntp_server: &str) -> (Result<NtpResult, NtpError>, AuditRecord) {
+    default_client().query_audited(ntp_server)
+}
+
+/// A validation check applied to a server response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Check {
+    /// The response holds the 48 byte header and is within [`Limits::max_packet`].
+    Length,
+    /// The originate timestamp echoes our transmit timestamp.
+    Originate,
+    /// Not a Kiss-o'-Death: a nonzero stratum.
+    Kiss,
+    /// The version of the request, 3 or 4.
+    Version,
+    /// Server mode.
+    Mode,
+    /// The server is synchronized: a leap indicator other than 3.
+    Leap,
+    /// Strict: nothing but well-formed extension fields and a MAC after the header.
+    Format,
+    /// Nonzero receive and transmit timestamps.
+    Timestamps,
+    /// The root dispersion is within [`SntpClientBuilder::max_root_dispersion`], if set.
+    RootDispersion,
+    /// The stratum is within [`SntpClientBuilder::stratum`], if set.
+    Stratum,
+    /// The reference timestamp is within [`SntpClientBuilder::max_reference_age`], if set.
+    ReferenceAge,
+    /// The TTL is close to the last one from the server, with the `ttl` feature's
+    /// `SntpClientBuilder::max_ttl_change`, if set.
+    Ttl,
+}
+
+/// Result of one [`Check`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Verdict {
+    pub check: Check,
+    pub passed: bool,
+}
+
+/// Everything sent and received during one exchange, and why the response was trusted or not.
+///
+/// Fields are filled as far as the exchange got, e.g. a timeout leaves `response` empty
+/// and `t2`, `t3`, `t4` unset.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuditRecord {
+    /// Server as given by the caller.
+    pub server: String,
+    /// Address the request was sent to.
+    pub addr: Option<SocketAddr>,
+    /// Raw request bytes.
+    pub request: Vec<u8>,
+    /// Raw response bytes.
+    pub response: Vec<u8>,
+    /// Client transmit time.
+    pub t1: Option<Duration>,
+    /// Server receive time.
+    pub t2: Option<Duration>,
+    /// Server transmit time.
+    pub t3: Option<Duration>,
+    /// Client receive time.
+    pub t4: Option<Duration>,
+    /// IP TTL or IPv6 hop limit of the response, with the `ttl` feature's
+    /// `SntpClientBuilder::recv_ttl`.
+    pub ttl: Option<u8>,
+    /// Validation checks in the order they were applied.
+    pub verdicts: Vec<Verdict>,
+    /// Where the time of the exchange went.
+    pub timing: QueryTiming,
+}
+
+/// Wall clock time spent in each step of an exchange, to tell a slow resolver
+/// from a slow network. Steps that were skipped or not reached stay zero, e.g.
+/// `resolve` and `socket` for a kept socket or an address.
+///
+/// Example
+/// ```rust,no_run
+/// # use simple_ntp::client::SntpClient;
+///
+/// fn main() {
+///     let (_, record) = SntpClient::default().query_audited("pool.ntp.org");
+///     let timing = record.timing;
+///     println!("resolve {:?}, socket {:?}, round trip {:?}, validate {:?}",
+///         timing.resolve, timing.socket, timing.round_trip, timing.validate);
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueryTiming {
+    /// Resolving the server name.
+    pub resolve: Duration,
+    /// Binding and connecting the socket.
+    pub socket: Duration,
+    /// From sending the request until the response arrived, or receiving gave up.
+    pub round_trip: Duration,
+    /// Parsing and checking the response.
+    pub validate: Duration,
+}
+
+impl QueryTiming {
+    /// Sum of all steps.
+    pub fn total(&self) -> Duration {
+        self.resolve + self.socket + self.round_trip + self.validate
+    }
+}
+
+impl AuditRecord {
+    fn verdict(&mut self, check: Check, passed: bool) -> bool {
+        self.verdicts.push(Verdict { check, passed });
+        passed
+    }
+}
+
+/// A single client/server exchange: the four timestamps plus what the server sent.
+pub(crate) struct Exchange {
+    pub(crate) peer: SocketAddr,
+    pub(crate) msg: NtpMsg,
+    pub(crate) t1: Duration,
+    pub(crate) t2: Duration,
+    pub(crate) t3: Duration,
+    pub(crate) t4: Duration,
+}
+
+impl Exchange {
+    /// System clock offset in nano seconds, ((t2 - t1) + (t3 - t4)) / 2.
+    pub(crate) fn offset_nanos(&self) -> i64 {
+        (nanos(self.t2) - nanos(self.t1) + nanos(self.t3) - nanos(self.t4)) / 2
+    }
+
+    /// Round-trip delay in nano seconds, (t4 - t1) - (t3 - t2).
+    pub(crate) fn delay_nanos(&self) -> i64 {
+        (nanos(self.t4) - nanos(self.t1)) - (nanos(self.t3) - nanos(self.t2))
+    }
+
+    /// Maximum error of the offset in nano seconds: half the round trips to the
+    /// primary source plus its dispersion, RFC 5905's root distance without the
+    /// jitter and age terms.
+    pub(crate) fn root_distance_nanos(&self) -> i64 {
+        NtpResult::from(self).root_distance_nanos()
+    }
+}
+
+fn nanos(d: Duration) -> i64 {
+    d.as_secs() as i64 * 1_000_000_000 + d.subsec_nanos() as i64
+}
+
+pub(crate) fn exchange(ntp_server: &str) -> Result<Exchange, NtpError> {
+    exchange_audited(ntp_server).0
+}
+
+pub(crate) fn exchange_audited(ntp_server: &str) -> (Result<Exchange, NtpError>, AuditRecord) {
+    default_client().exchange_audited(ntp_server)
+}
+
+/// How an exchange reaches its server: a connected [`UdpSocket`] normally, or
+/// e.g. `testing::MemoryTransport` to test without sockets, see [`SntpClient::query_via`].
+pub trait Transport {
+    /// Send one datagram to the server.
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize>;
+
+    /// Receive one datagram from the server, failing with `WouldBlock` or
+    /// `TimedOut` once the transport's timeout has passed.
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// [`recv`](Self::recv), plus the IP TTL or IPv6 hop limit of the datagram
+    /// if the transport knows it.
+    fn recv_ttl(&mut self, buf: &mut [u8]) -> io::Result<(usize, Option<u8>)> {
+        self.recv(buf).map(|n| (n, None))
+    }
+
+    /// Address of the server.
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Local address, if the transport has one.
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+impl Transport for UdpSocket {
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        UdpSocket::send(self, buf)
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        UdpSocket::recv(self, buf)
+    }
+
+    fn recv_ttl(&mut self, buf: &mut [u8]) -> io::Result<(usize, Option<u8>)> {
+        recv_ttl(self, buf)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::peer_addr(self)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+}
+
+/// Something that queries NTP servers: a [`SntpClient`], or in tests a
+/// [`MockClient`](crate::testing::MockClient), so code taking a `&dyn NtpClient`
+/// or `Box<dyn NtpClient>` can be unit-tested without the network.
+///
+/// Example
+/// ```rust
+/// # use std::time::Duration;
+/// # use simple_ntp::client::{NtpClient, SntpClient};
+/// # use simple_ntp::protocol::NtpError;
+///
+/// fn skewed(client: &dyn NtpClient, max: Duration) -> Result<bool, NtpError> {
+///     Ok(client.clock_offset_nanos("ntp.aliyun.com")?.unsigned_abs() as u128 > max.as_nanos())
+/// }
+///
+/// fn main() {
+///     let client: Box<dyn NtpClient> = Box::new(SntpClient::default());
+///     println!("{:?}", skewed(client.as_ref(), Duration::from_millis(100)));
+/// }
+/// ```
+pub trait NtpClient {
+    /// Query `ntp_server`, `host` or `host:port`, see [`query`].
+    fn query(&self, ntp_server: &str) -> Result<NtpResult, NtpError>;
+
+    /// System clock offset in nano seconds, see [`clock_offset_nanos`].
+    fn clock_offset_nanos(&self, ntp_server: &str) -> Result<i64, NtpError> {
+        Ok(self.query(ntp_server)?.offset_nanos)
+    }
+
+    /// The time by `ntp_server`, since unix epoch: the local clock corrected by the offset.
+    fn now(&self, ntp_server: &str) -> Result<Duration, NtpError> {
+        let offset = self.clock_offset_nanos(ntp_server)?;
+        Ok(shift(SystemClock.now(), offset))
+    }
+}
+
+impl NtpClient for SntpClient {
+    fn query(&self, ntp_server: &str) -> Result<NtpResult, NtpError> {
+        SntpClient::query(self, ntp_server)
+    }
+
+    /// Corrects the client's [time source](SntpClientBuilder::time_source).
+    fn now(&self, ntp_server: &str) -> Result<Duration, NtpError> {
+        let offset = self.clock_offset_nanos(ntp_server)?;
+        Ok(shift(self.time.now(), offset))
+    }
+}
+
+pub(crate) fn shift(d: Duration, offset_nanos: i64) -> Duration {
+    if offset_nanos >= 0 {
+        d + Duration::from_nanos(offset_nanos as u64)
+    } else {
+        d.saturating_sub(Duration::from_nanos(offset_nanos.unsigned_abs()))
+    }
+}
+
+/// How strictly a client checks responses, see [`SntpClientBuilder::parse_mode`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ParseMode {
+    /// The sanity checks of RFC 4330: a complete header echoing the request
+    /// from a synchronized server, see [`Check`]. Trailing bytes are ignored.
+    #[default]
+    Lenient,
+    /// Also [`Check::Format`], for security-sensitive users.
+    Strict,
+}
+
+
+impl Limits {
+    /// One byte more than the largest accepted packet, so a longer one is told apart.
+    pub(crate) fn receive_buffer(&self) -> Vec<u8> {
+        vec![0; self.max_packet.min(MAX_DATAGRAM) + 1]
+    }
+}
+
+/// What a client accepts: how strictly it checks responses and how large they may be.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct ResponsePolicy {
+    pub(crate) mode: ParseMode,
+    pub(crate) limits: Limits,
+    pub(crate) max_root_dispersion: Option<Duration>,
+    /// Lowest and highest accepted stratum.
+    pub(crate) stratum: Option<(u8, u8)>,
+    pub(crate) max_reference_age: Option<Duration>,
+}
+
+/// Address family a server name is resolved to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "config", derive(serde::Deserialize, serde::Serialize), serde(rename_all = "lowercase"))]
+pub enum IpFamily {
+    /// Whichever address the resolver returns first.
+    #[default]
+    Any,
+    V4,
+    V6,
+}
+
+impl FromStr for IpFamily {
+    type Err = NtpError;
+
+    /// `any`, `v4` or `v6`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "any" => Ok(IpFamily::Any),
+            "v4" => Ok(IpFamily::V4),
+            "v6" => Ok(IpFamily::V6),
+            _ => Err(NtpError::BadConfig(format!("invalid address family `{}`", s))),
+        }
+    }
+}
+
+/// A client with non-default settings; the free functions use [`default_client`].
+///
+/// Clients are `Send + Sync` and cheap to clone: the settings are small and
+/// the time source, pcap writer, cancel token and persistent sockets are
+/// shared between clones behind `Arc`s. Keep one in an application's shared
+/// state and query from any thread, without a mutex around it.
+///
+/// Besides NTP servers it can ask legacy TIME servers (RFC 868), as coarse
+/// fallbacks: `time://host[:port]` over UDP, `time+tcp://host[:port]` over TCP.
+///
+/// Example
+/// ```rust
+/// # use std::time::Duration;
+/// # use simple_ntp::client::{IpFamily, SntpClient};
+///
+/// fn main() {
+///     let client = SntpClient::builder()
+///         .timeout(Duration::from_secs(2))
+///         .family(IpFamily::V4)
+///         .build();
+///     match client.query("ntp.aliyun.com") {
+///         Ok(result) => println!("{:?}", result),
+///         Err(err) => println!("{:?}", err),
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct SntpClient {
+    timeout: Duration,
+    family: IpFamily,
+    /// `None` for the exchange timeout.
+    resolve_timeout: Option<Duration>,
+    /// Zero for an ephemeral port.
+    source_port: u16,
+    /// `None` for the unspecified address of the server's family.
+    bind_addr: Option<IpAddr>,
+    /// Of servers given without one.
+    port: u16,
+    /// Of requests, unless downgraded.
+    version: u8,
+    #[cfg(all(feature = "fwmark", target_os = "linux"))]
+    mark: Option<u32>,
+    #[cfg(all(feature = "netns", target_os = "linux"))]
+    netns: Option<std::path::PathBuf>,
+    #[cfg(all(feature = "ttl", target_os = "linux"))]
+    recv_ttl: bool,
+    #[cfg(all(feature = "ttl", target_os = "linux"))]
+    max_ttl_change: Option<u8>,
+    /// By server, see [`SntpClientBuilder::max_ttl_change`].
+    #[cfg(all(feature = "ttl", target_os = "linux"))]
+    ttls: Arc<Mutex<HashMap<String, SeenTtl>>>,
+    hooks: Hooks,
+    pub(crate) time: Arc<dyn TimeSource>,
+    pcap: Option<PcapWriter>,
+    /// `None` for the default, which leaves loopback servers alone.
+    min_interval: Option<Duration>,
+    cancel: Option<CancelToken>,
+    policy: ResponsePolicy,
+    /// Connected sockets by server, kept between queries if persistent.
+    sockets: Option<Arc<Mutex<HashMap<String, UdpSocket>>>>,
+    /// Versions that worked by server, if falling back to NTPv3.
+    versions: Option<Arc<Mutex<HashMap<String, u8>>>>,
+}
+
+/// The TTL of the last accepted response from a server, and a differing one
+/// rejected right after.
+#[cfg(all(feature = "ttl", target_os = "linux"))]
+#[derive(Debug, Clone, Copy)]
+struct SeenTtl {
+    accepted: u8,
+    rejected: Option<u8>,
+}
+
+/// Callbacks of a client, see [`SntpClientBuilder::on_socket`],
+/// [`on_send`](SntpClientBuilder::on_send) and [`on_receive`](SntpClientBuilder::on_receive).
+#[derive(Debug, Clone, Default)]
+struct Hooks {
+    socket: Option<Hook<SocketFn>>,
+    send: Option<Hook<SendFn>>,
+    receive: Option<Hook<ReceiveFn>>,
+}
+
+type SocketFn = dyn Fn(&UdpSocket) -> io::Result<()> + Send + Sync;
+type SendFn = dyn Fn(&mut Vec<u8>) + Send + Sync;
+type ReceiveFn = dyn Fn(SocketAddr, &[u8]) + Send + Sync;
+
+struct Hook<F: ?Sized>(Arc<F>);
+
+impl<F: ?Sized> Clone for Hook<F> {
+    fn clone(&self) -> Self {
+        Hook(self.0.clone())
+    }
+}
+
+impl<F: ?Sized> fmt::Debug for Hook<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Hook").finish_non_exhaustive()
+    }
+}
+
+impl Hooks {
+    /// `request` as the send hook leaves it, and the transmit timestamp it carries.
+    fn request(&self, version: u8, timestamp: u64) -> (Vec<u8>, u64) {
+        let mut request = NtpMsg::new_for_client(version, timestamp).marshal();
+        if let Some(hook) = &self.send {
+            (hook.0)(&mut request);
+        }
+        let sent = request.get(40..48).map_or(timestamp, |ts| u64::from_be_bytes(ts.try_into().unwrap()));
+
+        (request, sent)
+    }
+
+    fn received(&self, peer: SocketAddr, response: &[u8]) {
+        if let Some(hook) = &self.receive {
+            (hook.0)(peer, response);
+        }
+    }
+}
+
+impl Default for SntpClient {
+    fn default() -> Self {
+        SntpClient {
+            timeout: DEFAULT_TIMEOUT,
+            family: IpFamily::Any,
+            resolve_timeout: None,
+            source_port: 0,
+            bind_addr: None,
+            port: NTP_DEFAULT_PORT.parse().unwrap(),
+            version: NTP_VERSION_4,
+            #[cfg(all(feature = "fwmark", target_os = "linux"))]
+            mark: None,
+            #[cfg(all(feature = "netns", target_os = "linux"))]
+            netns: None,
+            #[cfg(all(feature = "ttl", target_os = "linux"))]
+            recv_ttl: false,
+            #[cfg(all(feature = "ttl", target_os = "linux"))]
+            max_ttl_change: None,
+            #[cfg(all(feature = "ttl", target_os = "linux"))]
+            ttls: Default::default(),
+            hooks: Hooks::default(),
+            time: timesource::system(),
+            pcap: None,
+            min_interval: None,
+            cancel: None,
+            policy: ResponsePolicy::default(),
+            sockets: None,
+            versions: None,
+        }
+    }
+}
+
+/// Builder for [`SntpClient`].
+#[derive(Debug, Clone, Default)]
+pub struct SntpClientBuilder {
+    client: SntpClient,
+}
+
+impl SntpClientBuilder {
+    /// Read and write timeout of each exchange, 5 seconds by default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.client.timeout = timeout;
+        self
+    }
+
+    /// Only use addresses of this family, like `ntpdate -4` / `-6`.
+    pub fn family(mut self, family: IpFamily) -> Self {
+        self.client.family = family;
+        self
+    }
+
+    /// Read the local time, t1 and t4, from `time` instead of the system clock,
+    /// e.g. a [`PhcClock`](crate::timesource::PhcClock); offsets are then relative to it.
+    pub fn time_source(mut self, time: Arc<dyn TimeSource>) -> Self {
+        self.client.time = time;
+        self
+    }
+
+    /// Write the datagrams of every NTP exchange to `pcap`, for Wireshark.
+    /// Legacy TIME exchanges are not captured.
+    pub fn pcap(mut self, pcap: PcapWriter) -> Self {
+        self.client.pcap = Some(pcap);
+        self
+    }
+
+    /// Least time between queries to one server address by any client of this
+    /// process. Sooner queries fail with `ServiceUnavailable` without being sent,
+    /// so a retry loop cannot break pool usage rules. [`DEFAULT_MIN_INTERVAL`]
+    /// by default, which exempts loopback servers; `Duration::ZERO` disables it.
+    pub fn min_interval(mut self, min_interval: Duration) -> Self {
+        self.client.min_interval = Some(min_interval);
+        self
+    }
+
+    /// Reject responses whose root dispersion, the server's own estimate of its
+    /// error, exceeds `max_root_dispersion`, e.g. 100 ms, so low-quality servers
+    /// are left out of selection. Unbounded by default.
+    pub fn max_root_dispersion(mut self, max_root_dispersion: Duration) -> Self {
+        self.client.policy.max_root_dispersion = Some(max_root_dispersion);
+        self
+    }
+
+    /// Reject responses from servers outside the `stratum` range, e.g. `1..=3`
+    /// where rules mandate proximity to a primary source. Any stratum by default.
+    pub fn stratum(mut self, stratum: RangeInclusive<u8>) -> Self {
+        self.client.policy.stratum = Some((*stratum.start(), *stratum.end()));
+        self
+    }
+
+    /// Reject responses whose reference timestamp, the server's last clock
+    /// update, is more than `max_reference_age` before its transmit timestamp,
+    /// e.g. 24 hours: such a server is free-running, whatever its stratum. A
+    /// zero reference timestamp, never updated, is always too old, and one after
+    /// the transmit timestamp is rejected too. Any age by default.
+    pub fn max_reference_age(mut self, max_reference_age: Duration) -> Self {
+        self.client.policy.max_reference_age = Some(max_reference_age);
+        self
+    }
+
+    /// Retry a server that does not answer an NTPv4 request as NTPv3 before
+    /// failing, for old appliances silently dropping v4, and keep asking it in
+    /// v3 once that worked, see [`SntpClient::version`]. Such an exchange can
+    /// take twice the timeout. Off by default.
+    pub fn downgrade(mut self, downgrade: bool) -> Self {
+        self.client.versions = downgrade.then(Default::default);
+        self
+    }
+
+    /// How strictly responses are checked, [`ParseMode::Lenient`] by default.
+    pub fn parse_mode(mut self, parse_mode: ParseMode) -> Self {
+        self.client.policy.mode = parse_mode;
+        self
+    }
+
+    /// Caps on response size, [`Limits::default`] by default. A lenient client
+    /// only enforces [`Limits::max_packet`], it does not parse extension fields.
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.client.policy.limits = limits;
+        self
+    }
+
+    /// Give up exchanges, including one waiting for its response, soon after
+    /// `cancel` is cancelled; they fail with `ServiceUnavailable`.
+    pub fn cancel(mut self, cancel: CancelToken) -> Self {
+        self.client.cancel = Some(cancel);
+        self
+    }
+
+    /// Give up resolving a server name after `timeout`, the exchange
+    /// [`timeout`](Self::timeout) by default, with [`NtpError::ResolveTimeout`].
+    /// The system resolver cannot be interrupted: a lookup that hangs goes on
+    /// in the background, on a thread of its own, until the resolver gives up.
+    pub fn resolve_timeout(mut self, timeout: Duration) -> Self {
+        self.client.resolve_timeout = Some(timeout);
+        self
+    }
+
+    /// Send from local port `port` instead of an ephemeral one, e.g. 123 for
+    /// firewalls that only pass NTP between port 123 on both ends. Ports
+    /// below 1024 need root or `CAP_NET_BIND_SERVICE` on Linux, and only one
+    /// query per address family can run at a time.
+    pub fn source_port(mut self, port: u16) -> Self {
+        self.client.source_port = port;
+        self
+    }
+
+    /// Send from the local address `addr` instead of the unspecified one, e.g.
+    /// one interface's address on a multi-homed host. Servers are then only
+    /// resolved to addresses of its family, unless [`family`](Self::family)
+    /// says otherwise, which fails the query.
+    pub fn bind_addr(mut self, addr: IpAddr) -> Self {
+        self.client.bind_addr = Some(addr);
+        self
+    }
+
+    /// Port of servers given without one, 123 by default.
+    pub fn port(mut self, port: u16) -> Self {
+        self.client.port = port;
+        self
+    }
+
+    /// Send NTPv`version` requests, 3 or 4 (the default); other versions are
+    /// clamped to those. See also [`downgrade`](Self::downgrade).
+    pub fn version(mut self, version: u8) -> Self {
+        self.client.version = version.clamp(NTP_VERSION_3, NTP_VERSION_4);
+        self
+    }
+
+    /// Tag the requests with the firewall mark `mark` (`SO_MARK`), so policy
+    /// routing and nftables rules can tell them apart, e.g. to keep NTP out of
+    /// a VPN tunnel. Needs `CAP_NET_ADMIN`.
+    #[cfg(all(feature = "fwmark", target_os = "linux"))]
+    pub fn mark(mut self, mark: u32) -> Self {
+        self.client.mark = Some(mark);
+        self
+    }
+
+    /// Send from the network namespace `netns`, a name as created by `ip netns
+    /// add` or a path like `/proc/<pid>/ns/net`, to measure time as seen from a
+    /// container or VRF. Only the socket is opened there, names are still
+    /// resolved in the caller's namespace. Needs `CAP_SYS_ADMIN`.
+    #[cfg(all(feature = "netns", target_os = "linux"))]
+    pub fn netns(mut self, netns: &str) -> Self {
+        self.client.netns = Some(crate::sockopt::netns_path(netns));
+        self
+    }
+
+    /// Record the IP TTL, or IPv6 hop limit, of every response in
+    /// [`AuditRecord::ttl`] (`IP_RECVTTL`, `IPV6_RECVHOPLIMIT`).
+    #[cfg(all(feature = "ttl", target_os = "linux"))]
+    pub fn recv_ttl(mut self) -> Self {
+        self.client.recv_ttl = true;
+        self
+    }
+
+    /// Reject a response whose TTL is more than `hops` off the last accepted one
+    /// from the same server, with [`Check::Ttl`]: a cheap check against off-path
+    /// spoofers, who rarely sit as many hops away as the server. A new TTL seen
+    /// twice in a row is accepted, as after a route change. Implies
+    /// [`recv_ttl`](Self::recv_ttl).
+    #[cfg(all(feature = "ttl", target_os = "linux"))]
+    pub fn max_ttl_change(mut self, hops: u8) -> Self {
+        self.client.recv_ttl = true;
+        self.client.max_ttl_change = Some(hops);
+        self
+    }
+
+    /// Call `hook` with every new socket right after it is bound, before
+    /// anything is sent, e.g. for an Android VPN app to pass its fd to
+    /// `VpnService.protect()` so NTP does not loop back into the tunnel, or to
+    /// set options this crate has no builder method for. An error fails the
+    /// query with [`NtpError::ServiceUnavailable`].
+    ///
+    /// Example
+    /// ```rust,no_run
+    /// # use simple_ntp::client::SntpClient;
+    /// # #[cfg(unix)]
+    /// use std::os::fd::AsRawFd;
+    ///
+    /// # #[cfg(unix)]
+    /// fn main() {
+    ///     let client = SntpClient::builder()
+    ///         .on_socket(|socket| {
+    ///             println!("protect fd {}", socket.as_raw_fd());
+    ///             socket.set_ttl(8)
+    ///         })
+    ///         .build();
+    ///     println!("{:?}", client.query("ntp.aliyun.com"));
+    /// }
+    /// # #[cfg(not(unix))]
+    /// # fn main() {}
+    /// ```
+    pub fn on_socket(mut self, hook: impl Fn(&UdpSocket) -> io::Result<()> + Send + Sync + 'static) -> Self {
+        self.client.hooks.socket = Some(Hook(Arc::new(hook)));
+        self
+    }
+
+    /// Call `hook` with every request before it is sent, to inspect or change
+    /// it, e.g. append extension fields. The response is matched to the
+    /// transmit timestamp the request ends up with; [`AuditRecord::request`]
+    /// and pcap captures have the changed bytes.
+    ///
+    /// Example
+    /// ```rust,no_run
+    /// # use simple_ntp::client::SntpClient;
+    ///
+    /// fn main() {
+    ///     // An unassigned extension field type with 4 bytes of value.
+    ///     let client = SntpClient::builder()
+    ///         .on_send(|request| request.extend_from_slice(&[0xff, 0x00, 0x00, 0x08, 0xca, 0xfe, 0xba, 0xbe]))
+    ///         .build();
+    ///     println!("{:?}", client.query("ntp.aliyun.com"));
+    /// }
+    /// ```
+    pub fn on_send(mut self, hook: impl Fn(&mut Vec<u8>) + Send + Sync + 'static) -> Self {
+        self.client.hooks.send = Some(Hook(Arc::new(hook)));
+        self
+    }
+
+    /// Call `hook` with the sender and bytes of every response before it is
+    /// validated, e.g. to read extension fields the client does not know.
+    pub fn on_receive(mut self, hook: impl Fn(SocketAddr, &[u8]) + Send + Sync + 'static) -> Self {
+        self.client.hooks.receive = Some(Hook(Arc::new(hook)));
+        self
+    }
+
+    /// Keep one connected socket per server across queries instead of binding,
+    /// connecting and configuring a new one each time. The server is resolved
+    /// once, and again only after a failed exchange, which also replaces the socket.
+    /// Clones of the built client share its sockets.
+    pub fn persistent(mut self, persistent: bool) -> Self {
+        self.client.sockets = persistent.then(Arc::default);
+        self
+    }
+
+    pub fn build(mut self) -> SntpClient {
+        if self.client.sockets.is_some() {
+            // Sockets were set up with the old settings, start over.
+            self.client.sockets = Some(Arc::default());
+        }
+        self.client
+    }
+}
+
+impl SntpClient {
+    pub fn builder() -> SntpClientBuilder {
+        SntpClientBuilder::default()
+    }
+
+    /// A builder starting from this client's settings.
+    pub fn to_builder(&self) -> SntpClientBuilder {
+        SntpClientBuilder { client: self.clone() }
+    }
+
+    /// See [`query`].
+    pub fn query(&self, ntp_server: &str) -> Result<NtpResult, NtpError> {
+        self.query_audited(ntp_server).0
+    }
+
+    /// See [`query_audited`].
+    pub fn query_audited(&self, ntp_server: &str) -> (Result<NtpResult, NtpError>, AuditRecord) {
+        let (result, record) = self.exchange_audited(ntp_server);
+
+        (result.map(|exchange| NtpResult::from(&exchange)), record)
+    }
+
+    /// See [`unix_timestamp`].
+    pub fn unix_timestamp(&self, ntp_server: &str) -> Result<Duration, NtpError> {
+        let exchange = self.exchange_audited(ntp_server).0?;
+
+        Ok(corrected_time((exchange.t1, exchange.t2, exchange.t3, exchange.t4)))
+    }
+
+    /// Clock offset in nano seconds, see [`clock_offset_nanos`].
+    pub fn clock_offset(&self, ntp_server: &str) -> Result<i64, NtpError> {
+        let exchange = self.exchange_audited(ntp_server).0?;
+
+        Ok(offset_nanos_of((exchange.t1, exchange.t2, exchange.t3, exchange.t4)))
+    }
+
+    /// Like [`query`](Self::query), over `transport` instead of a new socket to
+    /// a resolved server. The transport has its own timeout, the client's is not used.
+    pub fn query_via(&self, transport: &mut dyn Transport) -> Result<NtpResult, NtpError> {
+        let ntp_server = transport.peer_addr().map_err(|err| {
+            NtpError::UnexpectedErr(err.to_string())
+        })?.to_string();
+
+        self.exchange_via(transport, &ntp_server).0.map(|exchange| NtpResult::from(&exchange))
+    }
+
+    /// Like [`query`](Self::query), over `socket` instead of a new one, for
+    /// sockets created by someone else, e.g. inherited through socket
+    /// activation or handed out by a sandbox. A connected socket queries its
+    /// peer, recorded as `ntp_server`; otherwise `ntp_server` is resolved to an
+    /// address of the socket's family and datagrams from anyone else are
+    /// ignored. The socket's read timeout is restored afterwards.
+    ///
+    /// Example
+    /// ```rust,no_run
+    /// # use std::net::UdpSocket;
+    /// # use simple_ntp::client::SntpClient;
+    ///
+    /// fn main() {
+    ///     let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+    ///     println!("{:?}", SntpClient::default().query_with_socket(&socket, "ntp.aliyun.com"));
+    /// }
+    /// ```
+    pub fn query_with_socket(&self, socket: &UdpSocket, ntp_server: &str) -> Result<NtpResult, NtpError> {
+        let (peer, connected) = match socket.peer_addr() {
+            Ok(peer) => (peer, true),
+            Err(_) => {
+                let local = socket.local_addr().map_err(|err| NtpError::UnexpectedErr(err.to_string()))?;
+                let family = if local.is_ipv4() { IpFamily::V4 } else { IpFamily::V6 };
+                (self.resolve_family(ntp_server, &self.port.to_string(), family)?, false)
+            }
+        };
+        self.rate_limit(peer)?;
+
+        let timeout = socket.read_timeout().map_err(|err| NtpError::UnexpectedErr(err.to_string()))?;
+        let mut transport = Borrowed { socket, peer, connected, deadline: Instant::now() + self.timeout };
+        let (result, _) = self.exchange_via(&mut transport, ntp_server);
+        socket.set_read_timeout(timeout).map_err(|err| NtpError::UnexpectedErr(err.to_string()))?;
+
+        result.map(|exchange| NtpResult::from(&exchange))
+    }
+
+    /// Measure the offset of `server_b`'s clock from `server_a`'s, e.g. to
+    /// validate a new in-house server against a trusted reference before
+    /// cutover. Both are queried in `samples` rounds, alternating which goes
+    /// first so that drift of the local clock in between cancels out, and
+    /// rounds are spaced by the [`min_interval`](SntpClientBuilder::min_interval).
+    /// Fails with the last error if no round got both answers.
+    ///
+    /// Example
+    /// ```rust,no_run
+    /// # use simple_ntp::client::SntpClient;
+    ///
+    /// fn main() {
+    ///     let comparison = SntpClient::default().compare("time.cloudflare.com", "ntp.internal", 8).unwrap();
+    ///     println!("ntp.internal is {}", comparison);
+    /// }
+    /// ```
+    pub fn compare(&self, server_a: &str, server_b: &str, samples: usize) -> Result<Comparison, NtpError> {
+        let interval = self.min_interval.unwrap_or(DEFAULT_MIN_INTERVAL);
+        let mut rounds = Vec::new();
+        let mut error = None;
+        for round in 0..samples {
+            if round > 0 {
+                thread::sleep(interval);
+            }
+            let (a, b) = if round % 2 == 0 {
+                let a = self.query(server_a);
+                (a, self.query(server_b))
+            } else {
+                let b = self.query(server_b);
+                (self.query(server_a), b)
+            };
+            match (a, b) {
+                (Ok(a), Ok(b)) => rounds.push((b.offset_nanos - a.offset_nanos, (a.delay_nanos + b.delay_nanos) / 2)),
+                (Err(err), _) | (_, Err(err)) => error = Some(err),
+            }
+        }
+
+        let Some(&(offset_nanos, uncertainty_nanos)) = rounds.iter().min_by_key(|(_, uncertainty)| *uncertainty) else {
+            return Err(error.unwrap_or_else(|| NtpError::BadConfig("no samples".to_string())));
+        };
+        let squares: f64 = rounds.iter().map(|(offset, _)| ((offset - offset_nanos) as f64).powi(2)).sum();
+        let jitter_nanos = match rounds.len() {
+            1 => 0,
+            n => (squares / (n - 1) as f64).sqrt() as i64,
+        };
+
+        Ok(Comparison { samples: rounds.len(), offset_nanos, uncertainty_nanos, jitter_nanos })
+    }
+
+    /// Query all of `servers` at once: names are resolved up front, in
+    /// parallel, and the exchanges share sockets as in
+    /// [`query_multiplexed`](Self::query_multiplexed), so scanning hundreds of
+    /// servers takes about one timeout instead of one each. Results are in the
+    /// order of `servers`; `time://` and `daytime://` servers get a query each.
+    ///
+    /// Example
+    /// ```rust,no_run
+    /// # use simple_ntp::client::SntpClient;
+    ///
+    /// fn main() {
+    ///     let servers = ["time.google.com", "time.cloudflare.com", "ntp.aliyun.com"];
+    ///     for (server, result) in servers.iter().zip(SntpClient::default().query_many(&servers)) {
+    ///         println!("{}: {:?}", server, result);
+    ///     }
+    /// }
+    /// ```
+    pub fn query_many<S: AsRef<str> + Sync>(&self, servers: &[S]) -> Vec<Result<NtpResult, NtpError>> {
+        let lookups = parallel(servers.len(), RESOLVER_THREADS, |i| {
+            let ntp_server = servers[i].as_ref();
+            match legacy::parse(ntp_server) {
+                Some(_) => Lookup::Done(self.query(ntp_server)),
+                None => match self.resolve(ntp_server, &self.port.to_string()) {
+                    Ok(addr) => Lookup::Resolved(ntp_server.to_string(), addr),
+                    Err(err) => Lookup::Done(Err(err)),
+                },
+            }
+        });
+
+        let mut resolved = Vec::new();
+        let mut results: Vec<_> = lookups.into_iter()
+            .map(|lookup| match lookup {
+                Lookup::Resolved(ntp_server, addr) => {
+                    resolved.push((ntp_server, addr));
+                    None
+                }
+                Lookup::Done(result) => Some(result),
+            })
+            .collect();
+        let mut exchanges = self.exchange_multiplexed(&resolved).into_iter();
+        for result in results.iter_mut().filter(|result| result.is_none()) {
+            let (exchange, _) = exchanges.next().unwrap();
+            *result = Some(exchange.map(|exchange| NtpResult::from(&exchange)));
+        }
+
+        results.into_iter().map(Option::unwrap).collect()
+    }
+
+    /// Query [`servers::DEFAULT`](crate::servers::DEFAULT) at once and return
+    /// the answer with the median offset of those that are not outliers, see
+    /// [`query_multi`](Self::query_multi). Fails only if none answers.
+    pub fn query_default(&self) -> Result<NtpResult, NtpError> {
+        self.query_multi(&servers::DEFAULT, Strategy::Median).map(|multi| multi.best)
+    }
+
+    /// Query `servers` as `strategy` says, discard outliers among three or
+    /// more answers, and select one of the rest. Fails with the first error
+    /// if no server answers.
+    ///
+    /// Example
+    /// ```rust,no_run
+    /// # use simple_ntp::client::{SntpClient, Strategy};
+    ///
+    /// fn main() {
+    ///     let servers = ["time1.example.com", "time2.example.com", "time3.example.com"];
+    ///     let multi = SntpClient::default().query_multi(&servers, Strategy::LowestDelay).unwrap();
+    ///     for (server, result) in servers.iter().zip(&multi.results) {
+    ///         println!("{}: {:?}", server, result);
+    ///     }
+    ///     println!("offset {}ns", multi.best.offset_nanos);
+    /// }
+    /// ```
+    pub fn query_multi<S: AsRef<str> + Sync>(&self, servers: &[S], strategy: Strategy) -> Result<MultiResult, NtpError> {
+        let results = match strategy {
+            Strategy::Median | Strategy::LowestDelay => self.query_many(servers),
+            Strategy::Fallback => {
+                let mut results = Vec::new();
+                for ntp_server in servers {
+                    let result = self.query(ntp_server.as_ref());
+                    let answered = result.is_ok();
+                    results.push(result);
+                    if answered {
+                        break;
+                    }
+                }
+                results
+            }
+        };
+
+        let answered: Vec<&NtpResult> = results.iter().flatten().collect();
+        let discarded = outliers(&answered.iter().map(|result| result.offset_nanos).collect::<Vec<_>>());
+        let mut kept: Vec<_> = answered.iter().zip(&discarded).filter(|(_, &outlier)| !outlier).map(|(result, _)| *result).collect();
+        let best = match strategy {
+            Strategy::Median => {
+                kept.sort_by_key(|result| result.offset_nanos);
+                kept.get(kept.len() / 2).copied()
+            }
+            Strategy::LowestDelay => kept.into_iter().min_by_key(|result| result.delay_nanos),
+            Strategy::Fallback => kept.first().copied(),
+        };
+        let Some(best) = best.cloned() else {
+            let error = results.into_iter().find_map(Result::err);
+            return Err(error.unwrap_or_else(|| NtpError::BadNtpServerAddr("no ntp server configured".to_string())));
+        };
+        let outliers = (0..results.len())
+            .filter(|&i| results[i].is_ok())
+            .zip(discarded)
+            .filter_map(|(i, outlier)| outlier.then_some(i))
+            .collect();
+
+        Ok(MultiResult { best, results, outliers })
+    }
+
+    /// Query all of `addrs` at once, over a few unconnected sockets, one per
+    /// address family and [`MULTIPLEX_BATCH`] addresses, instead of a socket
+    /// each; results are in the order of `addrs`.
+    ///
+    /// Responses are told apart by source address and originate timestamp and
+    /// anything else is ignored, so unlike [`query`](Self::query) a response
+    /// with the wrong originate timestamp ends in a timeout, not `UntrustedMessage`.
+    ///
+    /// Example
+    /// ```rust,no_run
+    /// # use std::net::ToSocketAddrs;
+    /// # use simple_ntp::client::SntpClient;
+    ///
+    /// fn main() {
+    ///     let addrs: Vec<_> = "pool.ntp.org:123".to_socket_addrs().unwrap().collect();
+    ///     for (addr, result) in addrs.iter().zip(SntpClient::default().query_multiplexed(&addrs)) {
+    ///         println!("{}: {:?}", addr, result);
+    ///     }
+    /// }
+    /// ```
+    pub fn query_multiplexed(&self, addrs: &[SocketAddr]) -> Vec<Result<NtpResult, NtpError>> {
+        let servers: Vec<_> = addrs.iter().map(|addr| (addr.to_string(), *addr)).collect();
+
+        self.exchange_multiplexed(&servers)
+            .into_iter()
+            .map(|(result, _)| result.map(|exchange| NtpResult::from(&exchange)))
+            .collect()
+    }
+
+    /// The NTP version `ntp_server` is asked in: 3 if it only answered that
+    /// after a [`downgrade`](SntpClientBuilder::downgrade), otherwise 4.
+    ///
+    /// Example
+    /// ```rust,no_run
+    /// # use simple_ntp::client::SntpClient;
+    ///
+    /// fn main() {
+    ///     let client = SntpClient::builder().downgrade(true).build();
+    ///     if client.query("192.168.1.1").is_ok() {
+    ///         println!("NTPv{}", client.version("192.168.1.1"));
+    ///     }
+    /// }
+    /// ```
+    pub fn version(&self, ntp_server: &str) -> u8 {
+        self.versions.as_ref()
+            .and_then(|versions| versions.lock().unwrap().get(ntp_server).copied())
+            .unwrap_or(self.version)
+    }
+
+    /// Exchanges with resolved servers over shared sockets, see [`query_multiplexed`](Self::query_multiplexed).
+    pub(crate) fn exchange_multiplexed(&self, servers: &[(String, SocketAddr)]) -> Vec<AuditedExchange> {
+        let (v4, v6): (Vec<usize>, Vec<usize>) = (0..servers.len()).partition(|&i| servers[i].1.is_ipv4());
+        // A fixed source port can only be bound once per family.
+        let batch = if self.source_port == 0 { MULTIPLEX_BATCH } else { servers.len().max(1) };
+        let mut groups = v4.chunks(batch).chain(v6.chunks(batch));
+
+        // All sockets wait at the same time, so none delays another's t4.
+        let mut exchanges = thread::scope(|scope| {
+            let first = groups.next();
+            let others: Vec<_> = groups.map(|group| scope.spawn(|| self.multiplex(servers, group))).collect();
+            let mut exchanges = first.map(|group| self.multiplex(servers, group)).unwrap_or_default();
+            for other in others {
+                exchanges.extend(other.join().unwrap());
+            }
+            exchanges
+        });
+        exchanges.sort_by_key(|(i, _)| *i);
+
+        exchanges.into_iter().map(|(_, exchange)| exchange).collect()
+    }
+
+    /// Exchanges with the servers at `indices`, all of one address family, over one socket.
+    fn multiplex(&self, servers: &[(String, SocketAddr)], indices: &[usize]) -> Vec<(usize, AuditedExchange)> {
+        let mut records: Vec<_> = indices.iter()
+            .map(|&i| AuditRecord {
+                server: servers[i].0.clone(),
+                addr: Some(servers[i].1),
+                ..AuditRecord::default()
+            })
+            .collect();
+        let socket = match self.bind(servers[indices[0]].1.is_ipv4()) {
+            Ok(socket) => socket,
+            Err(err) => {
+                return indices.iter()
+                    .zip(records)
+                    .map(|(&i, record)| (i, (Err(NtpError::ServiceUnavailable(err.clone())), record)))
+                    .collect();
+            }
+        };
+
+        // Transmit timestamps of the outstanding requests, distinct so that
+        // responses from a server queried twice are told apart.
+        let mut pending: Vec<Option<u64>> = vec![None; indices.len()];
+        let mut sent: Vec<Option<Instant>> = vec![None; indices.len()];
+        let mut results: Vec<Option<Result<Exchange, NtpError>>> = indices.iter().map(|_| None).collect();
+        let mut last = 0;
+        for (j, record) in records.iter_mut().enumerate() {
+            let (server, addr) = &servers[indices[j]];
+            let cancelled = || self.cancel.as_ref().is_some_and(CancelToken::is_cancelled);
+            if let Err(err) = check_cancelled(&cancelled).and_then(|_| self.rate_limit(*addr)) {
+                results[j] = Some(Err(err));
+                continue;
+            }
+            let timestamp = duration_to_ntp_timestamp(&self.time.now()).max(last + 1);
+            last = timestamp;
+            let (request, timestamp) = self.hooks.request(self.version(server), timestamp);
+            record.request = request.clone();
+            debug!("sending ntp request to {} ({})", server, addr);
+            diag::count(diag::QUERIES, server);
+            record.t1 = Some(self.time.now());
+            sent[j] = Some(Instant::now());
+            match socket.send_to(&request, addr) {
+                Ok(_) => pending[j] = Some(timestamp),
+                Err(err) => results[j] = Some(Err(NtpError::ServiceUnavailable(err.to_string()))),
+            }
+        }
+
+        let deadline = Instant::now() + self.timeout;
+        let mut buf = self.policy.limits.receive_buffer();
+        while pending.iter().any(Option::is_some) {
+            let mut remaining = deadline.saturating_duration_since(Instant::now());
+            if let Some(cancel) = &self.cancel {
+                if cancel.is_cancelled() {
+                    break;
+                }
+                remaining = remaining.min(CANCEL_POLL_INTERVAL);
+            }
+            if remaining.is_zero() || socket.set_read_timeout(Some(remaining)).is_err() {
+                break;
+            }
+            let (n, from) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => continue,
+                // e.g. ICMP port unreachable from one of the servers.
+                Err(_) => continue,
+            };
+            let receive_time = self.time.now();
+            let origin = buf.get(24..32).filter(|_| n >= 32).map(|ts| u64::from_be_bytes(ts.try_into().unwrap()));
+            let matched = (0..indices.len()).find(|&j| records[j].addr == Some(from) && origin.is_some() && pending[j] == origin);
+            let (Some(j), Some(origin)) = (matched, origin) else {
+                debug!("ignored datagram from {}", from);
+                continue;
+            };
+
+            pending[j] = None;
+            let record = &mut records[j];
+            record.timing.round_trip = sent[j].map(|sent| sent.elapsed()).unwrap_or_default();
+            record.t4 = Some(receive_time);
+            record.response = buf[..n].to_vec();
+            debug!("received {} bytes from {}", n, from);
+            self.hooks.received(from, &buf[..n]);
+            let t1 = record.t1.unwrap_or_default();
+            let started = Instant::now();
+            let result = check_response(from, origin, t1, receive_time, &buf[..n], &self.policy, record);
+            record.timing.validate = started.elapsed();
+            observe(&record.server, &result);
+            results[j] = Some(result);
+        }
+
+        for (j, record) in records.iter_mut().enumerate().filter(|&(j, _)| pending[j].is_some()) {
+            record.timing.round_trip = sent[j].map(|sent| sent.elapsed()).unwrap_or_default();
+        }
+        let local = socket.local_addr().ok();
+        if !self.cancel.as_ref().is_some_and(CancelToken::is_cancelled) {
+            for record in records.iter().filter(|record| record.t1.is_some()) {
+                if let Some(addr) = record.addr {
+                    note_answered(addr, !record.response.is_empty());
+                }
+            }
+        }
+        indices.iter()
+            .zip(records)
+            .zip(results)
+            .map(|((&i, record), result)| {
+                let result = result.unwrap_or_else(|| {
+                    if self.cancel.as_ref().is_some_and(CancelToken::is_cancelled) {
+                        return Err(NtpError::ServiceUnavailable(CANCELLED.to_string()));
+                    }
+                    warn!("no response from {}", record.server);
+                    diag::count(diag::TIMEOUTS, &record.server);
+                    Err(NtpError::ServiceUnavailable(io::Error::from(io::ErrorKind::TimedOut).to_string()))
+                });
+                self.capture(local, &record);
+                (i, (result, record))
+            })
+            .collect()
+    }
+
+    /// [`exchange_audited`](Self::exchange_audited) over `transport`, recorded as `ntp_server`.
+    pub(crate) fn exchange_via(&self, transport: &mut dyn Transport, ntp_server: &str) -> (Result<Exchange, NtpError>, AuditRecord) {
+        let mut record = AuditRecord {
+            server: ntp_server.to_string(),
+            ..AuditRecord::default()
+        };
+
+        let result = exchange_once(transport, ntp_server, &*self.time, self.version(ntp_server), &self.policy, &self.hooks, &mut record);
+        self.capture(transport.local_addr().ok(), &record);
+
+        (result, record)
+    }
+
+    pub(crate) fn exchange_audited(&self, ntp_server: &str) -> (Result<Exchange, NtpError>, AuditRecord) {
+        self.exchange_cancellable(ntp_server, None)
+    }
+
+    /// [`exchange_audited`](Self::exchange_audited), also given up once `cancel` is cancelled.
+    pub(crate) fn exchange_cancellable(&self, ntp_server: &str, cancel: Option<&CancelToken>) -> (Result<Exchange, NtpError>, AuditRecord) {
+        let tokens: Vec<_> = self.cancel.iter().chain(cancel).collect();
+        let cancelled = || tokens.iter().any(|token| token.is_cancelled());
+        let mut record = AuditRecord {
+            server: ntp_server.to_string(),
+            ..AuditRecord::default()
+        };
+        let span = otel::ExchangeSpan::start(ntp_server);
+        let result = match legacy::parse(ntp_server) {
+            Some((protocol, transport, host)) => {
+                let started = Instant::now();
+                let resolved = self.resolve(host, protocol.default_port());
+                record.timing.resolve = started.elapsed();
+                resolved.and_then(|addr| {
+                    self.rate_limit(addr)?;
+                    let started = Instant::now();
+                    let result = legacy::exchange(protocol, transport, addr, self.timeout, &*self.time, &mut record);
+                    record.timing.round_trip = started.elapsed();
+                    result
+                })
+            }
+            None => self.socket(ntp_server, &mut record.timing)
+                .and_then(|mut socket| {
+                    let limited = socket.peer_addr().map_err(|err| {
+                        NtpError::UnexpectedErr(err.to_string())
+                    }).and_then(|peer| self.rate_limit(peer));
+                    if let Err(err) = limited.and_then(|_| check_cancelled(&cancelled)) {
+                        self.keep(ntp_server, socket);
+                        return Err(err);
+                    }
+                    let local = socket.local_addr().ok();
+                    let mut exchange = |version, record: &mut AuditRecord| {
+                        let result = if tokens.is_empty() {
+                            exchange_once(&mut socket, ntp_server, &*self.time, version, &self.policy, &self.hooks, record)
+                        } else {
+                            let mut transport = Cancellable { socket: &socket, timeout: self.timeout, cancelled: &cancelled };
+                            exchange_once(&mut transport, ntp_server, &*self.time, version, &self.policy, &self.hooks, record)
+                        };
+                        #[cfg(all(feature = "ttl", target_os = "linux"))]
+                        let result = result.and_then(|exchange| self.check_ttl(ntp_server, record).map(|()| exchange));
+                        self.capture(local, record);
+                        result
+                    };
+                    let version = self.version(ntp_server);
+                    let mut result = exchange(version, &mut record);
+                    let unanswered = matches!(result, Err(NtpError::ServiceUnavailable(_))) && record.t1.is_some() && record.response.is_empty();
+                    if let Some(versions) = self.versions.as_ref().filter(|_| version == NTP_VERSION_4 && unanswered && !cancelled()) {
+                        debug!("no response from {} to NTPv4, retrying as NTPv3", ntp_server);
+                        record = AuditRecord {
+                            server: ntp_server.to_string(),
+                            timing: QueryTiming { round_trip: Duration::ZERO, ..record.timing },
+                            ..AuditRecord::default()
+                        };
+                        result = exchange(NTP_VERSION_3, &mut record);
+                        if result.is_ok() {
+                            versions.lock().unwrap().insert(ntp_server.to_string(), NTP_VERSION_3);
+                        }
+                    }
+                    if let Some(addr) = record.addr.filter(|_| record.t1.is_some() && !cancelled()) {
+                        note_answered(addr, !record.response.is_empty());
+                    }
+                    if result.is_ok() {
+                        self.keep(ntp_server, socket);
+                    }
+                    result
+                }),
+        };
+        span.end(ntp_server, &result);
+
+        (result, record)
+    }
+
+    /// Reject the response in `record` if its TTL is too far off the last one from
+    /// `ntp_server`, see [`SntpClientBuilder::max_ttl_change`].
+    #[cfg(all(feature = "ttl", target_os = "linux"))]
+    fn check_ttl(&self, ntp_server: &str, record: &mut AuditRecord) -> Result<(), NtpError> {
+        let (Some(max), Some(ttl)) = (self.max_ttl_change, record.ttl) else {
+            return Ok(());
+        };
+        let mut ttls = self.ttls.lock().unwrap();
+        let Some(seen) = ttls.get_mut(ntp_server) else {
+            ttls.insert(ntp_server.to_string(), SeenTtl { accepted: ttl, rejected: None });
+            return Ok(());
+        };
+        if record.verdict(Check::Ttl, seen.accepted.abs_diff(ttl) <= max || seen.rejected == Some(ttl)) {
+            *seen = SeenTtl { accepted: ttl, rejected: None };
+            return Ok(());
+        }
+        warn!("response from {} rejected: ttl {} differs from {} by more than {}", ntp_server, ttl, seen.accepted, max);
+        seen.rejected = Some(ttl);
+        Err(NtpError::UntrustedMessage)
+    }
+
+    /// Note a query to `addr`, refused if the last one was less than the minimum interval ago.
+    fn rate_limit(&self, addr: SocketAddr) -> Result<(), NtpError> {
+        let min_interval = match self.min_interval {
+            Some(min_interval) => min_interval,
+            None if addr.ip().is_loopback() => Duration::ZERO,
+            None => DEFAULT_MIN_INTERVAL,
+        };
+        if min_interval.is_zero() {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let mut last_queries = LAST_QUERIES.get_or_init(Mutex::default).lock().unwrap();
+        if last_queries.len() >= MAX_RATE_LIMITED_SERVERS {
+            let keep = min_interval.max(DEFAULT_MIN_INTERVAL);
+            last_queries.retain(|_, last| now.duration_since(*last) < keep);
+        }
+        if let Some(since) = last_queries.get(&addr).map(|last| now.duration_since(*last)) {
+            if since < min_interval {
+                warn!("not querying {}, last queried {:?} ago", addr, since);
+                return Err(NtpError::ServiceUnavailable(format!(
+                    "rate limited, {} was queried {:?} ago, minimum interval {:?}", addr, since, min_interval
+                )));
+            }
+        }
+        last_queries.insert(addr, now);
+
+        Ok(())
+    }
+
+    /// Best effort, a capture that fails to write does not fail the query.
+    fn capture(&self, local: Option<SocketAddr>, record: &AuditRecord) {
+        if let Some(pcap) = &self.pcap {
+            if let Err(err) = pcap.write_record(local, record) {
+                warn!("pcap capture failed: {}", err);
+            }
+        }
+    }
+
+    fn resolve(&self, ntp_server: &str, default_port: &str) -> Result<SocketAddr, NtpError> {
+        let family = match (self.family, self.bind_addr) {
+            (IpFamily::Any, Some(IpAddr::V4(_))) => IpFamily::V4,
+            (IpFamily::Any, Some(IpAddr::V6(_))) => IpFamily::V6,
+            (family, _) => family,
+        };
+        self.resolve_family(ntp_server, default_port, family)
+    }
+
+    fn resolve_family(&self, ntp_server: &str, default_port: &str, family: IpFamily) -> Result<SocketAddr, NtpError> {
+        let addrs = lookup(getaddr(ntp_server, default_port), self.resolve_timeout.unwrap_or(self.timeout))?;
+        let addrs: Vec<_> = addrs.into_iter().filter(|addr| match family {
+            IpFamily::Any => true,
+            IpFamily::V4 => addr.is_ipv4(),
+            IpFamily::V6 => addr.is_ipv6(),
+        }).collect();
+
+        pick_responsive(&addrs).ok_or_else(|| {
+            NtpError::BadNtpServerAddr(format!("{}: no {:?} address", ntp_server, family))
+        })
+    }
+
+    /// The kept socket of a persistent client, or a new one.
+    fn socket(&self, ntp_server: &str, timing: &mut QueryTiming) -> Result<UdpSocket, NtpError> {
+        let kept = self.sockets.as_ref().and_then(|sockets| sockets.lock().unwrap().remove(ntp_server));
+        // Late or duplicated responses to earlier requests would fail the originate check.
+        match kept.filter(|socket| drain(socket).is_ok()) {
+            Some(socket) => Ok(socket),
+            None => self.make_socket(ntp_server, timing),
+        }
+    }
+
+    fn keep(&self, ntp_server: &str, socket: UdpSocket) {
+        if let Some(sockets) = &self.sockets {
+            sockets.lock().unwrap().insert(ntp_server.to_string(), socket);
+        }
+    }
+
+    /// An unconnected socket on the source port, or why there is none.
+    fn bind(&self, ipv4: bool) -> Result<UdpSocket, String> {
+        let port = self.source_port;
+        let local = match self.bind_addr {
+            Some(addr) if addr.is_ipv4() == ipv4 => SocketAddr::new(addr, port),
+            Some(addr) => return Err(format!("bind address {} is not of the server's address family", addr)),
+            None if ipv4 => SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)),
+            None => SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)),
+        };
+        let bind = || UdpSocket::bind(local).map_err(|err| match err.kind() {
+            io::ErrorKind::PermissionDenied => {
+                format!("binding source port {} needs root or CAP_NET_BIND_SERVICE: {}", port, err)
+            }
+            io::ErrorKind::AddrInUse => format!("source port {} is in use, e.g. by an ntp daemon: {}", port, err),
+            io::ErrorKind::AddrNotAvailable => format!("bind address {} is not on this host: {}", local.ip(), err),
+            _ => err.to_string(),
+        });
+        #[cfg(all(feature = "netns", target_os = "linux"))]
+        let socket = match &self.netns {
+            Some(netns) => crate::sockopt::in_netns(netns, bind)?,
+            None => bind()?,
+        };
+        #[cfg(not(all(feature = "netns", target_os = "linux")))]
+        let socket = bind()?;
+        #[cfg(all(feature = "fwmark", target_os = "linux"))]
+        if let Some(mark) = self.mark {
+            crate::sockopt::set_mark(&socket, mark)?;
+        }
+        #[cfg(all(feature = "ttl", target_os = "linux"))]
+        if self.recv_ttl {
+            crate::sockopt::set_recv_ttl(&socket, ipv4)?;
+        }
+        if let Some(hook) = &self.hooks.socket {
+            (hook.0)(&socket).map_err(|err| format!("socket hook failed: {}", err))?;
+        }
+
+        Ok(socket)
+    }
+
+    fn make_socket(&self, ntp_server: &str, timing: &mut QueryTiming) -> Result<UdpSocket, NtpError> {
+        let started = Instant::now();
+        let addr = self.resolve(ntp_server, &self.port.to_string());
+        timing.resolve = started.elapsed();
+        let addr = addr?;
+        let started = Instant::now();
+        let socket = self.bind(addr.is_ipv4()).map_err(NtpError::ServiceUnavailable)?;
+        socket.connect(addr).map_err(|err| {
+            NtpError::UnexpectedErr(err.to_string())
+        })?;
+        socket.set_write_timeout(Some(self.timeout)).map_err(|err| {
+            NtpError::UnexpectedErr(err.to_string())
+        })?;
+        socket.set_read_timeout(Some(self.timeout)).map_err(|err| {
+            NtpError::UnexpectedErr(err.to_string())
+        })?;
+        timing.socket = started.elapsed();
+
+        Ok(socket)
+    }
+}
+
+/// Count an exchange with `addr` towards skipping it, or clear the count once it answers.
+pub(crate) fn note_answered(addr: SocketAddr, answered: bool) {
+    let now = Instant::now();
+    let mut misses = MISSES.get_or_init(Mutex::default).lock().unwrap();
+    if answered {
+        misses.remove(&addr);
+        return;
+    }
+    if misses.len() >= MAX_RATE_LIMITED_SERVERS {
+        misses.retain(|_, (_, last)| now.duration_since(*last) < UNRESPONSIVE_FOR);
+    }
+    let (count, last) = misses.entry(addr).or_insert((0, now));
+    *count += 1;
+    *last = now;
+}
+
+/// The first of `addrs` not recently unresponsive, or the first if all are.
+pub(crate) fn pick_responsive(addrs: &[SocketAddr]) -> Option<SocketAddr> {
+    let now = Instant::now();
+    let misses = MISSES.get_or_init(Mutex::default).lock().unwrap();
+    let responsive = |addr: &&SocketAddr| match misses.get(addr) {
+        Some((count, last)) => *count < UNRESPONSIVE_AFTER || now.duration_since(*last) >= UNRESPONSIVE_FOR,
+        None => true,
+    };
+    let picked = addrs.iter().find(responsive).or(addrs.first()).copied();
+    if picked != addrs.first().copied() {
+        debug!("skipping unresponsive {:?}, using {:?}", addrs.first(), picked);
+    }
+
+    picked
+}
+
+/// Which of `offsets` are further from their median than [`OUTLIER_MADS`]
+/// median absolute deviations, or [`MIN_OUTLIER_DISTANCE`]; none of fewer than 3.
+fn outliers(offsets: &[i64]) -> Vec<bool> {
+    if offsets.len() < 3 {
+        return vec![false; offsets.len()];
+    }
+    let mut sorted = offsets.to_vec();
+    sorted.sort_unstable();
+    let center = sorted[sorted.len() / 2];
+    let mut deviations: Vec<u64> = offsets.iter().map(|offset| offset.abs_diff(center)).collect();
+    deviations.sort_unstable();
+    let limit = deviations[deviations.len() / 2].saturating_mul(OUTLIER_MADS).max(MIN_OUTLIER_DISTANCE.as_nanos() as u64);
+
+    offsets.iter().map(|offset| offset.abs_diff(center) > limit).collect()
+}
+
+/// A server name of [`SntpClient::query_many`], resolved or already answered.
+enum Lookup {
+    Resolved(String, SocketAddr),
+    Done(Result<NtpResult, NtpError>),
+}
+
+/// `f` of `0..count`, in order, on up to `threads` threads.
+fn parallel<T: Send>(count: usize, threads: usize, f: impl Fn(usize) -> T + Sync) -> Vec<T> {
+    let next = AtomicUsize::new(0);
+    let mut results = thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads.min(count))
+            .map(|_| scope.spawn(|| {
+                let mut results = Vec::new();
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    if i >= count {
+                        return results;
+                    }
+                    results.push((i, f(i)));
+                }
+            }))
+            .collect();
+        workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect::<Vec<_>>()
+    });
+    results.sort_by_key(|(i, _)| *i);
+
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+fn exchange_once(
+    socket: &mut dyn Transport,
+    ntp_server: &str,
+    time: &dyn TimeSource,
+    version: u8,
+    policy: &ResponsePolicy,
+    hooks: &Hooks,
+    record: &mut AuditRecord,
+) -> Result<Exchange, NtpError> {
+    let peer = socket.peer_addr().map_err(|err| {
+        NtpError::UnexpectedErr(err.to_string())
+    })?;
+    record.addr = Some(peer);
+
+    let validate_time = time.now();
+    let (request, timestamp) = hooks.request(version, duration_to_ntp_timestamp(&validate_time));
+    record.request = request.clone();
+    debug!("sending ntp request to {} ({})", ntp_server, peer);
+    diag::count(diag::QUERIES, ntp_server);
+    let transmit_time = time.now();
+    record.t1 = Some(transmit_time);
+    let started = Instant::now();
+    send_full(socket, &request)?;
+    let mut buf = policy.limits.receive_buffer();
+    let received = recv_full(socket, &mut buf, ntp_server);
+    record.timing.round_trip = started.elapsed();
+    let (n, ttl) = received.map_err(|err| {
+        warn!("no response from {}: {:?}", peer, err);
+        err
+    })?;
+    let receive_time = time.now();
+    let buf = &buf[..n];
+    record.t4 = Some(receive_time);
+    record.ttl = ttl;
+    record.response = buf.to_vec();
+    debug!("received {} bytes from {}", n, peer);
+    hooks.received(peer, buf);
+
+    let started = Instant::now();
+    let result = check_response(peer, timestamp, transmit_time, receive_time, buf, policy, record);
+    record.timing.validate = started.elapsed();
+    observe(ntp_server, &result);
+
+    result
+}
+
+/// Count and report the outcome of an exchange.
+pub(crate) fn observe(ntp_server: &str, result: &Result<Exchange, NtpError>) {
+    let exchange = match result {
+        Ok(exchange) => exchange,
+        Err(NtpError::TruncatedNtpMessage) => return diag::count(diag::PARSE_ERRORS, ntp_server),
+        Err(NtpError::KissOfDeath(code)) => return diag::count_code(diag::KISS_OF_DEATH, ntp_server, code),
+        Err(_) => return,
+    };
+    diag::gauge(diag::OFFSET, ntp_server, exchange.offset_nanos() as f64 / 1e9);
+    diag::gauge(diag::DELAY, ntp_server, exchange.delay_nanos() as f64 / 1e9);
+    diag::gauge(diag::STRATUM, ntp_server, exchange.msg.stratum as f64);
+}
+
+/// Parse and check the `response` to a request with transmit timestamp `origin`,
+/// sent at `t1` and received at `t4`, and record the verdicts and t2/t3.
+pub(crate) fn check_response(
+    peer: SocketAddr,
+    origin: u64,
+    t1: Duration,
+    t4: Duration,
+    response: &[u8],
+    policy: &ResponsePolicy,
+    record: &mut AuditRecord,
+) -> Result<Exchange, NtpError> {
+    let mut server_msg = NtpMsg::new();
+    let too_long = response.len() > policy.limits.max_packet;
+    record.verdict(Check::Length, response.len() >= 48 && !too_long);
+    if too_long {
+        warn!("oversized response from {}: {} bytes", peer, response.len());
+        return Err(ParseError::TooLong(response.len()).into());
+    }
+    server_msg.unmarshal(response.get(..48).unwrap_or(response)).inspect_err(|err| {
+        warn!("malformed response from {}: {:?}", peer, err);
+    })?;
+    debug!("response from {}: {}", peer, server_msg);
+
+    if policy.mode == ParseMode::Strict {
+        let format = NtpPacket::parse_limited(response, &policy.limits);
+        let passed = record.verdict(Check::Format, format.is_ok());
+        if let Err(err @ (ParseError::TooManyExtensions(_) | ParseError::FieldTooLong(_))) = format {
+            warn!("oversized response from {}: {:?}", peer, err);
+            return Err(err.into());
+        }
+        if !passed {
+            warn!("untrusted response from {}: failed strict checks {:?}", peer, record.verdicts);
+            return Err(NtpError::UntrustedMessage);
+        }
+    }
+
+    if !record.verdict(Check::Originate, server_msg.originate_timestamp == origin) {
+        warn!("untrusted response from {}: originate timestamp mismatch", peer);
+        return Err(NtpError::UntrustedMessage);
+    }
+    // Only believed once it echoes the request, or anyone could silence a server.
+    if !record.verdict(Check::Kiss, server_msg.stratum != 0) {
+        let code = refid(0, server_msg.reference_identifier);
+        warn!("kiss-o'-death {} from {}", code, peer);
+        return Err(NtpError::KissOfDeath(code));
+    }
+    let requested = record.request.first().map(|byte| byte >> 3 & 7);
+    let passed = [
+        record.verdict(Check::Mode, server_msg.mode == NTP_MODE_SERVER),
+        record.verdict(
+            Check::Version,
+            matches!(server_msg.version_number, NTP_VERSION_3 | NTP_VERSION_4)
+                && requested.is_none_or(|version| version == server_msg.version_number),
+        ),
+        record.verdict(Check::Leap, server_msg.leap_indicator != LEAP_ALARM),
+        record.verdict(Check::Timestamps, server_msg.receiver_timestamp != 0 && server_msg.transmit_timestamp != 0),
+    ];
+    if passed.contains(&false) {
+        warn!("untrusted response from {}: failed sanity checks {:?}", peer, record.verdicts);
+        return Err(NtpError::UntrustedMessage);
+    }
+    record.t2 = Some(ntp_timestamp_to_duration(server_msg.receiver_timestamp));
+    record.t3 = Some(ntp_timestamp_to_duration(server_msg.transmit_timestamp));
+    if let Some(max) = policy.max_root_dispersion {
+        let dispersion = ShortFormat(server_msg.root_dispersion);
+        if !record.verdict(Check::RootDispersion, dispersion.to_duration() <= max) {
+            warn!("response from {} rejected: root dispersion {} exceeds {:?}", peer, dispersion, max);
+            return Err(NtpError::UntrustedMessage);
+        }
+    }
+    if let Some((min, max)) = policy.stratum {
+        if !record.verdict(Check::Stratum, (min..=max).contains(&server_msg.stratum)) {
+            warn!("response from {} rejected: stratum {} outside {}..={}", peer, server_msg.stratum, min, max);
+            return Err(NtpError::UntrustedMessage);
+        }
+    }
+    if let Some(max) = policy.max_reference_age {
+        // A reference before 1970 reads as the next era, after the transmit time.
+        let reference = (server_msg.reference_timestamp != 0).then(|| ntp_timestamp_to_duration(server_msg.reference_timestamp));
+        let age = reference.and_then(|reference| ntp_timestamp_to_duration(server_msg.transmit_timestamp).checked_sub(reference));
+        if !record.verdict(Check::ReferenceAge, age.is_some_and(|age| age <= max)) {
+            match (reference, age) {
+                (_, Some(age)) => warn!("response from {} rejected: last clock update {:?} ago, more than {:?}", peer, age, max),
+                (Some(_), None) => warn!("response from {} rejected: reference timestamp after transmit timestamp", peer),
+                (None, None) => warn!("response from {} rejected: no reference timestamp", peer),
+            }
+            return Err(NtpError::UntrustedMessage);
+        }
+    }
+
+    Ok(Exchange {
+        peer,
+        t1,
+        t2: ntp_timestamp_to_duration(server_msg.receiver_timestamp),
+        t3: ntp_timestamp_to_duration(server_msg.transmit_timestamp),
+        t4,
+        msg: server_msg,
+    })
+}
+
+pub(crate) fn sys_time() -> Duration {
+    SystemClock.now()
+}
+
+/// Addresses of `host:port`, resolved on a thread of its own so that a hanging
+/// resolver fails the query after `timeout` instead of blocking it.
+fn lookup(addr: String, timeout: Duration) -> Result<Vec<SocketAddr>, NtpError> {
+    if let Ok(addr) = addr.parse::<SocketAddr>() {
+        return Ok(vec![addr]);
+    }
+
+    let (tx, rx) = mpsc::channel();
+    let host = addr.clone();
+    thread::Builder::new()
+        .name("sntp-resolve".to_string())
+        .spawn(move || {
+            // The caller may have given up already.
+            let _ = tx.send(host.to_socket_addrs().map(Iterator::collect));
+        })
+        .map_err(|err| NtpError::UnexpectedErr(err.to_string()))?;
+    match rx.recv_timeout(timeout) {
+        Ok(result) => result.map_err(|err| NtpError::BadNtpServerAddr(err.to_string())),
+        Err(_) => {
+            warn!("resolving {} timed out after {:?}", addr, timeout);
+            Err(NtpError::ResolveTimeout(format!("{}: no answer from the resolver in {:?}", addr, timeout)))
+        }
+    }
+}
+
+pub(crate) fn getaddr(svr: &str, default_port: &str) -> String {
+    if let Ok(ip) = svr.parse::<IpAddr>() {
+        SocketAddr::new(ip, default_port.parse().unwrap()).to_string()
+    } else if svr.contains(':') {
+        svr.to_string()
+    } else {
+        svr.to_string() + ":" + default_port
+    }
+}
+
+/// Cancels exchanges of the clients it was given to, see
+/// [`SntpClientBuilder::cancel`]. Clones cancel the same exchanges.
+///
+/// Example
+/// ```rust
+/// # use std::thread;
+/// # use std::time::Duration;
+/// # use simple_ntp::client::{CancelToken, SntpClient};
+///
+/// fn main() {
+///     let cancel = CancelToken::new();
+///     let client = SntpClient::builder().cancel(cancel.clone()).build();
+///     let query = thread::spawn(move || client.query("ntp.aliyun.com"));
+///     cancel.cancel();
+///     println!("{:?}", query.join().unwrap());
+/// }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
+
+    /// Cancel for good, a token cannot be reset.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Error message of a cancelled exchange.
+const CANCELLED: &str = "cancelled";
+
+fn check_cancelled(cancelled: &dyn Fn() -> bool) -> Result<(), NtpError> {
+    match cancelled() {
+        true => Err(NtpError::ServiceUnavailable(CANCELLED.to_string())),
+        false => Ok(()),
+    }
+}
+
+/// A connected socket waiting for the response in short steps, to give up once cancelled.
+struct Cancellable<'a> {
+    socket: &'a UdpSocket,
+    timeout: Duration,
+    cancelled: &'a dyn Fn() -> bool,
+}
+
+impl Cancellable<'_> {
+    fn wait(&self, buf: &mut [u8]) -> io::Result<(usize, Option<u8>)> {
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            if (self.cancelled)() {
+                return Err(io::Error::new(io::ErrorKind::Interrupted, CANCELLED));
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            self.socket.set_read_timeout(Some(remaining.min(CANCEL_POLL_INTERVAL)))?;
+            match recv_ttl(self.socket, buf) {
+                Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => continue,
+                result => return result,
+            }
+        }
+    }
+}
+
+impl Transport for Cancellable<'_> {
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.send(buf)
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.recv_ttl(buf).map(|(n, _)| n)
+    }
+
+    fn recv_ttl(&mut self, buf: &mut [u8]) -> io::Result<(usize, Option<u8>)> {
+        let result = self.wait(buf);
+        // A kept socket is reused with the client's timeout.
+        self.socket.set_read_timeout(Some(self.timeout))?;
+        result
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.peer_addr()
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+/// A caller's socket, see [`SntpClient::query_with_socket`].
+struct Borrowed<'a> {
+    socket: &'a UdpSocket,
+    peer: SocketAddr,
+    connected: bool,
+    deadline: Instant,
+}
+
+impl Transport for Borrowed<'_> {
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.connected {
+            true => self.socket.send(buf),
+            false => self.socket.send_to(buf, self.peer),
+        }
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let remaining = self.deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            self.socket.set_read_timeout(Some(remaining))?;
+            let (n, from) = self.socket.recv_from(buf)?;
+            if from == self.peer {
+                return Ok(n);
+            }
+            debug!("ignored datagram from {}", from);
+        }
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+/// Discard any datagrams waiting on `socket`.
+fn drain(socket: &UdpSocket) -> io::Result<()> {
+    socket.set_nonblocking(true)?;
+    let mut buf = [0u8; 48];
+    while socket.recv(&mut buf).is_ok() {}
+    socket.set_nonblocking(false)
+}
+
+fn send_full(socket: &mut dyn Transport, buf: &[u8]) -> Result<(), NtpError> {
+    socket.send(buf).map_err(|err| {
+        NtpError::ServiceUnavailable(err.to_string())
+    })?;
+
+    Ok(())
+}
+
+fn recv_full(socket: &mut dyn Transport, buf: &mut [u8], ntp_server: &str) -> Result<(usize, Option<u8>), NtpError> {
+    let received = socket.recv_ttl(buf).map_err(|err| {
+        if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) {
+            diag::count(diag::TIMEOUTS, ntp_server);
+        }
+        NtpError::ServiceUnavailable(err.to_string())
+    })?;
+
+    Ok(received)
+}
+
+/// `socket.recv(buf)`, with the TTL if the `ttl` feature asked the socket for it.
+fn recv_ttl(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, Option<u8>)> {
+    #[cfg(all(feature = "ttl", target_os = "linux"))]
+    let received = crate::sockopt::recv_ttl(socket, buf);
+    #[cfg(not(all(feature = "ttl", target_os = "linux")))]
+    let received = socket.recv(buf).map(|n| (n, None));
+    received
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::*;
+    use crate::protocol::*;
+    use crate::testing::{self, answer, MockServer};
+    use std::thread;
+
+    /// Answer one request on a loopback socket with `reply(request)`.
+    fn respond_once(reply: fn(&[u8]) -> Vec<u8>) -> String {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let mut buf = [0u8; 48];
+            let (n, from) = socket.recv_from(&mut buf).unwrap();
+            socket.send_to(&reply(&buf[..n]), from).unwrap();
+        });
+        addr
+    }
+
+    #[test]
+    fn test_audit_record() {
+        let server = respond_once(|request| {
+            let now = duration_to_ntp_timestamp(&sys_time());
+            let mut response = request.to_vec();
+            response[0] = 4 << 3 | 4;
+            response[1] = 2;
+            response.copy_within(40..48, 24);
+            response[32..40].copy_from_slice(&now.to_be_bytes());
+            response[40..48].copy_from_slice(&now.to_be_bytes());
+            response
+        });
+        let (result, record) = ntp_audited(&server);
+
+        assert!(result.is_ok());
+        assert_eq!(record.addr.unwrap().to_string(), server);
+        assert_eq!(record.request.len(), 48);
+        assert_eq!(record.response.len(), 48);
+        assert!(record.t1.is_some() && record.t2.is_some() && record.t3.is_some() && record.t4.is_some());
+        assert_eq!(record.verdicts, vec![
+            Verdict { check: Check::Length, passed: true },
+            Verdict { check: Check::Originate, passed: true },
+            Verdict { check: Check::Kiss, passed: true },
+            Verdict { check: Check::Mode, passed: true },
+            Verdict { check: Check::Version, passed: true },
+            Verdict { check: Check::Leap, passed: true },
+            Verdict { check: Check::Timestamps, passed: true },
+        ]);
+    }
+
+    #[cfg(all(feature = "ttl", target_os = "linux"))]
+    #[test]
+    fn test_max_ttl_change() {
+        let server = MockServer::builder().start().unwrap();
+        let client = SntpClient::builder().max_ttl_change(2).build();
+        let (_, first) = client.query_audited(&server.addr());
+        let (result, second) = client.query_audited(&server.addr());
+        assert!(result.is_ok());
+        assert!(first.ttl.is_some_and(|ttl| ttl > 0));
+        assert_eq!(second.ttl, first.ttl);
+        assert!(second.verdicts.contains(&Verdict { check: Check::Ttl, passed: true }));
+        assert_eq!(SntpClient::default().query_audited(&server.addr()).1.ttl, None);
+
+        let check = |ttl| {
+            let mut record = AuditRecord { ttl: Some(ttl), ..AuditRecord::default() };
+            client.check_ttl("spoofed", &mut record).is_ok()
+        };
+        assert!(check(57) && check(59) && check(58));
+        // A spoofer's packet, then the route really changing.
+        assert!(!check(64) && check(57));
+        assert!(!check(40) && check(40) && check(41));
+    }
+
+    #[test]
+    fn test_source_port() {
+        let server = MockServer::builder().start().unwrap();
+        let taken = UdpSocket::bind("0.0.0.0:0").unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let client = SntpClient::builder().source_port(port).build();
+        let err = client.query(&server.addr()).unwrap_err();
+        assert!(matches!(err, NtpError::ServiceUnavailable(msg) if msg.contains("in use")));
+
+        drop(taken);
+        assert!(client.query(&server.addr()).is_ok());
+        assert!(client.query_multiplexed(&[server.local_addr(), server.local_addr()]).iter().all(Result::is_ok));
+        server.stop();
+
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = client.to_builder().timeout(Duration::from_millis(100)).build();
+        let _ = client.query(&silent.local_addr().unwrap().to_string());
+        let (_, from) = silent.recv_from(&mut [0u8; 48]).unwrap();
+        assert_eq!(from.port(), port);
+    }
+
+    #[test]
+    fn test_bind_addr_port_version() {
+        let server = MockServer::builder().offset_nanos(2_000_000_000).start().unwrap();
+        let client = SntpClient::builder()
+            .bind_addr(Ipv4Addr::LOCALHOST.into())
+            .port(server.local_addr().port())
+            .version(3)
+            .build();
+        let (result, record) = client.query_audited("127.0.0.1");
+        assert!(result.is_ok());
+        assert_eq!(record.request[0] >> 3 & 7, NTP_VERSION_3);
+        assert_eq!((client.clock_offset("127.0.0.1").unwrap() as f64 / 1e9).round(), 2.0);
+        assert_eq!((client.unix_timestamp("127.0.0.1").unwrap().as_secs_f64() - sys_time().as_secs_f64()).round(), 2.0);
+        assert_eq!(SntpClient::builder().version(9).build().version("127.0.0.1"), NTP_VERSION_4);
+
+        // The bind address picks the family, and has to be on this host.
+        let err = client.to_builder().bind_addr(Ipv6Addr::LOCALHOST.into()).build().query("127.0.0.1").unwrap_err();
+        assert!(matches!(err, NtpError::BadNtpServerAddr(msg) if msg.contains("V6")));
+        let err = client.to_builder().family(IpFamily::V4).bind_addr(Ipv6Addr::LOCALHOST.into()).build().query("127.0.0.1").unwrap_err();
+        assert!(matches!(err, NtpError::ServiceUnavailable(msg) if msg.contains("address family")));
+        let err = client.to_builder().bind_addr(Ipv4Addr::new(192, 0, 2, 1).into()).build().query("127.0.0.1").unwrap_err();
+        assert!(matches!(err, NtpError::ServiceUnavailable(msg) if msg.contains("not on this host")));
+    }
+
+    #[test]
+    fn test_on_socket() {
+        let server = MockServer::builder().start().unwrap();
+        let sockets = Arc::new(Mutex::new(Vec::new()));
+        let seen = sockets.clone();
+        let client = SntpClient::builder()
+            .on_socket(move |socket| {
+                seen.lock().unwrap().push(socket.local_addr()?);
+                Ok(())
+            })
+            .build();
+        assert!(client.query(&server.addr()).is_ok());
+        assert_eq!(sockets.lock().unwrap().len(), 1);
+        assert!(client.query_multiplexed(&[server.local_addr()])[0].is_ok());
+        assert_eq!(sockets.lock().unwrap().len(), 2);
+
+        let client = SntpClient::builder()
+            .on_socket(|_| Err(io::Error::new(io::ErrorKind::PermissionDenied, "not protected")))
+            .build();
+        let err = client.query(&server.addr()).unwrap_err();
+        assert!(matches!(err, NtpError::ServiceUnavailable(msg) if msg.contains("not protected")));
+        server.stop();
+    }
+
+    #[test]
+    fn test_query_with_socket() {
+        let server = MockServer::builder().offset_nanos(2_000_000_000).start().unwrap();
+        let client = SntpClient::builder().timeout(Duration::from_millis(200)).build();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(7))).unwrap();
+
+        // A stranger's datagram is skipped.
+        UdpSocket::bind("127.0.0.1:0").unwrap().send_to(&[0u8; 48], socket.local_addr().unwrap()).unwrap();
+        let result = client.query_with_socket(&socket, &server.addr()).unwrap();
+        assert_eq!((result.offset_nanos as f64 / 1e9).round(), 2.0);
+        assert_eq!(socket.read_timeout().unwrap(), Some(Duration::from_secs(7)));
+
+        socket.connect(server.local_addr()).unwrap();
+        assert!(client.query_with_socket(&socket, "connected").is_ok());
+        server.stop();
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let started = Instant::now();
+        let result = client.query_with_socket(&socket, &silent.local_addr().unwrap().to_string());
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert!(matches!(result, Err(NtpError::ServiceUnavailable(_))));
+    }
+
+    #[test]
+    fn test_send_receive_hooks() {
+        let server = MockServer::builder().start().unwrap();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let seen = received.clone();
+        let client = SntpClient::builder()
+            .timeout(Duration::from_millis(100))
+            // A different transmit timestamp, which the response must echo.
+            .on_send(|request| request[47] ^= 0xff)
+            .on_receive(move |from, response| seen.lock().unwrap().push((from, response.len())))
+            .build();
+
+        let (result, record) = client.query_audited(&server.addr());
+        assert!(result.is_ok());
+        assert_eq!(record.request[47], u64::from_be_bytes(record.response[24..32].try_into().unwrap()) as u8);
+        assert_eq!(*received.lock().unwrap(), [(server.local_addr(), 48)]);
+        assert!(client.query_multiplexed(&[server.local_addr()])[0].is_ok());
+        assert_eq!(received.lock().unwrap().len(), 2);
+        server.stop();
+
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = client.to_builder().on_send(|request| request.extend_from_slice(&[0xff, 0x00, 0x00, 0x04])).build();
+        let _ = client.query(&silent.local_addr().unwrap().to_string());
+        assert_eq!(silent.recv(&mut [0u8; 64]).unwrap(), 52);
+    }
+
+    #[test]
+    fn test_resolve_timeout() {
+        let server = MockServer::builder().start().unwrap();
+        let client = SntpClient::builder().resolve_timeout(Duration::ZERO).build();
+        // Addresses need no resolver.
+        assert!(client.query(&server.addr()).is_ok());
+        assert!(matches!(client.query("localhost"), Err(NtpError::ResolveTimeout(msg)) if msg.contains("localhost:123")));
+        server.stop();
+
+        let client = SntpClient::builder().resolve_timeout(Duration::from_secs(5)).build();
+        assert_eq!(lookup("localhost:123".to_string(), Duration::from_secs(5)).unwrap()[0].port(), 123);
+        assert!(matches!(client.query("simple-ntp.invalid"), Err(NtpError::BadNtpServerAddr(_))));
+    }
+
+    #[test]
+    fn test_compare() {
+        let a = MockServer::builder().offset_nanos(1_000_000_000).start().unwrap();
+        let b = MockServer::builder().offset_nanos(-500_000_000).start().unwrap();
+        let client = SntpClient::builder().min_interval(Duration::ZERO).timeout(Duration::from_millis(200)).build();
+
+        let comparison = client.compare(&a.addr(), &b.addr(), 4).unwrap();
+        assert_eq!(comparison.samples, 4);
+        assert!((comparison.offset_nanos + 1_500_000_000).abs() <= comparison.uncertainty_nanos + 1_000_000);
+        assert!(comparison.uncertainty_nanos < 100_000_000);
+        assert!(comparison.to_string().starts_with("-1.") && comparison.to_string().ends_with("over 4 samples"));
+
+        let stopped = b.addr();
+        b.stop();
+        assert!(matches!(client.compare(&a.addr(), &stopped, 2), Err(NtpError::ServiceUnavailable(_))));
+        assert!(matches!(client.compare(&a.addr(), &a.addr(), 0), Err(NtpError::BadConfig(_))));
+        a.stop();
+    }
+
+    #[test]
+    fn test_query_timing() {
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = SntpClient::builder().timeout(Duration::from_millis(100)).build();
+        let (result, record) = client.query_audited(&silent.local_addr().unwrap().to_string());
+        assert!(result.is_err());
+        assert!(record.timing.round_trip >= Duration::from_millis(100));
+        assert_eq!(record.timing.validate, Duration::ZERO);
+
+        let server = MockServer::builder().start().unwrap();
+        let (result, record) = client.query_audited(&server.addr());
+        assert!(result.is_ok());
+        assert!(record.timing.round_trip > Duration::ZERO && record.timing.round_trip < Duration::from_millis(100));
+        assert!(record.timing.total() >= record.timing.round_trip + record.timing.validate);
+
+        let results = client.exchange_multiplexed(&[(server.addr(), server.local_addr())]);
+        assert!(results[0].1.timing.round_trip > Duration::ZERO);
+        server.stop();
+    }
+
+    #[test]
+    fn test_client() {
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = silent.local_addr().unwrap().to_string();
+
+        let client = SntpClient::builder().timeout(Duration::from_millis(100)).build();
+        let start = std::time::Instant::now();
+        assert!(matches!(client.query(&server), Err(NtpError::ServiceUnavailable(_))));
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        let client = SntpClient::builder().family(IpFamily::V6).build();
+        assert!(matches!(client.query(&server), Err(NtpError::BadNtpServerAddr(_))));
+
+        assert_eq!(getaddr("::1", NTP_DEFAULT_PORT), "[::1]:123");
+        assert_eq!(getaddr("127.0.0.1", NTP_DEFAULT_PORT), "127.0.0.1:123");
+        assert_eq!(getaddr("ntp.aliyun.com", NTP_DEFAULT_PORT), "ntp.aliyun.com:123");
+    }
+
+    #[test]
+    fn test_persistent_client() {
+        let server = MockServer::builder().start().unwrap();
+        let client = SntpClient::builder().timeout(Duration::from_millis(200)).persistent(true).build();
+        let kept = || {
+            let sockets = client.sockets.as_ref().unwrap().lock().unwrap();
+            sockets.get(&server.addr()).map(|socket| socket.local_addr().unwrap())
+        };
+
+        client.query(&server.addr()).unwrap();
+        let local = kept().unwrap();
+        client.clone().query(&server.addr()).unwrap();
+        assert_eq!(kept(), Some(local));
+
+        // A failed exchange drops the socket, its late answer goes nowhere.
+        server.push(testing::Response::Delay(Duration::from_millis(300)));
+        assert!(client.query(&server.addr()).is_err());
+        assert_eq!(kept(), None);
+        thread::sleep(Duration::from_millis(200));
+        client.query(&server.addr()).unwrap();
+        assert!(kept().is_some());
+        assert!(SntpClient::default().sockets.is_none());
+    }
+
+    #[test]
+    fn test_query_multiplexed() {
+        let a = MockServer::builder().offset_nanos(1_000_000_000).start().unwrap();
+        let b = MockServer::builder().offset_nanos(-2_000_000_000).script([testing::Response::BadOrigin]).start().unwrap();
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addrs = [a.local_addr(), b.local_addr(), silent.local_addr().unwrap(), a.local_addr()];
+
+        let client = SntpClient::builder().timeout(Duration::from_millis(300)).build();
+        let start = std::time::Instant::now();
+        let results = client.query_multiplexed(&addrs);
+        assert!(start.elapsed() < Duration::from_secs(1));
+        let seconds = |result: &Result<NtpResult, NtpError>| result.as_ref().map(|result| (result.offset_nanos as f64 / 1e9).round()).ok();
+        assert_eq!((seconds(&results[0]), seconds(&results[3])), (Some(1.0), Some(1.0)));
+        // The bad origin is ignored like any stray datagram.
+        assert!(matches!(results[1], Err(NtpError::ServiceUnavailable(_))));
+        assert!(matches!(results[2], Err(NtpError::ServiceUnavailable(_))));
+        assert_eq!(a.requests(), 2);
+
+        b.push(testing::Response::Reply);
+        let results = client.query_multiplexed(&addrs[1..2]);
+        assert_eq!(seconds(&results[0]), Some(-2.0));
+        assert!(client.query_multiplexed(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_query_many() {
+        let mocks: Vec<_> = (0..3)
+            .map(|i| MockServer::builder().offset_nanos(i * 1_000_000_000).start().unwrap())
+            .collect();
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut servers: Vec<_> = mocks.iter().map(|mock| mock.local_addr().to_string()).collect();
+        servers.insert(1, "invalid..name".to_string());
+        servers.push(silent.local_addr().unwrap().to_string());
+
+        let client = SntpClient::builder().timeout(Duration::from_millis(300)).build();
+        let start = std::time::Instant::now();
+        let results = client.query_many(&servers);
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(results.len(), 5);
+        let seconds = |i: usize| results[i].as_ref().map(|result| (result.offset_nanos as f64 / 1e9).round()).ok();
+        assert_eq!((seconds(0), seconds(2), seconds(3)), (Some(0.0), Some(1.0), Some(2.0)));
+        assert!(matches!(results[1], Err(NtpError::BadNtpServerAddr(_))));
+        assert!(matches!(results[4], Err(NtpError::ServiceUnavailable(_))));
+        assert_eq!(parallel(100, 7, |i| i * 2), (0..100).map(|i| i * 2).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_min_interval() {
+        let server = MockServer::builder().start().unwrap();
+        let client = SntpClient::builder().min_interval(Duration::from_millis(300)).build();
+        client.query(&server.addr()).unwrap();
+        assert!(matches!(client.query(&server.addr()), Err(NtpError::ServiceUnavailable(_))));
+        let results = client.query_multiplexed(&[server.local_addr()]);
+        assert!(matches!(results[0], Err(NtpError::ServiceUnavailable(_))));
+        assert_eq!(server.requests(), 1);
+
+        // Other clients apply their own limit, none for loopback by default.
+        SntpClient::default().query(&server.addr()).unwrap();
+        thread::sleep(Duration::from_millis(300));
+        client.query(&server.addr()).unwrap();
+        assert_eq!(server.requests(), 3);
+    }
+
+    #[test]
+    fn test_query_multi() {
+        assert_eq!(outliers(&[0, 5_000_000, -5_000_000, 2_000_000_000]), [false, false, false, true]);
+        assert_eq!(outliers(&[0, 2_000_000_000]), [false, false]);
+
+        let start = |offset_nanos, latency| MockServer::builder().offset_nanos(offset_nanos).latency(latency).start().unwrap();
+        let fast = start(1_000_000_000, Duration::ZERO);
+        let slow = start(1_002_000_000, Duration::from_millis(40));
+        let slower = start(998_000_000, Duration::from_millis(80));
+        let wrong = start(9_000_000_000, Duration::ZERO);
+        let dead = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let servers = [dead.clone(), wrong.addr(), slower.addr(), fast.addr(), slow.addr()];
+        let client = SntpClient::builder().timeout(Duration::from_millis(500)).build();
+
+        let multi = client.query_multi(&servers, Strategy::Median).unwrap();
+        assert_eq!(multi.results.len(), 5);
+        assert!(multi.results[0].is_err());
+        assert_eq!(multi.outliers, [1]);
+        assert!((multi.best.offset_nanos - 1_000_000_000).abs() < 1_500_000, "{}", multi.best);
+        let multi = client.query_multi(&servers, Strategy::LowestDelay).unwrap();
+        assert_eq!(multi.best.addr, fast.local_addr());
+
+        // Fallback stops at the first answer, outlier or not.
+        let multi = client.query_multi(&servers, Strategy::Fallback).unwrap();
+        assert_eq!(multi.results.len(), 2);
+        assert_eq!(multi.best.addr, wrong.local_addr());
+        assert!(matches!(client.query_multi(&[dead], Strategy::Median), Err(NtpError::ServiceUnavailable(_))));
+        assert!(client.query_multi::<&str>(&[], Strategy::Fallback).is_err());
+    }
+
+    #[test]
+    fn test_cancel() {
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = silent.local_addr().unwrap();
+        let cancel = CancelToken::new();
+        let client = SntpClient::builder().cancel(cancel.clone()).build();
+
+        let start = std::time::Instant::now();
+        let cancelled = cancel.clone();
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            cancelled.cancel();
+        });
+        let result = client.query(&addr.to_string());
+        canceller.join().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(matches!(result, Err(NtpError::ServiceUnavailable(err)) if err == CANCELLED));
+
+        // Nothing is sent once cancelled.
+        let server = MockServer::builder().start().unwrap();
+        assert!(client.query(&server.addr()).is_err());
+        assert!(client.query_multiplexed(&[server.local_addr(), addr]).iter().all(Result::is_err));
+        assert_eq!(server.requests(), 0);
+    }
+
+    #[test]
+    fn test_shared_client() {
+        fn shareable<T: Clone + Send + Sync + 'static>() {}
+        shareable::<SntpClient>();
+
+        let server = MockServer::builder().start().unwrap();
+        let client = SntpClient::builder().persistent(true).build();
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let (client, addr) = (client.clone(), server.addr());
+                thread::spawn(move || (0..5).all(|_| client.query(&addr).is_ok()))
+            })
+            .collect();
+        assert!(workers.into_iter().all(|worker| worker.join().unwrap()));
+        assert_eq!(server.requests(), 20);
+        assert_eq!(client.sockets.as_ref().unwrap().lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_limits() {
+        use crate::testing::{HostilePacket, Response};
+
+        let packet = NtpPacket {
+            header: NtpMsg::new_for_client(NTP_VERSION_4, 1),
+            extensions: vec![ExtensionField { field_type: 0x0104, value: vec![7; 24] }; 3],
+            mac: None,
+        };
+        let data = packet.marshal();
+        assert_eq!(NtpPacket::parse_limited(&data, &Limits::default()), Ok(packet));
+        let limits = |max_packet, max_extensions, max_field_length| Limits { max_packet, max_extensions, max_field_length };
+        assert_eq!(NtpPacket::parse_limited(&data, &limits(100, 16, 1024)), Err(ParseError::TooLong(132)));
+        assert_eq!(NtpPacket::parse_limited(&data, &limits(2048, 2, 1024)), Err(ParseError::TooManyExtensions(2)));
+        assert_eq!(NtpPacket::parse_limited(&data, &limits(2048, 16, 16)), Err(ParseError::FieldTooLong(48)));
+
+        let server = MockServer::builder()
+            .script([
+                Response::Packet(HostilePacket::server().length(200)),
+                Response::Packet(HostilePacket::server().extension(0x0104, 28, 24)),
+                Response::Packet(HostilePacket::server().extension(0x0104, 28, 24)),
+            ])
+            .start()
+            .unwrap();
+        let client = SntpClient::builder().limits(limits(100, 16, 16)).build();
+        assert!(matches!(client.query(&server.addr()), Err(NtpError::UnexpectedErr(err)) if err == "TooLong(101)"));
+        assert!(client.query(&server.addr()).is_ok());
+        let strict = client.to_builder().parse_mode(ParseMode::Strict).build();
+        assert!(matches!(strict.query(&server.addr()), Err(NtpError::UnexpectedErr(err)) if err == "FieldTooLong(48)"));
+    }
+
+    #[test]
+    fn test_refid() {
+        let mut result = NtpResult { reference_id: u32::from_be_bytes(*b"GPS\0"), ..answer(0, 0) };
+        assert_eq!(result.refid(), "GPS");
+        result.stratum = 2;
+        result.reference_id = u32::from_be_bytes([192, 0, 2, 1]);
+        assert_eq!(result.refid(), "192.0.2.1");
+    }
+
+    #[test]
+    fn test_display() {
+        let result = NtpResult {
+            addr: "192.0.2.1:123".parse().unwrap(),
+            stratum: 2,
+            reference_id: u32::from_be_bytes([10, 0, 0, 1]),
+            ..answer(1_234_567, 12_000_000)
+        };
+        assert!(result.to_string().starts_with("192.0.2.1:123 stratum 2 (secondary) refid 10.0.0.1 offset +0.001234567s delay 0.012000000s"));
+    }
+
+    #[test]
+    fn test_result_fields() {
+        let server = respond_once(|request| {
+            let request = NtpMsg::parse(request).unwrap();
+            let now = sys_time();
+            NtpMsg {
+                leap_indicator: 1,
+                version_number: 4,
+                mode: NTP_MODE_SERVER,
+                stratum: 2,
+                poll: 6,
+                precision: -20i8 as u8,
+                root_delay: 0x0000_8000,
+                root_dispersion: 0x0000_4000,
+                reference_identifier: u32::from_be_bytes([192, 0, 2, 1]),
+                reference_timestamp: duration_to_ntp_timestamp(&(now - Duration::from_secs(3600))),
+                originate_timestamp: request.transmit_timestamp,
+                receiver_timestamp: duration_to_ntp_timestamp(&now),
+                transmit_timestamp: duration_to_ntp_timestamp(&now),
+            }.marshal()
+        });
+        let result = query(&server).unwrap();
+
+        assert_eq!((result.leap_indicator, result.version, result.mode, result.stratum), (1, 4, 4, 2));
+        assert_eq!((result.poll, result.precision), (PollInterval::from_exponent(6), -20));
+        assert_eq!(result.refid(), "192.0.2.1");
+        let (t1, t2, t3, t4) = result.timestamps;
+        assert!(t1 <= t4 && t2 == t3);
+        assert_eq!(result.reference_age().unwrap().as_secs_f64().round(), 3600.0);
+        // Half the 0.5s root delay, plus the 0.25s root dispersion and half the delay.
+        assert_eq!(result.root_distance_nanos(), 500_000_000 + result.delay_nanos.max(0) / 2);
+    }
+
+    #[test]
+    fn test_audit_record_untrusted() {
+        let server = respond_once(|request| request.to_vec());
+        let (result, record) = ntp_audited(&server);
+
+        assert!(matches!(result, Err(NtpError::UntrustedMessage)));
+        assert_eq!(record.response.len(), 48);
+        assert!(record.t2.is_none());
+        assert_eq!(record.verdicts.last(), Some(&Verdict { check: Check::Originate, passed: false }));
+    }
+
+    #[test]
+    fn test_parse_mode() {
+        use crate::testing::{HostilePacket, Response};
+
+        let odd = [HostilePacket::server().length(54), HostilePacket::server().extension(0x0104, 0xfffc, 32)];
+        let server = MockServer::builder()
+            .script(odd.iter().chain(&odd).cloned().map(Response::Packet))
+            .start()
+            .unwrap();
+        let lenient = SntpClient::default();
+        for _ in &odd {
+            assert!(lenient.query(&server.addr()).is_ok());
+        }
+        let strict = SntpClient::builder().parse_mode(ParseMode::Strict).build();
+        for _ in &odd {
+            assert!(matches!(strict.query(&server.addr()), Err(NtpError::UntrustedMessage)));
+        }
+        assert!(strict.query(&server.addr()).is_ok());
+
+        let (_, record) = strict.exchange_audited(&server.addr());
+        let checks: Vec<_> = record.verdicts.iter().map(|verdict| verdict.check).collect();
+        assert_eq!(checks, [
+            Check::Length,
+            Check::Format,
+            Check::Originate,
+            Check::Kiss,
+            Check::Mode,
+            Check::Version,
+            Check::Leap,
+            Check::Timestamps,
+        ]);
+    }
+
+    #[test]
+    fn test_sanity_checks() {
+        use crate::testing::{HostilePacket, Response};
+
+        let insane = [
+            (HostilePacket::server().version(2), Check::Version),
+            (HostilePacket::server().mode(3), Check::Mode),
+            (HostilePacket::server().leap(3), Check::Leap),
+            (HostilePacket::server().zero_timestamps(), Check::Timestamps),
+            (HostilePacket::server().transmit(0), Check::Timestamps),
+        ];
+        let server = MockServer::builder()
+            .script(insane.iter().map(|(packet, _)| Response::Packet(packet.clone())))
+            .script(["RATE", "DENY", "RSTR"].map(|code| Response::Packet(HostilePacket::server().kiss(code.as_bytes().try_into().unwrap()))))
+            .script([Response::Packet(HostilePacket::server()), Response::Packet(HostilePacket::server().version(3))])
+            .start()
+            .unwrap();
+        for (_, check) in insane {
+            let (result, record) = query_audited(&server.addr());
+            assert!(matches!(result, Err(NtpError::UntrustedMessage)));
+            assert!(record.verdicts.contains(&Verdict { check, passed: false }), "{:?}", check);
+        }
+        for code in ["RATE", "DENY", "RSTR"] {
+            assert!(matches!(query(&server.addr()), Err(NtpError::KissOfDeath(kiss)) if kiss == code));
+        }
+
+        // The version of the request is echoed.
+        let v3 = SntpClient::builder().version(3).build();
+        assert!(matches!(v3.query(&server.addr()), Err(NtpError::UntrustedMessage)));
+        assert!(v3.query(&server.addr()).is_ok());
+    }
+
+    #[test]
+    fn test_max_root_dispersion() {
+        use crate::testing::{HostilePacket, Response};
+
+        let server = MockServer::builder()
+            .script([
+                Response::Packet(HostilePacket::server().root_dispersion(0x1_0000)),
+                Response::Packet(HostilePacket::server().root_dispersion(0x1000)),
+                Response::Packet(HostilePacket::server().root_dispersion(0x1_0000)),
+            ])
+            .start()
+            .unwrap();
+        let client = SntpClient::builder().max_root_dispersion(Duration::from_millis(100)).build();
+        let (result, record) = client.query_audited(&server.addr());
+        assert!(matches!(result, Err(NtpError::UntrustedMessage)));
+        assert_eq!(record.verdicts.last(), Some(&Verdict { check: Check::RootDispersion, passed: false }));
+        assert_eq!(client.query(&server.addr()).unwrap().root_dispersion, ShortFormat(0x1000));
+        assert!(SntpClient::default().query(&server.addr()).is_ok());
+    }
+
+    #[test]
+    fn test_stratum() {
+        let server = MockServer::builder().stratum(4).start().unwrap();
+        let client = SntpClient::builder().stratum(1..=3).build();
+        let (result, record) = client.query_audited(&server.addr());
+        assert!(matches!(result, Err(NtpError::UntrustedMessage)));
+        assert_eq!(record.verdicts.last(), Some(&Verdict { check: Check::Stratum, passed: false }));
+        assert_eq!(client.to_builder().stratum(2..=4).build().query(&server.addr()).unwrap().stratum, 4);
+    }
+
+    #[test]
+    fn test_reference_age() {
+        use crate::testing::{HostilePacket, Response};
+
+        let server = MockServer::builder()
+            .script([Response::Packet(HostilePacket::server().reference(0)), Response::Reply])
+            .start()
+            .unwrap();
+        let client = SntpClient::default();
+        let never = client.query(&server.addr()).unwrap();
+        assert_eq!(never.reference_time, Duration::ZERO);
+        assert_eq!(never.reference_age(), None);
+        assert!(client.query(&server.addr()).unwrap().reference_age().is_some());
+    }
+
+    #[test]
+    fn test_max_reference_age() {
+        use crate::testing::{HostilePacket, Response};
+
+        let server = MockServer::builder()
+            .script([
+                Response::Packet(HostilePacket::server().reference(1 << 32)),
+                Response::Packet(HostilePacket::server().reference(0)),
+                Response::Reply,
+            ])
+            .start()
+            .unwrap();
+        let client = SntpClient::builder().max_reference_age(Duration::from_secs(86400)).build();
+        for _ in 0..2 {
+            let (result, record) = client.query_audited(&server.addr());
+            assert!(matches!(result, Err(NtpError::UntrustedMessage)));
+            assert_eq!(record.verdicts.last(), Some(&Verdict { check: Check::ReferenceAge, passed: false }));
+        }
+        assert!(client.query(&server.addr()).is_ok());
+    }
+
+    #[test]
+    fn test_unresponsive_address() {
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let dead = silent.local_addr().unwrap();
+        let alive: SocketAddr = "127.0.0.1:9".parse().unwrap();
+        let client = SntpClient::builder().timeout(Duration::from_millis(50)).build();
+        for _ in 0..UNRESPONSIVE_AFTER {
+            assert_eq!(pick_responsive(&[dead, alive]), Some(dead));
+            assert!(client.query(&dead.to_string()).is_err());
+        }
+        assert_eq!(pick_responsive(&[dead, alive]), Some(alive));
+        assert_eq!(pick_responsive(&[dead]), Some(dead));
+        assert_eq!(pick_responsive(&[]), None);
+
+        note_answered(dead, true);
+        assert_eq!(pick_responsive(&[dead, alive]), Some(dead));
+    }
+
+    #[test]
+    fn test_downgrade() {
+        use crate::testing::Response;
+
+        let server = MockServer::builder().script([Response::Drop]).start().unwrap();
+        let client = SntpClient::builder().timeout(Duration::from_millis(200)).downgrade(true).build();
+        let (result, record) = client.query_audited(&server.addr());
+        assert!(result.is_ok());
+        assert_eq!(record.request[0] >> 3 & 7, NTP_VERSION_3);
+        assert_eq!(client.version(&server.addr()), NTP_VERSION_3);
+        let (_, record) = client.query_audited(&server.addr());
+        assert_eq!(record.request[0] >> 3 & 7, NTP_VERSION_3);
+        assert_eq!(server.requests(), 3);
+
+        // Without it, or once v3 does not help either, the exchange fails.
+        server.push(Response::Drop);
+        assert!(SntpClient::builder().timeout(Duration::from_millis(200)).build().query(&server.addr()).is_err());
+        assert_eq!(SntpClient::default().version(&server.addr()), NTP_VERSION_4);
+        let client = client.to_builder().downgrade(true).build();
+        server.push(Response::Drop);
+        server.push(Response::Drop);
+        assert!(client.query(&server.addr()).is_err());
+        assert_eq!(client.version(&server.addr()), NTP_VERSION_4);
+    }
+
+    #[test]
+    fn test_ntp() {
+        let server = MockServer::builder().offset_nanos(2_000_000_000).start().unwrap();
+        let (t1, t2, t3, t4) = ntp(&server.addr()).unwrap();
+        assert!(t1 <= t4);
+        assert_eq!(t2, t3);
+        assert!(t2 > t1 + Duration::from_secs(1) && t2 < t4 + Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_delta() {
+        let server = MockServer::builder()
+            .offset_nanos(-1_500_000_000)
+            .latency(Duration::from_millis(20))
+            .start()
+            .unwrap();
+        let offset = clock_offset_nanos(&server.addr()).unwrap();
+        assert!((offset + 1_500_000_000).abs() < 10_000_000, "{}", offset);
+    }
+
+    #[test]
+    fn test_timestamp() {
+        let server = MockServer::builder().offset_nanos(60_000_000_000).start().unwrap();
+        let timestamp = unix_timestamp(&server.addr()).unwrap();
+        let expected = sys_time() + Duration::from_secs(60);
+        assert!(timestamp.abs_diff(expected) < Duration::from_millis(100), "{:?} {:?}", timestamp, expected);
+
+        server.push(testing::Response::Drop);
+        let client = SntpClient::builder().timeout(Duration::from_millis(100)).build();
+        assert!(client.query(&server.addr()).is_err());
+        assert!(client.query(&server.addr()).is_ok());
+    }
+}
\ No newline at end of file
@@ -0,0 +1,46 @@
+//! Setting the system clock.
+//!
+//! Stepping the clock needs privileges (root or `CAP_SYS_TIME` on Linux).
+
+use crate::protocol::NtpError;
+
+/// Step the system clock by `offset_nanos`, as returned by
+/// [`clock_offset_nanos`](crate::client::clock_offset_nanos): a positive offset moves it forward.
+///
+/// Example
+/// ```rust,no_run
+/// # use simple_ntp::{client, clock};
+///
+/// fn main() {
+///     let offset = client::clock_offset_nanos("ntp.aliyun.com").unwrap();
+///     clock::step(offset).unwrap();
+/// }
+/// ```
+#[cfg(unix)]
+pub fn step(offset_nanos: i64) -> Result<(), NtpError> {
+    let mut now = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    // SAFETY: `now` is a valid, writable timespec.
+    if unsafe { libc::clock_gettime(libc::CLOCK_REALTIME, &mut now) } != 0 {
+        return Err(NtpError::UnexpectedErr(std::io::Error::last_os_error().to_string()));
+    }
+
+    // time_t and c_long are 32 bits on some targets.
+    #[allow(clippy::unnecessary_cast)]
+    let nanos = now.tv_sec as i64 * 1_000_000_000 + now.tv_nsec as i64 + offset_nanos;
+    let target = libc::timespec {
+        tv_sec: nanos.div_euclid(1_000_000_000) as libc::time_t,
+        tv_nsec: nanos.rem_euclid(1_000_000_000) as libc::c_long,
+    };
+    // SAFETY: `target` is a valid timespec with tv_nsec in range.
+    if unsafe { libc::clock_settime(libc::CLOCK_REALTIME, &target) } != 0 {
+        return Err(NtpError::UnexpectedErr(std::io::Error::last_os_error().to_string()));
+    }
+
+    Ok(())
+}
+
+/// Stepping the clock is only implemented for unix systems.
+#[cfg(not(unix))]
+pub fn step(_offset_nanos: i64) -> Result<(), NtpError> {
+    Err(NtpError::UnexpectedErr("setting the system clock is not supported on this platform".to_string()))
+}
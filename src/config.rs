@@ -0,0 +1,842 @@
+//! TOML configuration of a [`SntpSynchronizer`] and an [`NtpServer`], so a
+//! deployment is described by one file instead of a long list of flags.
+//!
+//! ```toml
+//! [client]
+//! timeout = "2s"
+//! max_root_dispersion = "100ms"
+//!
+//! [[server]]
+//! address = "time.cloudflare.com"
+//! family = "v4"
+//!
+//! [[server]]
+//! address = "ntp.aliyun.com"
+//!
+//! [poll]
+//! interval = "64s"
+//!
+//! [clock]
+//! max_offset = "1000s"
+//! trust_first_sync = true
+//! panic_threshold = "10s"
+//!
+//! [serve]
+//! bind = "0.0.0.0:123"
+//! rate_limit = "2s"
+//! local_root_dispersion = "10ms"
+//! kod = true
+//! allow = ["10.0.0.0/8"]
+//! deny_by_default = true
+//! ```
+//!
+//! Durations are seconds or strings with a unit, `"250ms"`, `"64s"`, `"2m"` or `"1h"`.
+//! Unknown keys are rejected, so a misspelled option does not go unnoticed.
+//!
+//! There is no `[keys]` section: the crate has no symmetric-key (MD5/SHA-1 MAC)
+//! authentication, so there are no keys to configure or reload. Requests are
+//! sent without a MAC and the MAC of a response, if any, is not checked.
+//!
+//! Containers are usually configured through the environment instead.
+//! [`Config::apply_env`] reads these variables, which take precedence over the
+//! file; command line flags in turn take precedence over the environment.
+//! Empty variables are ignored.
+//!
+//! | variable             | sets                                                   |
+//! |----------------------|--------------------------------------------------------|
+//! | `SNTP_SERVERS`       | the servers, comma or space separated                  |
+//! | `SNTP_TIMEOUT`       | `client.timeout`                                       |
+//! | `SNTP_FAMILY`        | `client.family`                                        |
+//! | `SNTP_SOURCE_PORT`   | `client.source_port`                                   |
+//! | `SNTP_POLL_INTERVAL` | `poll.interval`                                        |
+//! | `SNTP_MAX_OFFSET`    | `clock.max_offset`                                     |
+//! | `SNTP_BIND`          | `serve.bind`                                           |
+//! | `SNTP_LOCAL_STRATUM` | `serve.local_stratum`                                  |
+//! | `SNTP_RATE_LIMIT`    | `serve.rate_limit`                                     |
+//!
+//! [`SntpSynchronizer`]: crate::synchronizer::SntpSynchronizer
+//! [`NtpServer`]: crate::server::NtpServer
+
+use std::env;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::de::{self, Deserializer};
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::client::{IpFamily, SntpClient};
+use crate::protocol::{NtpError, PollInterval};
+use crate::server::{Access, Acl, IpNet, NtpServer, RateLimit, ServerBuilder, ServerHandle};
+use crate::synchronizer::{SntpSynchronizer, SyncHandle, SynchronizerBuilder};
+
+/// A whole configuration file.
+///
+/// Example
+/// ```rust,no_run
+/// # use simple_ntp::config::Config;
+///
+/// fn main() {
+///     let config = Config::load("/etc/sntp.toml").unwrap();
+///     let sync = config.synchronizer_builder().start().unwrap();
+///     let server = config.server_builder().upstream(sync).build().unwrap();
+///     server.run().unwrap();
+/// }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Upstream servers, `[[server]]` tables.
+    #[serde(rename = "server")]
+    pub servers: Vec<ServerConfig>,
+    pub client: ClientConfig,
+    pub poll: PollConfig,
+    pub clock: ClockConfig,
+    pub serve: ServeConfig,
+}
+
+/// An upstream server and how to reach it, overriding the [`ClientConfig`].
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    /// `host` or `host:port`.
+    pub address: String,
+    #[serde(default, with = "opt_duration", skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<Duration>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub family: Option<IpFamily>,
+}
+
+/// Options of every query, see [`SntpClient`].
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    /// Timeout of each exchange, 5 seconds by default.
+    #[serde(with = "opt_duration", skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<Duration>,
+    /// See [`SntpClientBuilder::resolve_timeout`](crate::client::SntpClientBuilder::resolve_timeout), the timeout by default.
+    #[serde(with = "opt_duration", skip_serializing_if = "Option::is_none")]
+    pub resolve_timeout: Option<Duration>,
+    /// `any`, `v4` or `v6`.
+    pub family: IpFamily,
+    /// See [`SntpClientBuilder::max_root_dispersion`](crate::client::SntpClientBuilder::max_root_dispersion), unlimited by default.
+    #[serde(with = "opt_duration", skip_serializing_if = "Option::is_none")]
+    pub max_root_dispersion: Option<Duration>,
+    /// Lowest accepted stratum, see [`SntpClientBuilder::stratum`](crate::client::SntpClientBuilder::stratum).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_stratum: Option<u8>,
+    /// Highest accepted stratum, e.g. 3 to stay close to primary sources.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_stratum: Option<u8>,
+    /// See [`SntpClientBuilder::max_reference_age`](crate::client::SntpClientBuilder::max_reference_age), unlimited by default.
+    #[serde(with = "opt_duration", skip_serializing_if = "Option::is_none")]
+    pub max_reference_age: Option<Duration>,
+    /// See [`SntpClientBuilder::source_port`](crate::client::SntpClientBuilder::source_port), ephemeral by default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_port: Option<u16>,
+    /// See [`SntpClientBuilder::mark`](crate::client::SntpClientBuilder::mark).
+    #[cfg(all(feature = "fwmark", target_os = "linux"))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mark: Option<u32>,
+    /// See [`SntpClientBuilder::netns`](crate::client::SntpClientBuilder::netns).
+    #[cfg(all(feature = "netns", target_os = "linux"))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub netns: Option<String>,
+}
+
+/// How often the upstream servers are polled.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PollConfig {
+    #[serde(with = "duration")]
+    pub interval: Duration,
+}
+
+/// Which upstream samples may steer the clock.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClockConfig {
+    /// See [`SynchronizerBuilder::max_offset`], unlimited by default.
+    #[serde(with = "opt_duration", skip_serializing_if = "Option::is_none")]
+    pub max_offset: Option<Duration>,
+    /// See [`SynchronizerBuilder::trust_first_sync`], off by default.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub trust_first_sync: bool,
+    /// See [`SynchronizerBuilder::panic_threshold`], unlimited by default.
+    #[serde(with = "opt_duration", skip_serializing_if = "Option::is_none")]
+    pub panic_threshold: Option<Duration>,
+}
+
+/// The server side: where to listen and whom to answer.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServeConfig {
+    pub bind: String,
+    /// Stratum of the local clock, served when no upstream server is configured.
+    pub local_stratum: u8,
+    /// Root delay served with the local clock, zero by default.
+    #[serde(with = "duration")]
+    pub local_root_delay: Duration,
+    /// Root dispersion served with the local clock, zero by default.
+    #[serde(with = "duration")]
+    pub local_root_dispersion: Duration,
+    /// Minimum interval between requests of one client, zero disables rate limiting.
+    #[serde(with = "duration")]
+    pub rate_limit: Duration,
+    /// Answer rate limited clients with a RATE Kiss-o'-Death instead of dropping them.
+    pub kod: bool,
+    #[serde(with = "nets")]
+    pub allow: Vec<IpNet>,
+    #[serde(with = "nets")]
+    pub deny: Vec<IpNet>,
+    /// Deny clients not matched by an `allow` network.
+    pub deny_by_default: bool,
+    /// See [`ServerBuilder::mark`].
+    #[cfg(all(feature = "fwmark", target_os = "linux"))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mark: Option<u32>,
+}
+
+impl Default for PollConfig {
+    fn default() -> Self {
+        PollConfig {
+            interval: Duration::from_secs(64),
+        }
+    }
+}
+
+impl Default for ServeConfig {
+    fn default() -> Self {
+        ServeConfig {
+            bind: "0.0.0.0:123".to_string(),
+            local_stratum: 10,
+            local_root_delay: Duration::ZERO,
+            local_root_dispersion: Duration::ZERO,
+            rate_limit: RateLimit::default().min_interval,
+            kod: false,
+            allow: Vec::new(),
+            deny: Vec::new(),
+            deny_by_default: false,
+            #[cfg(all(feature = "fwmark", target_os = "linux"))]
+            mark: None,
+        }
+    }
+}
+
+impl FromStr for Config {
+    type Err = NtpError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        toml::from_str(s).map_err(|err| NtpError::BadConfig(err.to_string()))
+    }
+}
+
+impl Config {
+    /// Read and parse a TOML file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Config, NtpError> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path).map_err(|err| {
+            NtpError::BadConfig(format!("{}: {}", path.display(), err))
+        })?;
+
+        content.parse().map_err(|err| match err {
+            NtpError::BadConfig(msg) => NtpError::BadConfig(format!("{}: {}", path.display(), msg)),
+            err => err,
+        })
+    }
+
+    /// Best-effort conversion of an ntpd `ntp.conf` or a chrony `chrony.conf`, to ease migration.
+    ///
+    /// `server`, `pool` and `peer` lines become servers, ntpd `restrict` and chrony
+    /// `allow`/`deny` lines the ACL, `discard minimum` and `ratelimit interval` the
+    /// rate limit, `tinker panic` and `maxchange` the max offset. Also returns the
+    /// lines that were not understood, with their line numbers, for manual review.
+    ///
+    /// Example
+    /// ```rust
+    /// # use simple_ntp::config::Config;
+    ///
+    /// fn main() {
+    ///     let (config, skipped) = Config::import("pool pool.ntp.org iburst\ndriftfile /var/lib/ntp/drift\n");
+    ///     assert_eq!(config.servers[0].address, "pool.ntp.org");
+    ///     assert_eq!(skipped, ["line 2: driftfile /var/lib/ntp/drift"]);
+    ///     print!("{}", config.to_toml());
+    /// }
+    /// ```
+    pub fn import(content: &str) -> (Config, Vec<String>) {
+        let mut config = Config::default();
+        let mut skipped = Vec::new();
+        for (i, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            // chrony also starts comments with `!`, `;` and `%`.
+            if line.is_empty() || line.starts_with(['!', ';', '%']) {
+                continue;
+            }
+
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let (directive, args) = (words[0], &words[1..]);
+            let seconds = |value: Option<&str>| value.and_then(|value| value.parse::<f64>().ok());
+            let understood = match directive {
+                "server" | "pool" | "peer" => import_server(&mut config, args),
+                "restrict" => import_restrict(&mut config, args),
+                "allow" => import_chrony_acl(&mut config, Access::Allow, args),
+                "deny" => import_chrony_acl(&mut config, Access::Deny, args),
+                "discard" => seconds(option(args, "minimum"))
+                    .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                    .map(|rate_limit| config.serve.rate_limit = rate_limit)
+                    .is_some(),
+                // log2 seconds, 8 by default.
+                "ratelimit" => option(args, "interval").or(Some("3"))
+                    .and_then(|log2| log2.parse().ok())
+                    .map(|log2| PollInterval::from_exponent(log2).to_duration())
+                    .map(|rate_limit| config.serve.rate_limit = rate_limit)
+                    .is_some(),
+                // A panic threshold of 0 disables it.
+                "tinker" => seconds(option(args, "panic"))
+                    .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                    .map(|panic| config.clock.max_offset = (!panic.is_zero()).then_some(panic))
+                    .is_some(),
+                "maxchange" => seconds(args.first().copied())
+                    .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                    .map(|max_offset| config.clock.max_offset = Some(max_offset))
+                    .is_some(),
+                _ => false,
+            };
+            if !understood {
+                skipped.push(format!("line {}: {}", i + 1, line));
+            }
+        }
+
+        (config, skipped)
+    }
+
+    /// This configuration as a TOML file that [`load`](Self::load) reads back.
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).expect("config is serializable")
+    }
+
+    /// The defaults with the environment applied, for tools without a configuration file.
+    pub fn from_env() -> Result<Config, NtpError> {
+        let mut config = Config::default();
+        config.apply_env()?;
+        Ok(config)
+    }
+
+    /// Override options with the `SNTP_*` environment variables listed in the module documentation.
+    pub fn apply_env(&mut self) -> Result<(), NtpError> {
+        self.apply_vars(|name| env::var(name).ok())
+    }
+
+    fn apply_vars(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<(), NtpError> {
+        let var = |name: &str| var(name).filter(|value| !value.trim().is_empty());
+        let invalid = |name: &str, value: &str| NtpError::BadConfig(format!("{}: invalid value `{}`", name, value));
+        let duration = |name: &str| {
+            var(name).map(|value| parse_duration(&value).map_err(|_| invalid(name, &value))).transpose()
+        };
+
+        if let Some(servers) = var("SNTP_SERVERS") {
+            self.servers = servers.split([',', ' ']).filter(|server| !server.is_empty()).map(ServerConfig::new).collect();
+        }
+        if let Some(timeout) = duration("SNTP_TIMEOUT")? {
+            self.client.timeout = Some(timeout);
+        }
+        if let Some(family) = var("SNTP_FAMILY") {
+            self.client.family = family.parse().map_err(|_| invalid("SNTP_FAMILY", &family))?;
+        }
+        if let Some(port) = var("SNTP_SOURCE_PORT") {
+            self.client.source_port = Some(port.parse().map_err(|_| invalid("SNTP_SOURCE_PORT", &port))?);
+        }
+        if let Some(interval) = duration("SNTP_POLL_INTERVAL")? {
+            self.poll.interval = interval;
+        }
+        if let Some(max_offset) = duration("SNTP_MAX_OFFSET")? {
+            self.clock.max_offset = Some(max_offset);
+        }
+        if let Some(bind) = var("SNTP_BIND") {
+            self.serve.bind = bind;
+        }
+        if let Some(stratum) = var("SNTP_LOCAL_STRATUM") {
+            self.serve.local_stratum = stratum.parse().map_err(|_| invalid("SNTP_LOCAL_STRATUM", &stratum))?;
+        }
+        if let Some(rate_limit) = duration("SNTP_RATE_LIMIT")? {
+            self.serve.rate_limit = rate_limit;
+        }
+
+        Ok(())
+    }
+
+    /// A client with the `[client]` options, for servers not listed in the file.
+    pub fn client(&self) -> SntpClient {
+        self.server_client(&ServerConfig::new(""))
+    }
+
+    /// The client polling `server`, its own options taking precedence over `[client]`.
+    pub fn server_client(&self, server: &ServerConfig) -> SntpClient {
+        let mut builder = SntpClient::builder().family(server.family.unwrap_or(self.client.family));
+        if let Some(timeout) = server.timeout.or(self.client.timeout) {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(resolve_timeout) = self.client.resolve_timeout {
+            builder = builder.resolve_timeout(resolve_timeout);
+        }
+        if let Some(max_root_dispersion) = self.client.max_root_dispersion {
+            builder = builder.max_root_dispersion(max_root_dispersion);
+        }
+        if self.client.min_stratum.is_some() || self.client.max_stratum.is_some() {
+            builder = builder.stratum(self.client.min_stratum.unwrap_or(0)..=self.client.max_stratum.unwrap_or(u8::MAX));
+        }
+        if let Some(max_reference_age) = self.client.max_reference_age {
+            builder = builder.max_reference_age(max_reference_age);
+        }
+        if let Some(port) = self.client.source_port {
+            builder = builder.source_port(port);
+        }
+        #[cfg(all(feature = "fwmark", target_os = "linux"))]
+        if let Some(mark) = self.client.mark {
+            builder = builder.mark(mark);
+        }
+        #[cfg(all(feature = "netns", target_os = "linux"))]
+        if let Some(netns) = &self.client.netns {
+            builder = builder.netns(netns);
+        }
+        builder.build()
+    }
+
+    /// A synchronizer polling the configured servers. Add statistics or hooks before starting it.
+    pub fn synchronizer_builder(&self) -> SynchronizerBuilder {
+        let mut builder = SntpSynchronizer::builder().interval(self.poll.interval);
+        for server in &self.servers {
+            builder = builder.server_with(&server.address, self.server_client(server));
+        }
+        if let Some(max_offset) = self.clock.max_offset {
+            builder = builder.max_offset(max_offset);
+        }
+        if self.clock.trust_first_sync {
+            builder = builder.trust_first_sync();
+        }
+        if let Some(threshold) = self.clock.panic_threshold {
+            builder = builder.panic_threshold(threshold);
+        }
+        builder
+    }
+
+    /// A server with the configured address, ACL and rate limit, serving the local
+    /// clock until given an [`upstream`](ServerBuilder::upstream).
+    pub fn server_builder(&self) -> ServerBuilder {
+        let mut builder = NtpServer::builder()
+            .bind(&self.serve.bind)
+            .local_clock(self.serve.local_stratum)
+            .local_root_delay(self.serve.local_root_delay)
+            .local_root_dispersion(self.serve.local_root_dispersion)
+            .acl(self.acl());
+        if let Some(rate_limit) = self.rate_limit() {
+            builder = builder.rate_limit(rate_limit);
+        }
+        #[cfg(all(feature = "fwmark", target_os = "linux"))]
+        if let Some(mark) = self.serve.mark {
+            builder = builder.mark(mark);
+        }
+        builder
+    }
+
+    pub fn acl(&self) -> Acl {
+        let mut acl = Acl::new(if self.serve.deny_by_default { Access::Deny } else { Access::Allow });
+        for net in &self.serve.allow {
+            acl = acl.rule(*net, Access::Allow);
+        }
+        for net in &self.serve.deny {
+            acl = acl.rule(*net, Access::Deny);
+        }
+        acl
+    }
+
+    pub fn rate_limit(&self) -> Option<RateLimit> {
+        (!self.serve.rate_limit.is_zero()).then_some(RateLimit {
+            min_interval: self.serve.rate_limit,
+            kod: self.serve.kod,
+        })
+    }
+
+    /// Apply this configuration to a running server and its synchronizer, if any.
+    ///
+    /// The bind address and local stratum only take effect on restart.
+    pub fn reload(&self, server: &ServerHandle, sync: Option<&SyncHandle>) -> Result<(), NtpError> {
+        if let Some(sync) = sync {
+            let servers = self.servers.iter().map(|server| (server.address.clone(), self.server_client(server))).collect();
+            sync.reconfigure_with(servers, self.poll.interval)?;
+            sync.set_max_offset(self.clock.max_offset);
+        }
+        server.reload(self.acl(), self.rate_limit());
+
+        Ok(())
+    }
+}
+
+impl ServerConfig {
+    /// A server with default options.
+    pub fn new(address: &str) -> Self {
+        ServerConfig {
+            address: address.to_string(),
+            timeout: None,
+            family: None,
+        }
+    }
+}
+
+/// Parse a duration: seconds, or a number followed by `ms`, `s`, `m` or `h`.
+///
+/// Example
+/// ```rust
+/// # use std::time::Duration;
+/// # use simple_ntp::config::parse_duration;
+///
+/// fn main() {
+///     assert_eq!(parse_duration("250ms").unwrap(), Duration::from_millis(250));
+/// }
+/// ```
+pub fn parse_duration(s: &str) -> Result<Duration, NtpError> {
+    let s = s.trim();
+    let (number, scale) = if let Some(number) = s.strip_suffix("ms") {
+        (number, 1e-3)
+    } else if let Some(number) = s.strip_suffix('s') {
+        (number, 1.0)
+    } else if let Some(number) = s.strip_suffix('m') {
+        (number, 60.0)
+    } else if let Some(number) = s.strip_suffix('h') {
+        (number, 3600.0)
+    } else {
+        (s, 1.0)
+    };
+
+    let invalid = || NtpError::BadConfig(format!("invalid duration `{}`", s));
+    let value: f64 = number.trim().parse().map_err(|_| invalid())?;
+    Duration::try_from_secs_f64(value * scale).map_err(|_| invalid())
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawDuration {
+    Secs(f64),
+    Text(String),
+}
+
+/// The shortest of `64s`, `250ms` or fractional seconds that [`parse_duration`] reads back.
+fn format_duration(duration: &Duration) -> String {
+    if duration.subsec_nanos() == 0 {
+        format!("{}s", duration.as_secs())
+    } else if duration.subsec_nanos().is_multiple_of(1_000_000) {
+        format!("{}ms", duration.as_millis())
+    } else {
+        format!("{}", duration.as_secs_f64())
+    }
+}
+
+mod duration {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format_duration(duration))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        let invalid = |value: &dyn std::fmt::Display| de::Error::custom(format!("invalid duration `{}`", value));
+        match RawDuration::deserialize(deserializer)? {
+            RawDuration::Secs(secs) => Duration::try_from_secs_f64(secs).map_err(|_| invalid(&secs)),
+            RawDuration::Text(text) => parse_duration(&text).map_err(|_| invalid(&text)),
+        }
+    }
+}
+
+mod opt_duration {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(duration: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error> {
+        duration.as_ref().map(format_duration).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+        duration::deserialize(deserializer).map(Some)
+    }
+}
+
+mod nets {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(nets: &[IpNet], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(nets.iter().map(IpNet::to_string))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<IpNet>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|net| net.parse().map_err(|_| de::Error::custom(format!("invalid network `{}`", net))))
+            .collect()
+    }
+}
+
+/// Value following the option `name`, like `minpoll 6`.
+fn option<'a>(args: &[&'a str], name: &str) -> Option<&'a str> {
+    args.iter().position(|arg| *arg == name).and_then(|i| args.get(i + 1)).copied()
+}
+
+/// `server`, `pool` and `peer` lines, e.g. `server -4 ntp.example iburst` or `pool ntp.example port 1123`.
+fn import_server(config: &mut Config, args: &[&str]) -> bool {
+    let Some(host) = args.iter().find(|arg| !arg.starts_with('-')) else {
+        return false;
+    };
+    // 127.127.t.u are ntpd reference clock drivers.
+    if host.starts_with("127.127.") {
+        return false;
+    }
+
+    let mut server = ServerConfig::new(host);
+    if let Some(port) = option(args, "port") {
+        server.address = if host.contains(':') { format!("[{}]:{}", host, port) } else { format!("{}:{}", host, port) };
+    }
+    if args.contains(&"-4") {
+        server.family = Some(IpFamily::V4);
+    } else if args.contains(&"-6") {
+        server.family = Some(IpFamily::V6);
+    }
+    config.servers.push(server);
+    true
+}
+
+/// ntpd `restrict default|ADDR [mask MASK] [flags]`, where `ignore` and `noserve` deny time service.
+fn import_restrict(config: &mut Config, args: &[&str]) -> bool {
+    let args: Vec<&str> = args.iter().copied().filter(|arg| *arg != "-4" && *arg != "-6").collect();
+    let Some((&target, flags)) = args.split_first() else {
+        return false;
+    };
+    let access = if flags.iter().any(|flag| matches!(*flag, "ignore" | "noserve")) { Access::Deny } else { Access::Allow };
+    if flags.contains(&"kod") {
+        config.serve.kod = true;
+    }
+
+    let net = match target {
+        "default" => {
+            config.serve.deny_by_default = access == Access::Deny;
+            return true;
+        }
+        "source" => return false,
+        addr => match option(flags, "mask") {
+            Some(mask) => mask_prefix(mask).and_then(|prefix| IpNet::new(addr.parse().ok()?, prefix).ok()),
+            None => addr.parse().ok(),
+        },
+    };
+    let Some(net) = net else {
+        return false;
+    };
+    match access {
+        Access::Allow => config.serve.allow.push(net),
+        Access::Deny => config.serve.deny.push(net),
+    }
+    true
+}
+
+/// Prefix length of a contiguous netmask like `255.255.0.0`.
+fn mask_prefix(mask: &str) -> Option<u8> {
+    let (ones, bits) = match mask.parse().ok()? {
+        IpAddr::V4(mask) => (u32::from(mask).leading_ones(), u32::from(mask).count_ones()),
+        IpAddr::V6(mask) => (u128::from(mask).leading_ones(), u128::from(mask).count_ones()),
+    };
+    (ones == bits).then_some(ones as u8)
+}
+
+/// chrony `allow|deny [all] [SUBNET]`, where a missing subnet means everyone and
+/// subnets may be abbreviated, `192.168` for `192.168.0.0/16`.
+fn import_chrony_acl(config: &mut Config, access: Access, args: &[&str]) -> bool {
+    let nets = match args.iter().find(|arg| **arg != "all") {
+        None => vec![IpNet::new(Ipv4Addr::UNSPECIFIED.into(), 0).unwrap(), IpNet::new(Ipv6Addr::UNSPECIFIED.into(), 0).unwrap()],
+        Some(subnet) => match chrony_subnet(subnet) {
+            Some(net) => vec![net],
+            None => return false,
+        },
+    };
+
+    // chrony serves nobody who is not allowed explicitly.
+    config.serve.deny_by_default = true;
+    match access {
+        Access::Allow => config.serve.allow.extend(nets),
+        Access::Deny => config.serve.deny.extend(nets),
+    }
+    true
+}
+
+fn chrony_subnet(subnet: &str) -> Option<IpNet> {
+    if let Ok(net) = subnet.parse() {
+        return Some(net);
+    }
+
+    let (addr, prefix) = match subnet.split_once('/') {
+        Some((addr, prefix)) => (addr, Some(prefix.parse().ok()?)),
+        None => (subnet, None),
+    };
+    let octets = addr.split('.').map(|octet| octet.parse().ok()).collect::<Option<Vec<u8>>>()?;
+    if octets.len() > 4 {
+        return None;
+    }
+    let mut bytes = [0u8; 4];
+    bytes[..octets.len()].copy_from_slice(&octets);
+    IpNet::new(Ipv4Addr::from(bytes).into(), prefix.unwrap_or(8 * octets.len() as u8)).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::*;
+
+    #[test]
+    fn test_parse() {
+        let config: Config = r#"
+            [client]
+            family = "v6"
+            max_root_dispersion = "100ms"
+            max_stratum = 3
+            max_reference_age = "24h"
+
+            [[server]]
+            address = "time.cloudflare.com"
+            timeout = "2s"
+            family = "v4"
+
+            [[server]]
+            address = "ntp.aliyun.com"
+
+            [poll]
+            interval = 16
+
+            [clock]
+            max_offset = "1h"
+            trust_first_sync = true
+            panic_threshold = "5s"
+
+            [serve]
+            rate_limit = "0"
+            local_root_dispersion = "10ms"
+            allow = ["10.0.0.0/8"]
+            deny_by_default = true
+        "#.parse().unwrap();
+
+        assert_eq!(config.servers.len(), 2);
+        assert_eq!(config.servers[0].timeout, Some(Duration::from_secs(2)));
+        assert_eq!(config.servers[0].family, Some(IpFamily::V4));
+        assert_eq!(config.client.family, IpFamily::V6);
+        assert_eq!(config.client.max_root_dispersion, Some(Duration::from_millis(100)));
+        assert_eq!((config.client.min_stratum, config.client.max_stratum), (None, Some(3)));
+        assert_eq!(config.client.max_reference_age, Some(Duration::from_secs(86400)));
+        assert_eq!(config.servers[1], ServerConfig::new("ntp.aliyun.com"));
+        assert_eq!(config.poll.interval, Duration::from_secs(16));
+        assert_eq!(config.clock.max_offset, Some(Duration::from_secs(3600)));
+        assert!(config.clock.trust_first_sync);
+        assert_eq!(config.clock.panic_threshold, Some(Duration::from_secs(5)));
+        assert_eq!(config.serve.bind, "0.0.0.0:123");
+        assert_eq!(config.serve.local_root_dispersion, Duration::from_millis(10));
+        assert_eq!(config.rate_limit(), None);
+        assert_eq!(config.acl().check("10.1.2.3".parse().unwrap()), Access::Allow);
+        assert_eq!(config.acl().check("192.0.2.1".parse().unwrap()), Access::Deny);
+
+        assert_eq!("".parse::<Config>().unwrap(), Config::default());
+        for bad in ["pool = 1", "[poll]\ninterval = \"soon\"", "[serve]\nallow = [\"10.0.0.0/33\"]"] {
+            assert!(matches!(bad.parse::<Config>(), Err(NtpError::BadConfig(_))), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_apply_env() {
+        let vars = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| vars.iter().find(|(var, _)| *var == name).map(|(_, value)| value.to_string())
+        };
+        let mut config = Config::default();
+        config.apply_vars(vars(&[
+            ("SNTP_SERVERS", "a.example, b.example:1123"),
+            ("SNTP_TIMEOUT", "500ms"),
+            ("SNTP_FAMILY", "v4"),
+            ("SNTP_SOURCE_PORT", "123"),
+            ("SNTP_MAX_OFFSET", "10s"),
+            ("SNTP_BIND", ""),
+        ])).unwrap();
+        assert_eq!(config.servers, [ServerConfig::new("a.example"), ServerConfig::new("b.example:1123")]);
+        assert_eq!(config.client.timeout, Some(Duration::from_millis(500)));
+        assert_eq!(config.client.family, IpFamily::V4);
+        assert_eq!(config.client.source_port, Some(123));
+        assert_eq!(config.clock.max_offset, Some(Duration::from_secs(10)));
+        assert_eq!(config.serve.bind, ServeConfig::default().bind);
+
+        let err = config.apply_vars(vars(&[("SNTP_LOCAL_STRATUM", "high")])).unwrap_err();
+        assert!(matches!(err, NtpError::BadConfig(msg) if msg.contains("SNTP_LOCAL_STRATUM")));
+    }
+
+    #[test]
+    fn test_import() {
+        let (config, skipped) = Config::import(r#"
+            # ntp.conf
+            driftfile /var/lib/ntp/drift
+            server -4 0.pool.ntp.org iburst
+            server 127.127.1.0
+            pool ntp.example minpoll 6
+            restrict default kod limited nomodify noquery
+            restrict -6 ::1
+            restrict 192.0.2.0 mask 255.255.255.0 ignore
+            restrict source nomodify
+            discard average 3 minimum 1
+            tinker panic 0
+        "#);
+        assert_eq!(skipped, [
+            "line 3: driftfile /var/lib/ntp/drift",
+            "line 5: server 127.127.1.0",
+            "line 10: restrict source nomodify",
+        ]);
+        assert_eq!(config.servers[0].address, "0.pool.ntp.org");
+        assert_eq!(config.servers[0].family, Some(IpFamily::V4));
+        assert_eq!(config.servers[1], ServerConfig::new("ntp.example"));
+        assert!(config.serve.kod && !config.serve.deny_by_default);
+        assert_eq!(config.serve.allow, ["::1".parse().unwrap()]);
+        assert_eq!(config.serve.deny, ["192.0.2.0/24".parse().unwrap()]);
+        assert_eq!(config.serve.rate_limit, Duration::from_secs(1));
+        assert_eq!(config.clock.max_offset, None);
+
+        let (config, skipped) = Config::import(r#"
+            ! chrony.conf
+            server ntp.example port 1123 iburst
+            server 2001:db8::1 port 1123
+            allow 192.168
+            allow 10.0.0.0/8
+            deny 10.1
+            ratelimit interval 1 burst 16
+            maxchange 1000 1 2
+            makestep 1.0 3
+        "#);
+        assert_eq!(skipped, ["line 10: makestep 1.0 3"]);
+        assert_eq!(config.servers[0].address, "ntp.example:1123");
+        assert_eq!(config.servers[1].address, "[2001:db8::1]:1123");
+        assert!(config.serve.deny_by_default);
+        assert_eq!(config.serve.allow, ["192.168.0.0/16".parse().unwrap(), "10.0.0.0/8".parse().unwrap()]);
+        assert_eq!(config.serve.deny, ["10.1.0.0/16".parse().unwrap()]);
+        assert_eq!(config.serve.rate_limit, Duration::from_secs(2));
+        assert_eq!(config.clock.max_offset, Some(Duration::from_secs(1000)));
+
+        assert_eq!(config.to_toml().parse::<Config>().unwrap(), config);
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("250ms").unwrap(), Duration::from_millis(250));
+        assert_eq!(parse_duration("2m").unwrap(), Duration::from_secs(120));
+        assert_eq!(parse_duration("1.5").unwrap(), Duration::from_millis(1500));
+        assert!(parse_duration("-1s").is_err());
+        assert!(parse_duration("soon").is_err());
+    }
+
+    #[test]
+    fn test_reload() {
+        let mut config: Config = "[serve]\nbind = \"127.0.0.1:0\"".parse().unwrap();
+        let server = config.server_builder().build().unwrap().spawn().unwrap();
+        let sync = SntpSynchronizer::builder().server("127.0.0.1:1").start().unwrap();
+
+        assert!(config.reload(&server, Some(&sync.handle())).is_err());
+        config.servers.push(ServerConfig::new(&server.local_addr().to_string()));
+        config.reload(&server, Some(&sync.handle())).unwrap();
+    }
+}
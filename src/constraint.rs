@@ -0,0 +1,475 @@
+//! Time constraints from the `Date` header of HTTPS servers, like OpenBSD ntpd's
+//! `constraints from "https://..."`.
+//!
+//! Unauthenticated NTP can be spoofed by anyone on the path. A constraint is a
+//! rough time from an authenticated source: the `Date` header of a TLS server,
+//! accurate to about a second. [`Constraints`] takes the median of several URLs,
+//! and NTP samples further than a margin from it are rejected, so an attacker has
+//! to subvert the TLS servers as well to move the clock far.
+//!
+//! The crate has no TLS stack of its own: `https://` URLs need a [`TlsConnector`]
+//! wrapping e.g. `rustls`. A `Date` header over plain `http://` is as easy to
+//! spoof as the NTP packet it is meant to check, so such URLs are rejected
+//! unless [`ConstraintClientBuilder::allow_insecure_http`] opts in, e.g. for a
+//! proxy on a trusted network.
+
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::client::sys_time;
+use crate::protocol::{days_from_civil, NtpError};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How far NTP may stray from the constraint median, OpenBSD's CONSTRAINT_MARGIN.
+const DEFAULT_MARGIN: Duration = Duration::from_secs(120);
+
+/// How often constraints are fetched again.
+const DEFAULT_REFRESH: Duration = Duration::from_secs(3600);
+
+const MAX_HEADER_LINES: usize = 64;
+
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// A connection a [`TlsConnector`] returns.
+pub trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
+
+/// Wraps a connected TCP stream in TLS, verifying the certificate of `domain`.
+///
+/// Example, with `rustls`:
+/// ```text
+/// struct Rustls(Arc<rustls::ClientConfig>);
+///
+/// impl TlsConnector for Rustls {
+///     fn connect(&self, domain: &str, stream: TcpStream) -> io::Result<Box<dyn Stream>> {
+///         let name = domain.to_string().try_into().map_err(io::Error::other)?;
+///         let conn = rustls::ClientConnection::new(self.0.clone(), name).map_err(io::Error::other)?;
+///         Ok(Box::new(rustls::StreamOwned::new(conn, stream)))
+///     }
+/// }
+/// ```
+pub trait TlsConnector: Send + Sync {
+    fn connect(&self, domain: &str, stream: TcpStream) -> io::Result<Box<dyn Stream>>;
+}
+
+/// The time of one URL, see [`ConstraintClient::fetch`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Constraint {
+    pub url: String,
+    /// The `Date` header, since the unix epoch.
+    pub date: Duration,
+    /// Local time the request was sent.
+    pub sent: Duration,
+    /// Local time the response headers were received.
+    pub received: Duration,
+}
+
+impl Constraint {
+    /// Bounds of the local clock offset in nano seconds, remote sub local. The date
+    /// is truncated to the second and was taken somewhere between sending and receiving.
+    pub fn offset_bounds_nanos(&self) -> (i64, i64) {
+        let nanos = |d: Duration| d.as_nanos() as i64;
+        (
+            nanos(self.date) - nanos(self.received),
+            nanos(self.date) + 1_000_000_000 - nanos(self.sent),
+        )
+    }
+
+    /// Middle of [`Constraint::offset_bounds_nanos`].
+    pub fn offset_nanos(&self) -> i64 {
+        let (low, high) = self.offset_bounds_nanos();
+        low + (high - low) / 2
+    }
+}
+
+/// Fetches [`Constraint`]s.
+#[derive(Clone)]
+pub struct ConstraintClient {
+    timeout: Duration,
+    tls: Option<Arc<dyn TlsConnector>>,
+    allow_insecure_http: bool,
+}
+
+impl fmt::Debug for ConstraintClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConstraintClient")
+            .field("timeout", &self.timeout)
+            .field("tls", &self.tls.is_some())
+            .field("allow_insecure_http", &self.allow_insecure_http)
+            .finish()
+    }
+}
+
+impl Default for ConstraintClient {
+    fn default() -> Self {
+        ConstraintClient {
+            timeout: DEFAULT_TIMEOUT,
+            tls: None,
+            allow_insecure_http: false,
+        }
+    }
+}
+
+/// Builder for [`ConstraintClient`].
+#[derive(Debug, Clone, Default)]
+pub struct ConstraintClientBuilder {
+    client: ConstraintClient,
+}
+
+impl ConstraintClientBuilder {
+    /// Connect, read and write timeout, 10 seconds by default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.client.timeout = timeout;
+        self
+    }
+
+    /// Enable `https://` URLs.
+    pub fn tls(mut self, connector: impl TlsConnector + 'static) -> Self {
+        self.client.tls = Some(Arc::new(connector));
+        self
+    }
+
+    /// Accept plain `http://` URLs, whose `Date` header anyone on the path can
+    /// forge. Only for trusted networks.
+    pub fn allow_insecure_http(mut self) -> Self {
+        self.client.allow_insecure_http = true;
+        self
+    }
+
+    pub fn build(self) -> ConstraintClient {
+        self.client
+    }
+}
+
+impl ConstraintClient {
+    pub fn builder() -> ConstraintClientBuilder {
+        ConstraintClientBuilder::default()
+    }
+
+    /// Send a `HEAD` request to `url` and read the `Date` header of the response.
+    pub fn fetch(&self, url: &str) -> Result<Constraint, NtpError> {
+        let (tls, host, port, path) = parse_url(url)?;
+        if tls && self.tls.is_none() {
+            return Err(NtpError::BadConfig(format!("{}: https needs a TLS connector", url)));
+        }
+        if !tls && !self.allow_insecure_http {
+            return Err(NtpError::BadConfig(format!("{}: plain http can be spoofed, use https or allow_insecure_http", url)));
+        }
+        let unavailable = |err: io::Error| NtpError::ServiceUnavailable(format!("{}: {}", url, err));
+
+        let addr = (host.as_str(), port)
+            .to_socket_addrs()
+            .map_err(|err| NtpError::BadNtpServerAddr(err.to_string()))?
+            .next()
+            .ok_or_else(|| NtpError::BadNtpServerAddr(format!("{}: no address", host)))?;
+        let tcp = TcpStream::connect_timeout(&addr, self.timeout).map_err(unavailable)?;
+        tcp.set_read_timeout(Some(self.timeout)).map_err(unavailable)?;
+        tcp.set_write_timeout(Some(self.timeout)).map_err(unavailable)?;
+        let mut stream: Box<dyn Stream> = match &self.tls {
+            Some(connector) if tls => connector.connect(&host, tcp).map_err(unavailable)?,
+            _ => Box::new(tcp),
+        };
+
+        debug!("fetching constraint from {}", url);
+        let sent = sys_time();
+        write!(
+            stream,
+            "HEAD {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: simple-ntp\r\nConnection: close\r\n\r\n",
+            path, host
+        )
+        .and_then(|_| stream.flush())
+        .map_err(unavailable)?;
+
+        let mut reader = BufReader::new(stream);
+        let mut date = None;
+        for _ in 0..MAX_HEADER_LINES {
+            let mut line = String::new();
+            if reader.read_line(&mut line).map_err(unavailable)? == 0 || line.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("date") {
+                    date = Some(value.trim().to_string());
+                }
+            }
+        }
+        let received = sys_time();
+
+        let date = date.ok_or_else(|| NtpError::UnexpectedErr(format!("{}: no Date header", url)))?;
+        let date = parse_http_date(&date)
+            .ok_or_else(|| NtpError::UnexpectedErr(format!("{}: bad Date header `{}`", url, date)))?;
+
+        Ok(Constraint {
+            url: url.to_string(),
+            date,
+            sent,
+            received,
+        })
+    }
+}
+
+/// The constraint of several URLs, refreshed periodically. Pass it to
+/// [`SynchronizerBuilder::constraints`](crate::synchronizer::SynchronizerBuilder::constraints).
+///
+/// Example
+/// ```rust,no_run
+/// # use simple_ntp::constraint::{ConstraintClient, Constraints};
+/// # use simple_ntp::client;
+///
+/// fn main() {
+///     // Plain http only on a trusted network, use https and a TLS connector otherwise.
+///     let mut constraints = Constraints::builder()
+///         .client(ConstraintClient::builder().allow_insecure_http().build())
+///         .url("http://intranet.example/")
+///         .url("http://proxy.example/")
+///         .build();
+///     constraints.refresh().unwrap();
+///     let result = client::query("ntp.aliyun.com").unwrap();
+///     constraints.check(result.offset_nanos).expect("ntp outside the constraint");
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Constraints {
+    urls: Vec<String>,
+    client: ConstraintClient,
+    margin: Duration,
+    refresh: Duration,
+    /// Median offset and when it was fetched.
+    median: Option<(i64, Instant)>,
+}
+
+/// Builder for [`Constraints`].
+#[derive(Debug, Clone)]
+pub struct ConstraintsBuilder {
+    constraints: Constraints,
+}
+
+impl ConstraintsBuilder {
+    /// Add a URL, `https://host[:port][/path]`, or `http://...` if the client
+    /// [allows it](ConstraintClientBuilder::allow_insecure_http).
+    pub fn url(mut self, url: &str) -> Self {
+        self.constraints.urls.push(url.to_string());
+        self
+    }
+
+    /// Client used for fetching, e.g. with a [`TlsConnector`].
+    pub fn client(mut self, client: ConstraintClient) -> Self {
+        self.constraints.client = client;
+        self
+    }
+
+    /// How far an NTP offset may be from the median, 2 minutes by default like OpenBSD.
+    pub fn margin(mut self, margin: Duration) -> Self {
+        self.constraints.margin = margin;
+        self
+    }
+
+    /// How often [`Constraints::refresh_if_stale`] fetches again, hourly by default.
+    pub fn refresh(mut self, refresh: Duration) -> Self {
+        self.constraints.refresh = refresh;
+        self
+    }
+
+    pub fn build(self) -> Constraints {
+        self.constraints
+    }
+}
+
+impl Constraints {
+    pub fn builder() -> ConstraintsBuilder {
+        ConstraintsBuilder {
+            constraints: Constraints {
+                urls: Vec::new(),
+                client: ConstraintClient::default(),
+                margin: DEFAULT_MARGIN,
+                refresh: DEFAULT_REFRESH,
+                median: None,
+            },
+        }
+    }
+
+    /// Fetch all URLs and keep the median offset of those that answered.
+    pub fn refresh(&mut self) -> Result<i64, NtpError> {
+        let mut offsets: Vec<i64> = self.urls.iter()
+            .filter_map(|url| match self.client.fetch(url) {
+                Ok(constraint) => Some(constraint.offset_nanos()),
+                Err(err) => {
+                    warn!("constraint {} failed: {:?}", url, err);
+                    None
+                }
+            })
+            .collect();
+        if offsets.is_empty() {
+            return Err(NtpError::ServiceUnavailable("no constraint answered".to_string()));
+        }
+
+        offsets.sort_unstable();
+        let median = offsets[offsets.len() / 2];
+        info!("constraint offset {}ns from {} of {} urls", median, offsets.len(), self.urls.len());
+        self.median = Some((median, Instant::now()));
+        Ok(median)
+    }
+
+    /// [`Constraints::refresh`] if never fetched or older than the refresh interval.
+    pub fn refresh_if_stale(&mut self) {
+        if self.median.is_none_or(|(_, fetched)| fetched.elapsed() >= self.refresh) {
+            let _ = self.refresh();
+        }
+    }
+
+    /// Median offset of the last refresh in nano seconds, remote sub local.
+    pub fn offset_nanos(&self) -> Option<i64> {
+        self.median.map(|(median, _)| median)
+    }
+
+    /// Reject `offset_nanos` if it is further than the margin from the median.
+    /// Without a constraint everything is rejected, as NTP alone is not trusted.
+    pub fn check(&self, offset_nanos: i64) -> Result<(), NtpError> {
+        let Some(median) = self.offset_nanos() else {
+            return Err(NtpError::UntrustedMessage);
+        };
+        if offset_nanos.abs_diff(median) as u128 > self.margin.as_nanos() {
+            return Err(NtpError::UntrustedMessage);
+        }
+        Ok(())
+    }
+}
+
+/// Split a URL into (https, host, port, path).
+fn parse_url(url: &str) -> Result<(bool, String, u16, String), NtpError> {
+    let bad = || NtpError::BadConfig(format!("bad url `{}`", url));
+    let (tls, rest) = match url.split_once("://") {
+        Some(("https", rest)) => (true, rest),
+        Some(("http", rest)) => (false, rest),
+        _ => return Err(bad()),
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let default_port = if tls { 443 } else { 80 };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => (host, port.parse().map_err(|_| bad())?),
+        _ => (authority, default_port),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return Err(bad());
+    }
+
+    Ok((tls, host.to_string(), port, path.to_string()))
+}
+
+/// An IMF-fixdate as HTTP servers send it, `Sun, 06 Nov 1994 08:49:37 GMT`.
+fn parse_http_date(date: &str) -> Option<Duration> {
+    let (_, date) = date.split_once(", ")?;
+    let fields: Vec<&str> = date.split_whitespace().collect();
+    let [day, month, year, time, "GMT"] = fields.as_slice() else {
+        return None;
+    };
+    let month = MONTHS.iter().position(|m| m == month)? as u32 + 1;
+    let mut hms = time.splitn(3, ':').map(|n| n.parse::<u64>());
+    let (hour, minute, second) = (hms.next()?.ok()?, hms.next()?.ok()?, hms.next()?.ok()?);
+    let (year, day): (i64, u32) = (year.parse().ok()?, day.parse().ok()?);
+    if !(1970..=9999).contains(&year) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    let days = days_from_civil(year, month, day);
+    let secs = u64::try_from(days).ok()? * 86400 + hour * 3600 + minute * 60 + second;
+    Some(Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::constraint::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Answer one request with `date`, returning the URL.
+    fn serve(date: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(&stream);
+            let mut request = String::new();
+            reader.read_line(&mut request).unwrap();
+            assert_eq!(request, "HEAD / HTTP/1.1\r\n");
+            // Read the rest, closing with unread data resets the connection.
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            write!(stream, "HTTP/1.1 200 OK\r\nServer: test\r\ndate: {}\r\n\r\n", date).unwrap();
+        });
+        url
+    }
+
+    struct Passthrough;
+
+    impl TlsConnector for Passthrough {
+        fn connect(&self, domain: &str, stream: TcpStream) -> io::Result<Box<dyn Stream>> {
+            assert_eq!(domain, "127.0.0.1");
+            Ok(Box::new(stream))
+        }
+    }
+
+    fn insecure() -> ConstraintClient {
+        ConstraintClient::builder().allow_insecure_http().build()
+    }
+
+    #[test]
+    fn test_fetch() {
+        let url = serve("Tue, 14 Nov 2023 22:13:20 GMT");
+        assert!(matches!(ConstraintClient::default().fetch(&url), Err(NtpError::BadConfig(_))));
+        let constraint = insecure().fetch(&url).unwrap();
+        assert_eq!(constraint.date, Duration::from_secs(1_700_000_000));
+        let (low, high) = constraint.offset_bounds_nanos();
+        assert!(low < high && high - low >= 1_000_000_000);
+
+        let https = serve("Tue, 14 Nov 2023 22:13:20 GMT").replace("http", "https");
+        assert!(matches!(ConstraintClient::default().fetch(&https), Err(NtpError::BadConfig(_))));
+        let client = ConstraintClient::builder().tls(Passthrough).build();
+        assert_eq!(client.fetch(&https).unwrap().date, Duration::from_secs(1_700_000_000));
+
+        let url = serve("yesterday");
+        assert!(matches!(insecure().fetch(&url), Err(NtpError::UnexpectedErr(_))));
+    }
+
+    #[test]
+    fn test_constraints() {
+        let mut constraints = Constraints::builder()
+            .client(insecure())
+            .url(&serve("Tue, 14 Nov 2023 22:13:20 GMT"))
+            .url(&serve("Tue, 14 Nov 2023 22:13:21 GMT"))
+            .url(&serve("Thu, 01 Jan 1970 00:00:00 GMT"))
+            .build();
+        assert!(constraints.check(0).is_err());
+
+        let median = constraints.refresh().unwrap();
+        let expected = Duration::from_secs(1_700_000_000).as_nanos() as i64 - sys_time().as_nanos() as i64;
+        assert!((median - expected).abs() < 3_000_000_000, "{} {}", median, expected);
+        assert!(constraints.check(median + 60_000_000_000).is_ok());
+        assert!(constraints.check(median - 121_000_000_000).is_err());
+        assert!(constraints.check(0).is_err());
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse_url("https://example.com").unwrap(), (true, "example.com".to_string(), 443, "/".to_string()));
+        assert_eq!(parse_url("http://[::1]:8080/time?x").unwrap(), (false, "::1".to_string(), 8080, "/time?x".to_string()));
+        assert!(parse_url("ftp://example.com").is_err());
+
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(Duration::from_secs(784111777)));
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 25:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 06 Nov 999999999999 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("Sun, 32 Nov 1994 08:49:37 GMT"), None);
+    }
+}
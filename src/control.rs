@@ -0,0 +1,270 @@
+//! Local control socket of a running synchronizer, like `chronyc` talking to `chronyd`.
+//!
+//! A client connects to the unix socket, writes one request line and reads the
+//! reply until the connection is closed. The reply starts with a line `OK` or
+//! `ERR <reason>`, followed by the output of the request:
+//!
+//! - `status`: the selected server and the current offset
+//! - `tracking`: the fields of `chronyc tracking`, see [`status::tracking`](crate::status::tracking)
+//! - `sources`: a line per server with its reachability, score, latest offset and jitter
+//! - `poll`: poll all servers now
+//! - `add HOST`: start polling another server
+//! - `remove HOST`: stop polling a server
+//!
+//! `status json`, `tracking json` and `sources json` reply with JSON instead:
+//! the [`status::json`] document, the tracking fields and the sources array.
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::client::SntpClient;
+use crate::protocol::NtpError;
+use crate::status;
+use crate::synchronizer::SyncHandle;
+
+/// Where `sntp serve --control` listens and `sntp ctl` connects by default.
+pub const DEFAULT_SOCKET: &str = "/run/sntp.sock";
+
+/// A client must send its request within this time.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Serve control requests for `sync` on a background thread, until the returned
+/// handle is stopped or dropped.
+///
+/// The socket is only accessible to the owner and group. A stale socket file is
+/// replaced, one still in use is an error.
+///
+/// Example
+/// ```rust,no_run
+/// # use simple_ntp::control;
+/// # use simple_ntp::synchronizer::SntpSynchronizer;
+///
+/// fn main() {
+///     let sync = SntpSynchronizer::builder().server("ntp.aliyun.com").start().unwrap();
+///     let _control = control::spawn(control::DEFAULT_SOCKET, sync.handle()).unwrap();
+///     println!("{}", control::request(control::DEFAULT_SOCKET, "sources").unwrap());
+/// }
+/// ```
+pub fn spawn(path: impl AsRef<Path>, sync: SyncHandle) -> Result<ControlHandle, NtpError> {
+    let path = path.as_ref().to_path_buf();
+    let unavailable = |err: io::Error| NtpError::ServiceUnavailable(format!("{}: {}", path.display(), err));
+    if path.exists() {
+        if UnixStream::connect(&path).is_ok() {
+            return Err(NtpError::ServiceUnavailable(format!("{}: already in use", path.display())));
+        }
+        fs::remove_file(&path).map_err(unavailable)?;
+    }
+
+    let listener = UnixListener::bind(&path).map_err(unavailable)?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o660)).map_err(unavailable)?;
+
+    let running = Arc::new(AtomicBool::new(true));
+    let listening = running.clone();
+    let thread = thread::Builder::new()
+        .name("sntp-control".to_string())
+        .spawn(move || listen(listener, &sync, &listening))
+        .map_err(|err| NtpError::UnexpectedErr(err.to_string()))?;
+
+    Ok(ControlHandle {
+        path,
+        running,
+        thread: Some(thread),
+    })
+}
+
+/// Send `request` to the control socket at `path` and return the output.
+pub fn request(path: impl AsRef<Path>, request: &str) -> Result<String, NtpError> {
+    let path = path.as_ref();
+    let unavailable = |err: io::Error| NtpError::ServiceUnavailable(format!("{}: {}", path.display(), err));
+    let mut stream = UnixStream::connect(path).map_err(unavailable)?;
+    stream.write_all(format!("{}\n", request.trim()).as_bytes()).map_err(unavailable)?;
+    stream.shutdown(Shutdown::Write).map_err(unavailable)?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).map_err(unavailable)?;
+
+    let (status, output) = reply.split_once('\n').unwrap_or((&reply, ""));
+    match status.strip_prefix("ERR") {
+        Some(reason) => Err(NtpError::UnexpectedErr(reason.trim().to_string())),
+        None if status == "OK" => Ok(output.to_string()),
+        None => Err(NtpError::UnexpectedErr(format!("malformed reply `{}`", status))),
+    }
+}
+
+/// A running control socket, see [`spawn`]. Removes the socket file when stopped.
+#[derive(Debug)]
+pub struct ControlHandle {
+    path: PathBuf,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ControlHandle {
+    /// Stop serving requests and remove the socket file.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            // Wake up the blocking accept.
+            let _ = UnixStream::connect(&self.path);
+            let _ = thread.join();
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+impl Drop for ControlHandle {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn listen(listener: UnixListener, sync: &SyncHandle, running: &AtomicBool) {
+    for stream in listener.incoming() {
+        if !running.load(Ordering::Relaxed) {
+            return;
+        }
+        match stream {
+            Ok(stream) => {
+                if let Err(err) = serve(stream, sync) {
+                    debug!("control request failed: {}", err);
+                }
+            }
+            Err(err) => warn!("control socket accept failed: {}", err),
+        }
+    }
+}
+
+fn serve(stream: UnixStream, sync: &SyncHandle) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+
+    let reply = match handle(sync, line.trim()) {
+        Ok(output) => format!("OK\n{}", output),
+        Err(reason) => format!("ERR {}\n", reason),
+    };
+    (&stream).write_all(reply.as_bytes())
+}
+
+fn handle(sync: &SyncHandle, request: &str) -> Result<String, String> {
+    let words: Vec<&str> = request.split_whitespace().collect();
+    match words.as_slice() {
+        ["status"] => Ok(status(sync)),
+        ["tracking"] => Ok(status::tracking(sync).to_string()),
+        ["sources"] => Ok(sources(sync)),
+        ["status", "json"] => Ok(status::json(sync)),
+        ["tracking", "json"] => Ok(status::tracking(sync).json()),
+        ["sources", "json"] => Ok(status::sources_json(sync)),
+        ["poll"] => {
+            sync.poll_now();
+            Ok(String::new())
+        }
+        ["add", server] => sync.add_server(server, SntpClient::default()).map(|_| String::new()).map_err(reason),
+        ["remove", server] => sync.remove_server(server).map(|_| String::new()).map_err(reason),
+        _ => Err(format!("unknown request `{}`", request)),
+    }
+}
+
+fn reason(err: NtpError) -> String {
+    match err {
+        NtpError::ServiceUnavailable(reason)
+        | NtpError::BadNtpServerAddr(reason)
+        | NtpError::UnexpectedErr(reason)
+        | NtpError::BadConfig(reason)
+        | NtpError::ResolveTimeout(reason) => reason,
+        NtpError::KissOfDeath(code) => format!("kiss-o'-death {}", code),
+        err => format!("{:?}", err),
+    }
+}
+
+fn status(sync: &SyncHandle) -> String {
+    let Some((result, selected_at)) = sync.selected() else {
+        return format!("{:<16}none\n", "selected");
+    };
+    let mut out = format!("{:<16}{}\n", "selected", result.addr);
+    out += &format!("{:<16}{:+.6} s\n", "offset", result.offset_nanos as f64 / 1e9);
+    out += &format!("{:<16}{:.6} s\n", "delay", result.delay_nanos as f64 / 1e9);
+    out += &format!("{:<16}{}\n", "stratum", result.stratum);
+    out += &format!("{:<16}{}\n", "refid", result.refid());
+    out += &format!("{:<16}{:.6} s\n", "root dispersion", result.root_dispersion.as_secs_f64());
+    out += &format!("{:<16}{} s\n", "last sync", sync.local_now().saturating_sub(selected_at).as_secs());
+    out
+}
+
+/// `*` marks the selected server and `x` demoted ones, reach is octal like ntpq's.
+fn sources(sync: &SyncHandle) -> String {
+    let mut out = format!("  {:<32} {:>5} {:>5} {:>12} {:>10}\n", "server", "reach", "score", "offset", "jitter");
+    for source in sync.sources() {
+        let offset = source.offset_nanos.map_or("-".to_string(), |offset| format!("{:+.6}", offset as f64 / 1e9));
+        let tally = match (source.selected, source.demoted) {
+            (true, _) => '*',
+            (false, true) => 'x',
+            (false, false) => ' ',
+        };
+        out += &format!(
+            "{} {:<32} {:>5o} {:>5} {:>12} {:>10.6}\n",
+            tally,
+            source.server,
+            source.reach,
+            source.score,
+            offset,
+            source.jitter_nanos / 1e9
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::control::*;
+    use crate::server::NtpServer;
+    use crate::synchronizer::SntpSynchronizer;
+
+    #[test]
+    fn test_control() {
+        let server = NtpServer::builder().bind("127.0.0.1:0").build().unwrap().spawn().unwrap();
+        let sync = SntpSynchronizer::builder()
+            .server(&server.local_addr().to_string())
+            .interval(Duration::from_secs(3600))
+            .start()
+            .unwrap();
+        let path = std::env::temp_dir().join(format!("sntp-control-{}.sock", std::process::id()));
+        let control = spawn(&path, sync.handle()).unwrap();
+        assert!(spawn(&path, sync.handle()).is_err());
+
+        for _ in 0..50 {
+            if sync.offset_nanos().is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        let status = request(&path, "status").unwrap();
+        assert!(status.starts_with(&format!("selected        {}\n", server.local_addr())), "{}", status);
+        let tracking = request(&path, "tracking").unwrap();
+        assert!(tracking.contains("Stratum         : 11\n"), "{}", tracking);
+        let sources = request(&path, "sources").unwrap();
+        assert!(sources.lines().nth(1).unwrap().starts_with(&format!("* {}", server.local_addr())), "{}", sources);
+        assert!(request(&path, "status json").unwrap().starts_with(r#"{"state":"synchronized","#));
+        assert!(request(&path, "tracking json").unwrap().contains(r#""stratum":11,"#));
+        assert!(request(&path, "sources json").unwrap().starts_with(&format!(r#"[{{"server":"{}","#, server.local_addr())));
+
+        assert_eq!(request(&path, "poll").unwrap(), "");
+        request(&path, "add 127.0.0.1:1").unwrap();
+        assert!(matches!(request(&path, "remove 192.0.2.1"), Err(NtpError::UnexpectedErr(_))));
+        assert!(matches!(request(&path, "reboot"), Err(NtpError::UnexpectedErr(reason)) if reason.contains("unknown")));
+
+        control.stop();
+        assert!(!path.exists());
+    }
+}
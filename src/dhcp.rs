@@ -0,0 +1,102 @@
+//! NTP servers offered by DHCP.
+//!
+//! Managed networks hand out their time servers in DHCP option 42. The DHCP
+//! clients save it with their leases: systemd-networkd as `NTP=` in
+//! `/run/systemd/netif/leases/*`, dhclient as `option ntp-servers` in
+//! `/var/lib/dhcp/*.leases` and `/var/lib/dhclient/*.lease(s)`.
+
+use std::fs;
+use std::path::Path;
+
+/// Lease files of systemd-networkd, one per interface.
+const NETWORKD_LEASES: &str = "/run/systemd/netif/leases";
+
+/// Lease directories of dhclient, by distribution.
+const DHCLIENT_LEASES: [&str; 2] = ["/var/lib/dhcp", "/var/lib/dhclient"];
+
+/// NTP servers in the current DHCP leases of all interfaces, without duplicates.
+///
+/// Example
+/// ```rust,no_run
+/// # use simple_ntp::dhcp;
+///
+/// fn main() {
+///     for server in dhcp::ntp_servers() {
+///         println!("{}", server);
+///     }
+/// }
+/// ```
+pub fn ntp_servers() -> Vec<String> {
+    let mut servers = Vec::new();
+    for file in lease_files(Path::new(NETWORKD_LEASES), |_| true) {
+        servers.extend(parse_networkd(&file));
+    }
+    for dir in DHCLIENT_LEASES {
+        for file in lease_files(Path::new(dir), |name| name.ends_with(".leases") || name.ends_with(".lease")) {
+            servers.extend(parse_dhclient(&file));
+        }
+    }
+
+    let mut unique = Vec::new();
+    for server in servers {
+        if !unique.contains(&server) {
+            unique.push(server);
+        }
+    }
+    unique
+}
+
+/// Contents of the files in `dir` whose names pass `filter`; unreadable ones are skipped.
+fn lease_files(dir: &Path, filter: impl Fn(&str) -> bool) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    entries.flatten()
+        .filter(|entry| filter(&entry.file_name().to_string_lossy()))
+        .filter_map(|entry| fs::read_to_string(entry.path()).ok())
+        .collect()
+}
+
+/// `NTP=10.0.0.1 10.0.0.2` of a systemd-networkd lease.
+fn parse_networkd(lease: &str) -> Vec<String> {
+    lease.lines()
+        .filter_map(|line| line.strip_prefix("NTP="))
+        .flat_map(|servers| servers.split_whitespace().map(str::to_string))
+        .collect()
+}
+
+/// `option ntp-servers 10.0.0.1,10.0.0.2;` of the last lease in a dhclient
+/// leases file, which appends renewed leases.
+fn parse_dhclient(leases: &str) -> Vec<String> {
+    let last = leases.rsplit("lease {").next().unwrap_or_default();
+    last.lines()
+        .rev()
+        .find_map(|line| line.trim().strip_prefix("option ntp-servers"))
+        .map(|servers| {
+            servers.trim().trim_end_matches(';')
+                .split(',')
+                .map(|server| server.trim().to_string())
+                .filter(|server| !server.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dhcp::*;
+
+    #[test]
+    fn test_parse_leases() {
+        let networkd = "# This is private data. Do not parse.\nADDRESS=10.0.0.23\nNTP=10.0.0.1 10.0.0.2\nDNS=10.0.0.1\n";
+        assert_eq!(parse_networkd(networkd), ["10.0.0.1", "10.0.0.2"]);
+        assert!(parse_networkd("ADDRESS=10.0.0.23\n").is_empty());
+
+        let dhclient = "lease {\n  interface \"eth0\";\n  option ntp-servers 192.0.2.1;\n}\n\
+            lease {\n  interface \"eth0\";\n  option routers 10.0.0.1;\n  option ntp-servers 10.0.0.1,10.0.0.2;\n  renew 4 2026/10/15 10:00:00;\n}\n";
+        assert_eq!(parse_dhclient(dhclient), ["10.0.0.1", "10.0.0.2"]);
+        // The server was dropped from the renewed lease.
+        assert!(parse_dhclient("lease {\n option ntp-servers 192.0.2.1;\n}\nlease {\n}\n").is_empty());
+        assert!(parse_dhclient("").is_empty());
+    }
+}
@@ -0,0 +1,117 @@
+//! Internal diagnostics.
+//!
+//! With the `log` feature enabled the macros forward to the `log` facade,
+//! otherwise they compile to nothing and the crate stays dependency-free.
+//! The module is `#[macro_use]`, so the macros are in scope crate-wide.
+//!
+//! Likewise the `metrics` feature reports counters and gauges through the
+//! `metrics` facade, all labelled with the `server` they concern.
+// Without the `client` or the `server` feature some of them go unused.
+#![cfg_attr(not(all(feature = "client", feature = "server")), allow(dead_code, unused_macros))]
+
+macro_rules! debug {
+    ($($arg:tt)+) => {{
+        #[cfg(feature = "log")]
+        log::debug!($($arg)+);
+        #[cfg(not(feature = "log"))]
+        let _ = format_args!($($arg)+);
+    }};
+}
+
+macro_rules! info {
+    ($($arg:tt)+) => {{
+        #[cfg(feature = "log")]
+        log::info!($($arg)+);
+        #[cfg(not(feature = "log"))]
+        let _ = format_args!($($arg)+);
+    }};
+}
+
+macro_rules! warn {
+    ($($arg:tt)+) => {{
+        #[cfg(feature = "log")]
+        log::warn!($($arg)+);
+        #[cfg(not(feature = "log"))]
+        let _ = format_args!($($arg)+);
+    }};
+}
+
+/// Requests sent.
+pub(crate) const QUERIES: &str = "sntp_queries_total";
+/// Requests that got no response before the read timeout.
+pub(crate) const TIMEOUTS: &str = "sntp_timeouts_total";
+/// Kiss-o'-Death responses, also labelled with the kiss `code`.
+pub(crate) const KISS_OF_DEATH: &str = "sntp_kiss_of_death_total";
+/// Responses that could not be parsed.
+pub(crate) const PARSE_ERRORS: &str = "sntp_parse_errors_total";
+/// Last measured clock offset in seconds.
+pub(crate) const OFFSET: &str = "sntp_offset_seconds";
+/// RMS jitter of recent offsets in seconds.
+pub(crate) const JITTER: &str = "sntp_jitter_seconds";
+/// Last measured round-trip delay in seconds.
+pub(crate) const DELAY: &str = "sntp_delay_seconds";
+/// Synchronizer reachability register, the last 8 polls as bits (ntpd's `reach`).
+pub(crate) const REACHABILITY: &str = "sntp_reachability";
+/// Last stratum reported by the server.
+pub(crate) const STRATUM: &str = "sntp_stratum";
+/// Replies sent by the server, unlabelled.
+pub(crate) const SERVER_RESPONSES: &str = "sntp_server_responses_total";
+/// Requests dropped by the server's access control list, unlabelled.
+pub(crate) const SERVER_DENIED: &str = "sntp_server_denied_total";
+/// Requests dropped or answered with a RATE Kiss-o'-Death by the server, unlabelled.
+pub(crate) const SERVER_RATE_LIMITED: &str = "sntp_server_rate_limited_total";
+/// Offset of the synchronizer's selected server in seconds, unlabelled.
+pub(crate) const SYSTEM_OFFSET: &str = "sntp_system_offset_seconds";
+/// RMS jitter of the synchronizer's selected offsets in seconds, unlabelled.
+pub(crate) const SYSTEM_JITTER: &str = "sntp_system_jitter_seconds";
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn count(name: &'static str, server: &str) {
+    #[cfg(feature = "metrics")]
+    metrics::counter!(name, "server" => server.to_string()).increment(1);
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn count_code(name: &'static str, server: &str, code: &str) {
+    #[cfg(feature = "metrics")]
+    metrics::counter!(name, "server" => server.to_string(), "code" => code.to_string()).increment(1);
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn gauge(name: &'static str, server: &str, value: f64) {
+    #[cfg(feature = "metrics")]
+    metrics::gauge!(name, "server" => server.to_string()).set(value);
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn system_count(name: &'static str) {
+    #[cfg(feature = "metrics")]
+    metrics::counter!(name).increment(1);
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn system_gauge(name: &'static str, value: f64) {
+    #[cfg(feature = "metrics")]
+    metrics::gauge!(name).set(value);
+}
+
+/// Register units and help texts for every metric with the installed recorder.
+#[cfg(feature = "prometheus")]
+pub(crate) fn describe() {
+    use metrics::{describe_counter, describe_gauge, Unit};
+
+    describe_counter!(QUERIES, "NTP requests sent");
+    describe_counter!(TIMEOUTS, "NTP requests that timed out");
+    describe_counter!(KISS_OF_DEATH, "Kiss-o'-Death responses received");
+    describe_counter!(PARSE_ERRORS, "NTP responses that could not be parsed");
+    describe_gauge!(OFFSET, Unit::Seconds, "Last measured clock offset");
+    describe_gauge!(DELAY, Unit::Seconds, "Last measured round-trip delay");
+    describe_gauge!(JITTER, Unit::Seconds, "RMS jitter of recent offsets");
+    describe_gauge!(REACHABILITY, "Reachability register of the last 8 polls");
+    describe_gauge!(STRATUM, "Stratum reported by the server");
+    describe_counter!(SERVER_RESPONSES, "Replies sent by the server");
+    describe_counter!(SERVER_DENIED, "Requests dropped by the access control list");
+    describe_counter!(SERVER_RATE_LIMITED, "Requests rate limited by the server");
+    describe_gauge!(SYSTEM_OFFSET, Unit::Seconds, "Offset of the selected server");
+    describe_gauge!(SYSTEM_JITTER, Unit::Seconds, "RMS jitter of the selected offsets");
+}
@@ -0,0 +1,184 @@
+//! C interface.
+//!
+//! `extern "C"` functions for C and C++ programs linking the crate as a static
+//! library, declared in `include/simple_ntp.h`:
+//! ```shell
+//! cargo rustc --release --lib --features ffi --crate-type staticlib
+//! cc main.c -Iinclude target/release/libsimple_ntp.a -lpthread -ldl -lm
+//! ```
+//! Every function returns [`SNTP_OK`] or an error status, described by
+//! [`sntp_strerror`]. Panics are caught and reported as [`SNTP_UNEXPECTED`].
+//! The header is generated with `cbindgen --config cbindgen.toml --output include/simple_ntp.h`.
+
+use std::ffi::{c_char, CStr};
+use std::panic::{self, AssertUnwindSafe};
+use std::time::Duration;
+
+use crate::client::{NtpResult, SntpClient};
+use crate::protocol::NtpError;
+
+/// Success.
+pub const SNTP_OK: i32 = 0;
+/// No response, or the socket could not be set up.
+pub const SNTP_SERVICE_UNAVAILABLE: i32 = 1;
+/// The server name does not resolve.
+pub const SNTP_BAD_SERVER_ADDR: i32 = 2;
+/// Any other failure, including a panic.
+pub const SNTP_UNEXPECTED: i32 = 3;
+/// The response was too short.
+pub const SNTP_TRUNCATED: i32 = 4;
+/// The response failed validation.
+pub const SNTP_UNTRUSTED: i32 = 5;
+/// Invalid settings.
+pub const SNTP_BAD_CONFIG: i32 = 6;
+/// A null pointer or a server name that is not UTF-8.
+pub const SNTP_INVALID_ARGUMENT: i32 = 7;
+/// The server name did not resolve in time.
+pub const SNTP_RESOLVE_TIMEOUT: i32 = 8;
+/// The server answered with a Kiss-o'-Death: poll less often, or stop.
+pub const SNTP_KISS_OF_DEATH: i32 = 9;
+
+/// The answer of a server, see [`NtpResult`].
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SntpResult {
+    pub stratum: u8,
+    pub reference_id: u32,
+    /// NTP short format, 16.16 fixed point seconds.
+    pub root_delay: u32,
+    /// NTP short format, 16.16 fixed point seconds.
+    pub root_dispersion: u32,
+    pub offset_nanos: i64,
+    pub delay_nanos: i64,
+}
+
+impl From<&NtpResult> for SntpResult {
+    fn from(result: &NtpResult) -> Self {
+        SntpResult {
+            stratum: result.stratum,
+            reference_id: result.reference_id,
+            root_delay: result.root_delay.0,
+            root_dispersion: result.root_dispersion.0,
+            offset_nanos: result.offset_nanos,
+            delay_nanos: result.delay_nanos,
+        }
+    }
+}
+
+/// Query `server`, `host` or `host:port`, waiting up to `timeout_ms`
+/// milliseconds, 0 for the default of 5 seconds.
+///
+/// # Safety
+///
+/// `server` must be a NUL terminated string and `result` valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn sntp_query(server: *const c_char, timeout_ms: u32, result: *mut SntpResult) -> i32 {
+    guard(|| {
+        let server = server_name(server)?;
+        let out = result.as_mut().ok_or(SNTP_INVALID_ARGUMENT)?;
+        let answer = client(timeout_ms).query(server).map_err(|err| status(&err))?;
+        *out = SntpResult::from(&answer);
+        Ok(())
+    })
+}
+
+/// Offset of the local clock from `server` in nanoseconds, see [`sntp_query`].
+///
+/// # Safety
+///
+/// `server` must be a NUL terminated string and `offset_nanos` valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn sntp_clock_offset_nanos(server: *const c_char, timeout_ms: u32, offset_nanos: *mut i64) -> i32 {
+    guard(|| {
+        let server = server_name(server)?;
+        let out = offset_nanos.as_mut().ok_or(SNTP_INVALID_ARGUMENT)?;
+        *out = client(timeout_ms).query(server).map_err(|err| status(&err))?.offset_nanos;
+        Ok(())
+    })
+}
+
+/// A static description of `status`.
+#[no_mangle]
+pub extern "C" fn sntp_strerror(status: i32) -> *const c_char {
+    let message = match status {
+        SNTP_OK => c"ok",
+        SNTP_SERVICE_UNAVAILABLE => c"service unavailable",
+        SNTP_BAD_SERVER_ADDR => c"bad server address",
+        SNTP_TRUNCATED => c"truncated response",
+        SNTP_UNTRUSTED => c"untrusted response",
+        SNTP_BAD_CONFIG => c"bad configuration",
+        SNTP_INVALID_ARGUMENT => c"invalid argument",
+        SNTP_RESOLVE_TIMEOUT => c"resolve timeout",
+        SNTP_KISS_OF_DEATH => c"kiss-o'-death",
+        _ => c"unexpected error",
+    };
+    message.as_ptr()
+}
+
+fn guard(f: impl FnOnce() -> Result<(), i32>) -> i32 {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => SNTP_OK,
+        Ok(Err(status)) => status,
+        Err(_) => SNTP_UNEXPECTED,
+    }
+}
+
+unsafe fn server_name<'a>(server: *const c_char) -> Result<&'a str, i32> {
+    if server.is_null() {
+        return Err(SNTP_INVALID_ARGUMENT);
+    }
+    CStr::from_ptr(server).to_str().map_err(|_| SNTP_INVALID_ARGUMENT)
+}
+
+fn client(timeout_ms: u32) -> SntpClient {
+    match timeout_ms {
+        0 => SntpClient::default(),
+        ms => SntpClient::builder().timeout(Duration::from_millis(ms as u64)).build(),
+    }
+}
+
+fn status(err: &NtpError) -> i32 {
+    match err {
+        NtpError::ServiceUnavailable(_) => SNTP_SERVICE_UNAVAILABLE,
+        NtpError::BadNtpServerAddr(_) => SNTP_BAD_SERVER_ADDR,
+        NtpError::UnexpectedErr(_) => SNTP_UNEXPECTED,
+        NtpError::TruncatedNtpMessage => SNTP_TRUNCATED,
+        NtpError::UntrustedMessage => SNTP_UNTRUSTED,
+        NtpError::BadConfig(_) => SNTP_BAD_CONFIG,
+        NtpError::ResolveTimeout(_) => SNTP_RESOLVE_TIMEOUT,
+        NtpError::KissOfDeath(_) => SNTP_KISS_OF_DEATH,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+    use std::ptr;
+
+    use crate::ffi::*;
+    use crate::testing::MockServer;
+
+    #[test]
+    fn test_ffi() {
+        let server = MockServer::builder().offset_nanos(1_000_000_000).start().unwrap();
+        let name = CString::new(server.addr()).unwrap();
+        let mut result = SntpResult::default();
+        assert_eq!(unsafe { sntp_query(name.as_ptr(), 1000, &mut result) }, SNTP_OK);
+        assert_eq!((result.offset_nanos as f64 / 1e9).round(), 1.0);
+
+        let mut offset = 0;
+        assert_eq!(unsafe { sntp_clock_offset_nanos(name.as_ptr(), 0, &mut offset) }, SNTP_OK);
+        assert_eq!((offset as f64 / 1e9).round(), 1.0);
+
+        assert_eq!(unsafe { sntp_query(ptr::null(), 0, &mut result) }, SNTP_INVALID_ARGUMENT);
+        assert_eq!(unsafe { sntp_query(name.as_ptr(), 0, ptr::null_mut()) }, SNTP_INVALID_ARGUMENT);
+        let invalid = CString::new(vec![0xff, 0xfe]).unwrap();
+        assert_eq!(unsafe { sntp_query(invalid.as_ptr(), 0, &mut result) }, SNTP_INVALID_ARGUMENT);
+        let unresolvable = CString::new("invalid..name").unwrap();
+        assert_eq!(unsafe { sntp_query(unresolvable.as_ptr(), 0, &mut result) }, SNTP_BAD_SERVER_ADDR);
+
+        let message = unsafe { CStr::from_ptr(sntp_strerror(SNTP_BAD_SERVER_ADDR)) };
+        assert_eq!(message.to_str().unwrap(), "bad server address");
+        assert_eq!(guard(|| panic!("boom")), SNTP_UNEXPECTED);
+    }
+}
@@ -0,0 +1,45 @@
+//! Just enough HTTP/1.1 for the crate's small read-only endpoints.
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+/// Upper bound on request header lines read from a client.
+const MAX_HEADER_LINES: usize = 64;
+
+/// Read a request and answer it with `route(method, path)`, which returns the
+/// status line, content type and body. The connection is closed afterwards.
+pub(crate) fn respond(
+    stream: TcpStream,
+    route: impl FnOnce(&str, &str) -> (&'static str, &'static str, String),
+) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Drain the headers, the request has no body we care about.
+    let mut line = String::new();
+    for _ in 0..MAX_HEADER_LINES {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line == "\r\n" || line == "\n" {
+            break;
+        }
+    }
+
+    let mut stream = reader.into_inner();
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    // The query string is ignored.
+    let path = parts.next().unwrap_or_default().split('?').next().unwrap_or_default();
+    let (status, content_type, body) = route(method, path);
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
@@ -0,0 +1,348 @@
+//! Legacy time protocols as coarse fallback sources, for gear that speaks nothing
+//! else: TIME (RFC 868) and DAYTIME (RFC 867).
+//!
+//! They are picked by a scheme prefix wherever a server is taken, see
+//! [`SntpClient`](crate::client::SntpClient). The answer is a whole second, so the
+//! result carries at least half a second of root dispersion: the synchronizer ranks
+//! sources by root distance and only falls back to these while no NTP server answers.
+//!
+//! DAYTIME has no defined format. The common ones are understood: NIST's
+//! `60262 23-11-14 22:13:20 00 0 0 50.0 UTC(NIST) *`, ctime's
+//! `Tue Nov 14 22:13:20 2023`, RFC 867's `Tuesday, November 14, 2023 22:13:20-UTC`,
+//! its SMTP style `14 NOV 23 22:13:20 GMT` and ISO 8601. A missing time zone is
+//! taken as UTC.
+
+use std::io::Read;
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::time::Duration;
+
+use crate::client::{AuditRecord, Exchange};
+use crate::protocol::{days_from_civil, ntp_seconds_to_unix, NtpError, NtpMsg, NTP_MODE_SERVER, NTP_VERSION_4};
+use crate::timesource::TimeSource;
+
+/// The worst stratum a relay can still serve from.
+const STRATUM: u8 = 14;
+
+/// Half a second, the truncation error, in 16.16 fixed point.
+const TIME_DISPERSION: u32 = 1 << 15;
+
+/// Two seconds, beyond RFC 5905's maximum distance of 1.5 seconds, so any usable
+/// NTP server outranks a server whose time zone handling nobody can vouch for.
+const DAYTIME_DISPERSION: u32 = 2 << 16;
+
+/// Longest DAYTIME answer read.
+const MAX_DAYTIME_LEN: usize = 512;
+
+const MONTHS: [&str; 12] = ["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+
+/// Offsets of the zone names RFC 867 and ctime answers carry, in hours.
+const ZONES: [(&str, i64); 12] = [
+    ("utc", 0), ("gmt", 0), ("ut", 0), ("z", 0),
+    ("est", -5), ("edt", -4), ("cst", -6), ("cdt", -5),
+    ("mst", -7), ("mdt", -6), ("pst", -8), ("pdt", -7),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Protocol {
+    Time,
+    Daytime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Transport {
+    Udp,
+    Tcp,
+}
+
+/// Split `time://host`, `daytime+tcp://host` etc. into the protocol and the rest.
+pub(crate) fn parse(server: &str) -> Option<(Protocol, Transport, &str)> {
+    let (scheme, host) = server.split_once("://")?;
+    match scheme {
+        "time" => Some((Protocol::Time, Transport::Udp, host)),
+        "time+tcp" => Some((Protocol::Time, Transport::Tcp, host)),
+        "daytime" => Some((Protocol::Daytime, Transport::Udp, host)),
+        "daytime+tcp" => Some((Protocol::Daytime, Transport::Tcp, host)),
+        _ => None,
+    }
+}
+
+impl Protocol {
+    pub(crate) fn default_port(self) -> &'static str {
+        match self {
+            Protocol::Time => "37",
+            Protocol::Daytime => "13",
+        }
+    }
+
+    /// The time an answer names, and its root dispersion.
+    fn decode(self, answer: &[u8]) -> Result<(Duration, u32), NtpError> {
+        match self {
+            Protocol::Time => {
+                let seconds: [u8; 4] = answer.try_into().map_err(|_| NtpError::TruncatedNtpMessage)?;
+                Ok((time_to_unix(u32::from_be_bytes(seconds)), TIME_DISPERSION))
+            }
+            Protocol::Daytime => {
+                let answer = String::from_utf8_lossy(answer);
+                let time = parse_daytime(&answer).ok_or_else(|| {
+                    NtpError::UnexpectedErr(format!("unknown daytime format `{}`", answer.trim()))
+                })?;
+                Ok((time, DAYTIME_DISPERSION))
+            }
+        }
+    }
+}
+
+/// Ask `peer` for the time, as if it had answered an NTP request.
+pub(crate) fn exchange(
+    protocol: Protocol,
+    transport: Transport,
+    peer: SocketAddr,
+    timeout: Duration,
+    time: &dyn TimeSource,
+    record: &mut AuditRecord,
+) -> Result<Exchange, NtpError> {
+    record.addr = Some(peer);
+    let unavailable = |err: std::io::Error| NtpError::ServiceUnavailable(format!("{}: {}", peer, err));
+
+    let mut buf = vec![0; MAX_DAYTIME_LEN];
+    debug!("sending {:?} request over {:?} to {}", protocol, transport, peer);
+    let t1 = time.now();
+    match transport {
+        Transport::Udp => {
+            let socket = UdpSocket::bind(if peer.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }).map_err(unavailable)?;
+            socket.set_read_timeout(Some(timeout)).map_err(unavailable)?;
+            socket.connect(peer).map_err(unavailable)?;
+            // An empty datagram is the request.
+            socket.send(&[]).map_err(unavailable)?;
+            let n = socket.recv(&mut buf).map_err(unavailable)?;
+            buf.truncate(n);
+        }
+        Transport::Tcp => {
+            // The server sends the time on connect and closes.
+            let mut stream = TcpStream::connect_timeout(&peer, timeout).map_err(unavailable)?;
+            stream.set_read_timeout(Some(timeout)).map_err(unavailable)?;
+            if protocol == Protocol::Time {
+                buf.truncate(4);
+                stream.read_exact(&mut buf).map_err(|_| NtpError::TruncatedNtpMessage)?;
+            } else {
+                buf.clear();
+                stream.take(MAX_DAYTIME_LEN as u64).read_to_end(&mut buf).map_err(unavailable)?;
+            }
+        }
+    }
+    let t4 = time.now();
+    record.t1 = Some(t1);
+    record.t4 = Some(t4);
+    record.response = buf.clone();
+
+    let (time, root_dispersion) = protocol.decode(&buf)?;
+    record.t2 = Some(time);
+    record.t3 = Some(time);
+    let mut msg = NtpMsg::new();
+    msg.version_number = NTP_VERSION_4;
+    msg.mode = NTP_MODE_SERVER;
+    msg.stratum = STRATUM;
+    msg.root_dispersion = root_dispersion;
+
+    Ok(Exchange { peer, msg, t1, t2: time, t3: time, t4 })
+}
+
+/// Seconds since 1900 to the middle of that second since the unix epoch. Values
+/// before 1970 are taken as the next era, which starts in 2036.
+fn time_to_unix(seconds: u32) -> Duration {
+    Duration::from_secs(ntp_seconds_to_unix(seconds)) + Duration::from_millis(500)
+}
+
+/// The middle of the second a DAYTIME answer names, since the unix epoch.
+fn parse_daytime(answer: &str) -> Option<Duration> {
+    let mut ymd = None;
+    let mut hms = None;
+    let mut month = None;
+    let mut numbers = Vec::new();
+    let mut zone = None;
+
+    let tokens = answer.split(|c: char| c.is_whitespace() || c == ',').filter(|token| !token.is_empty());
+    for token in tokens.flat_map(|token| split_iso(token)) {
+        let lower = token.to_ascii_lowercase();
+        if token.bytes().filter(|b| *b == b'-').count() == 2 && token.starts_with(|c: char| c.is_ascii_digit()) {
+            let mut parts = token.splitn(3, '-').map(|n| n.parse::<i64>());
+            ymd = Some((parts.next()?.ok()?, parts.next()?.ok()?, parts.next()?.ok()?));
+        } else if token.contains(':') && token.starts_with(|c: char| c.is_ascii_digit()) && hms.is_none() {
+            // The zone may be glued on, `17:37:43-PST`, `22:13:20Z` or `22:13:20+01:00`.
+            let end = token.find(|c: char| !c.is_ascii_digit() && c != ':' && c != '.').unwrap_or(token.len());
+            let mut parts = token[..end].split(':').map(|n| n.split('.').next().unwrap_or("").parse::<i64>());
+            hms = Some((parts.next()?.ok()?, parts.next()?.ok()?, parts.next().unwrap_or(Ok(0)).ok()?));
+            let suffix = &lower[end..];
+            if !suffix.is_empty() {
+                zone = parse_zone(suffix).or_else(|| parse_zone(suffix.trim_start_matches('-')));
+            }
+        } else if let Some(m) = MONTHS.iter().position(|m| lower.len() >= 3 && lower.starts_with(m)) {
+            month = Some(m as i64 + 1);
+        } else if let Ok(n) = token.parse::<i64>() {
+            numbers.push(n);
+        } else if zone.is_none() {
+            zone = parse_zone(&lower);
+        }
+    }
+
+    // With a month name the day comes before the year in every format.
+    let (year, month, day) = match (ymd, month, numbers.as_slice()) {
+        (Some(ymd), _, _) => ymd,
+        (None, Some(month), [.., day, year]) => (*year, month, *day),
+        _ => return None,
+    };
+    let year = match year {
+        0..=69 => year + 2000,
+        70..=99 => year + 1900,
+        _ => year,
+    };
+    let (hour, minute, second) = hms?;
+    if !(1970..=9999).contains(&year)
+        || !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return None;
+    }
+
+    let seconds = days_from_civil(year, month as u32, day as u32) * 86400 + hour * 3600 + minute * 60 + second
+        - zone.unwrap_or(0);
+    Some(Duration::from_secs(u64::try_from(seconds).ok()?) + Duration::from_millis(500))
+}
+
+/// `2023-11-14T22:13:20Z` into date and time.
+fn split_iso(token: &str) -> Vec<&str> {
+    match token.split_once('T') {
+        Some((date, time)) if date.contains('-') && time.contains(':') => vec![date, time],
+        _ => vec![token],
+    }
+}
+
+/// A zone name or a `+hh[:]mm` offset, in seconds east of UTC.
+fn parse_zone(zone: &str) -> Option<i64> {
+    if let Some((_, hours)) = ZONES.iter().find(|(name, _)| *name == zone) {
+        return Some(hours * 3600);
+    }
+    let sign = match zone.as_bytes().first()? {
+        b'+' => 1,
+        b'-' => -1,
+        _ => return None,
+    };
+    let digits: String = zone[1..].chars().filter(|c| *c != ':').collect();
+    if !digits.bytes().all(|b| b.is_ascii_digit()) || !(digits.len() == 2 || digits.len() == 4) {
+        return None;
+    }
+    let hours: i64 = digits[..2].parse().ok()?;
+    let minutes: i64 = digits.get(2..).filter(|m| !m.is_empty()).map_or(Some(0), |m| m.parse().ok())?;
+    Some(sign * (hours * 3600 + minutes * 60))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::legacy::*;
+    use crate::client::{sys_time, SntpClient};
+    use crate::protocol::{ShortFormat, NTP_UNIX_EPOCH_DELTA};
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn now_1900() -> [u8; 4] {
+        ((sys_time().as_secs() + NTP_UNIX_EPOCH_DELTA) as u32).to_be_bytes()
+    }
+
+    #[test]
+    fn test_time_to_unix() {
+        assert_eq!(time_to_unix(2208988800), Duration::from_millis(500));
+        assert_eq!(time_to_unix(0), Duration::from_secs((1 << 32) - 2208988800) + Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_time() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let udp = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 16];
+            let (n, peer) = socket.recv_from(&mut buf).unwrap();
+            assert_eq!(n, 0);
+            socket.send_to(&now_1900(), peer).unwrap();
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let tcp = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(&now_1900()).unwrap();
+        });
+
+        for server in [format!("time://{}", udp), format!("time+tcp://{}", tcp)] {
+            let result = SntpClient::default().query(&server).unwrap();
+            assert!(result.offset_nanos.abs() < 1_500_000_000, "{}: {:?}", server, result);
+            assert_eq!((result.stratum, result.root_dispersion), (STRATUM, ShortFormat(TIME_DISPERSION)));
+        }
+        assert!(SntpClient::default().query("time+tcp://127.0.0.1:1").is_err());
+    }
+
+    #[test]
+    fn test_parse_daytime() {
+        let expected = Some(Duration::from_secs(1_700_000_000) + Duration::from_millis(500));
+        for answer in [
+            "\n60262 23-11-14 22:13:20 00 0 0 50.0 UTC(NIST) * \n",
+            "Tue Nov 14 22:13:20 2023\r\n",
+            "Tue Nov 14 22:13:20 UTC 2023",
+            "Tuesday, November 14, 2023 14:13:20-PST",
+            "14 NOV 23 17:13:20 EST",
+            "2023-11-14T23:13:20+01:00",
+            "2023-11-14 22:13:20.123Z",
+            "Tue, 14 Nov 2023 22:13:20 GMT",
+        ] {
+            assert_eq!(parse_daytime(answer), expected, "{}", answer);
+        }
+        assert_eq!(parse_daytime("Tue Nov 14 2023"), None);
+        assert_eq!(parse_daytime("hello"), None);
+        assert_eq!(parse_daytime("999999999999999-01-01 00:00:00 UTC"), None);
+    }
+
+    #[test]
+    fn test_daytime() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let (y, m, d) = crate::protocol::civil_from_days((sys_time().as_secs() / 86400) as i64);
+            let secs = sys_time().as_secs() % 86400;
+            write!(stream, "{}-{:02}-{:02} {:02}:{:02}:{:02} UTC\r\n", y, m, d, secs / 3600, secs / 60 % 60, secs % 60).unwrap();
+        });
+
+        let result = SntpClient::default().query(&format!("daytime+tcp://{}", addr)).unwrap();
+        assert!(result.offset_nanos.abs() < 1_500_000_000, "{:?}", result);
+        assert_eq!(result.root_dispersion, ShortFormat(DAYTIME_DISPERSION));
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn test_fallback() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let time = format!("time+tcp://{}", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let _ = stream.write_all(&now_1900());
+            }
+        });
+        let server = crate::server::NtpServer::builder().bind("127.0.0.1:0").build().unwrap().spawn().unwrap();
+        let sync = crate::synchronizer::SntpSynchronizer::builder()
+            .server(&time)
+            .server(&server.local_addr().to_string())
+            .interval(Duration::from_secs(3600))
+            .start()
+            .unwrap();
+        for _ in 0..50 {
+            if sync.offset_nanos().is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(sync.selected().unwrap().0.addr, server.local_addr());
+        assert_eq!(sync.handle().sources()[0].reach, 1);
+    }
+}
@@ -1 +1,62 @@
-pub mod sntp;
\ No newline at end of file
+#[macro_use]
+mod diag;
+#[cfg(any(feature = "server", feature = "prometheus"))]
+mod http;
+#[cfg(feature = "client")]
+mod legacy;
+#[cfg(feature = "client")]
+mod otel;
+#[cfg(all(any(feature = "fwmark", feature = "netns", feature = "ttl"), target_os = "linux"))]
+mod sockopt;
+
+#[cfg(feature = "tokio")]
+pub mod asntp;
+#[cfg(feature = "broadcast")]
+pub mod broadcast;
+#[cfg(feature = "client")]
+pub mod client;
+#[cfg(feature = "clock")]
+pub mod clock;
+#[cfg(feature = "config")]
+pub mod config;
+#[cfg(feature = "client")]
+pub mod constraint;
+#[cfg(all(feature = "client", target_os = "linux"))]
+pub mod dhcp;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(all(feature = "server", unix))]
+pub mod control;
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
+pub mod protocol;
+#[cfg(feature = "ptp")]
+pub mod ptp;
+#[cfg(feature = "roughtime")]
+pub mod roughtime;
+#[cfg(feature = "client")]
+pub mod pcap;
+#[cfg(all(feature = "probe", target_os = "linux"))]
+pub mod probe;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(feature = "client")]
+pub mod servers;
+#[cfg(all(feature = "client", any(test, feature = "testing")))]
+pub mod simulation;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod sntp;
+#[cfg(feature = "client")]
+pub mod statsd;
+#[cfg(feature = "client")]
+pub mod stats;
+#[cfg(feature = "server")]
+pub mod status;
+#[cfg(feature = "client")]
+pub mod synchronizer;
+#[cfg(all(feature = "client", unix))]
+pub mod systemd;
+#[cfg(all(feature = "client", any(test, feature = "testing")))]
+pub mod testing;
+pub mod timesource;
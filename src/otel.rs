@@ -0,0 +1,92 @@
+//! OpenTelemetry instrumentation.
+//!
+//! With the `opentelemetry` feature every exchange becomes an `ntp.exchange`
+//! span and offsets are recorded as gauges, through the global tracer and meter
+//! providers. The host application installs those providers (e.g. with
+//! `opentelemetry-otlp`), which decides where the data is exported to. The
+//! gauges are created on first use, so install the meter provider before the
+//! first query. Without the feature everything here is a no-op.
+
+use crate::client::Exchange;
+use crate::protocol::NtpError;
+
+#[cfg(feature = "opentelemetry")]
+use std::sync::OnceLock;
+
+#[cfg(feature = "opentelemetry")]
+use opentelemetry::{global, metrics::Gauge, trace::{Span, Status, Tracer}, KeyValue};
+
+/// Instrumentation scope name used for the tracer and the meter.
+#[cfg(feature = "opentelemetry")]
+const SCOPE: &str = "simple-ntp";
+
+/// Gauges built once, so recording on the hot path does not rebuild them.
+#[cfg(feature = "opentelemetry")]
+struct Instruments {
+    offset: Gauge<f64>,
+    delay: Gauge<f64>,
+    system_offset: Gauge<f64>,
+}
+
+#[cfg(feature = "opentelemetry")]
+fn instruments() -> &'static Instruments {
+    static INSTRUMENTS: OnceLock<Instruments> = OnceLock::new();
+    INSTRUMENTS.get_or_init(|| {
+        let meter = global::meter(SCOPE);
+        Instruments {
+            offset: meter.f64_gauge("ntp.offset").with_unit("s").build(),
+            delay: meter.f64_gauge("ntp.delay").with_unit("s").build(),
+            system_offset: meter.f64_gauge("ntp.system.offset").with_unit("s").build(),
+        }
+    })
+}
+
+/// Span covering a single request/response exchange.
+pub(crate) struct ExchangeSpan {
+    #[cfg(feature = "opentelemetry")]
+    span: global::BoxedSpan,
+}
+
+impl ExchangeSpan {
+    #[cfg_attr(not(feature = "opentelemetry"), allow(unused_variables))]
+    pub(crate) fn start(ntp_server: &str) -> Self {
+        #[cfg(feature = "opentelemetry")]
+        {
+            let mut span = global::tracer(SCOPE).start("ntp.exchange");
+            span.set_attribute(KeyValue::new("server.address", ntp_server.to_string()));
+            ExchangeSpan { span }
+        }
+        #[cfg(not(feature = "opentelemetry"))]
+        ExchangeSpan {}
+    }
+
+    #[cfg_attr(not(feature = "opentelemetry"), allow(unused_variables, unused_mut))]
+    pub(crate) fn end(mut self, ntp_server: &str, result: &Result<Exchange, NtpError>) {
+        #[cfg(feature = "opentelemetry")]
+        {
+            match result {
+                Ok(exchange) => {
+                    let offset = exchange.offset_nanos() as f64 / 1e9;
+                    let delay = exchange.delay_nanos() as f64 / 1e9;
+                    self.span.set_attribute(KeyValue::new("network.peer.address", exchange.peer.ip().to_string()));
+                    self.span.set_attribute(KeyValue::new("ntp.stratum", exchange.msg.stratum as i64));
+                    self.span.set_attribute(KeyValue::new("ntp.offset", offset));
+                    self.span.set_attribute(KeyValue::new("ntp.delay", delay));
+
+                    let attributes = [KeyValue::new("server.address", ntp_server.to_string())];
+                    instruments().offset.record(offset, &attributes);
+                    instruments().delay.record(delay, &attributes);
+                }
+                Err(err) => self.span.set_status(Status::error(format!("{:?}", err))),
+            }
+            self.span.end();
+        }
+    }
+}
+
+/// Record the synchronizer's selected offset, once per poll round.
+#[cfg_attr(not(feature = "opentelemetry"), allow(unused_variables))]
+pub(crate) fn record_system_offset(offset_nanos: i64) {
+    #[cfg(feature = "opentelemetry")]
+    instruments().system_offset.record(offset_nanos as f64 / 1e9, &[]);
+}
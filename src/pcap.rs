@@ -0,0 +1,495 @@
+//! Capture of NTP exchanges in pcap format, for Wireshark, and offline replay.
+//!
+//! A [`PcapWriter`] given to [`SntpClientBuilder::pcap`](crate::client::SntpClientBuilder::pcap)
+//! records every request and response with the client's own send and receive
+//! times, the t1 and t4 of the offset computation, so what Wireshark shows is
+//! what the client measured. IP and UDP headers are made up from the socket's
+//! addresses; the file uses nanosecond timestamps and raw IP link type.
+//!
+//! [`replay`] goes the other way: it runs the client's checks and offset
+//! computation over the exchanges in a capture, its own or one taken with
+//! `tcpdump -w` on the client host, to see after the fact what it computed.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::client::{self, AuditRecord, NtpResult};
+use crate::protocol::{NtpError, NTP_MODE_CLIENT, NTP_MODE_SERVER};
+
+/// pcap magic with nanosecond resolution timestamps.
+pub(crate) const MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+/// pcap magic with microsecond resolution timestamps.
+pub(crate) const MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+/// pcapng section header block type, not supported.
+const MAGIC_PCAPNG: u32 = 0x0a0d_0d0a;
+
+/// LINKTYPE_NULL: BSD loopback, a 4 byte address family precedes the IP header.
+const LINKTYPE_NULL: u32 = 0;
+/// LINKTYPE_ETHERNET.
+const LINKTYPE_ETHERNET: u32 = 1;
+/// LINKTYPE_RAW: packets start with the IPv4 or IPv6 header.
+pub(crate) const LINKTYPE_RAW: u32 = 101;
+/// LINKTYPE_LINUX_SLL: `tcpdump -i any`.
+const LINKTYPE_LINUX_SLL: u32 = 113;
+/// LINKTYPE_IPV4 and LINKTYPE_IPV6, raw IP of one family.
+const LINKTYPE_IPV4: u32 = 228;
+const LINKTYPE_IPV6: u32 = 229;
+
+const SNAPLEN: u32 = 65535;
+const TTL: u8 = 64;
+const PROTO_UDP: u8 = 17;
+
+/// Appends datagrams to a pcap stream. Clones write to the same stream, so one
+/// writer can be shared by several clients.
+///
+/// Example
+/// ```rust,no_run
+/// # use simple_ntp::pcap::PcapWriter;
+/// # use simple_ntp::client::SntpClient;
+///
+/// fn main() {
+///     let pcap = PcapWriter::create("ntp.pcap").unwrap();
+///     let client = SntpClient::builder().pcap(pcap).build();
+///     println!("{:?}", client.query("ntp.aliyun.com"));
+/// }
+/// ```
+#[derive(Clone)]
+pub struct PcapWriter {
+    out: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl fmt::Debug for PcapWriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PcapWriter").finish_non_exhaustive()
+    }
+}
+
+impl PcapWriter {
+    /// Create or truncate `path` and write the file header.
+    pub fn create(path: impl AsRef<Path>) -> Result<PcapWriter, NtpError> {
+        let path = path.as_ref();
+        let file = File::create(path).map_err(|err| {
+            NtpError::BadConfig(format!("{}: {}", path.display(), err))
+        })?;
+        PcapWriter::new(file).map_err(|err| {
+            NtpError::BadConfig(format!("{}: {}", path.display(), err))
+        })
+    }
+
+    /// Write the file header to `out`, the datagrams follow.
+    pub fn new(out: impl Write + Send + 'static) -> io::Result<PcapWriter> {
+        let mut out: Box<dyn Write + Send> = Box::new(out);
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(&MAGIC_NANOS.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        header.extend_from_slice(&0i32.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&SNAPLEN.to_le_bytes());
+        header.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+        out.write_all(&header)?;
+        out.flush()?;
+
+        Ok(PcapWriter { out: Arc::new(Mutex::new(out)) })
+    }
+
+    /// Write one UDP datagram captured at `time`, since the Unix epoch.
+    pub fn write(&self, time: Duration, from: SocketAddr, to: SocketAddr, payload: &[u8]) -> io::Result<()> {
+        let packet = ip_udp(from, to, payload);
+        let mut record = Vec::with_capacity(16 + packet.len());
+        record.extend_from_slice(&(time.as_secs() as u32).to_le_bytes());
+        record.extend_from_slice(&time.subsec_nanos().to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        record.extend_from_slice(&packet);
+
+        // One write per record and a flush, so the file is readable while running.
+        let mut out = self.out.lock().unwrap();
+        out.write_all(&record)?;
+        out.flush()
+    }
+
+    /// Write the request and response of an exchange at its t1 and t4, as far
+    /// as it got. `local` is the client's address, unspecified if unknown.
+    pub fn write_record(&self, local: Option<SocketAddr>, record: &AuditRecord) -> io::Result<()> {
+        let Some(peer) = record.addr else {
+            return Ok(());
+        };
+        let local = local.unwrap_or_else(|| match peer {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        });
+
+        if let (Some(t1), false) = (record.t1, record.request.is_empty()) {
+            self.write(t1, local, peer, &record.request)?;
+        }
+        if let (Some(t4), false) = (record.t4, record.response.is_empty()) {
+            self.write(t4, peer, local, &record.response)?;
+        }
+        Ok(())
+    }
+}
+
+/// A UDP datagram read from a capture.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Datagram {
+    /// Capture time since the Unix epoch.
+    pub time: Duration,
+    pub from: SocketAddr,
+    pub to: SocketAddr,
+    pub payload: Vec<u8>,
+}
+
+/// One exchange of [`replay`], as [`SntpClient::query_audited`](crate::client::SntpClient::query_audited) returns it.
+pub type Replayed = (Result<NtpResult, NtpError>, AuditRecord);
+
+/// Read the UDP datagrams of a pcap capture, skipping any other traffic.
+///
+/// Reads classic pcap, not pcapng, with Ethernet (VLAN tagged too), raw IP,
+/// BSD loopback or Linux cooked (`tcpdump -i any`) link types. Fragmented
+/// datagrams are skipped, and a record cut short by a killed capture ends it.
+pub fn read(mut input: impl Read) -> Result<Vec<Datagram>, NtpError> {
+    let mut data = Vec::new();
+    input.read_to_end(&mut data).map_err(|err| {
+        NtpError::BadConfig(err.to_string())
+    })?;
+    let bad = |reason: &str| NtpError::BadConfig(format!("not a pcap capture: {}", reason));
+
+    let magic = u32::from_le_bytes(data.get(..4).ok_or_else(|| bad("truncated header"))?.try_into().unwrap());
+    let (swapped, nanos) = match magic {
+        MAGIC_NANOS => (false, true),
+        MAGIC_MICROS => (false, false),
+        _ if magic.swap_bytes() == MAGIC_NANOS => (true, true),
+        _ if magic.swap_bytes() == MAGIC_MICROS => (true, false),
+        MAGIC_PCAPNG => return Err(bad("pcapng, save it as pcap")),
+        _ => return Err(bad("unknown magic")),
+    };
+    if data.len() < 24 {
+        return Err(bad("truncated header"));
+    }
+    let u32_at = |i: usize| {
+        let value = u32::from_le_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+        if swapped { value.swap_bytes() } else { value }
+    };
+
+    // The upper bits may carry FCS information.
+    let linktype = u32_at(20) & 0xffff;
+    if ![LINKTYPE_NULL, LINKTYPE_ETHERNET, LINKTYPE_RAW, LINKTYPE_LINUX_SLL, LINKTYPE_IPV4, LINKTYPE_IPV6].contains(&linktype) {
+        return Err(bad(&format!("unsupported link type {}", linktype)));
+    }
+
+    let mut datagrams = Vec::new();
+    let mut i = 24;
+    while i + 16 <= data.len() {
+        let subsec = u32_at(i + 4) as u64 * if nanos { 1 } else { 1000 };
+        let time = Duration::from_secs(u32_at(i) as u64) + Duration::from_nanos(subsec);
+        let len = u32_at(i + 8) as usize;
+        let Some(packet) = data.get(i + 16..i + 16 + len) else {
+            warn!("pcap capture ends with a truncated record");
+            break;
+        };
+        i += 16 + len;
+
+        if let Some((from, to, payload)) = link_payload(linktype, packet).and_then(udp_payload) {
+            datagrams.push(Datagram { time, from, to, payload: payload.to_vec() });
+        }
+    }
+
+    Ok(datagrams)
+}
+
+/// The NTP exchanges of `datagrams` as [`SntpClient::query_audited`](crate::client::SntpClient::query_audited)
+/// would have returned them, in the order of the requests.
+///
+/// A client request is answered by the first server response from the
+/// address it was sent to, back to the client's address, as on the client's
+/// connected socket; t1 and t4 are the capture times of the two. Requests
+/// without a response fail with `ServiceUnavailable`, responses to no request
+/// are ignored.
+///
+/// Example
+/// ```rust,no_run
+/// # use std::fs::File;
+/// # use simple_ntp::pcap;
+///
+/// fn main() {
+///     let datagrams = pcap::read(File::open("ntp.pcap").unwrap()).unwrap();
+///     for (result, record) in pcap::replay(&datagrams) {
+///         println!("{:?} {}: {:?}", record.t1, record.server, result);
+///     }
+/// }
+/// ```
+pub fn replay(datagrams: &[Datagram]) -> Vec<Replayed> {
+    let mut exchanges: Vec<Replayed> = Vec::new();
+    // Client address, server address, transmit timestamp, index into exchanges.
+    let mut pending: Vec<(SocketAddr, SocketAddr, u64, usize)> = Vec::new();
+
+    for datagram in datagrams {
+        let Some(mode) = datagram.payload.first().map(|byte| byte & 0b111) else {
+            continue;
+        };
+        if mode == NTP_MODE_CLIENT {
+            let Some(origin) = datagram.payload.get(40..48).map(|ts| u64::from_be_bytes(ts.try_into().unwrap())) else {
+                continue;
+            };
+            // A retry replaces the request, its socket only takes a response to the latest.
+            pending.retain(|&(client, server, _, _)| (client, server) != (datagram.from, datagram.to));
+            pending.push((datagram.from, datagram.to, origin, exchanges.len()));
+            let record = AuditRecord {
+                server: datagram.to.to_string(),
+                addr: Some(datagram.to),
+                request: datagram.payload.clone(),
+                t1: Some(datagram.time),
+                ..AuditRecord::default()
+            };
+            let err = NtpError::ServiceUnavailable("no response in capture".to_string());
+            exchanges.push((Err(err), record));
+        } else if mode == NTP_MODE_SERVER {
+            let Some(at) = pending.iter().position(|&(client, server, _, _)| (client, server) == (datagram.to, datagram.from)) else {
+                continue;
+            };
+            let (_, _, origin, index) = pending.remove(at);
+            let (result, record) = &mut exchanges[index];
+            let t1 = record.t1.unwrap_or_default();
+            record.t4 = Some(datagram.time);
+            record.response = datagram.payload.clone();
+            *result = client::check_response(datagram.from, origin, t1, datagram.time, &datagram.payload, &client::ResponsePolicy::default(), record)
+                .map(|exchange| NtpResult::from(&exchange));
+        }
+    }
+
+    exchanges
+}
+
+/// [`read`] the capture at `path` and [`replay`] it.
+pub fn replay_file(path: impl AsRef<Path>) -> Result<Vec<Replayed>, NtpError> {
+    let path = path.as_ref();
+    let file = File::open(path).map_err(|err| {
+        NtpError::BadConfig(format!("{}: {}", path.display(), err))
+    })?;
+
+    Ok(replay(&read(io::BufReader::new(file))?))
+}
+
+/// The IP packet in a link layer frame.
+fn link_payload(linktype: u32, frame: &[u8]) -> Option<&[u8]> {
+    let is_ip = |ethertype: u16| ethertype == 0x0800 || ethertype == 0x86dd;
+    match linktype {
+        LINKTYPE_NULL => frame.get(4..),
+        LINKTYPE_ETHERNET => {
+            let mut at = 12;
+            // 802.1Q and 802.1ad tags.
+            while matches!(be16(frame, at)?, 0x8100 | 0x88a8) {
+                at += 4;
+            }
+            is_ip(be16(frame, at)?).then(|| frame.get(at + 2..)).flatten()
+        }
+        LINKTYPE_LINUX_SLL => is_ip(be16(frame, 14)?).then(|| frame.get(16..)).flatten(),
+        _ => Some(frame),
+    }
+}
+
+/// Source, destination and payload of an unfragmented UDP datagram.
+fn udp_payload(packet: &[u8]) -> Option<(SocketAddr, SocketAddr, &[u8])> {
+    let (from, to, udp): (IpAddr, IpAddr, _) = match packet.first()? >> 4 {
+        4 => {
+            let header_len = (packet[0] & 0xf) as usize * 4;
+            let fragmented = be16(packet, 6)? & 0x3fff != 0;
+            if *packet.get(9)? != PROTO_UDP || fragmented {
+                return None;
+            }
+            let from: [u8; 4] = packet.get(12..16)?.try_into().ok()?;
+            let to: [u8; 4] = packet.get(16..20)?.try_into().ok()?;
+            let packet = packet.get(..be16(packet, 2)? as usize)?;
+            (from.into(), to.into(), packet.get(header_len..)?)
+        }
+        6 => {
+            // Extension headers are not followed.
+            if *packet.get(6)? != PROTO_UDP {
+                return None;
+            }
+            let from: [u8; 16] = packet.get(8..24)?.try_into().ok()?;
+            let to: [u8; 16] = packet.get(24..40)?.try_into().ok()?;
+            (from.into(), to.into(), packet.get(40..40 + be16(packet, 4)? as usize)?)
+        }
+        _ => return None,
+    };
+    let payload = udp.get(8..be16(udp, 4)? as usize)?;
+
+    Some((SocketAddr::new(from, be16(udp, 0)?), SocketAddr::new(to, be16(udp, 2)?), payload))
+}
+
+fn be16(data: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(at..at + 2)?.try_into().ok()?))
+}
+
+/// An IPv4 or IPv6 packet carrying `payload` in a UDP datagram.
+fn ip_udp(from: SocketAddr, to: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let udp_len = 8 + payload.len();
+    let mut udp = Vec::with_capacity(udp_len);
+    udp.extend_from_slice(&from.port().to_be_bytes());
+    udp.extend_from_slice(&to.port().to_be_bytes());
+    udp.extend_from_slice(&(udp_len as u16).to_be_bytes());
+    udp.extend_from_slice(&[0, 0]);
+    udp.extend_from_slice(payload);
+
+    let mut packet;
+    let mut pseudo = Vec::with_capacity(40);
+    match (from.ip(), to.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            packet = Vec::with_capacity(20 + udp_len);
+            packet.extend_from_slice(&[0x45, 0]);
+            packet.extend_from_slice(&((20 + udp_len) as u16).to_be_bytes());
+            packet.extend_from_slice(&[0, 0, 0x40, 0, TTL, PROTO_UDP, 0, 0]);
+            packet.extend_from_slice(&src.octets());
+            packet.extend_from_slice(&dst.octets());
+            let checksum = checksum(&packet);
+            packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+            pseudo.extend_from_slice(&src.octets());
+            pseudo.extend_from_slice(&dst.octets());
+            pseudo.extend_from_slice(&[0, PROTO_UDP]);
+            pseudo.extend_from_slice(&(udp_len as u16).to_be_bytes());
+        }
+        (src, dst) => {
+            let v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            let (src, dst) = (v6(src), v6(dst));
+            packet = Vec::with_capacity(40 + udp_len);
+            packet.extend_from_slice(&[0x60, 0, 0, 0]);
+            packet.extend_from_slice(&(udp_len as u16).to_be_bytes());
+            packet.extend_from_slice(&[PROTO_UDP, TTL]);
+            packet.extend_from_slice(&src.octets());
+            packet.extend_from_slice(&dst.octets());
+            pseudo.extend_from_slice(&src.octets());
+            pseudo.extend_from_slice(&dst.octets());
+            pseudo.extend_from_slice(&(udp_len as u32).to_be_bytes());
+            pseudo.extend_from_slice(&[0, 0, 0, PROTO_UDP]);
+        }
+    }
+
+    pseudo.extend_from_slice(&udp);
+    let checksum = match checksum(&pseudo) {
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    udp[6..8].copy_from_slice(&checksum.to_be_bytes());
+    packet.extend_from_slice(&udp);
+    packet
+}
+
+/// The Internet checksum, RFC 1071.
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data.chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], pair.get(1).copied().unwrap_or(0)]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pcap::*;
+    use crate::testing::{MockServer, Response};
+    use crate::client::QueryTiming;
+    use crate::client::SntpClient;
+
+    #[test]
+    fn test_checksum() {
+        // RFC 1071's example, the sum of these words is 0xddf2.
+        assert_eq!(checksum(&[0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7]), !0xddf2);
+        let packet = ip_udp("192.0.2.1:123".parse().unwrap(), "198.51.100.7:40000".parse().unwrap(), &[1, 2, 3]);
+        assert_eq!(packet.len(), 20 + 8 + 3);
+        assert_eq!(checksum(&packet[..20]), 0);
+        let packet = ip_udp("[2001:db8::1]:123".parse().unwrap(), "[2001:db8::2]:40000".parse().unwrap(), &[1, 2, 3]);
+        assert_eq!((packet.len(), packet[0] >> 4, packet[6]), (40 + 8 + 3, 6, PROTO_UDP));
+    }
+
+    #[test]
+    fn test_pcap_capture() {
+        let path = std::env::temp_dir().join(format!("simple-ntp-{}.pcap", std::process::id()));
+        let server = MockServer::builder().start().unwrap();
+        let client = SntpClient::builder().pcap(PcapWriter::create(&path).unwrap()).build();
+        let (result, record) = client.query_audited(&server.addr());
+        assert!(result.is_ok());
+
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(data[..4], MAGIC_NANOS.to_le_bytes());
+        assert_eq!(data[20..24], LINKTYPE_RAW.to_le_bytes());
+        let request = &data[24..];
+        let t1 = record.t1.unwrap();
+        assert_eq!(request[..8], [(t1.as_secs() as u32).to_le_bytes(), t1.subsec_nanos().to_le_bytes()].concat());
+        assert_eq!(u32::from_le_bytes(request[8..12].try_into().unwrap()), 20 + 8 + 48);
+        assert_eq!(request[16 + 16..16 + 20], [127, 0, 0, 1]);
+        assert_eq!(request[16 + 22..16 + 24], server.local_addr().port().to_be_bytes());
+        assert_eq!(request[16 + 28..16 + 76], record.request[..]);
+        let response = &request[16 + 76..];
+        assert_eq!(response.len(), 16 + 76);
+        assert_eq!(response[16 + 28..], record.response[..]);
+    }
+
+    #[test]
+    fn test_replay() {
+        let path = std::env::temp_dir().join(format!("simple-ntp-replay-{}.pcap", std::process::id()));
+        let server = MockServer::builder()
+            .offset_nanos(-1_500_000_000)
+            .script([Response::Reply, Response::BadOrigin, Response::Drop])
+            .start()
+            .unwrap();
+        let client = SntpClient::builder()
+            .timeout(std::time::Duration::from_millis(200))
+            .pcap(PcapWriter::create(&path).unwrap())
+            .build();
+        let live: Vec<_> = (0..3).map(|_| client.query_audited(&server.addr())).collect();
+
+        let replayed = replay_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(replayed.len(), 3);
+        let (live_result, live_record) = &live[0];
+        let (result, record) = &replayed[0];
+        assert_eq!(result.as_ref().unwrap(), live_result.as_ref().unwrap());
+        // A capture has no wall clock timing of the steps.
+        assert_eq!(record, &AuditRecord { timing: QueryTiming::default(), ..live_record.clone() });
+        assert!(matches!(live[1].0, Err(NtpError::UntrustedMessage)));
+        assert!(matches!(replayed[1].0, Err(NtpError::UntrustedMessage)));
+        assert!(matches!(replayed[2].0, Err(NtpError::ServiceUnavailable(_))));
+    }
+
+    #[test]
+    fn test_read_link_types() {
+        let from: SocketAddr = "192.0.2.1:40000".parse().unwrap();
+        let to: SocketAddr = "198.51.100.7:123".parse().unwrap();
+        let packet = ip_udp(from, to, &[0x23; 48]);
+
+        // Big-endian microsecond pcap with a VLAN tagged Ethernet frame and trailing padding.
+        let mut data = Vec::new();
+        for word in [MAGIC_MICROS.to_be_bytes(), [0, 2, 0, 4], [0; 4], [0; 4], 65535u32.to_be_bytes(), LINKTYPE_ETHERNET.to_be_bytes()] {
+            data.extend_from_slice(&word);
+        }
+        let frame = [&[0; 12][..], &[0x81, 0, 0, 7, 0x08, 0], &packet, &[0; 4]].concat();
+        for word in [1_700_000_000u32, 250_000, frame.len() as u32, frame.len() as u32] {
+            data.extend_from_slice(&word.to_be_bytes());
+        }
+        data.extend_from_slice(&frame);
+        // A record cut short, as left by a killed tcpdump.
+        data.extend_from_slice(&[0; 20]);
+
+        let datagrams = read(&data[..]).unwrap();
+        assert_eq!(datagrams, vec![Datagram {
+            time: Duration::from_millis(1_700_000_000_250),
+            from,
+            to,
+            payload: vec![0x23; 48],
+        }]);
+        assert!(matches!(read(&[0x0a, 0x0d, 0x0d, 0x0a][..]), Err(NtpError::BadConfig(_))));
+    }
+}
@@ -0,0 +1,469 @@
+//! Paced trains of NTP requests, for characterizing a network path.
+//!
+//! A [`ProbeTrain`] sends requests to one server at a fixed interval and
+//! collects the responses in batches with `recvmmsg`, each stamped by the
+//! kernel as it arrived (`SO_TIMESTAMPNS`), so scheduling delays of the
+//! receiving thread stay out of the measurements. Send times are read just
+//! before each `send`.
+//!
+//! Every answered request is a [`Probe`] with its four timestamps. Besides the
+//! round trip delay, a probe splits the trip into its outbound and inbound
+//! legs, which include the clock offset between the two hosts: one-way delays
+//! only up to a constant, good for spotting asymmetry, queueing and its
+//! changes along a train, not for disciplining a clock.
+//!
+//! Trains are far denser than servers allow ordinary clients, whatever their
+//! rate limit: probe servers you run, or may load so.
+
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::net::{SocketAddr, UdpSocket};
+use std::os::fd::AsRawFd;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::client::sys_time;
+use crate::protocol::{
+    duration_to_ntp_timestamp, ntp_timestamp_to_duration, NtpError, NtpMsg, NTP_MODE_SERVER, NTP_VERSION_4,
+};
+
+/// Datagrams received per `recvmmsg` call at most.
+const BATCH: usize = 64;
+
+/// Receive buffer per datagram, room for a header with a few extension fields.
+const DATAGRAM_SIZE: usize = 512;
+
+/// Control buffer per datagram, room for one `SCM_TIMESTAMPNS`.
+const CONTROL_SIZE: usize = 64;
+
+/// How often the receiving thread checks whether the train is over.
+const RECV_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Sleep until this close to a send time, then spin, for a steadier pace.
+const SPIN: Duration = Duration::from_micros(200);
+
+/// One answered request of a train. Times are since the Unix epoch, `sent`
+/// and `received` by the local clock, the others by the server's.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Probe {
+    /// Position in the train, from 0.
+    pub seq: usize,
+    /// T1, when the request was sent.
+    pub sent: Duration,
+    /// T2, when the server received the request.
+    pub server_received: Duration,
+    /// T3, when the server sent the response.
+    pub server_sent: Duration,
+    /// T4, when the response arrived.
+    pub received: Duration,
+    /// Whether `received` was taken by the kernel, not read after `recvmmsg` returned.
+    pub kernel_timestamp: bool,
+}
+
+impl Probe {
+    /// Round trip delay in nano seconds, without the time spent in the server.
+    pub fn delay_nanos(&self) -> i64 {
+        nanos(self.received) - nanos(self.sent) - (nanos(self.server_sent) - nanos(self.server_received))
+    }
+
+    /// Clock offset in nano seconds, server time sub local time.
+    pub fn offset_nanos(&self) -> i64 {
+        (self.outbound_nanos() - self.inbound_nanos()) / 2
+    }
+
+    /// T2 - T1: the outbound delay plus the clock offset.
+    pub fn outbound_nanos(&self) -> i64 {
+        nanos(self.server_received) - nanos(self.sent)
+    }
+
+    /// T4 - T3: the inbound delay minus the clock offset.
+    pub fn inbound_nanos(&self) -> i64 {
+        nanos(self.received) - nanos(self.server_sent)
+    }
+}
+
+/// Spread of the round trip delays of a train, in nano seconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DelaySummary {
+    pub min: i64,
+    pub median: i64,
+    pub max: i64,
+    /// Mean absolute difference between the delays of consecutive answered probes.
+    pub jitter: i64,
+}
+
+/// What came back from a train.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrainReport {
+    /// Requests sent.
+    pub sent: usize,
+    /// Answered requests, in the order their responses arrived.
+    pub probes: Vec<Probe>,
+    /// Responses to requests answered before, not in `probes`.
+    pub duplicates: usize,
+}
+
+impl TrainReport {
+    /// Share of requests left unanswered, from 0 to 1.
+    pub fn loss(&self) -> f64 {
+        if self.sent == 0 {
+            return 0.0;
+        }
+        1.0 - self.probes.len() as f64 / self.sent as f64
+    }
+
+    /// Responses that arrived after one to a later request.
+    pub fn reordered(&self) -> usize {
+        let mut latest = None;
+        let mut reordered = 0;
+        for probe in &self.probes {
+            match latest {
+                Some(seq) if probe.seq < seq => reordered += 1,
+                _ => latest = Some(probe.seq),
+            }
+        }
+        reordered
+    }
+
+    /// Round trip delay statistics, `None` when nothing was answered.
+    pub fn delay_summary(&self) -> Option<DelaySummary> {
+        let mut in_order: Vec<&Probe> = self.probes.iter().collect();
+        in_order.sort_by_key(|probe| probe.seq);
+        let jitter = if in_order.len() < 2 {
+            0
+        } else {
+            let total: i64 = in_order.windows(2).map(|pair| (pair[1].delay_nanos() - pair[0].delay_nanos()).abs()).sum();
+            total / (in_order.len() - 1) as i64
+        };
+
+        let mut delays: Vec<i64> = self.probes.iter().map(Probe::delay_nanos).collect();
+        delays.sort_unstable();
+        Some(DelaySummary {
+            min: *delays.first()?,
+            median: delays[delays.len() / 2],
+            max: *delays.last()?,
+            jitter,
+        })
+    }
+}
+
+/// Configure a [`ProbeTrain`].
+pub struct ProbeTrainBuilder {
+    count: usize,
+    interval: Duration,
+    timeout: Duration,
+}
+
+impl ProbeTrainBuilder {
+    /// Requests per train, 100 by default.
+    pub fn count(mut self, count: usize) -> Self {
+        self.count = count;
+        self
+    }
+
+    /// Time between two requests, 10ms by default.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// How long to wait for responses after the last request, 1s by default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn build(self) -> ProbeTrain {
+        ProbeTrain {
+            count: self.count,
+            interval: self.interval,
+            timeout: self.timeout,
+        }
+    }
+}
+
+/// Sends paced trains of requests and measures the path from their responses (Linux).
+///
+/// Example
+/// ```rust,no_run
+/// # use std::net::ToSocketAddrs;
+/// # use std::time::Duration;
+/// # use simple_ntp::probe::ProbeTrain;
+///
+/// fn main() {
+///     let addr = "ntp.example.net:123".to_socket_addrs().unwrap().next().unwrap();
+///     let train = ProbeTrain::builder().count(500).interval(Duration::from_millis(2)).build();
+///     let report = train.run(addr).unwrap();
+///     println!("loss {:.1}%, reordered {}", report.loss() * 100.0, report.reordered());
+///     if let Some(delay) = report.delay_summary() {
+///         println!("delay min {}ns median {}ns jitter {}ns", delay.min, delay.median, delay.jitter);
+///     }
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct ProbeTrain {
+    count: usize,
+    interval: Duration,
+    timeout: Duration,
+}
+
+impl ProbeTrain {
+    pub fn builder() -> ProbeTrainBuilder {
+        ProbeTrainBuilder {
+            count: 100,
+            interval: Duration::from_millis(10),
+            timeout: Duration::from_secs(1),
+        }
+    }
+
+    /// Send one train to `addr` and wait for its responses.
+    pub fn run(&self, addr: SocketAddr) -> Result<TrainReport, NtpError> {
+        let bind = if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(bind).map_err(|err| {
+            NtpError::ServiceUnavailable(format!("bind {}: {}", bind, err))
+        })?;
+        socket.connect(addr).map_err(|err| {
+            NtpError::ServiceUnavailable(format!("connect {}: {}", addr, err))
+        })?;
+        socket.set_read_timeout(Some(RECV_POLL_INTERVAL)).map_err(|err| {
+            NtpError::UnexpectedErr(err.to_string())
+        })?;
+        // Without kernel timestamps the train still runs, on user space receive times.
+        let _ = enable_timestamps(&socket);
+
+        let mut origins = HashMap::with_capacity(self.count);
+        let mut sends = Vec::with_capacity(self.count);
+        let done = AtomicBool::new(false);
+        let answered = AtomicUsize::new(0);
+        let (received, send_result) = thread::scope(|scope| {
+            let receiver = scope.spawn(|| receive(&socket, &done, &answered, self.count, self.timeout));
+            let send_result = self.send_all(&socket, &mut origins, &mut sends);
+            done.store(true, Ordering::Release);
+            (receiver.join(), send_result)
+        });
+        send_result?;
+        let received = received.map_err(|_| NtpError::UnexpectedErr("probe receiver panicked".to_string()))??;
+
+        let mut report = TrainReport {
+            sent: sends.len(),
+            probes: Vec::with_capacity(received.len()),
+            duplicates: 0,
+        };
+        let mut seen = vec![false; sends.len()];
+        for (data, kernel_time) in received {
+            let Some(msg) = data.get(..48).and_then(|header| NtpMsg::parse(header).ok()) else {
+                continue;
+            };
+            if msg.mode != NTP_MODE_SERVER {
+                continue;
+            }
+            let Some(&seq) = origins.get(&msg.originate_timestamp) else {
+                continue;
+            };
+            if mem::replace(&mut seen[seq], true) {
+                report.duplicates += 1;
+                continue;
+            }
+            report.probes.push(Probe {
+                seq,
+                sent: sends[seq],
+                server_received: ntp_timestamp_to_duration(msg.receiver_timestamp),
+                server_sent: ntp_timestamp_to_duration(msg.transmit_timestamp),
+                received: kernel_time.0,
+                kernel_timestamp: kernel_time.1,
+            });
+        }
+
+        Ok(report)
+    }
+
+    /// Send the requests on schedule, each with its own transmit timestamp to
+    /// match the responses by.
+    fn send_all(&self, socket: &UdpSocket, origins: &mut HashMap<u64, usize>, sends: &mut Vec<Duration>) -> Result<(), NtpError> {
+        let start = Instant::now();
+        let mut last_origin = 0;
+        for seq in 0..self.count {
+            let due = start + self.interval * seq as u32;
+            while let Some(wait) = due.checked_duration_since(Instant::now()) {
+                if wait > SPIN {
+                    thread::sleep(wait - SPIN);
+                } else {
+                    std::hint::spin_loop();
+                }
+            }
+
+            let sent = sys_time();
+            // Two sends within one NTP fraction would share a timestamp.
+            let origin = duration_to_ntp_timestamp(&sent).max(last_origin + 1);
+            last_origin = origin;
+            let request = NtpMsg::new_for_client(NTP_VERSION_4, origin).marshal();
+            socket.send(&request).map_err(|err| {
+                NtpError::ServiceUnavailable(format!("send: {}", err))
+            })?;
+            origins.insert(origin, seq);
+            sends.push(sent);
+        }
+
+        Ok(())
+    }
+}
+
+/// A received datagram, with its receive time and whether the kernel took it.
+type Datagram = (Vec<u8>, (Duration, bool));
+
+/// Collect responses until all `count` arrived or `timeout` after the last
+/// request was sent.
+fn receive(socket: &UdpSocket, done: &AtomicBool, answered: &AtomicUsize, count: usize, timeout: Duration) -> Result<Vec<Datagram>, NtpError> {
+    let mut received = Vec::with_capacity(count);
+    let mut deadline = None;
+    loop {
+        match recv_batch(socket) {
+            Ok(batch) => {
+                answered.fetch_add(batch.len(), Ordering::Relaxed);
+                received.extend(batch);
+            }
+            Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted) => {}
+            // ICMP errors of the connected socket, e.g. port unreachable: keep waiting out the train.
+            Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => {}
+            Err(err) => return Err(NtpError::ServiceUnavailable(format!("recvmmsg: {}", err))),
+        }
+
+        if deadline.is_none() && done.load(Ordering::Acquire) {
+            deadline = Some(Instant::now() + timeout);
+        }
+        if let Some(deadline) = deadline {
+            if answered.load(Ordering::Relaxed) >= count || Instant::now() >= deadline {
+                return Ok(received);
+            }
+        }
+    }
+}
+
+fn enable_timestamps(socket: &UdpSocket) -> io::Result<()> {
+    let on: libc::c_int = 1;
+    // SAFETY: `on` outlives the call and its size is passed along.
+    let rc = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_TIMESTAMPNS,
+            ptr::from_ref(&on).cast(),
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Receive up to [`BATCH`] datagrams with one `recvmmsg`, blocking for the
+/// first at most the socket's read timeout.
+fn recv_batch(socket: &UdpSocket) -> io::Result<Vec<Datagram>> {
+    let mut buffers = vec![[0u8; DATAGRAM_SIZE]; BATCH];
+    let mut controls = vec![[0u8; CONTROL_SIZE]; BATCH];
+    // SAFETY: all zeros is a valid iovec and mmsghdr, null pointers and zero lengths.
+    let mut iovecs: Vec<libc::iovec> = (0..BATCH).map(|_| unsafe { mem::zeroed() }).collect();
+    let mut messages: Vec<libc::mmsghdr> = (0..BATCH).map(|_| unsafe { mem::zeroed() }).collect();
+    for (((message, iovec), buffer), control) in messages.iter_mut().zip(&mut iovecs).zip(&mut buffers).zip(&mut controls) {
+        iovec.iov_base = buffer.as_mut_ptr().cast();
+        iovec.iov_len = buffer.len();
+        message.msg_hdr.msg_iov = iovec;
+        message.msg_hdr.msg_iovlen = 1;
+        message.msg_hdr.msg_control = control.as_mut_ptr().cast();
+        message.msg_hdr.msg_controllen = control.len() as _;
+    }
+
+    // SAFETY: every header points at its own buffers, which outlive the call.
+    let n = unsafe {
+        libc::recvmmsg(socket.as_raw_fd(), messages.as_mut_ptr(), BATCH as _, libc::MSG_WAITFORONE, ptr::null_mut())
+    };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let now = sys_time();
+
+    Ok(messages[..n as usize]
+        .iter()
+        .zip(&buffers)
+        .map(|(message, buffer)| {
+            let len = (message.msg_len as usize).min(DATAGRAM_SIZE);
+            let time = kernel_time(&message.msg_hdr).map_or((now, false), |time| (time, true));
+            (buffer[..len].to_vec(), time)
+        })
+        .collect())
+}
+
+/// The `SCM_TIMESTAMPNS` receive time in a received message's control data.
+fn kernel_time(header: &libc::msghdr) -> Option<Duration> {
+    // SAFETY: `header` was filled in by recvmmsg, its control data is within
+    // the buffer it points at, and the CMSG macros stay inside it.
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(header);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_TIMESTAMPNS {
+                let time: libc::timespec = ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast());
+                return Some(Duration::new(time.tv_sec as u64, time.tv_nsec as u32));
+            }
+            cmsg = libc::CMSG_NXTHDR(header, cmsg);
+        }
+    }
+    None
+}
+
+fn nanos(d: Duration) -> i64 {
+    d.as_nanos() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::probe::*;
+    use crate::testing::{MockServer, Response};
+
+    #[test]
+    fn test_train() {
+        let server = MockServer::builder().offset_nanos(3_000_000_000).start().unwrap();
+        let train = ProbeTrain::builder().count(20).interval(Duration::from_millis(2)).build();
+        let report = train.run(server.local_addr()).unwrap();
+
+        assert_eq!(report.sent, 20);
+        assert_eq!(report.probes.len(), 20);
+        assert_eq!(report.loss(), 0.0);
+        assert_eq!(report.duplicates, 0);
+        let mut seqs: Vec<usize> = report.probes.iter().map(|probe| probe.seq).collect();
+        seqs.sort_unstable();
+        assert_eq!(seqs, (0..20).collect::<Vec<_>>());
+        for probe in &report.probes {
+            assert!(probe.kernel_timestamp);
+            assert_eq!((probe.offset_nanos() as f64 / 1e9).round(), 3.0);
+            assert_eq!((probe.outbound_nanos() as f64 / 1e9).round(), 3.0);
+            assert_eq!((probe.inbound_nanos() as f64 / 1e9).round(), -3.0);
+            assert!(probe.delay_nanos() < 1_000_000_000);
+        }
+        let delay = report.delay_summary().unwrap();
+        assert!(delay.min <= delay.median && delay.median <= delay.max);
+    }
+
+    #[test]
+    fn test_train_loss() {
+        let server = MockServer::builder()
+            .script([Response::Reply, Response::Drop, Response::Reply, Response::Drop])
+            .start()
+            .unwrap();
+        let train = ProbeTrain::builder().count(4).interval(Duration::from_millis(1)).timeout(Duration::from_millis(200)).build();
+        let report = train.run(server.local_addr()).unwrap();
+
+        assert_eq!(report.sent, 4);
+        assert_eq!(report.loss(), 0.5);
+        let mut seqs: Vec<usize> = report.probes.iter().map(|probe| probe.seq).collect();
+        seqs.sort_unstable();
+        assert_eq!(seqs, vec![0, 2]);
+
+        let empty = TrainReport { sent: 3, probes: Vec::new(), duplicates: 0 };
+        assert_eq!(empty.loss(), 1.0);
+        assert_eq!(empty.delay_summary(), None);
+    }
+}
@@ -0,0 +1,117 @@
+//! Prometheus exporter for the crate's metrics.
+//!
+//! Installs a Prometheus recorder as the global `metrics` recorder and serves
+//! the text exposition format on `GET /metrics` from a background thread, so
+//! the synchronizer's offset, delay, jitter and reachability can be scraped
+//! without pulling in an async HTTP stack.
+
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::thread;
+
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+
+use crate::diag;
+use crate::http;
+use crate::protocol::NtpError;
+
+/// The Prometheus text exposition format.
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// A running exporter. The listener lives as long as the process.
+#[derive(Debug)]
+pub struct PrometheusExporter {
+    local_addr: SocketAddr,
+    handle: PrometheusHandle,
+}
+
+impl PrometheusExporter {
+    /// Address the HTTP endpoint is bound to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Render the current metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        self.handle.render()
+    }
+}
+
+/// Install the global recorder and serve metrics on `addr`.
+///
+/// Fails if another `metrics` recorder has already been installed.
+///
+/// Example
+/// ```rust,no_run
+/// # use simple_ntp::prometheus;
+/// # use simple_ntp::synchronizer::SntpSynchronizer;
+///
+/// fn main() {
+///     let exporter = prometheus::serve("0.0.0.0:9123").unwrap();
+///     let sync = SntpSynchronizer::builder().server("ntp.aliyun.com").start().unwrap();
+///     println!("metrics on http://{}/metrics", exporter.local_addr());
+/// #   drop(sync);
+/// }
+/// ```
+pub fn serve(addr: impl ToSocketAddrs) -> Result<PrometheusExporter, NtpError> {
+    let listener = TcpListener::bind(addr).map_err(|err| {
+        NtpError::ServiceUnavailable(err.to_string())
+    })?;
+    let local_addr = listener.local_addr().map_err(|err| {
+        NtpError::UnexpectedErr(err.to_string())
+    })?;
+    let handle = PrometheusBuilder::new().install_recorder().map_err(|err| {
+        NtpError::UnexpectedErr(err.to_string())
+    })?;
+    diag::describe();
+
+    let scrape_handle = handle.clone();
+    thread::Builder::new()
+        .name("sntp-prometheus".to_string())
+        .spawn(move || {
+            for stream in listener.incoming().flatten() {
+                if let Err(err) = respond(stream, &scrape_handle) {
+                    debug!("prometheus scrape failed: {}", err);
+                }
+            }
+        })
+        .map_err(|err| {
+            NtpError::UnexpectedErr(err.to_string())
+        })?;
+
+    Ok(PrometheusExporter { local_addr, handle })
+}
+
+fn respond(stream: TcpStream, handle: &PrometheusHandle) -> std::io::Result<()> {
+    http::respond(stream, |method, path| match (method, path) {
+        ("GET", "/metrics") => {
+            handle.run_upkeep();
+            ("200 OK", CONTENT_TYPE, handle.render())
+        }
+        _ => ("404 Not Found", CONTENT_TYPE, String::new()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prometheus::*;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn test_scrape() {
+        let exporter = serve("127.0.0.1:0").unwrap();
+        diag::count(diag::QUERIES, "test.invalid");
+
+        let mut stream = TcpStream::connect(exporter.local_addr()).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("sntp_queries_total{server=\"test.invalid\"} 1"));
+
+        let mut stream = TcpStream::connect(exporter.local_addr()).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 404"));
+    }
+}
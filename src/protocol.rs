@@ -0,0 +1,982 @@
+//! The NTP wire format of RFC 5905: packet headers, extension fields, and
+//! the timestamp and short formats. Nothing here does I/O, so it builds
+//! without the `client` and `server` features, e.g. for embedded targets.
+
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Debug)]
+pub enum NtpError {
+    ServiceUnavailable(String),
+    BadNtpServerAddr(String),
+    UnexpectedErr(String),
+    TruncatedNtpMessage,
+    UntrustedMessage,
+    BadConfig(String),
+    /// The server name did not resolve in time, see
+    /// [`SntpClientBuilder::resolve_timeout`](crate::client::SntpClientBuilder::resolve_timeout).
+    ResolveTimeout(String),
+    /// The server answered with a Kiss-o'-Death and this code, e.g. `RATE` to
+    /// poll less often, or `DENY` and `RSTR` to stop.
+    KissOfDeath(String),
+}
+
+#[cfg_attr(not(feature = "client"), allow(dead_code))]
+pub(crate) const NTP_VERSION_3: u8 = 3;
+#[cfg_attr(not(feature = "client"), allow(dead_code))]
+pub(crate) const NTP_VERSION_4: u8 = 4;
+
+#[cfg_attr(not(feature = "client"), allow(dead_code))]
+pub(crate) const NTP_MODE_CLIENT: u8 = 3;
+pub(crate) const NTP_MODE_SERVER: u8 = 4;
+
+/// Seconds from 1900-01-01 (NTP era 0) to 1970-01-01.
+pub(crate) const NTP_UNIX_EPOCH_DELTA: u64 = 2208988800;
+
+pub(crate) const SECONDS_PER_DAY: u64 = 86400;
+
+/// Frequency tolerance of RFC 5905, 15 PPM: the dispersion added per second
+/// since the last clock update.
+pub const PHI: f64 = 15e-6;
+
+/// Convert time.Duration (since unix epoch) to ntp timestamp format
+///
+/// Breaking change: up to 0.1.1 the seconds were left since 1970 and the
+/// fraction was 4 units per nano second, so the result was neither a wire
+/// timestamp nor the inverse of [`ntp_timestamp_to_duration`]. It is both now.
+pub fn duration_to_ntp_timestamp(d: &Duration) -> u64 {
+    let seconds = d.as_secs() + NTP_UNIX_EPOCH_DELTA;
+    let fraction = ((d.subsec_nanos() as u64) << 32) / 1_000_000_000;
+
+    seconds << 32 | fraction
+}
+
+/// Convert ntp timestamp to time.Duration (since unix epoch). Seconds before
+/// 1970 are taken as the next era, which starts in 2036; zero, a timestamp
+/// that was never set, stays zero.
+pub fn ntp_timestamp_to_duration(t: u64) -> Duration {
+    if t == 0 {
+        return Duration::ZERO;
+    }
+    let seconds = ntp_seconds_to_unix((t >> 32) as u32);
+    let nanos = ((t & u32::MAX as u64) * 1_000_000_000) >> 32;
+
+    Duration::new(seconds, nanos as u32)
+}
+
+/// Seconds since 1900 to seconds since the unix epoch, with the era pivot of
+/// [`ntp_timestamp_to_duration`].
+pub(crate) fn ntp_seconds_to_unix(seconds: u32) -> u64 {
+    match (seconds as u64).checked_sub(NTP_UNIX_EPOCH_DELTA) {
+        Some(seconds) => seconds,
+        None => seconds as u64 + (1 << 32) - NTP_UNIX_EPOCH_DELTA,
+    }
+}
+
+/// An NTP short format value: 16.16 fixed point seconds, as root delay and
+/// root dispersion are on the wire. Displays as seconds.
+///
+/// Example
+/// ```rust
+/// # use std::time::Duration;
+/// # use simple_ntp::protocol::ShortFormat;
+///
+/// fn main() {
+///     let half = ShortFormat::from(Duration::from_millis(500));
+///     assert_eq!(half, ShortFormat(0x8000));
+///     assert_eq!(half.to_duration(), Duration::from_millis(500));
+///     assert_eq!(half.to_string(), "0.500000s");
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ShortFormat(pub u32);
+
+impl ShortFormat {
+    /// Just under 65536 seconds.
+    pub const MAX: ShortFormat = ShortFormat(u32::MAX);
+
+    /// Rounded down to a 65536th of a second, saturating at [`ShortFormat::MAX`].
+    pub fn from_duration(d: Duration) -> Self {
+        ShortFormat((d.as_nanos() * 65536 / 1_000_000_000).min(u32::MAX as u128) as u32)
+    }
+
+    /// Rounded up to a nano second, so converting back gives the same value.
+    pub fn to_duration(self) -> Duration {
+        Duration::from_nanos((self.0 as u64 * 1_000_000_000).div_ceil(65536))
+    }
+
+    pub fn as_secs_f64(self) -> f64 {
+        self.0 as f64 / 65536.0
+    }
+
+    pub fn saturating_add(self, other: ShortFormat) -> Self {
+        ShortFormat(self.0.saturating_add(other.0))
+    }
+}
+
+impl From<Duration> for ShortFormat {
+    fn from(d: Duration) -> Self {
+        ShortFormat::from_duration(d)
+    }
+}
+
+impl From<ShortFormat> for Duration {
+    fn from(value: ShortFormat) -> Self {
+        value.to_duration()
+    }
+}
+
+impl fmt::Display for ShortFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.6}s", self.as_secs_f64())
+    }
+}
+
+/// The poll field: the interval between polls as a signed power of two
+/// seconds. Displays as the exponent with the interval, e.g. `6 (64s)`.
+///
+/// Example
+/// ```rust
+/// # use std::time::Duration;
+/// # use simple_ntp::protocol::PollInterval;
+///
+/// fn main() {
+///     let poll = PollInterval::from(Duration::from_secs(60));
+///     assert_eq!(poll.exponent(), 6);
+///     assert_eq!(poll.to_duration(), Duration::from_secs(64));
+///     assert_eq!(PollInterval::from_exponent(1).clamped(), PollInterval::MIN);
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PollInterval(i8);
+
+impl PollInterval {
+    /// 16 seconds, RFC 5905's MINPOLL.
+    pub const MIN: PollInterval = PollInterval(4);
+    /// 36.4 hours, RFC 5905's MAXPOLL.
+    pub const MAX: PollInterval = PollInterval(17);
+
+    pub const fn from_exponent(exponent: i8) -> Self {
+        PollInterval(exponent)
+    }
+
+    /// log2 seconds.
+    pub const fn exponent(self) -> i8 {
+        self.0
+    }
+
+    /// The nearest power of two seconds.
+    pub fn from_duration(d: Duration) -> Self {
+        Self::from_log2(d.as_secs_f64().log2().round())
+    }
+
+    /// The shortest poll interval not shorter than `d`, e.g. to tell clients
+    /// how long to back off.
+    pub fn at_least(d: Duration) -> Self {
+        Self::from_log2(d.as_secs_f64().log2().ceil())
+    }
+
+    fn from_log2(log2: f64) -> Self {
+        // Zero durations are -inf, clamped like any other out of range value.
+        PollInterval(log2.clamp(i8::MIN as f64, i8::MAX as f64) as i8)
+    }
+
+    /// Saturating at [`Duration::MAX`].
+    pub fn to_duration(self) -> Duration {
+        Duration::try_from_secs_f64(2f64.powi(self.0 as i32)).unwrap_or(Duration::MAX)
+    }
+
+    /// Within [`PollInterval::MIN`] and [`PollInterval::MAX`].
+    pub fn clamped(self) -> Self {
+        self.clamp(Self::MIN, Self::MAX)
+    }
+
+    /// Twice as long, up to `max`.
+    pub fn longer(self, max: PollInterval) -> Self {
+        PollInterval(self.0.saturating_add(1)).min(max)
+    }
+
+    /// Half as long, down to `min`.
+    pub fn shorter(self, min: PollInterval) -> Self {
+        PollInterval(self.0.saturating_sub(1)).max(min)
+    }
+}
+
+impl From<Duration> for PollInterval {
+    fn from(d: Duration) -> Self {
+        PollInterval::from_duration(d)
+    }
+}
+
+impl From<PollInterval> for Duration {
+    fn from(poll: PollInterval) -> Self {
+        poll.to_duration()
+    }
+}
+
+/// The field as on the wire.
+impl From<u8> for PollInterval {
+    fn from(poll: u8) -> Self {
+        PollInterval(poll as i8)
+    }
+}
+
+impl From<PollInterval> for u8 {
+    fn from(poll: PollInterval) -> Self {
+        poll.0 as u8
+    }
+}
+
+impl fmt::Display for PollInterval {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({}s)", self.0, 2f64.powi(self.0 as i32))
+    }
+}
+
+/// Reference ID as ntpq prints it: the ASCII code for stratum 0 and 1, an IPv4 address otherwise.
+pub(crate) fn refid(stratum: u8, reference_id: u32) -> String {
+    let bytes = reference_id.to_be_bytes();
+    if stratum <= 1 {
+        bytes.iter()
+            .take_while(|b| **b != 0)
+            .map(|b| if b.is_ascii_graphic() { *b as char } else { '?' })
+            .collect()
+    } else {
+        format!("{}.{}.{}.{}", bytes[0], bytes[1], bytes[2], bytes[3])
+    }
+}
+
+pub(crate) fn stratum_name(stratum: u8) -> &'static str {
+    match stratum {
+        0 => "unspecified",
+        1 => "primary",
+        2..=15 => "secondary",
+        16 => "unsynchronized",
+        _ => "reserved",
+    }
+}
+
+/// Caps on what a client or [`NtpPacket::parse_limited`] accepts, bounding
+/// memory and parsing work on hostile input.
+///
+/// Example
+/// ```rust
+/// # use simple_ntp::protocol::{Limits, NtpPacket, ParseError};
+///
+/// fn main() {
+///     let limits = Limits { max_packet: 512, ..Limits::default() };
+///     assert!(matches!(NtpPacket::parse_limited(&[0u8; 1024], &limits), Err(ParseError::TooLong(1024))));
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Bytes in a packet, header included.
+    pub max_packet: usize,
+    /// Extension fields in a packet.
+    pub max_extensions: usize,
+    /// Bytes in one extension field, its 4 byte header included.
+    pub max_field_length: usize,
+}
+
+impl Default for Limits {
+    /// Room for NTS with a full set of cookies.
+    fn default() -> Self {
+        Limits {
+            max_packet: 2048,
+            max_extensions: 16,
+            max_field_length: 1024,
+        }
+    }
+}
+
+/// Why [`NtpMsg::parse`] rejected a packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    /// Shorter than the 48 byte header, with the length received.
+    Truncated(usize),
+    /// This many bytes after the header. [`NtpMsg::parse`] reads the header only, use
+    /// [`NtpPacket::parse`] for extension fields and MACs.
+    TrailingData(usize),
+    /// Version number outside 1 to 4.
+    BadVersion(u8),
+    /// Mode 0, which is reserved.
+    BadMode(u8),
+    /// A malformed extension field or MAC at this offset.
+    BadExtension(usize),
+    /// Longer than [`Limits::max_packet`], with the length received; a client
+    /// receives at most one byte more than the limit.
+    TooLong(usize),
+    /// More extension fields than [`Limits::max_extensions`], with the limit.
+    TooManyExtensions(usize),
+    /// An extension field at this offset longer than [`Limits::max_field_length`].
+    FieldTooLong(usize),
+}
+
+impl From<ParseError> for NtpError {
+    fn from(err: ParseError) -> Self {
+        match err {
+            ParseError::Truncated(_) => NtpError::TruncatedNtpMessage,
+            err => NtpError::UnexpectedErr(format!("{:?}", err)),
+        }
+    }
+}
+
+/// An NTP packet header, RFC 5905 section 7.3. Timestamps are 32.32 and
+/// delay and dispersion 16.16 fixed point seconds, as on the wire.
+///
+/// Example
+/// ```rust
+/// # use simple_ntp::protocol::NtpMsg;
+///
+/// fn main() {
+///     let mut packet = [0u8; 48];
+///     packet[0] = 0x24; // version 4, server mode
+///     packet[1] = 2;
+///     let msg = NtpMsg::parse(&packet).unwrap();
+///     assert_eq!((msg.version_number, msg.mode, msg.stratum), (4, 4, 2));
+///     assert_eq!(msg.marshal(), packet);
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NtpMsg {
+    /// 2 bits, 3 means unsynchronized.
+    pub leap_indicator: u8,
+    /// 3 bits.
+    pub version_number: u8,
+    /// 3 bits, 3 for client and 4 for server.
+    pub mode: u8,
+    pub stratum: u8,
+    /// log2 seconds.
+    pub poll: u8,
+    /// log2 seconds, signed.
+    pub precision: u8,
+    pub root_delay: u32,
+    pub root_dispersion: u32,
+    pub reference_identifier: u32,
+    pub reference_timestamp: u64,
+    pub originate_timestamp: u64,
+    pub receiver_timestamp: u64,
+    pub transmit_timestamp: u64,
+}
+
+impl NtpMsg {
+    pub(crate) fn new() -> Self {
+        NtpMsg {
+            leap_indicator: 0,
+            version_number: 0,
+            mode: 0,
+            stratum: 0,
+            poll: 0,
+            precision: 0,
+            root_delay: 0,
+            root_dispersion: 0,
+            reference_identifier: 0,
+            reference_timestamp: 0,
+            originate_timestamp: 0,
+            receiver_timestamp: 0,
+            transmit_timestamp: 0,
+        }
+    }
+
+    #[cfg_attr(not(feature = "client"), allow(dead_code))]
+    pub(crate) fn new_for_client(version: u8, transmit_timestamp: u64) -> Self {
+        NtpMsg {
+            leap_indicator: 0,
+            version_number: version,
+            mode: NTP_MODE_CLIENT,
+            stratum: 0,
+            poll: 0,
+            precision: 0,
+            root_delay: 0,
+            root_dispersion: 0,
+            reference_identifier: 0,
+            reference_timestamp: 0,
+            originate_timestamp: 0,
+            receiver_timestamp: 0,
+            transmit_timestamp,
+        }
+    }
+
+    /// Parse a packet strictly: exactly one 48 byte header, a known version
+    /// and a defined mode. Never panics, whatever the input.
+    pub fn parse(data: &[u8]) -> Result<NtpMsg, ParseError> {
+        if data.len() < 48 {
+            return Err(ParseError::Truncated(data.len()));
+        }
+        if data.len() > 48 {
+            return Err(ParseError::TrailingData(data.len() - 48));
+        }
+
+        let mut msg = NtpMsg::new();
+        msg.unmarshal(data).map_err(|_| ParseError::Truncated(data.len()))?;
+        if !(1..=4).contains(&msg.version_number) {
+            return Err(ParseError::BadVersion(msg.version_number));
+        }
+        if msg.mode == 0 {
+            return Err(ParseError::BadMode(msg.mode));
+        }
+
+        Ok(msg)
+    }
+
+    /// The 48 byte wire format. Fields wider than their bits are truncated.
+    pub fn marshal(&self) -> Vec<u8> {
+        let mut data = [0u8; 48];
+        self.marshal_into(&mut data);
+
+        data.to_vec()
+    }
+
+    /// Like [`marshal`](Self::marshal), into a caller's buffer instead of a new `Vec`.
+    ///
+    /// Example
+    /// ```rust
+    /// # use simple_ntp::protocol::NtpMsg;
+    ///
+    /// fn main() {
+    ///     let msg = NtpMsg::parse(&[0x23; 48]).unwrap();
+    ///     let mut buf = [0u8; 48];
+    ///     msg.marshal_into(&mut buf);
+    ///     assert_eq!(buf, [0x23; 48]);
+    /// }
+    /// ```
+    pub fn marshal_into(&self, data: &mut [u8; 48]) {
+        data[0] = (self.leap_indicator & 0b11) << 6 | (self.version_number & 0b111) << 3 | (self.mode & 0b111);
+        data[1] = self.stratum;
+        data[2] = self.poll;
+        data[3] = self.precision;
+        data[4..8].copy_from_slice(&self.root_delay.to_be_bytes());
+        data[8..12].copy_from_slice(&self.root_dispersion.to_be_bytes());
+        data[12..16].copy_from_slice(&self.reference_identifier.to_be_bytes());
+        data[16..24].copy_from_slice(&self.reference_timestamp.to_be_bytes());
+        data[24..32].copy_from_slice(&self.originate_timestamp.to_be_bytes());
+        data[32..40].copy_from_slice(&self.receiver_timestamp.to_be_bytes());
+        data[40..48].copy_from_slice(&self.transmit_timestamp.to_be_bytes());
+    }
+
+    pub(crate) fn unmarshal(&mut self, data: &[u8]) -> Result<(), NtpError> {
+        let data: &[u8; 48] = data.try_into().map_err(|_| NtpError::TruncatedNtpMessage)?;
+        let u32_at = |i: usize| u32::from_be_bytes([data[i], data[i + 1], data[i + 2], data[i + 3]]);
+        let u64_at = |i: usize| (u32_at(i) as u64) << 32 | u32_at(i + 4) as u64;
+
+        self.leap_indicator = data[0] >> 6;
+        self.version_number = (data[0] >> 3) & 0b111;
+        self.mode = data[0] & 0b111;
+        self.stratum = data[1];
+        self.poll = data[2];
+        self.precision = data[3];
+        self.root_delay = u32_at(4);
+        self.root_dispersion = u32_at(8);
+        self.reference_identifier = u32_at(12);
+        self.reference_timestamp = u64_at(16);
+        self.originate_timestamp = u64_at(24);
+        self.receiver_timestamp = u64_at(32);
+        self.transmit_timestamp = u64_at(40);
+
+        Ok(())
+    }
+}
+
+impl NtpMsg {
+    /// Field names and decoded values, in wire order.
+    fn fields(&self) -> [(&'static str, String); 13] {
+        let leap = match self.leap_indicator {
+            0 => "no warning",
+            1 => "61 second minute",
+            2 => "59 second minute",
+            _ => "unsynchronized",
+        };
+        let mode = match self.mode {
+            1 => "symmetric active",
+            2 => "symmetric passive",
+            3 => "client",
+            4 => "server",
+            5 => "broadcast",
+            6 => "control",
+            7 => "private",
+            _ => "reserved",
+        };
+        // A stratum 0 server reply carries a kiss code, not a reference.
+        let stratum = if self.stratum == 0 && self.mode == NTP_MODE_SERVER { "kiss-o'-death" } else { stratum_name(self.stratum) };
+        [
+            ("leap", format!("{} ({})", self.leap_indicator, leap)),
+            ("version", self.version_number.to_string()),
+            ("mode", format!("{} ({})", self.mode, mode)),
+            ("stratum", format!("{} ({})", self.stratum, stratum)),
+            ("poll", PollInterval::from(self.poll).to_string()),
+            ("precision", format!("{} ({:.9}s)", self.precision as i8, 2f64.powi(self.precision as i8 as i32))),
+            ("root delay", ShortFormat(self.root_delay).to_string()),
+            ("root dispersion", ShortFormat(self.root_dispersion).to_string()),
+            ("refid", refid(self.stratum, self.reference_identifier)),
+            ("reference", utc(self.reference_timestamp)),
+            ("originate", utc(self.originate_timestamp)),
+            ("receive", utc(self.receiver_timestamp)),
+            ("transmit", utc(self.transmit_timestamp)),
+        ]
+    }
+}
+
+impl fmt::Display for NtpMsg {
+    /// The decoded fields on one line, or one per line with `{:#}`;
+    /// timestamps as UTC datetimes, `-` when zero.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (name, value)) in self.fields().iter().enumerate() {
+            if f.alternate() {
+                writeln!(f, "{:<16}{}", name, value)?;
+            } else {
+                write!(f, "{}{} {}", if i == 0 { "" } else { ", " }, name, value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Render a packet as an annotated hexdump, one field per line: offset, bytes,
+/// field name and decoded value, for traces and bug reports. Takes any input:
+/// fields cut short are marked truncated, and what follows the header is
+/// broken into extension fields and a MAC if it parses as such, or dumped
+/// as is.
+///
+/// Example
+/// ```rust
+/// # use simple_ntp::protocol::hexdump;
+///
+/// fn main() {
+///     let mut packet = [0u8; 48];
+///     packet[0] = 0x24;
+///     packet[1] = 2;
+///     print!("{}", hexdump(&packet));
+///     // 0000  24                       leap, vn, mode      0 (no warning), 4, 4 (server)
+///     // 0001  02                       stratum             2 (secondary)
+///     // ...
+/// }
+/// ```
+pub fn hexdump(packet: &[u8]) -> String {
+    let mut header = [0u8; 48];
+    let n = packet.len().min(48);
+    header[..n].copy_from_slice(&packet[..n]);
+    let mut msg = NtpMsg::new();
+    let _ = msg.unmarshal(&header);
+    let [leap, version, mode, fields @ ..] = msg.fields();
+
+    let mut out = String::new();
+    let mut line = |offset: usize, len: usize, name: &str, value: &str| {
+        let Some(bytes) = packet.get(offset..) else { return };
+        let bytes = &bytes[..len.min(bytes.len())];
+        if bytes.is_empty() {
+            return;
+        }
+        let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        let value = if bytes.len() < len { "truncated" } else { value };
+        out += format!("{:04x}  {:<23}  {:<20}{}", offset, hex.join(" "), name, value).trim_end();
+        out.push('\n');
+    };
+
+    line(0, 1, "leap, vn, mode", &format!("{}, {}, {}", leap.1, version.1, mode.1));
+    let sizes = [1, 1, 1, 4, 4, 4, 8, 8, 8, 8];
+    let mut offset = 1;
+    for ((name, value), size) in fields.iter().zip(sizes) {
+        line(offset, size, name, value);
+        offset += size;
+    }
+
+    match NtpPacket::parse(packet) {
+        Ok(parsed) => {
+            for extension in &parsed.extensions {
+                let length = 4 + extension.value.len();
+                line(offset, 4, "extension", &format!("type {:#06x}, length {}", extension.field_type, length));
+                for chunk in (offset + 4..offset + length).step_by(8) {
+                    line(chunk, (offset + length - chunk).min(8), "", "");
+                }
+                offset += length;
+            }
+            if let Some(mac) = &parsed.mac {
+                line(offset, 4, if mac.digest.is_empty() { "crypto-nak" } else { "mac key id" }, &mac.key_id.to_string());
+                for chunk in (offset + 4..packet.len()).step_by(8) {
+                    line(chunk, (packet.len() - chunk).min(8), if chunk == offset + 4 { "digest" } else { "" }, "");
+                }
+            }
+        }
+        Err(_) => {
+            for chunk in (48..packet.len()).step_by(8) {
+                line(chunk, (packet.len() - chunk).min(8), if chunk == 48 { "unparsed" } else { "" }, "");
+            }
+        }
+    }
+    out
+}
+
+/// An NTP timestamp as an RFC 3339 UTC datetime with nanoseconds, `-` for zero.
+fn utc(timestamp: u64) -> String {
+    if timestamp == 0 {
+        return "-".to_string();
+    }
+    rfc3339(ntp_timestamp_to_duration(timestamp))
+}
+
+/// An RFC 7822 extension field.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtensionField {
+    pub field_type: u16,
+    /// The value as on the wire, including padding.
+    pub value: Vec<u8>,
+}
+
+/// A message authentication code. A crypto-NAK has an empty digest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mac {
+    pub key_id: u32,
+    /// 16 bytes for MD5, 20 for SHA-1.
+    pub digest: Vec<u8>,
+}
+
+/// An NTP packet: the header, any extension fields and a MAC.
+///
+/// What follows the header is told apart as in RFC 7822: 4, 20 or 24
+/// remaining bytes are a MAC, anything else an extension field, of at least
+/// 16 bytes, or 28 if it is the last item.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NtpPacket {
+    pub header: NtpMsg,
+    pub extensions: Vec<ExtensionField>,
+    pub mac: Option<Mac>,
+}
+
+impl NtpPacket {
+    /// Parse a packet strictly, see [`NtpMsg::parse`] for the header, within
+    /// [`Limits::default`]. Never panics, whatever the input.
+    pub fn parse(data: &[u8]) -> Result<NtpPacket, ParseError> {
+        Self::parse_limited(data, &Limits::default())
+    }
+
+    /// [`parse`](Self::parse) within `limits`, checked before any field is copied.
+    pub fn parse_limited(data: &[u8], limits: &Limits) -> Result<NtpPacket, ParseError> {
+        if data.len() > limits.max_packet {
+            return Err(ParseError::TooLong(data.len()));
+        }
+        let header = NtpMsg::parse(data.get(..48).ok_or(ParseError::Truncated(data.len()))?)?;
+        let mut packet = NtpPacket {
+            header,
+            extensions: Vec::new(),
+            mac: None,
+        };
+
+        let mut at = 48;
+        while at < data.len() {
+            let rest = &data[at..];
+            if let 4 | 20 | 24 = rest.len() {
+                packet.mac = Some(Mac {
+                    key_id: u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]),
+                    digest: rest[4..].to_vec(),
+                });
+                break;
+            }
+            if rest.len() < 16 {
+                return Err(ParseError::BadExtension(at));
+            }
+            let length = u16::from_be_bytes([rest[2], rest[3]]) as usize;
+            let last = length == rest.len();
+            if length < 16 || !length.is_multiple_of(4) || length > rest.len() || (last && length < 28) {
+                return Err(ParseError::BadExtension(at));
+            }
+            if length > limits.max_field_length {
+                return Err(ParseError::FieldTooLong(at));
+            }
+            if packet.extensions.len() == limits.max_extensions {
+                return Err(ParseError::TooManyExtensions(limits.max_extensions));
+            }
+            packet.extensions.push(ExtensionField {
+                field_type: u16::from_be_bytes([rest[0], rest[1]]),
+                value: rest[4..length].to_vec(),
+            });
+            at += length;
+        }
+
+        Ok(packet)
+    }
+
+    /// The wire format. Extension field values are zero padded to a multiple of
+    /// 4 and the minimum length; the digest is written as is.
+    pub fn marshal(&self) -> Vec<u8> {
+        let mut data = self.header.marshal();
+        for (i, field) in self.extensions.iter().enumerate() {
+            let min = if i + 1 == self.extensions.len() && self.mac.is_none() { 28 } else { 16 };
+            let length = (4 + field.value.len()).next_multiple_of(4).max(min);
+            data.extend_from_slice(&field.field_type.to_be_bytes());
+            data.extend_from_slice(&(length.min(u16::MAX as usize) as u16).to_be_bytes());
+            data.extend_from_slice(&field.value);
+            data.resize(data.len() + length - 4 - field.value.len(), 0);
+        }
+        if let Some(mac) = &self.mac {
+            data.extend_from_slice(&mac.key_id.to_be_bytes());
+            data.extend_from_slice(&mac.digest);
+        }
+
+        data
+    }
+}
+
+/// `time` since unix epoch as an RFC 3339 UTC datetime with nanoseconds.
+pub(crate) fn rfc3339(time: Duration) -> String {
+    let secs = time.as_secs();
+    let (year, month, day) = civil_from_days((secs / SECONDS_PER_DAY) as i64);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:09}Z",
+        year,
+        month,
+        day,
+        secs % SECONDS_PER_DAY / 3600,
+        secs % 3600 / 60,
+        secs % 60,
+        time.subsec_nanos()
+    )
+}
+
+/// Convert days since 1970-01-01 into a (year, month, day) civil date.
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+
+    (y, m, d)
+}
+
+/// Convert a civil date into days since 1970-01-01, the inverse of [`civil_from_days`].
+#[cfg_attr(not(feature = "client"), allow(dead_code))]
+pub(crate) fn days_from_civil(y: i64, m: u32, d: u32) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let mp = if m > 2 { m - 3 } else { m + 9 } as i64;
+    let doy = (153 * mp + 2) / 5 + d as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    era * 146097 + doe - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::*;
+
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+    use proptest::sample::Index;
+
+    fn msg() -> impl Strategy<Value = NtpMsg> {
+        (
+            (0..4u8, 1..=4u8, 1..=7u8, any::<u8>(), any::<u8>(), any::<u8>()),
+            any::<[u32; 3]>(),
+            any::<[u64; 4]>(),
+        )
+            .prop_map(|((leap_indicator, version_number, mode, stratum, poll, precision), [delay, dispersion, id], t)| NtpMsg {
+                leap_indicator,
+                version_number,
+                mode,
+                stratum,
+                poll,
+                precision,
+                root_delay: delay,
+                root_dispersion: dispersion,
+                reference_identifier: id,
+                reference_timestamp: t[0],
+                originate_timestamp: t[1],
+                receiver_timestamp: t[2],
+                transmit_timestamp: t[3],
+            })
+    }
+
+    fn packet() -> impl Strategy<Value = NtpPacket> {
+        (any::<u64>(), vec((any::<u16>(), any::<u8>(), 0..16usize), 0..4), proptest::option::of(any::<u32>())).prop_map(
+            |(transmit, extensions, key_id)| NtpPacket {
+                header: NtpMsg::new_for_client(NTP_VERSION_4, transmit),
+                extensions: extensions
+                    .into_iter()
+                    .map(|(field_type, byte, words)| ExtensionField { field_type, value: vec![byte; 24 + words * 4] })
+                    .collect(),
+                mac: key_id.map(|key_id| Mac { key_id, digest: vec![1; 20] }),
+            },
+        )
+    }
+
+    proptest! {
+        #[test]
+        fn test_parse_round_trip(msg in msg()) {
+            let data = msg.marshal();
+            let mut buf = [0u8; 48];
+            msg.marshal_into(&mut buf);
+            prop_assert_eq!(&data, &buf);
+            prop_assert_eq!(NtpMsg::parse(&data), Ok(msg));
+        }
+
+        // Every length up to 1500, and many bare headers.
+        #[test]
+        fn test_parse_arbitrary_input(data in prop_oneof![vec(any::<u8>(), 48), vec(any::<u8>(), 0..=1500)]) {
+            let len = data.len();
+            match NtpMsg::parse(&data) {
+                Ok(msg) => prop_assert_eq!(msg.marshal(), data),
+                Err(ParseError::Truncated(n)) => prop_assert!(n < 48 && n == len),
+                Err(ParseError::TrailingData(n)) => prop_assert_eq!(n + 48, len),
+                Err(ParseError::BadVersion(version)) => prop_assert!(version == 0 || version > 4),
+                Err(ParseError::BadMode(mode)) => prop_assert_eq!(mode, 0),
+                Err(ParseError::BadExtension(_) | ParseError::TooLong(_) | ParseError::TooManyExtensions(_) | ParseError::FieldTooLong(_)) => {
+                    unreachable!()
+                }
+            }
+        }
+
+        #[test]
+        fn test_packet_arbitrary_input(mut tail in vec(any::<u8>(), 0..=1452), length in proptest::option::of(any::<Index>())) {
+            // Plausible field lengths now and then, to get past the first check.
+            if let Some(length) = length.filter(|_| tail.len() >= 4) {
+                let length = length.index(tail.len() + 1) & !3;
+                tail[2..4].copy_from_slice(&(length as u16).to_be_bytes());
+            }
+            let mut data = NtpMsg::new_for_client(NTP_VERSION_4, 1).marshal();
+            data.extend_from_slice(&tail);
+            if let Ok(packet) = NtpPacket::parse(&data) {
+                prop_assert_eq!(packet.marshal(), data);
+            }
+        }
+
+        #[test]
+        fn test_packet_round_trip(packet in packet()) {
+            prop_assert_eq!(NtpPacket::parse(&packet.marshal()), Ok(packet));
+        }
+    }
+
+    #[test]
+    fn test_packet() {
+        let mut data = NtpMsg::new_for_client(NTP_VERSION_4, 1).marshal();
+        data.extend_from_slice(&[0x01, 0x04, 0x00, 0x10]);
+        data.extend_from_slice(&[7; 12]);
+        data.extend_from_slice(&[0, 0, 0, 5]);
+        data.extend_from_slice(&[9; 16]);
+        let packet = NtpPacket::parse(&data).unwrap();
+        assert_eq!(packet.extensions, vec![ExtensionField { field_type: 0x0104, value: vec![7; 12] }]);
+        assert_eq!(packet.mac, Some(Mac { key_id: 5, digest: vec![9; 16] }));
+        assert_eq!(packet.marshal(), data);
+
+        // A 16 byte field cannot be the last item, and lengths must add up.
+        assert_eq!(NtpPacket::parse(&data[..64]), Err(ParseError::BadExtension(48)));
+        data[51] = 0x40;
+        assert_eq!(NtpPacket::parse(&data), Err(ParseError::BadExtension(48)));
+        assert_eq!(NtpPacket::parse(&data[..47]), Err(ParseError::Truncated(47)));
+        assert_eq!(NtpPacket::parse(&data[..52]).unwrap().mac.unwrap().digest, Vec::<u8>::new());
+    }
+
+    #[test]
+    fn test_ntp_timestamp_round_trip() {
+        let d = Duration::new(1704067200, 123_456_789);
+        let t = duration_to_ntp_timestamp(&d);
+        assert_eq!(t >> 32, 1704067200 + 2208988800);
+
+        let back = ntp_timestamp_to_duration(t);
+        assert_eq!(back.as_secs(), d.as_secs());
+        assert!(back.subsec_nanos().abs_diff(d.subsec_nanos()) <= 1);
+
+        // Past 2036 the seconds wrap into the next era.
+        let d = Duration::new(2_200_000_000, 0);
+        assert_eq!(ntp_timestamp_to_duration(duration_to_ntp_timestamp(&d)), d);
+        assert_eq!(ntp_timestamp_to_duration(0), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_display() {
+        let mut msg = NtpMsg::new_for_client(NTP_VERSION_4, duration_to_ntp_timestamp(&Duration::new(1714564800, 500_000_000)));
+        msg.mode = NTP_MODE_SERVER;
+        msg.stratum = 1;
+        msg.reference_identifier = u32::from_be_bytes(*b"GPS\0");
+        msg.poll = 6;
+        msg.precision = -20i8 as u8;
+        msg.root_dispersion = 0x8000;
+        let line = msg.to_string();
+        assert!(line.starts_with("leap 0 (no warning), version 4, mode 4 (server), stratum 1 (primary), poll 6 (64s)"), "{}", line);
+        assert!(line.contains("root dispersion 0.500000s, refid GPS, reference -,"), "{}", line);
+        assert!(line.ends_with("transmit 2024-05-01T12:00:00.500000000Z"), "{}", line);
+        assert_eq!(format!("{:#}", msg).lines().nth(3), Some("stratum         1 (primary)"));
+
+        msg.stratum = 0;
+        msg.reference_identifier = u32::from_be_bytes(*b"RATE");
+        assert!(msg.to_string().contains("stratum 0 (kiss-o'-death)"));
+        assert!(msg.to_string().contains("refid RATE"));
+    }
+
+    #[test]
+    fn test_short_format() {
+        assert_eq!(ShortFormat::from(Duration::from_millis(250)), ShortFormat(0x4000));
+        assert_eq!(ShortFormat(0x1_8000).to_duration(), Duration::from_millis(1500));
+        assert_eq!(ShortFormat::from(Duration::from_secs(70_000)), ShortFormat::MAX);
+        for bits in [1, 3, 0xffff, 0x1234_5678, u32::MAX] {
+            assert_eq!(ShortFormat::from(ShortFormat(bits).to_duration()), ShortFormat(bits));
+        }
+        assert_eq!(ShortFormat::MAX.saturating_add(ShortFormat(1)), ShortFormat::MAX);
+        assert_eq!(ShortFormat(0x8000).to_string(), "0.500000s");
+    }
+
+    #[test]
+    fn test_poll_interval() {
+        assert_eq!(PollInterval::from(Duration::from_secs(64)).exponent(), 6);
+        assert_eq!(PollInterval::from(Duration::from_millis(250)).exponent(), -2);
+        assert_eq!(PollInterval::at_least(Duration::from_secs(65)).exponent(), 7);
+        assert_eq!(PollInterval::from(Duration::ZERO), PollInterval::from_exponent(i8::MIN));
+        assert_eq!(PollInterval::from_exponent(i8::MAX).to_duration(), Duration::MAX);
+        assert_eq!(PollInterval::from_exponent(-1).to_duration(), Duration::from_millis(500));
+        assert_eq!(PollInterval::from(0xfa_u8).exponent(), -6);
+        assert_eq!(u8::from(PollInterval::from_exponent(-6)), 0xfa);
+        assert_eq!(PollInterval::from_exponent(30).clamped(), PollInterval::MAX);
+        assert_eq!(PollInterval::MAX.longer(PollInterval::MAX), PollInterval::MAX);
+        assert_eq!(PollInterval::from_exponent(6).shorter(PollInterval::MIN).exponent(), 5);
+        assert_eq!(PollInterval::from_exponent(6).to_string(), "6 (64s)");
+    }
+
+    #[test]
+    fn test_hexdump() {
+        let mut packet = [0u8; 48];
+        packet[..4].copy_from_slice(&[0x24, 2, 6, 0xec]);
+        packet[4..8].copy_from_slice(&0x8000u32.to_be_bytes());
+        let lines: Vec<String> = hexdump(&packet).lines().map(String::from).collect();
+        assert_eq!(lines.len(), 11);
+        assert_eq!(lines[0], format!("0000  {:<23}  {:<20}0 (no warning), 4, 4 (server)", "24", "leap, vn, mode"));
+        assert_eq!(lines[4], format!("0004  {:<23}  {:<20}0.500000s", "00 00 80 00", "root delay"));
+        assert_eq!(lines[10], format!("0028  {:<23}  {:<20}-", "00 00 00 00 00 00 00 00", "transmit"));
+
+        let lines: Vec<String> = hexdump(&packet[..10]).lines().map(String::from).collect();
+        assert_eq!(lines.len(), 6);
+        assert!(lines[5].starts_with("0008  00 00 ") && lines[5].ends_with("truncated"));
+
+        let mut packet = packet.to_vec();
+        packet.extend_from_slice(&[0x01, 0x04, 0x00, 0x1c]);
+        packet.extend_from_slice(&[0xaa; 24]);
+        let lines: Vec<String> = hexdump(&packet).lines().map(String::from).collect();
+        assert_eq!(lines[11], format!("0030  {:<23}  {:<20}type 0x0104, length 28", "01 04 00 1c", "extension"));
+        assert_eq!(lines.len(), 15);
+
+        packet.extend_from_slice(&[0, 0, 0, 7]);
+        assert!(hexdump(&packet).ends_with(&format!("004c  {:<23}  {:<20}7\n", "00 00 00 07", "crypto-nak")));
+        packet.push(1);
+        assert!(hexdump(&packet).contains("unparsed"));
+    }
+
+    #[test]
+    fn test_rfc3339() {
+        assert_eq!(rfc3339(Duration::ZERO), "1970-01-01T00:00:00.000000000Z");
+        assert_eq!(rfc3339(Duration::new(1709210096, 123_456_789)), "2024-02-29T12:34:56.123456789Z");
+    }
+
+    #[test]
+    fn test_civil_from_days() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(19723), (2024, 1, 1));
+        assert_eq!(civil_from_days(19782), (2024, 2, 29));
+        for days in [-1, 0, 59, 19723, 19782, 100_000] {
+            let (y, m, d) = civil_from_days(days);
+            assert_eq!(days_from_civil(y, m, d), days);
+        }
+    }
+}
@@ -0,0 +1,537 @@
+//! Passive IEEE 1588 (PTPv2) monitor, to cross-check NTP against a LAN grandmaster.
+//!
+//! A [`PtpMonitor`] listens for the multicast Announce, Sync and Follow_Up
+//! messages of a grandmaster and turns each Sync into an offset of the local
+//! clock, in the same sense as [`NtpResult::offset_nanos`]. It never sends
+//! Delay_Req, so the one-way path delay is not compensated for: on a switched
+//! LAN that is microseconds, far below what matters when comparing with NTP.
+//!
+//! PTP keeps TAI. The UTC offset comes from the grandmaster's Announce, and no
+//! offset is reported before one was seen.
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::client::{sys_time, NtpResult};
+use crate::protocol::NtpError;
+use crate::timesource;
+
+/// PTP primary multicast group, for all domains but the peer delay messages.
+const PTP_PRIMARY_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 1, 129);
+
+const MSG_SYNC: u8 = 0x0;
+const MSG_FOLLOW_UP: u8 = 0x8;
+const MSG_ANNOUNCE: u8 = 0xb;
+
+const HEADER_SIZE: usize = 34;
+const SYNC_SIZE: usize = 44;
+const ANNOUNCE_SIZE: usize = 64;
+
+/// flagField[0]: the Sync is followed by a Follow_Up carrying its timestamp.
+const FLAG_TWO_STEP: u8 = 0x02;
+/// flagField[1]: currentUtcOffset in the Announce can be trusted.
+const FLAG_UTC_OFFSET_VALID: u8 = 0x04;
+
+/// How often the listening threads check whether the monitor was stopped.
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// The latest offset derived from a grandmaster's Sync.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PtpSample {
+    /// Clock identity of the grandmaster, from its Announce.
+    pub grandmaster: [u8; 8],
+    /// PTP domain the messages were sent in.
+    pub domain: u8,
+    /// TAI - UTC in seconds as announced, or the current value if not flagged valid.
+    pub utc_offset: i16,
+    /// System clock offset in nano seconds, grandmaster time sub local time.
+    pub offset_nanos: i64,
+    /// Local time the Sync was received, since the Unix epoch.
+    pub received: Duration,
+}
+
+impl PtpSample {
+    /// Grandmaster identity as PTP tools print it, e.g. `001122.fffe.334455`.
+    pub fn grandmaster_id(&self) -> String {
+        let g = self.grandmaster;
+        format!(
+            "{:02x}{:02x}{:02x}.{:02x}{:02x}.{:02x}{:02x}{:02x}",
+            g[0], g[1], g[2], g[3], g[4], g[5], g[6], g[7]
+        )
+    }
+
+    /// Put this sample next to an NTP result. Both should be taken close
+    /// together, the local clock drifts in between.
+    pub fn compare(&self, result: &NtpResult) -> PtpComparison {
+        PtpComparison {
+            ptp_offset_nanos: self.offset_nanos,
+            ntp_offset_nanos: result.offset_nanos,
+            difference_nanos: result.offset_nanos - self.offset_nanos,
+        }
+    }
+}
+
+/// PTP and NTP offsets of the local clock side by side.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PtpComparison {
+    pub ptp_offset_nanos: i64,
+    pub ntp_offset_nanos: i64,
+    /// NTP offset sub PTP offset, how far NTP time is ahead of PTP time.
+    pub difference_nanos: i64,
+}
+
+impl PtpComparison {
+    /// Whether the two sources agree to within `tolerance`.
+    pub fn agrees(&self, tolerance: Duration) -> bool {
+        self.difference_nanos.unsigned_abs() as u128 <= tolerance.as_nanos()
+    }
+}
+
+/// Configure a [`PtpMonitor`].
+pub struct PtpMonitorBuilder {
+    event: String,
+    general: String,
+    domain: u8,
+    multicast: Option<Ipv4Addr>,
+}
+
+impl PtpMonitorBuilder {
+    /// Address to receive Sync messages on, `0.0.0.0:319` by default.
+    pub fn event_addr(mut self, addr: &str) -> Self {
+        self.event = addr.to_string();
+        self
+    }
+
+    /// Address to receive Announce and Follow_Up messages on, `0.0.0.0:320` by default.
+    pub fn general_addr(mut self, addr: &str) -> Self {
+        self.general = addr.to_string();
+        self
+    }
+
+    /// PTP domain to follow, 0 (the default domain) by default.
+    pub fn domain(mut self, domain: u8) -> Self {
+        self.domain = domain;
+        self
+    }
+
+    /// Join the PTP multicast group on the interface with this address, or
+    /// `None` to only receive what arrives anyway (unicast, already joined
+    /// groups). `Some(0.0.0.0)`, the default, lets the kernel pick the interface.
+    pub fn multicast(mut self, interface: Option<Ipv4Addr>) -> Self {
+        self.multicast = interface;
+        self
+    }
+
+    /// Bind both ports and start listening on background threads.
+    pub fn start(self) -> Result<PtpMonitor, NtpError> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let state = Arc::new(Mutex::new(State::new(self.domain)));
+
+        // Bind both before spawning, so a failure leaves no thread behind.
+        let mut sockets = Vec::with_capacity(2);
+        for addr in [&self.event, &self.general] {
+            let socket = UdpSocket::bind(addr).map_err(|err| {
+                NtpError::ServiceUnavailable(format!("bind {}: {}", addr, err))
+            })?;
+            if let Some(interface) = self.multicast {
+                socket.join_multicast_v4(&PTP_PRIMARY_GROUP, &interface).map_err(|err| {
+                    NtpError::ServiceUnavailable(format!("join {} on {}: {}", PTP_PRIMARY_GROUP, interface, err))
+                })?;
+            }
+            socket.set_read_timeout(Some(STOP_POLL_INTERVAL)).map_err(|err| {
+                NtpError::UnexpectedErr(err.to_string())
+            })?;
+            let local_addr = socket.local_addr().map_err(|err| {
+                NtpError::UnexpectedErr(err.to_string())
+            })?;
+            sockets.push((socket, local_addr));
+        }
+        let local_addrs = (sockets[0].1, sockets[1].1);
+
+        let mut monitor = PtpMonitor {
+            local_addrs,
+            state,
+            stop,
+            workers: Vec::with_capacity(2),
+        };
+        for (socket, _) in sockets {
+            let stopped = monitor.stop.clone();
+            let state = monitor.state.clone();
+            let worker = thread::Builder::new()
+                .name("sntp-ptp".to_string())
+                .spawn(move || listen(socket, &state, &stopped))
+                .map_err(|err| {
+                    NtpError::UnexpectedErr(err.to_string())
+                })?;
+            monitor.workers.push(worker);
+        }
+
+        Ok(monitor)
+    }
+}
+
+/// Listens for a PTP grandmaster on background threads until stopped or dropped.
+///
+/// Example
+/// ```rust,no_run
+/// # use std::time::Duration;
+/// # use simple_ntp::ptp::PtpMonitor;
+/// # use simple_ntp::client;
+///
+/// fn main() {
+///     let monitor = PtpMonitor::builder().start().unwrap();
+///     std::thread::sleep(Duration::from_secs(3));
+///     let result = client::query("ntp.aliyun.com").unwrap();
+///     if let Some(sample) = monitor.sample() {
+///         let comparison = sample.compare(&result);
+///         if !comparison.agrees(Duration::from_millis(10)) {
+///             println!("ntp is {}ns off ptp", comparison.difference_nanos);
+///         }
+///     }
+/// }
+/// ```
+#[derive(Debug)]
+pub struct PtpMonitor {
+    local_addrs: (SocketAddr, SocketAddr),
+    state: Arc<Mutex<State>>,
+    stop: Arc<AtomicBool>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl PtpMonitor {
+    pub fn builder() -> PtpMonitorBuilder {
+        PtpMonitorBuilder {
+            event: "0.0.0.0:319".to_string(),
+            general: "0.0.0.0:320".to_string(),
+            domain: 0,
+            multicast: Some(Ipv4Addr::UNSPECIFIED),
+        }
+    }
+
+    /// Addresses the event and general sockets are bound to.
+    pub fn local_addrs(&self) -> (SocketAddr, SocketAddr) {
+        self.local_addrs
+    }
+
+    /// The latest sample, `None` until both an Announce and a Sync were received.
+    pub fn sample(&self) -> Option<PtpSample> {
+        self.state.lock().unwrap().sample
+    }
+
+    /// Offset of the latest sample, see [`PtpSample::offset_nanos`].
+    pub fn offset_nanos(&self) -> Option<i64> {
+        self.sample().map(|sample| sample.offset_nanos)
+    }
+
+    /// Compare the latest sample with an NTP result, `None` without a sample.
+    pub fn compare(&self, result: &NtpResult) -> Option<PtpComparison> {
+        self.sample().map(|sample| sample.compare(result))
+    }
+
+    /// Stop listening and wait for the threads to exit.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Drop for PtpMonitor {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn listen(socket: UdpSocket, state: &Mutex<State>, stop: &AtomicBool) {
+    let mut buf = [0u8; 1500];
+    while !stop.load(Ordering::Relaxed) {
+        let n = match socket.recv(&mut buf) {
+            Ok(n) => n,
+            Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => continue,
+            Err(err) => {
+                debug!("ptp recv failed: {}", err);
+                continue;
+            }
+        };
+        let received = sys_time();
+        state.lock().unwrap().handle(&buf[..n], received);
+    }
+}
+
+/// The grandmaster we follow, from its latest Announce.
+#[derive(Debug, Clone, Copy)]
+struct Announce {
+    source: [u8; 10],
+    grandmaster: [u8; 8],
+    utc_offset: i16,
+}
+
+/// A two-step Sync waiting for its Follow_Up.
+#[derive(Debug, Clone, Copy)]
+struct PendingSync {
+    sequence: u16,
+    correction: i64,
+    received: Duration,
+}
+
+#[derive(Debug)]
+struct State {
+    domain: u8,
+    announce: Option<Announce>,
+    pending: Option<PendingSync>,
+    sample: Option<PtpSample>,
+}
+
+impl State {
+    fn new(domain: u8) -> Self {
+        State {
+            domain,
+            announce: None,
+            pending: None,
+            sample: None,
+        }
+    }
+
+    fn handle(&mut self, data: &[u8], received: Duration) {
+        let Some(header) = Header::parse(data) else {
+            return;
+        };
+        if header.domain != self.domain {
+            return;
+        }
+
+        match header.message_type {
+            MSG_ANNOUNCE if data.len() >= ANNOUNCE_SIZE => {
+                let utc_offset = if header.flags[1] & FLAG_UTC_OFFSET_VALID != 0 {
+                    i16::from_be_bytes([data[44], data[45]])
+                } else {
+                    // The grandmaster does not vouch for its own, use the known leap seconds.
+                    timesource::tai_offset(received).as_secs() as i16
+                };
+                let grandmaster = data[53..61].try_into().unwrap();
+                if self.announce.is_some_and(|announce| announce.grandmaster != grandmaster) {
+                    debug!("ptp grandmaster changed");
+                    self.pending = None;
+                }
+                self.announce = Some(Announce {
+                    source: header.source,
+                    grandmaster,
+                    utc_offset,
+                });
+            }
+            MSG_SYNC if data.len() >= SYNC_SIZE => {
+                if !self.is_master(&header) {
+                    return;
+                }
+                if header.flags[0] & FLAG_TWO_STEP != 0 {
+                    self.pending = Some(PendingSync {
+                        sequence: header.sequence,
+                        correction: header.correction,
+                        received,
+                    });
+                } else {
+                    self.pending = None;
+                    self.update(timestamp(&data[34..44]), header.correction.into(), received);
+                }
+            }
+            MSG_FOLLOW_UP if data.len() >= SYNC_SIZE => {
+                if !self.is_master(&header) {
+                    return;
+                }
+                match self.pending.take() {
+                    Some(sync) if sync.sequence == header.sequence => {
+                        let correction = i128::from(sync.correction) + i128::from(header.correction);
+                        self.update(timestamp(&data[34..44]), correction, sync.received);
+                    }
+                    pending => self.pending = pending,
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn is_master(&self, header: &Header) -> bool {
+        self.announce.is_some_and(|announce| announce.source == header.source)
+    }
+
+    /// `origin` is the grandmaster's TAI when the Sync left, `correction` in 2^-16 ns.
+    /// A sample whose offset does not fit an `i64` is dropped.
+    fn update(&mut self, origin: Duration, correction: i128, received: Duration) {
+        let Some(announce) = self.announce else {
+            return;
+        };
+        let tai_nanos = origin.as_nanos() as i128 + (correction >> 16);
+        let utc_nanos = tai_nanos - announce.utc_offset as i128 * 1_000_000_000;
+        let Ok(offset_nanos) = i64::try_from(utc_nanos - received.as_nanos() as i128) else {
+            return;
+        };
+        self.sample = Some(PtpSample {
+            grandmaster: announce.grandmaster,
+            domain: self.domain,
+            utc_offset: announce.utc_offset,
+            offset_nanos,
+            received,
+        });
+    }
+}
+
+/// The fields of the common PTPv2 message header we use.
+struct Header {
+    message_type: u8,
+    domain: u8,
+    flags: [u8; 2],
+    correction: i64,
+    source: [u8; 10],
+    sequence: u16,
+}
+
+impl Header {
+    fn parse(data: &[u8]) -> Option<Header> {
+        if data.len() < HEADER_SIZE || data[1] & 0x0f != 2 {
+            return None;
+        }
+        let length = u16::from_be_bytes([data[2], data[3]]) as usize;
+        if length > data.len() {
+            return None;
+        }
+        Some(Header {
+            message_type: data[0] & 0x0f,
+            domain: data[4],
+            flags: [data[6], data[7]],
+            correction: i64::from_be_bytes(data[8..16].try_into().unwrap()),
+            source: data[20..30].try_into().unwrap(),
+            sequence: u16::from_be_bytes([data[30], data[31]]),
+        })
+    }
+}
+
+/// 48 bit seconds and 32 bit nanoseconds.
+fn timestamp(data: &[u8]) -> Duration {
+    let mut seconds = [0u8; 8];
+    seconds[2..].copy_from_slice(&data[..6]);
+    let nanos = u32::from_be_bytes(data[6..10].try_into().unwrap());
+    Duration::new(u64::from_be_bytes(seconds), nanos.min(999_999_999))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ptp::*;
+    use crate::testing::answer;
+
+    const SOURCE: [u8; 10] = [0x00, 0x11, 0x22, 0xff, 0xfe, 0x33, 0x44, 0x55, 0x00, 0x01];
+
+    fn message(message_type: u8, size: usize, flags: [u8; 2], correction: i64, sequence: u16) -> Vec<u8> {
+        let mut data = vec![0u8; size];
+        data[0] = message_type;
+        data[1] = 2;
+        data[2..4].copy_from_slice(&(size as u16).to_be_bytes());
+        data[6..8].copy_from_slice(&flags);
+        data[8..16].copy_from_slice(&correction.to_be_bytes());
+        data[20..30].copy_from_slice(&SOURCE);
+        data[30..32].copy_from_slice(&sequence.to_be_bytes());
+        data
+    }
+
+    fn announce(utc_offset: i16) -> Vec<u8> {
+        let mut data = message(MSG_ANNOUNCE, ANNOUNCE_SIZE, [0, FLAG_UTC_OFFSET_VALID], 0, 1);
+        data[44..46].copy_from_slice(&utc_offset.to_be_bytes());
+        data[53..61].copy_from_slice(&SOURCE[..8]);
+        data
+    }
+
+    fn with_timestamp(mut data: Vec<u8>, t: Duration) -> Vec<u8> {
+        data[34..40].copy_from_slice(&t.as_secs().to_be_bytes()[2..]);
+        data[40..44].copy_from_slice(&t.subsec_nanos().to_be_bytes());
+        data
+    }
+
+    #[test]
+    fn test_state() {
+        let received = Duration::new(1_700_000_000, 0);
+        let tai = received + Duration::from_secs(37) + Duration::from_micros(1500);
+        let mut state = State::new(0);
+
+        // Nothing before the Announce tells the UTC offset.
+        state.handle(&with_timestamp(message(MSG_SYNC, SYNC_SIZE, [0, 0], 0, 7), tai), received);
+        assert!(state.sample.is_none());
+
+        state.handle(&announce(37), received);
+        state.handle(&with_timestamp(message(MSG_SYNC, SYNC_SIZE, [0, 0], 500 << 16, 8), tai), received);
+        let sample = state.sample.unwrap();
+        assert_eq!(sample.offset_nanos, 1_500_500);
+        assert_eq!(sample.grandmaster_id(), "001122.fffe.334455");
+
+        // Two-step: the Follow_Up carries the origin, corrections add up.
+        let sync = message(MSG_SYNC, SYNC_SIZE, [FLAG_TWO_STEP, 0], 100 << 16, 9);
+        state.handle(&sync, received + Duration::from_secs(1));
+        let stray = with_timestamp(message(MSG_FOLLOW_UP, SYNC_SIZE, [0, 0], 0, 10), tai);
+        state.handle(&stray, received + Duration::from_secs(1));
+        assert_eq!(state.sample.unwrap().received, received);
+        let follow_up = with_timestamp(message(MSG_FOLLOW_UP, SYNC_SIZE, [0, 0], 200 << 16, 9), tai);
+        state.handle(&follow_up, received + Duration::from_secs(1));
+        assert_eq!(state.sample.unwrap().offset_nanos, 1_500_300 - 1_000_000_000);
+
+        // Other domains and PTPv1 are ignored.
+        let mut other = with_timestamp(message(MSG_SYNC, SYNC_SIZE, [0, 0], 0, 11), tai);
+        other[4] = 1;
+        state.handle(&other, received);
+        other[4] = 0;
+        other[1] = 1;
+        state.handle(&other, received);
+        assert_eq!(state.sample.unwrap().offset_nanos, 1_500_300 - 1_000_000_000);
+
+        // Offsets beyond an i64 of nanoseconds are dropped instead of wrapping.
+        let far = Duration::new((1 << 48) - 1, 999_999_999);
+        state.handle(&with_timestamp(message(MSG_SYNC, SYNC_SIZE, [0, 0], i64::MAX, 12), far), received);
+        let sync = message(MSG_SYNC, SYNC_SIZE, [FLAG_TWO_STEP, 0], i64::MAX, 13);
+        state.handle(&sync, received);
+        let follow_up = with_timestamp(message(MSG_FOLLOW_UP, SYNC_SIZE, [0, 0], i64::MAX, 13), tai);
+        state.handle(&follow_up, received);
+        assert_eq!(state.sample.unwrap().offset_nanos, 1_500_000 + (1 << 48) - 1);
+    }
+
+    #[test]
+    fn test_monitor() {
+        let monitor = PtpMonitor::builder()
+            .event_addr("127.0.0.1:0")
+            .general_addr("127.0.0.1:0")
+            .multicast(None)
+            .start()
+            .unwrap();
+        let (event_addr, general_addr) = monitor.local_addrs();
+        assert!(monitor.sample().is_none());
+
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.send_to(&announce(37), general_addr).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let tai = sys_time() + Duration::from_secs(37) + Duration::from_millis(250);
+        sender.send_to(&with_timestamp(message(MSG_SYNC, SYNC_SIZE, [0, 0], 0, 2), tai), event_addr).unwrap();
+
+        let mut sample = None;
+        for _ in 0..50 {
+            sample = monitor.sample();
+            if sample.is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        let sample = sample.expect("no ptp sample");
+        assert!((sample.offset_nanos - 250_000_000).abs() < 100_000_000);
+
+        let result = answer(sample.offset_nanos + 2_000_000, 0);
+        let comparison = monitor.compare(&result).unwrap();
+        assert_eq!(comparison.difference_nanos, 2_000_000);
+        assert!(comparison.agrees(Duration::from_millis(5)));
+        assert!(!comparison.agrees(Duration::from_millis(1)));
+        monitor.stop();
+    }
+}
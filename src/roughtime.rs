@@ -0,0 +1,471 @@
+//! Roughtime client, a signed and bounded timestamp as a sanity anchor for NTP.
+//!
+//! Implements Google's original Roughtime protocol: the request carries a random
+//! nonce, the server answers with its time (midpoint) and uncertainty (radius),
+//! signed by a delegated key certified by the server's long-term public key. A
+//! valid response proves the time came from the key holder after the nonce was
+//! made up, something plain NTP cannot give without NTS.
+//!
+//! Only Google's framing is spoken. The IETF drafts (`draft-ietf-ntp-roughtime`)
+//! wrap messages in a `ROUGHTIM` packet, negotiate a version and sign with other
+//! contexts; servers that only speak them, as the Cloudflare ecosystem is moving
+//! to, are not supported.
+//!
+//! [`Roughtime::cross_check`] tells whether an NTP offset is consistent with it.
+
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+use ed25519_dalek::{Signature, VerifyingKey};
+use sha2::{Digest, Sha512};
+
+use crate::client::{sys_time, NtpResult};
+use crate::protocol::NtpError;
+
+const ROUGHTIME_DEFAULT_PORT: u16 = 2002;
+
+/// Requests are padded to this size so a response is never larger than its request.
+const REQUEST_SIZE: usize = 1024;
+const MAX_RESPONSE_SIZE: usize = 4096;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+const TAG_SIG: u32 = u32::from_le_bytes(*b"SIG\0");
+const TAG_NONC: u32 = u32::from_le_bytes(*b"NONC");
+const TAG_PAD: u32 = u32::from_le_bytes(*b"PAD\xff");
+const TAG_PATH: u32 = u32::from_le_bytes(*b"PATH");
+const TAG_SREP: u32 = u32::from_le_bytes(*b"SREP");
+const TAG_CERT: u32 = u32::from_le_bytes(*b"CERT");
+const TAG_INDX: u32 = u32::from_le_bytes(*b"INDX");
+const TAG_RADI: u32 = u32::from_le_bytes(*b"RADI");
+const TAG_MIDP: u32 = u32::from_le_bytes(*b"MIDP");
+const TAG_ROOT: u32 = u32::from_le_bytes(*b"ROOT");
+const TAG_DELE: u32 = u32::from_le_bytes(*b"DELE");
+const TAG_PUBK: u32 = u32::from_le_bytes(*b"PUBK");
+const TAG_MINT: u32 = u32::from_le_bytes(*b"MINT");
+const TAG_MAXT: u32 = u32::from_le_bytes(*b"MAXT");
+
+/// Prefixes of the signed data, so one signature cannot stand in for the other.
+const DELEGATION_CONTEXT: &[u8] = b"RoughTime v1 delegation signature--\0";
+const RESPONSE_CONTEXT: &[u8] = b"RoughTime v1 response signature\0";
+
+/// Query a Roughtime server with the default client.
+///
+/// Example
+/// ```rust,no_run
+/// # use simple_ntp::roughtime;
+/// # use simple_ntp::client;
+///
+/// fn main() {
+///     let public_key = roughtime::parse_public_key("<base64 key published by the operator>").unwrap();
+///     let roughtime = roughtime::query("roughtime.example.com:2002", &public_key).unwrap();
+///     let result = client::query("ntp.aliyun.com").unwrap();
+///     roughtime.cross_check(&result).expect("ntp disagrees with roughtime");
+/// }
+/// ```
+pub fn query(server: &str, public_key: &[u8; 32]) -> Result<Roughtime, NtpError> {
+    RoughtimeClient::default().query(server, public_key)
+}
+
+/// Decode a server's long-term public key, as base64 (how operators publish
+/// them) or hex.
+pub fn parse_public_key(s: &str) -> Result<[u8; 32], NtpError> {
+    let s = s.trim();
+    let bytes = if s.len() == 64 && s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        (0..64).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    } else {
+        decode_base64(s).ok_or_else(|| NtpError::BadConfig(format!("bad public key `{}`", s)))?
+    };
+
+    bytes.try_into().map_err(|bytes: Vec<u8>| {
+        NtpError::BadConfig(format!("public key is {} bytes, expected 32", bytes.len()))
+    })
+}
+
+/// A verified Roughtime response.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Roughtime {
+    /// Address the request was sent to.
+    pub addr: SocketAddr,
+    /// Server time, since the unix epoch.
+    pub midpoint: Duration,
+    /// The true time is within `midpoint` ± `radius`, according to the server.
+    pub radius: Duration,
+    /// Local time the request was sent.
+    pub sent: Duration,
+    /// Local time the response was received.
+    pub received: Duration,
+}
+
+impl Roughtime {
+    /// Bounds of the local clock offset in nano seconds, remote sub local like
+    /// [`NtpResult::offset_nanos`]. The midpoint was taken somewhere between
+    /// sending and receiving, so the round trip widens the radius.
+    pub fn offset_bounds_nanos(&self) -> (i64, i64) {
+        let nanos = |d: Duration| d.as_nanos() as i64;
+        (
+            nanos(self.midpoint) - nanos(self.radius) - nanos(self.received),
+            nanos(self.midpoint) + nanos(self.radius) - nanos(self.sent),
+        )
+    }
+
+    /// Whether `result` is consistent with this response: the NTP offset, give
+    /// or take half its round trip, overlaps [`Roughtime::offset_bounds_nanos`].
+    /// Both should be taken close together, the local clock drifts in between.
+    pub fn cross_check(&self, result: &NtpResult) -> Result<(), NtpError> {
+        let (low, high) = self.offset_bounds_nanos();
+        let slack = result.delay_nanos.max(0) / 2;
+        if result.offset_nanos + slack < low || result.offset_nanos - slack > high {
+            warn!(
+                "ntp offset {}ns from {} is outside [{}ns, {}ns] of roughtime server {}",
+                result.offset_nanos, result.addr, low, high, self.addr
+            );
+            return Err(NtpError::UntrustedMessage);
+        }
+        Ok(())
+    }
+}
+
+/// Roughtime client with its own timeout.
+#[derive(Debug, Clone)]
+pub struct RoughtimeClient {
+    timeout: Duration,
+}
+
+impl Default for RoughtimeClient {
+    fn default() -> Self {
+        RoughtimeClient { timeout: DEFAULT_TIMEOUT }
+    }
+}
+
+/// Builder for [`RoughtimeClient`].
+#[derive(Debug, Clone, Default)]
+pub struct RoughtimeClientBuilder {
+    client: RoughtimeClient,
+}
+
+impl RoughtimeClientBuilder {
+    /// Read and write timeout of each exchange, 5 seconds by default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.client.timeout = timeout;
+        self
+    }
+
+    pub fn build(self) -> RoughtimeClient {
+        self.client
+    }
+}
+
+impl RoughtimeClient {
+    pub fn builder() -> RoughtimeClientBuilder {
+        RoughtimeClientBuilder::default()
+    }
+
+    /// See [`query`]. `server` is `host` or `host:port`, the port defaults to 2002.
+    pub fn query(&self, server: &str, public_key: &[u8; 32]) -> Result<Roughtime, NtpError> {
+        let addr = resolve(server)?;
+        let socket = UdpSocket::bind(if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }).map_err(|err| {
+            NtpError::ServiceUnavailable(err.to_string())
+        })?;
+        socket.connect(addr).map_err(|err| {
+            NtpError::UnexpectedErr(err.to_string())
+        })?;
+        socket.set_read_timeout(Some(self.timeout)).map_err(|err| {
+            NtpError::UnexpectedErr(err.to_string())
+        })?;
+
+        let nonce = nonce()?;
+        let request = request(&nonce);
+        debug!("sending roughtime request to {} ({})", server, addr);
+        let sent = sys_time();
+        socket.send(&request).map_err(|err| {
+            NtpError::ServiceUnavailable(err.to_string())
+        })?;
+        let mut buf = vec![0; MAX_RESPONSE_SIZE];
+        let n = socket.recv(&mut buf).map_err(|err| {
+            warn!("no roughtime response from {}: {}", addr, err);
+            NtpError::ServiceUnavailable(err.to_string())
+        })?;
+        let received = sys_time();
+        debug!("received {} bytes from {}", n, addr);
+
+        let (midpoint, radius) = verify_response(&buf[..n], &nonce, public_key).map_err(|err| {
+            warn!("rejected roughtime response from {}: {:?}", addr, err);
+            err
+        })?;
+
+        Ok(Roughtime { addr, midpoint, radius, sent, received })
+    }
+}
+
+fn resolve(server: &str) -> Result<SocketAddr, NtpError> {
+    let addr = match server.parse::<IpAddr>() {
+        Ok(ip) => SocketAddr::new(ip, ROUGHTIME_DEFAULT_PORT).to_string(),
+        Err(_) if server.contains(':') => server.to_string(),
+        Err(_) => format!("{}:{}", server, ROUGHTIME_DEFAULT_PORT),
+    };
+    addr.to_socket_addrs()
+        .map_err(|err| NtpError::BadNtpServerAddr(err.to_string()))?
+        .next()
+        .ok_or_else(|| NtpError::BadNtpServerAddr(format!("{}: no address", server)))
+}
+
+/// A nonce nobody can predict, from the operating system's CSPRNG, so an old
+/// response cannot be replayed.
+fn nonce() -> Result<[u8; 64], NtpError> {
+    let mut nonce = [0; 64];
+    getrandom::fill(&mut nonce).map_err(|err| {
+        NtpError::UnexpectedErr(format!("no randomness for the nonce: {}", err))
+    })?;
+    Ok(nonce)
+}
+
+fn request(nonce: &[u8; 64]) -> Vec<u8> {
+    // Header: tag count, one offset and two tags.
+    let padding = vec![0; REQUEST_SIZE - 16 - nonce.len()];
+    encode(&[(TAG_NONC, nonce), (TAG_PAD, &padding)])
+}
+
+/// Check the certificate chain and that `nonce` is in the signed Merkle tree,
+/// returning the midpoint and radius.
+fn verify_response(response: &[u8], nonce: &[u8; 64], public_key: &[u8; 32]) -> Result<(Duration, Duration), NtpError> {
+    let message = decode(response)?;
+    let srep = get(&message, TAG_SREP)?;
+    let cert = decode(get(&message, TAG_CERT)?)?;
+    let dele = get(&cert, TAG_DELE)?;
+    let delegation = decode(dele)?;
+
+    if !verify(public_key, &[DELEGATION_CONTEXT, dele].concat(), get_array(&cert, TAG_SIG)?) {
+        return Err(NtpError::UntrustedMessage);
+    }
+    if !verify(get_array(&delegation, TAG_PUBK)?, &[RESPONSE_CONTEXT, srep].concat(), get_array(&message, TAG_SIG)?) {
+        return Err(NtpError::UntrustedMessage);
+    }
+
+    let signed = decode(srep)?;
+    let path = get(&message, TAG_PATH)?;
+    if !path.len().is_multiple_of(64) {
+        return Err(NtpError::TruncatedNtpMessage);
+    }
+    let mut index = u32::from_le_bytes(*get_array(&message, TAG_INDX)?);
+    let mut hash = leaf_hash(nonce);
+    for node in path.chunks_exact(64) {
+        hash = if index & 1 == 0 { node_hash(&hash, node) } else { node_hash(node, &hash) };
+        index >>= 1;
+    }
+    if index != 0 || hash != *get_array::<64>(&signed, TAG_ROOT)? {
+        return Err(NtpError::UntrustedMessage);
+    }
+
+    let midpoint = u64::from_le_bytes(*get_array(&signed, TAG_MIDP)?);
+    let radius = u32::from_le_bytes(*get_array(&signed, TAG_RADI)?);
+    let min = u64::from_le_bytes(*get_array(&delegation, TAG_MINT)?);
+    let max = u64::from_le_bytes(*get_array(&delegation, TAG_MAXT)?);
+    if midpoint < min || midpoint > max {
+        return Err(NtpError::UntrustedMessage);
+    }
+
+    Ok((Duration::from_micros(midpoint), Duration::from_micros(radius as u64)))
+}
+
+/// Whether `signature` is `public_key`'s Ed25519 signature of `message`, in
+/// the strict form that rejects weak keys and malleable signatures.
+fn verify(public_key: &[u8; 32], message: &[u8], signature: &[u8; 64]) -> bool {
+    VerifyingKey::from_bytes(public_key)
+        .and_then(|key| key.verify_strict(message, &Signature::from_bytes(signature)))
+        .is_ok()
+}
+
+fn leaf_hash(leaf: &[u8]) -> [u8; 64] {
+    Sha512::new().chain_update([0]).chain_update(leaf).finalize().into()
+}
+
+fn node_hash(left: &[u8], right: &[u8]) -> [u8; 64] {
+    Sha512::new().chain_update([1]).chain_update(left).chain_update(right).finalize().into()
+}
+
+/// A message: the number of tags, the offsets of all values but the first, the
+/// tags in ascending order, then the values. Everything is little endian and
+/// 4 byte aligned.
+fn encode(values: &[(u32, &[u8])]) -> Vec<u8> {
+    let mut values = values.to_vec();
+    values.sort_by_key(|(tag, _)| *tag);
+
+    let mut out = (values.len() as u32).to_le_bytes().to_vec();
+    let mut offset = 0;
+    for (_, value) in &values[..values.len().saturating_sub(1)] {
+        offset += value.len() as u32;
+        out.extend(offset.to_le_bytes());
+    }
+    for (tag, _) in &values {
+        out.extend(tag.to_le_bytes());
+    }
+    for (_, value) in &values {
+        out.extend(*value);
+    }
+    out
+}
+
+fn decode(message: &[u8]) -> Result<Vec<(u32, &[u8])>, NtpError> {
+    let word = |i: usize| -> Result<u32, NtpError> {
+        message.get(i * 4..i * 4 + 4)
+            .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+            .ok_or(NtpError::TruncatedNtpMessage)
+    };
+    if !message.len().is_multiple_of(4) {
+        return Err(NtpError::TruncatedNtpMessage);
+    }
+    let count = word(0)? as usize;
+    if count == 0 {
+        return Ok(Vec::new());
+    }
+    let header = 4 * (2 * count);
+    let values = message.get(header..).ok_or(NtpError::TruncatedNtpMessage)?;
+
+    let mut tags = Vec::with_capacity(count);
+    let mut start = 0;
+    for i in 0..count {
+        let end = if i + 1 == count { values.len() } else { word(1 + i)? as usize };
+        let tag = word(count + i)?;
+        if end < start || end > values.len() || !end.is_multiple_of(4) || tags.last().is_some_and(|(last, _)| *last >= tag) {
+            return Err(NtpError::TruncatedNtpMessage);
+        }
+        tags.push((tag, &values[start..end]));
+        start = end;
+    }
+    Ok(tags)
+}
+
+fn get<'a>(message: &[(u32, &'a [u8])], tag: u32) -> Result<&'a [u8], NtpError> {
+    message.iter()
+        .find(|(t, _)| *t == tag)
+        .map(|(_, value)| *value)
+        .ok_or(NtpError::TruncatedNtpMessage)
+}
+
+fn get_array<'a, const N: usize>(message: &[(u32, &'a [u8])], tag: u32) -> Result<&'a [u8; N], NtpError> {
+    get(message, tag)?.try_into().map_err(|_| NtpError::TruncatedNtpMessage)
+}
+
+fn decode_base64(s: &str) -> Option<Vec<u8>> {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = Vec::new();
+    let (mut bits, mut n) = (0u32, 0);
+    for b in s.trim_end_matches('=').bytes() {
+        bits = bits << 6 | ALPHABET.iter().position(|c| *c == b)? as u32;
+        n += 6;
+        if n >= 8 {
+            n -= 8;
+            out.push((bits >> n) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::roughtime::*;
+    use crate::testing::answer;
+    use ed25519_dalek::{Signer, SigningKey};
+    use std::thread;
+
+    const ROOT_SECRET: [u8; 32] = [1; 32];
+    const DELEGATED_SECRET: [u8; 32] = [2; 32];
+
+    /// The public key of `secret` and its signature of `message`.
+    fn sign(secret: &[u8; 32], message: &[u8]) -> ([u8; 32], [u8; 64]) {
+        let key = SigningKey::from_bytes(secret);
+        (key.verifying_key().to_bytes(), key.sign(message).to_bytes())
+    }
+
+    /// A response for the nonces `leaves`, answering the one at `index`.
+    fn response(leaves: &[[u8; 64]], index: usize, midpoint: u64) -> Vec<u8> {
+        let (delegated_key, _) = sign(&DELEGATED_SECRET, b"");
+        let dele = encode(&[
+            (TAG_PUBK, &delegated_key),
+            (TAG_MINT, &0u64.to_le_bytes()),
+            (TAG_MAXT, &u64::MAX.to_le_bytes()),
+        ]);
+        let (_, dele_sig) = sign(&ROOT_SECRET, &[DELEGATION_CONTEXT, &dele].concat());
+        let cert = encode(&[(TAG_SIG, &dele_sig), (TAG_DELE, &dele)]);
+
+        assert!(matches!(leaves.len(), 1 | 2), "a tree of one or two leaves");
+        let hashes: Vec<_> = leaves.iter().map(|leaf| leaf_hash(leaf)).collect();
+        let (root, path) = match hashes.as_slice() {
+            [left, right] => (node_hash(left, right), hashes[1 - index].to_vec()),
+            _ => (hashes[0], Vec::new()),
+        };
+        let srep = encode(&[
+            (TAG_RADI, &1_000_000u32.to_le_bytes()),
+            (TAG_MIDP, &midpoint.to_le_bytes()),
+            (TAG_ROOT, &root),
+        ]);
+        let (_, srep_sig) = sign(&DELEGATED_SECRET, &[RESPONSE_CONTEXT, &srep].concat());
+
+        encode(&[
+            (TAG_SIG, &srep_sig),
+            (TAG_PATH, &path),
+            (TAG_SREP, &srep),
+            (TAG_CERT, &cert),
+            (TAG_INDX, &(index as u32).to_le_bytes()),
+        ])
+    }
+
+    #[test]
+    fn test_verify_response() {
+        let (public_key, _) = sign(&ROOT_SECRET, b"");
+        let (nonce, other) = ([3; 64], [4; 64]);
+        let midpoint = Duration::from_secs(1_700_000_000);
+        let expected = (midpoint, Duration::from_secs(1));
+
+        let single = response(&[nonce], 0, midpoint.as_micros() as u64);
+        assert_eq!(verify_response(&single, &nonce, &public_key).unwrap(), expected);
+        let right = response(&[other, nonce], 1, midpoint.as_micros() as u64);
+        assert_eq!(verify_response(&right, &nonce, &public_key).unwrap(), expected);
+        let left = response(&[nonce, other], 0, midpoint.as_micros() as u64);
+        assert_eq!(verify_response(&left, &nonce, &public_key).unwrap(), expected);
+
+        assert!(matches!(verify_response(&single, &other, &public_key), Err(NtpError::UntrustedMessage)));
+        assert!(matches!(verify_response(&single, &nonce, &[9; 32]), Err(NtpError::UntrustedMessage)));
+        let mut tampered = single.clone();
+        let len = tampered.len();
+        tampered[len - 100] ^= 1;
+        assert!(verify_response(&tampered, &nonce, &public_key).is_err());
+        assert!(matches!(verify_response(&single[..40], &nonce, &public_key), Err(NtpError::TruncatedNtpMessage)));
+        assert_ne!(crate::roughtime::nonce().unwrap(), crate::roughtime::nonce().unwrap());
+    }
+
+    #[test]
+    fn test_query() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; REQUEST_SIZE];
+            let (n, peer) = socket.recv_from(&mut buf).unwrap();
+            assert_eq!(n, REQUEST_SIZE);
+            let request = decode(&buf).unwrap();
+            let nonce: [u8; 64] = *get_array(&request, TAG_NONC).unwrap();
+            socket.send_to(&response(&[nonce], 0, sys_time().as_micros() as u64), peer).unwrap();
+        });
+
+        let (public_key, _) = sign(&ROOT_SECRET, b"");
+        let roughtime = query(&addr.to_string(), &public_key).unwrap();
+        assert_eq!(roughtime.addr, addr);
+        let (low, high) = roughtime.offset_bounds_nanos();
+        assert!(low < 0 && high > 0, "{:?}", (low, high));
+
+        let mut result = NtpResult { addr, ..answer(5_000_000, 1_000_000) };
+        assert!(roughtime.cross_check(&result).is_ok());
+        result.offset_nanos = 5_000_000_000;
+        assert!(matches!(roughtime.cross_check(&result), Err(NtpError::UntrustedMessage)));
+    }
+
+    #[test]
+    fn test_parse_public_key() {
+        let key = parse_public_key("AQIDBAUGBwgJCgsMDQ4PEBESExQVFhcYGRobHB0eHyA=").unwrap();
+        assert_eq!(key, core::array::from_fn(|i| i as u8 + 1));
+        assert_eq!(parse_public_key(&"01".repeat(32)).unwrap(), [1; 32]);
+        assert!(parse_public_key("AQID").is_err());
+        assert!(parse_public_key("not base64!").is_err());
+    }
+}
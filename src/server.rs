@@ -0,0 +1,811 @@
+//! SNTP server.
+//!
+//! An [`NtpServer`] answers mode 3 (client) requests with mode 4 (server)
+//! replies, either from the local clock or relaying the time of upstream
+//! servers tracked by a [`SntpSynchronizer`]. The local clock can be any
+//! [`TimeSource`]. Clients can be filtered by an [`Acl`] and throttled by a [`RateLimit`].
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use md5::{Digest, Md5};
+
+use crate::client::shift;
+use crate::diag;
+use crate::protocol::{
+    duration_to_ntp_timestamp, NtpError, NtpMsg, PollInterval, ShortFormat, NTP_MODE_CLIENT, NTP_MODE_SERVER, NTP_VERSION_3,
+    NTP_VERSION_4, PHI,
+};
+use crate::synchronizer::SntpSynchronizer;
+use crate::timesource::{self, TimeSource};
+
+/// Leap indicator: clock unsynchronized.
+const LEAP_ALARM: u8 = 3;
+
+/// Stratum sent while unsynchronized.
+const STRATUM_UNSYNC: u8 = 16;
+
+/// log2 seconds, about one microsecond.
+const PRECISION: i8 = -20;
+
+/// How often a spawned server checks whether it was stopped.
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Upper bound on clients tracked by the rate limiter. Once reached, new
+/// clients are limited until a sweep drops the idle ones.
+const MAX_RATE_LIMIT_CLIENTS: usize = 100_000;
+
+/// An IPv4 or IPv6 network, `192.0.2.0/24` or a single address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    /// Create a network from an address and a prefix length.
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Self, NtpError> {
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix > max {
+            return Err(NtpError::BadNtpServerAddr(format!("invalid prefix length /{}", prefix)));
+        }
+
+        Ok(IpNet { addr, prefix })
+    }
+
+    /// Prefix length in bits.
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    /// Whether `ip` is inside this network.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = NtpError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || NtpError::BadNtpServerAddr(format!("invalid network {}", s));
+        match s.split_once('/') {
+            Some((addr, prefix)) => {
+                let addr = addr.parse().map_err(|_| invalid())?;
+                let prefix = prefix.parse().map_err(|_| invalid())?;
+                IpNet::new(addr, prefix)
+            }
+            None => {
+                let addr: IpAddr = s.parse().map_err(|_| invalid())?;
+                IpNet::new(addr, if addr.is_ipv4() { 32 } else { 128 })
+            }
+        }
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// What to do with requests from a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "config", derive(serde::Deserialize), serde(rename_all = "lowercase"))]
+pub enum Access {
+    /// Answer requests.
+    Allow,
+    /// Silently drop requests.
+    Deny,
+}
+
+/// Access control list, the most specific matching network wins.
+#[derive(Debug, Clone)]
+pub struct Acl {
+    default: Access,
+    rules: Vec<(IpNet, Access)>,
+}
+
+impl Acl {
+    /// An empty list applying `default` to every client.
+    pub fn new(default: Access) -> Self {
+        Acl {
+            default,
+            rules: Vec::new(),
+        }
+    }
+
+    /// Apply `access` to clients in `net`.
+    pub fn rule(mut self, net: IpNet, access: Access) -> Self {
+        self.rules.push((net, access));
+        self
+    }
+
+    /// Access for `ip`.
+    pub fn check(&self, ip: IpAddr) -> Access {
+        self.rules.iter()
+            .filter(|(net, _)| net.contains(ip))
+            .max_by_key(|(net, _)| net.prefix)
+            .map(|(_, access)| *access)
+            .unwrap_or(self.default)
+    }
+}
+
+impl Default for Acl {
+    fn default() -> Self {
+        Acl::new(Access::Allow)
+    }
+}
+
+/// Per-client rate limiting, like ntpd's `discard minimum` with `restrict ... limited`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Minimum interval between two requests of one client address.
+    pub min_interval: Duration,
+    /// Answer limited requests with a RATE Kiss-o'-Death instead of dropping them.
+    pub kod: bool,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        RateLimit {
+            min_interval: Duration::from_secs(2),
+            kod: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RateLimiter {
+    limit: RateLimit,
+    clients: HashMap<IpAddr, Instant>,
+    /// When idle clients were last dropped, at most once per `min_interval`.
+    swept: Option<Instant>,
+}
+
+impl RateLimiter {
+    fn new(limit: RateLimit) -> Self {
+        RateLimiter {
+            limit,
+            clients: HashMap::new(),
+            swept: None,
+        }
+    }
+
+    /// Record a request from `ip`, returning whether it is within the limit.
+    fn check(&mut self, ip: IpAddr, now: Instant) -> bool {
+        let min_interval = self.limit.min_interval;
+        if let Some(last) = self.clients.get_mut(&ip) {
+            let allowed = now.duration_since(*last) >= min_interval;
+            *last = now;
+            return allowed;
+        }
+
+        if self.clients.len() >= MAX_RATE_LIMIT_CLIENTS {
+            if self.swept.is_some_and(|swept| now.duration_since(swept) < min_interval) {
+                return false;
+            }
+            self.clients.retain(|_, last| now.duration_since(*last) < min_interval);
+            self.swept = Some(now);
+            if self.clients.len() >= MAX_RATE_LIMIT_CLIENTS {
+                return false;
+            }
+        }
+        self.clients.insert(ip, now);
+
+        true
+    }
+}
+
+/// Client filtering, replaceable while the server runs.
+#[derive(Debug)]
+struct Policy {
+    acl: Acl,
+    limiter: Option<RateLimiter>,
+}
+
+impl Policy {
+    /// Swap in a new ACL and rate limit, keeping the rate limiter's client history.
+    fn reload(&mut self, acl: Acl, rate_limit: Option<RateLimit>) {
+        self.acl = acl;
+        self.limiter = match (self.limiter.take(), rate_limit) {
+            (Some(mut limiter), Some(limit)) => {
+                limiter.limit = limit;
+                Some(limiter)
+            }
+            (None, Some(limit)) => Some(RateLimiter::new(limit)),
+            (_, None) => None,
+        };
+    }
+}
+
+/// Where the served time comes from.
+enum Reference {
+    /// The local system clock, at a fixed stratum and distance to its source.
+    Local { stratum: u8, root_delay: ShortFormat, root_dispersion: ShortFormat },
+    /// The local clock corrected by the offset to upstream servers.
+    Upstream(SntpSynchronizer),
+}
+
+/// Stratum, reference and timing data copied into every reply.
+struct SystemState {
+    leap: u8,
+    stratum: u8,
+    reference_id: u32,
+    reference_time: Duration,
+    root_delay: ShortFormat,
+    root_dispersion: ShortFormat,
+    offset_nanos: i64,
+}
+
+/// Configure an [`NtpServer`].
+pub struct ServerBuilder {
+    bind: String,
+    reference: Reference,
+    root_delay: ShortFormat,
+    root_dispersion: ShortFormat,
+    acl: Acl,
+    rate_limit: Option<RateLimit>,
+    time: Arc<dyn TimeSource>,
+    #[cfg(all(feature = "fwmark", target_os = "linux"))]
+    mark: Option<u32>,
+}
+
+impl ServerBuilder {
+    /// Address to listen on, `0.0.0.0:123` by default.
+    pub fn bind(mut self, addr: &str) -> Self {
+        self.bind = addr.to_string();
+        self
+    }
+
+    /// Serve the local clock at `stratum`. This is the default, at stratum 10.
+    pub fn local_clock(mut self, stratum: u8) -> Self {
+        self.reference = Reference::Local {
+            stratum,
+            root_delay: ShortFormat(0),
+            root_dispersion: ShortFormat(0),
+        };
+        self
+    }
+
+    /// Root delay served with the local clock, e.g. the path delay to a
+    /// reference clock it is disciplined by, zero by default.
+    pub fn local_root_delay(mut self, root_delay: Duration) -> Self {
+        self.root_delay = ShortFormat::from(root_delay);
+        self
+    }
+
+    /// Root dispersion served with the local clock, the error it is known to
+    /// be within, zero by default.
+    pub fn local_root_dispersion(mut self, root_dispersion: Duration) -> Self {
+        self.root_dispersion = ShortFormat::from(root_dispersion);
+        self
+    }
+
+    /// Relay the time of the servers polled by `sync`, one stratum below the selected one.
+    pub fn upstream(mut self, sync: SntpSynchronizer) -> Self {
+        self.reference = Reference::Upstream(sync);
+        self
+    }
+
+    /// Read the local clock from `time` instead of the system clock, e.g. a
+    /// [`PhcClock`](crate::timesource::PhcClock) or, in tests, a
+    /// [`MockClock`](crate::timesource::MockClock). With [`upstream`](ServerBuilder::upstream),
+    /// give the synchronizer the same source.
+    pub fn time_source(mut self, time: Arc<dyn TimeSource>) -> Self {
+        self.time = time;
+        self
+    }
+
+    /// Filter clients, everyone is allowed by default.
+    pub fn acl(mut self, acl: Acl) -> Self {
+        self.acl = acl;
+        self
+    }
+
+    /// Throttle clients, unlimited by default.
+    pub fn rate_limit(mut self, limit: RateLimit) -> Self {
+        self.rate_limit = Some(limit);
+        self
+    }
+
+    /// Tag the replies with the firewall mark `mark` (`SO_MARK`), for policy
+    /// routing and nftables rules. Needs `CAP_NET_ADMIN`.
+    #[cfg(all(feature = "fwmark", target_os = "linux"))]
+    pub fn mark(mut self, mark: u32) -> Self {
+        self.mark = Some(mark);
+        self
+    }
+
+    /// Bind the socket.
+    pub fn build(self) -> Result<NtpServer, NtpError> {
+        let socket = UdpSocket::bind(&self.bind).map_err(|err| {
+            NtpError::ServiceUnavailable(err.to_string())
+        })?;
+        #[cfg(all(feature = "fwmark", target_os = "linux"))]
+        if let Some(mark) = self.mark {
+            crate::sockopt::set_mark(&socket, mark).map_err(NtpError::ServiceUnavailable)?;
+        }
+
+        let mut policy = Policy {
+            acl: Acl::default(),
+            limiter: None,
+        };
+        policy.reload(self.acl, self.rate_limit);
+
+        let mut reference = self.reference;
+        if let Reference::Local { root_delay, root_dispersion, .. } = &mut reference {
+            *root_delay = self.root_delay;
+            *root_dispersion = self.root_dispersion;
+        }
+        Ok(NtpServer {
+            socket,
+            reference,
+            policy: Arc::new(Mutex::new(policy)),
+            time: self.time,
+        })
+    }
+}
+
+/// An SNTP server.
+///
+/// Example
+/// ```rust,no_run
+/// # use simple_ntp::server::{NtpServer, RateLimit};
+/// # use simple_ntp::synchronizer::SntpSynchronizer;
+///
+/// fn main() {
+///     let upstream = SntpSynchronizer::builder().server("ntp.aliyun.com").start().unwrap();
+///     let server = NtpServer::builder()
+///         .bind("0.0.0.0:123")
+///         .upstream(upstream)
+///         .rate_limit(RateLimit::default())
+///         .build()
+///         .unwrap();
+///     server.run().unwrap();
+/// }
+/// ```
+pub struct NtpServer {
+    socket: UdpSocket,
+    reference: Reference,
+    policy: Arc<Mutex<Policy>>,
+    time: Arc<dyn TimeSource>,
+}
+
+impl NtpServer {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder {
+            bind: "0.0.0.0:123".to_string(),
+            reference: Reference::Local {
+                stratum: 10,
+                root_delay: ShortFormat(0),
+                root_dispersion: ShortFormat(0),
+            },
+            root_delay: ShortFormat(0),
+            root_dispersion: ShortFormat(0),
+            acl: Acl::default(),
+            rate_limit: None,
+            time: timesource::system(),
+            #[cfg(all(feature = "fwmark", target_os = "linux"))]
+            mark: None,
+        }
+    }
+
+    /// Address the server is listening on.
+    pub fn local_addr(&self) -> Result<SocketAddr, NtpError> {
+        self.socket.local_addr().map_err(|err| {
+            NtpError::UnexpectedErr(err.to_string())
+        })
+    }
+
+    /// Serve requests on the current thread, forever.
+    pub fn run(mut self) -> Result<(), NtpError> {
+        self.serve(&AtomicBool::new(false))
+    }
+
+    /// Serve requests on a background thread until the returned handle is stopped or dropped.
+    pub fn spawn(mut self) -> Result<ServerHandle, NtpError> {
+        let local_addr = self.local_addr()?;
+        self.socket.set_read_timeout(Some(STOP_POLL_INTERVAL)).map_err(|err| {
+            NtpError::UnexpectedErr(err.to_string())
+        })?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let policy = self.policy.clone();
+        let worker = thread::Builder::new()
+            .name("sntp-server".to_string())
+            .spawn(move || {
+                if let Err(err) = self.serve(&stopped) {
+                    warn!("ntp server stopped: {:?}", err);
+                }
+            })
+            .map_err(|err| {
+                NtpError::UnexpectedErr(err.to_string())
+            })?;
+
+        Ok(ServerHandle {
+            local_addr,
+            policy,
+            stop,
+            worker: Some(worker),
+        })
+    }
+
+    /// The loop reuses one receive and one reply buffer for every request, so a
+    /// request costs no heap allocation (`bench/benches/allocations.rs`, down
+    /// from one per request when replies were marshalled into a `Vec`). Being
+    /// single threaded, it needs no pool to share them.
+    fn serve(&mut self, stop: &AtomicBool) -> Result<(), NtpError> {
+        let mut buf = [0u8; 1024];
+        let mut out = [0u8; 48];
+        while !stop.load(Ordering::Relaxed) {
+            let (n, from) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => continue,
+                Err(err) => {
+                    // e.g. ICMP port unreachable from an earlier reply, not fatal.
+                    debug!("ntp server recv failed: {}", err);
+                    continue;
+                }
+            };
+            let received = self.time.now();
+
+            if let Some(mut reply) = self.handle(&buf[..n], from, received) {
+                reply.transmit_timestamp = duration_to_ntp_timestamp(&self.now(self.time.now()));
+                reply.marshal_into(&mut out);
+                if let Err(err) = self.socket.send_to(&out, from) {
+                    debug!("ntp server send to {} failed: {}", from, err);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Build the reply to a request, `None` to drop it. The transmit timestamp is set by the caller.
+    fn handle(&mut self, data: &[u8], from: SocketAddr, received: Duration) -> Option<NtpMsg> {
+        if data.len() < 48 {
+            return None;
+        }
+        let mut request = NtpMsg::new();
+        request.unmarshal(&data[..48]).ok()?;
+        if request.mode != NTP_MODE_CLIENT || !(NTP_VERSION_3..=NTP_VERSION_4).contains(&request.version_number) {
+            return None;
+        }
+
+        let mut policy = self.policy.lock().unwrap();
+        if policy.acl.check(from.ip()) == Access::Deny {
+            debug!("denied request from {}", from);
+            diag::system_count(diag::SERVER_DENIED);
+            return None;
+        }
+        if let Some(limiter) = policy.limiter.as_mut() {
+            if !limiter.check(from.ip(), Instant::now()) {
+                debug!("rate limited request from {}", from);
+                diag::system_count(diag::SERVER_RATE_LIMITED);
+                if !limiter.limit.kod {
+                    return None;
+                }
+                let mut reply = reply_to(&request);
+                reply.leap_indicator = LEAP_ALARM;
+                reply.reference_identifier = u32::from_be_bytes(*b"RATE");
+                reply.receiver_timestamp = duration_to_ntp_timestamp(&received);
+                // As ntpd does, the poll field tells the client how long to back off.
+                reply.poll = PollInterval::from(request.poll).max(PollInterval::at_least(limiter.limit.min_interval)).into();
+                return Some(reply);
+            }
+        }
+        drop(policy);
+
+        let state = self.state(received);
+        let mut reply = reply_to(&request);
+        reply.leap_indicator = state.leap;
+        reply.stratum = state.stratum;
+        reply.reference_identifier = state.reference_id;
+        reply.root_delay = state.root_delay.0;
+        reply.root_dispersion = state.root_dispersion.0;
+        // Never synchronized is zero on the wire, not 1970.
+        reply.reference_timestamp = match state.reference_time {
+            Duration::ZERO => 0,
+            reference_time => duration_to_ntp_timestamp(&reference_time),
+        };
+        reply.receiver_timestamp = duration_to_ntp_timestamp(&shift(received, state.offset_nanos));
+        diag::system_count(diag::SERVER_RESPONSES);
+
+        Some(reply)
+    }
+
+    /// Served time for a local timestamp.
+    fn now(&self, local: Duration) -> Duration {
+        match &self.reference {
+            Reference::Local { .. } => local,
+            Reference::Upstream(sync) => shift(local, sync.offset_nanos().unwrap_or(0)),
+        }
+    }
+
+    fn state(&self, now: Duration) -> SystemState {
+        match &self.reference {
+            Reference::Local { stratum, root_delay, root_dispersion } => SystemState {
+                leap: 0,
+                stratum: *stratum,
+                reference_id: u32::from_be_bytes(*b"LOCL"),
+                reference_time: now,
+                root_delay: *root_delay,
+                root_dispersion: *root_dispersion,
+                offset_nanos: 0,
+            },
+            Reference::Upstream(sync) => match sync.selected() {
+                Some((result, updated)) if result.stratum < STRATUM_UNSYNC - 1 => {
+                    let age = now.saturating_sub(updated).as_secs_f64();
+                    SystemState {
+                        leap: 0,
+                        stratum: result.stratum + 1,
+                        reference_id: refid_of(result.addr.ip()),
+                        reference_time: shift(updated, result.offset_nanos),
+                        root_delay: result.root_delay.saturating_add(Duration::from_nanos(result.delay_nanos.max(0) as u64).into()),
+                        root_dispersion: result.root_dispersion.saturating_add(Duration::from_secs_f64(PHI * age).into()),
+                        offset_nanos: result.offset_nanos,
+                    }
+                }
+                _ => SystemState {
+                    leap: LEAP_ALARM,
+                    stratum: STRATUM_UNSYNC,
+                    reference_id: u32::from_be_bytes(*b"INIT"),
+                    reference_time: Duration::ZERO,
+                    root_delay: ShortFormat(0),
+                    root_dispersion: ShortFormat(0),
+                    offset_nanos: 0,
+                },
+            },
+        }
+    }
+}
+
+/// Handle to a server running on a background thread.
+#[derive(Debug)]
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    policy: Arc<Mutex<Policy>>,
+    stop: Arc<AtomicBool>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl ServerHandle {
+    /// Address the server is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Replace the ACL and rate limit without restarting. Clients keep their rate limit history.
+    pub fn reload(&self, acl: Acl, rate_limit: Option<RateLimit>) {
+        self.policy.lock().unwrap().reload(acl, rate_limit);
+    }
+
+    /// Whether the server thread is still serving; it exits on a socket error.
+    pub fn is_running(&self) -> bool {
+        self.worker.as_ref().is_some_and(|worker| !worker.is_finished())
+    }
+
+    /// Stop serving and wait for the thread to exit.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// A stratum 0 reply echoing the request's version, poll and transmit timestamp.
+fn reply_to(request: &NtpMsg) -> NtpMsg {
+    let mut reply = NtpMsg::new();
+    reply.version_number = request.version_number;
+    reply.mode = NTP_MODE_SERVER;
+    reply.poll = request.poll;
+    reply.precision = PRECISION as u8;
+    reply.originate_timestamp = request.transmit_timestamp;
+    reply
+}
+
+/// Reference ID of a stratum 2+ server: the upstream IPv4 address, or the first
+/// four octets of the MD5 hash of an IPv6 address.
+pub(crate) fn refid_of(ip: IpAddr) -> u32 {
+    match ip {
+        IpAddr::V4(ip) => u32::from(ip),
+        // RFC 5905 7.3: the first four octets of the MD5 hash of the address.
+        IpAddr::V6(ip) => u32::from_be_bytes(Md5::digest(ip.octets())[..4].try_into().unwrap()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::*;
+    use crate::client::{query, sys_time};
+    use crate::timesource::MockClock;
+
+    #[test]
+    fn test_ipnet() {
+        let net: IpNet = "192.0.2.0/24".parse().unwrap();
+        assert!(net.contains("192.0.2.7".parse().unwrap()));
+        assert!(!net.contains("192.0.3.7".parse().unwrap()));
+        assert!(!net.contains("::1".parse().unwrap()));
+
+        let any: IpNet = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains("203.0.113.1".parse().unwrap()));
+        let host: IpNet = "2001:db8::1".parse().unwrap();
+        assert!(host.contains("2001:db8::1".parse().unwrap()));
+        assert!(!host.contains("2001:db8::2".parse().unwrap()));
+        assert!("192.0.2.0/33".parse::<IpNet>().is_err());
+    }
+
+    #[test]
+    fn test_acl_most_specific_wins() {
+        let acl = Acl::new(Access::Deny)
+            .rule("10.0.0.0/8".parse().unwrap(), Access::Allow)
+            .rule("10.1.0.0/16".parse().unwrap(), Access::Deny);
+        assert_eq!(acl.check("10.2.0.1".parse().unwrap()), Access::Allow);
+        assert_eq!(acl.check("10.1.0.1".parse().unwrap()), Access::Deny);
+        assert_eq!(acl.check("192.0.2.1".parse().unwrap()), Access::Deny);
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(RateLimit::default());
+        let ip = "192.0.2.1".parse().unwrap();
+        let now = Instant::now();
+        assert!(limiter.check(ip, now));
+        assert!(!limiter.check(ip, now + Duration::from_secs(1)));
+        assert!(limiter.check(ip, now + Duration::from_secs(4)));
+
+        // Full of active clients: newcomers are limited, not tracked, and
+        // the table is swept at most once per interval.
+        let mut limiter = RateLimiter::new(RateLimit::default());
+        for i in 0..MAX_RATE_LIMIT_CLIENTS as u32 {
+            limiter.clients.insert(IpAddr::from(i.to_be_bytes()), now);
+        }
+        assert!(!limiter.check(ip, now));
+        assert_eq!((limiter.clients.len(), limiter.swept), (MAX_RATE_LIMIT_CLIENTS, Some(now)));
+        assert!(!limiter.check(ip, now + Duration::from_secs(1)));
+        assert_eq!(limiter.swept, Some(now));
+        assert!(limiter.check(ip, now + Duration::from_secs(3)));
+        assert_eq!(limiter.clients.len(), 1);
+    }
+
+    #[test]
+    fn test_refid_of() {
+        assert_eq!(refid_of("192.0.2.1".parse().unwrap()), 0xc000_0201);
+        assert_eq!(refid_of("2001:db8::1".parse().unwrap()), 0x39ab_9b37);
+    }
+
+    #[test]
+    fn test_serve_local_clock() {
+        let server = NtpServer::builder().bind("127.0.0.1:0").local_clock(3).build().unwrap();
+        let handle = server.spawn().unwrap();
+
+        let result = query(&handle.local_addr().to_string()).unwrap();
+        assert_eq!(result.stratum, 3);
+        assert_eq!(result.reference_id, u32::from_be_bytes(*b"LOCL"));
+        assert_eq!(result.root_dispersion, ShortFormat(0));
+        assert!(result.offset_nanos.abs() < 1_000_000_000);
+        handle.stop();
+
+        let server = NtpServer::builder()
+            .bind("127.0.0.1:0")
+            .local_root_delay(Duration::from_millis(2))
+            .local_root_dispersion(Duration::from_millis(500))
+            .local_clock(1)
+            .build()
+            .unwrap();
+        let handle = server.spawn().unwrap();
+        let result = query(&handle.local_addr().to_string()).unwrap();
+        assert_eq!(result.root_delay, ShortFormat::from(Duration::from_millis(2)));
+        assert_eq!(result.root_dispersion.to_duration(), Duration::from_millis(500));
+        handle.stop();
+    }
+
+    #[test]
+    fn test_serve_time_source() {
+        let clock = Arc::new(MockClock::new(sys_time() + Duration::from_secs(3600)));
+        let server = NtpServer::builder().bind("127.0.0.1:0").time_source(clock.clone()).build().unwrap();
+        let handle = server.spawn().unwrap();
+
+        let result = query(&handle.local_addr().to_string()).unwrap();
+        assert_eq!((result.offset_nanos as f64 / 1e9).round(), 3600.0);
+        assert!(result.reference_time.abs_diff(clock.now()) < Duration::from_micros(1));
+        handle.stop();
+    }
+
+    #[test]
+    fn test_serve_rate_limited_kod() {
+        let server = NtpServer::builder()
+            .bind("127.0.0.1:0")
+            .rate_limit(RateLimit { min_interval: Duration::from_secs(60), kod: true })
+            .build()
+            .unwrap();
+        let handle = server.spawn().unwrap();
+        let addr = handle.local_addr().to_string();
+
+        assert_eq!(query(&addr).unwrap().stratum, 10);
+        assert!(matches!(query(&addr), Err(NtpError::KissOfDeath(code)) if code == "RATE"));
+    }
+
+    #[test]
+    fn test_handle_denied() {
+        let acl = Acl::new(Access::Allow).rule("192.0.2.0/24".parse().unwrap(), Access::Deny);
+        let mut server = NtpServer::builder().bind("127.0.0.1:0").acl(acl).build().unwrap();
+        let request = NtpMsg::new_for_client(NTP_VERSION_4, 1).marshal();
+
+        assert!(server.handle(&request, "192.0.2.1:123".parse().unwrap(), sys_time()).is_none());
+        assert!(server.handle(&request, "198.51.100.1:123".parse().unwrap(), sys_time()).is_some());
+    }
+
+    #[test]
+    fn test_handle_reload() {
+        let mut server = NtpServer::builder()
+            .bind("127.0.0.1:0")
+            .rate_limit(RateLimit { min_interval: Duration::from_secs(60), kod: false })
+            .build()
+            .unwrap();
+        let request = NtpMsg::new_for_client(NTP_VERSION_4, 1).marshal();
+        let client = "192.0.2.1:123".parse().unwrap();
+        assert!(server.handle(&request, client, sys_time()).is_some());
+
+        // The client stays limited across a reload, and is denied once the ACL says so.
+        let limit = RateLimit { min_interval: Duration::from_secs(60), kod: true };
+        server.policy.lock().unwrap().reload(Acl::default(), Some(limit));
+        let kod = server.handle(&request, client, sys_time()).unwrap();
+        assert_eq!(kod.stratum, 0);
+        assert_eq!(PollInterval::from(kod.poll), PollInterval::from_exponent(6));
+        let acl = Acl::new(Access::Deny);
+        server.policy.lock().unwrap().reload(acl, None);
+        assert!(server.handle(&request, client, sys_time()).is_none());
+    }
+
+    #[test]
+    fn test_handle_unsynchronized() {
+        let upstream = SntpSynchronizer::builder().server("127.0.0.1:1").start().unwrap();
+        let mut server = NtpServer::builder().bind("127.0.0.1:0").upstream(upstream).build().unwrap();
+        let request = NtpMsg::new_for_client(NTP_VERSION_4, 1).marshal();
+
+        let reply = server.handle(&request, "192.0.2.1:123".parse().unwrap(), sys_time()).unwrap();
+        assert_eq!((reply.leap_indicator, reply.stratum), (LEAP_ALARM, STRATUM_UNSYNC));
+        assert_eq!(reply.reference_timestamp, 0);
+    }
+
+    #[test]
+    fn test_handle_ignores_non_client_modes() {
+        let mut server = NtpServer::builder().bind("127.0.0.1:0").build().unwrap();
+        let mut request = NtpMsg::new_for_client(NTP_VERSION_4, 1);
+        request.mode = NTP_MODE_SERVER;
+
+        assert!(server.handle(&request.marshal(), "192.0.2.1:123".parse().unwrap(), sys_time()).is_none());
+        assert!(server.handle(&[0u8; 12], "192.0.2.1:123".parse().unwrap(), sys_time()).is_none());
+    }
+}
@@ -0,0 +1,199 @@
+//! Well-known public NTP servers.
+//!
+//! Names of common public services, alone and as groups of their individual
+//! hosts, so quick starts do not all hard-code one vendor. Google and some
+//! others smear leap seconds over a day instead of inserting them, so their
+//! servers should not be mixed with others in one synchronizer; [`DEFAULT`]
+//! leaves them out.
+//!
+//! The pool also has zones per continent, country and vendor, closer and
+//! so usually faster than the global one, see [`pool_zone`] and [`local_pool`].
+//!
+//! Example
+//! ```rust,no_run
+//! # use simple_ntp::servers;
+//! # use simple_ntp::synchronizer::SntpSynchronizer;
+//!
+//! fn main() {
+//!     let mut builder = SntpSynchronizer::builder();
+//!     for server in servers::POOL_GROUP {
+//!         builder = builder.server(server);
+//!     }
+//!     let sync = builder.start().unwrap();
+//!     println!("{:?}", sync.offset_nanos());
+//! }
+//! ```
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use crate::protocol::NtpError;
+
+/// The NTP Pool Project, volunteer servers picked by DNS.
+pub const POOL: &str = "pool.ntp.org";
+
+/// Google Public NTP, smearing leap seconds.
+pub const GOOGLE: &str = "time.google.com";
+
+/// Cloudflare, anycast.
+pub const CLOUDFLARE: &str = "time.cloudflare.com";
+
+/// NIST Internet Time Service, round robin over its servers.
+pub const NIST: &str = "time.nist.gov";
+
+/// Alibaba Cloud.
+pub const ALIYUN: &str = "ntp.aliyun.com";
+
+/// The pool's numbered names, each resolving to different servers.
+pub const POOL_GROUP: [&str; 4] = ["0.pool.ntp.org", "1.pool.ntp.org", "2.pool.ntp.org", "3.pool.ntp.org"];
+
+/// Google's individual hosts, all smearing leap seconds.
+pub const GOOGLE_GROUP: [&str; 4] = ["time1.google.com", "time2.google.com", "time3.google.com", "time4.google.com"];
+
+/// NIST's Gaithersburg hosts.
+pub const NIST_GROUP: [&str; 4] = ["time-a-g.nist.gov", "time-b-g.nist.gov", "time-c-g.nist.gov", "time-d-g.nist.gov"];
+
+/// A redundant set of independent operators, none smearing leap seconds,
+/// queried by [`SntpClient::query_default`](crate::client::SntpClient::query_default).
+pub const DEFAULT: [&str; 4] = ["0.pool.ntp.org", "1.pool.ntp.org", CLOUDFLARE, NIST];
+
+/// The pool's continental zones; Antarctica has no servers of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Continent {
+    Africa,
+    Asia,
+    Europe,
+    NorthAmerica,
+    Oceania,
+    SouthAmerica,
+}
+
+impl Continent {
+    /// Zone name, e.g. `north-america`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Continent::Africa => "africa",
+            Continent::Asia => "asia",
+            Continent::Europe => "europe",
+            Continent::NorthAmerica => "north-america",
+            Continent::Oceania => "oceania",
+            Continent::SouthAmerica => "south-america",
+        }
+    }
+
+    /// The continent of an IANA time zone, e.g. `Europe/Berlin`.
+    pub fn from_timezone(timezone: &str) -> Option<Self> {
+        let (area, city) = timezone.split_once('/')?;
+        match area {
+            "Africa" => Some(Continent::Africa),
+            "Asia" => Some(Continent::Asia),
+            "Europe" => Some(Continent::Europe),
+            "Australia" | "Pacific" => Some(Continent::Oceania),
+            "America" if SOUTH_AMERICA.iter().any(|prefix| city.starts_with(prefix)) => Some(Continent::SouthAmerica),
+            "America" | "Canada" | "US" => Some(Continent::NorthAmerica),
+            "Brazil" | "Chile" => Some(Continent::SouthAmerica),
+            _ => None,
+        }
+    }
+
+    /// The continent of the system time zone, from `TZ`, `/etc/localtime` or
+    /// `/etc/timezone`. The locale is no help, `en_US` is used everywhere.
+    pub fn local() -> Option<Self> {
+        let timezone = env::var("TZ").ok()
+            .or_else(|| fs::read_link("/etc/localtime").ok().map(|path| path.to_string_lossy().into_owned()))
+            .or_else(|| fs::read_to_string("/etc/timezone").ok())?;
+
+        Self::from_timezone(iana_name(&timezone))
+    }
+}
+
+/// `America/...` time zones in South America.
+const SOUTH_AMERICA: [&str; 30] = [
+    "Argentina/", "Araguaina", "Asuncion", "Bahia", "Belem", "Boa_Vista", "Bogota", "Buenos_Aires", "Campo_Grande",
+    "Caracas", "Cayenne", "Cuiaba", "Eirunepe", "Fortaleza", "Guayaquil", "Guyana", "La_Paz", "Lima", "Maceio",
+    "Manaus", "Montevideo", "Noronha", "Paramaribo", "Porto_Velho", "Punta_Arenas", "Recife", "Rio_Branco",
+    "Santarem", "Santiago", "Sao_Paulo",
+];
+
+/// `Europe/Berlin` out of `:Europe/Berlin`, `/usr/share/zoneinfo/Europe/Berlin` and the like.
+fn iana_name(timezone: &str) -> &str {
+    let timezone = timezone.trim().trim_start_matches(':');
+    match timezone.rsplit_once("zoneinfo/") {
+        Some((_, name)) => name,
+        None if Path::new(timezone).is_absolute() => "",
+        None => timezone,
+    }
+}
+
+/// A pool zone by name: a continent such as `europe`, a country such as `cn`
+/// or a vendor zone such as `debian`.
+///
+/// Example
+/// ```rust
+/// # use simple_ntp::servers::pool_zone;
+///
+/// fn main() {
+///     assert_eq!(pool_zone("europe"), "europe.pool.ntp.org");
+/// }
+/// ```
+pub fn pool_zone(zone: &str) -> String {
+    format!("{}.{}", zone, POOL)
+}
+
+/// The numbered names of a zone, like [`POOL_GROUP`] for the global one.
+pub fn pool_zone_group(zone: &str) -> [String; 4] {
+    [0, 1, 2, 3].map(|i| format!("{}.{}.{}", i, zone, POOL))
+}
+
+/// The zone of a country by ISO 3166 code, e.g. `CN`.
+pub fn country_zone(code: &str) -> Result<String, NtpError> {
+    if code.len() != 2 || !code.bytes().all(|b| b.is_ascii_alphabetic()) {
+        return Err(NtpError::BadNtpServerAddr(format!("invalid country code `{}`", code)));
+    }
+
+    Ok(pool_zone(&code.to_ascii_lowercase()))
+}
+
+/// The zone of the local continent, see [`Continent::local`], or the global
+/// [`POOL`] if it cannot be told.
+pub fn local_pool() -> String {
+    match Continent::local() {
+        Some(continent) => pool_zone(continent.name()),
+        None => POOL.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::servers::*;
+
+    #[test]
+    fn test_zones() {
+        assert_eq!(pool_zone(Continent::NorthAmerica.name()), "north-america.pool.ntp.org");
+        assert_eq!(pool_zone_group("debian")[3], "3.debian.pool.ntp.org");
+        assert_eq!(country_zone("CN").unwrap(), "cn.pool.ntp.org");
+        assert!(country_zone("chn").is_err());
+        assert!(country_zone("c1").is_err());
+    }
+
+    #[test]
+    fn test_default() {
+        // Google smears leap seconds, mixing it with the others would skew the median.
+        assert!(!DEFAULT.iter().any(|server| server.contains("google")));
+    }
+
+    #[test]
+    fn test_timezone() {
+        let continent = |timezone| Continent::from_timezone(iana_name(timezone));
+        assert_eq!(continent("Europe/Berlin"), Some(Continent::Europe));
+        assert_eq!(continent(":Asia/Shanghai"), Some(Continent::Asia));
+        assert_eq!(continent("/usr/share/zoneinfo/America/Sao_Paulo"), Some(Continent::SouthAmerica));
+        assert_eq!(continent("America/Argentina/Cordoba"), Some(Continent::SouthAmerica));
+        assert_eq!(continent("America/New_York"), Some(Continent::NorthAmerica));
+        assert_eq!(continent("Australia/Sydney\n"), Some(Continent::Oceania));
+        assert_eq!(continent("UTC"), None);
+        assert_eq!(continent("Etc/GMT+2"), None);
+        assert_eq!(continent("/etc/localtime"), None);
+    }
+}
@@ -0,0 +1,413 @@
+//! Simulated networks for testing the synchronizer's algorithms.
+//!
+//! A [`Simulation`] runs a synchronizer against [`VirtualServer`]s over
+//! [`MemoryTransport`]s on a [`MockClock`]: servers with their own clock
+//! errors, asymmetric and jittery paths, loss, outages and clock steps, and a
+//! local clock that is off and drifts. Poll rounds run back to back in the
+//! caller's thread, so hours of polling take milliseconds, and every round
+//! records how far the synchronizer's offset is from the truth.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::client::{shift, SntpClient};
+use crate::protocol::{NtpError, NtpMsg, ShortFormat, NTP_MODE_CLIENT};
+use crate::synchronizer::{SntpSynchronizer, Stepper, SyncHandle};
+use crate::testing::{self, Distribution, MemoryTransport};
+use crate::timesource::{MockClock, TimeSource};
+
+/// True time at the start of every simulation.
+const EPOCH: Duration = Duration::from_secs(1_700_000_000);
+
+/// A simulated server and the path to it.
+///
+/// Times like [`outage`](Self::outage)'s are true time since the start of the simulation.
+#[derive(Debug, Clone)]
+pub struct VirtualServer {
+    name: String,
+    offset_nanos: i64,
+    stratum: u8,
+    root_dispersion: Duration,
+    delay: Distribution,
+    return_delay: Distribution,
+    loss: f64,
+    outages: Vec<(Duration, Duration)>,
+    steps: Vec<(Duration, i64)>,
+}
+
+impl VirtualServer {
+    /// A stratum 1 server with a perfect clock behind an instant, lossless path.
+    pub fn new(name: &str) -> Self {
+        VirtualServer {
+            name: name.to_string(),
+            offset_nanos: 0,
+            stratum: 1,
+            root_dispersion: Duration::ZERO,
+            delay: Distribution::Fixed(Duration::ZERO),
+            return_delay: Distribution::Fixed(Duration::ZERO),
+            loss: 0.0,
+            outages: Vec::new(),
+            steps: Vec::new(),
+        }
+    }
+
+    /// How far the server's clock is ahead of true time, in nano seconds.
+    pub fn offset_nanos(mut self, offset_nanos: i64) -> Self {
+        self.offset_nanos = offset_nanos;
+        self
+    }
+
+    pub fn stratum(mut self, stratum: u8) -> Self {
+        self.stratum = stratum;
+        self
+    }
+
+    /// Root dispersion the server reports.
+    pub fn root_dispersion(mut self, root_dispersion: Duration) -> Self {
+        self.root_dispersion = root_dispersion;
+        self
+    }
+
+    /// One-way delay both ways.
+    pub fn delay(mut self, delay: Distribution) -> Self {
+        self.delay = delay;
+        self.return_delay = delay;
+        self
+    }
+
+    /// One-way delay of the responses, set after [`delay`](Self::delay) for an asymmetric path.
+    pub fn return_delay(mut self, delay: Distribution) -> Self {
+        self.return_delay = delay;
+        self
+    }
+
+    /// Probability that a datagram is lost, each way.
+    pub fn loss(mut self, probability: f64) -> Self {
+        self.loss = probability;
+        self
+    }
+
+    /// Ignore requests for `length` from `start`.
+    pub fn outage(mut self, start: Duration, length: Duration) -> Self {
+        self.outages.push((start, start + length));
+        self
+    }
+
+    /// Step the server's clock to `offset_nanos` ahead of true time at `at`.
+    pub fn step(mut self, at: Duration, offset_nanos: i64) -> Self {
+        self.steps.push((at, offset_nanos));
+        self
+    }
+
+    /// Clock error at `elapsed` true time, `None` during an outage.
+    fn offset_at(&self, elapsed: Duration) -> Option<i64> {
+        if self.outages.iter().any(|(start, end)| (*start..*end).contains(&elapsed)) {
+            return None;
+        }
+        let stepped = self.steps.iter().filter(|(at, _)| *at <= elapsed).max_by_key(|(at, _)| *at);
+
+        Some(stepped.map_or(self.offset_nanos, |(_, offset)| *offset))
+    }
+}
+
+/// The local clock's error over time, to tell true time from local time.
+#[derive(Debug, Clone, Copy)]
+struct Model {
+    /// Local time at the start.
+    start: Duration,
+    drift_ppm: f64,
+}
+
+impl Model {
+    /// True time since the start when the local clock reads `local`.
+    fn elapsed(&self, local: Duration) -> Duration {
+        let local_nanos = (local.as_nanos() as i128 - self.start.as_nanos() as i128) as f64;
+        Duration::from_nanos((local_nanos / (1.0 + self.drift_ppm / 1e6)).max(0.0) as u64)
+    }
+
+    /// How far the local clock is behind true time when it reads `local`.
+    fn true_offset_nanos(&self, local: Duration) -> i64 {
+        ((EPOCH + self.elapsed(local)).as_nanos() as i128 - local.as_nanos() as i128) as i64
+    }
+}
+
+/// Configure a [`Simulation`].
+#[derive(Debug, Clone)]
+pub struct SimulationBuilder {
+    servers: Vec<VirtualServer>,
+    interval: Duration,
+    offset_nanos: i64,
+    drift_ppm: f64,
+    max_offset: Option<Duration>,
+    seed: u64,
+}
+
+impl SimulationBuilder {
+    pub fn server(mut self, server: VirtualServer) -> Self {
+        self.servers.push(server);
+        self
+    }
+
+    /// Poll interval, 64 seconds by default.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// How far the local clock is behind true time at the start, in nano seconds.
+    pub fn offset_nanos(mut self, offset_nanos: i64) -> Self {
+        self.offset_nanos = offset_nanos;
+        self
+    }
+
+    /// Frequency error of the local clock, positive when it runs fast.
+    pub fn drift_ppm(mut self, drift_ppm: f64) -> Self {
+        self.drift_ppm = drift_ppm;
+        self
+    }
+
+    /// See [`SynchronizerBuilder::max_offset`](crate::synchronizer::SynchronizerBuilder::max_offset).
+    pub fn max_offset(mut self, max_offset: Duration) -> Self {
+        self.max_offset = Some(max_offset);
+        self
+    }
+
+    /// Seed of the paths' random choices, the same seed gives the same run.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn build(self) -> Result<Simulation, NtpError> {
+        let model = Model {
+            start: shift(EPOCH, -self.offset_nanos),
+            drift_ppm: self.drift_ppm,
+        };
+        let clock = Arc::new(MockClock::new(model.start));
+        let client = SntpClient::builder().time_source(clock.clone()).build();
+
+        let mut builder = SntpSynchronizer::builder().interval(self.interval).time_source(clock.clone());
+        if let Some(max_offset) = self.max_offset {
+            builder = builder.max_offset(max_offset);
+        }
+        for (i, server) in self.servers.into_iter().enumerate() {
+            let transport = MemoryTransport::builder(clock.clone())
+                .delay(server.delay)
+                .return_delay(server.return_delay)
+                .loss(server.loss)
+                .seed(self.seed.wrapping_add(i as u64))
+                .peer(SocketAddr::from(([192, 0, 2, i as u8 + 1], 123)));
+            let name = server.name.clone();
+            let root_dispersion = ShortFormat::from(server.root_dispersion).0;
+            let transport = transport.respond_with(move |data, arrival| {
+                let mut request = NtpMsg::new();
+                if request.unmarshal(data).is_err() || request.mode != NTP_MODE_CLIENT {
+                    return None;
+                }
+                let elapsed = model.elapsed(arrival);
+                let now = shift(EPOCH + elapsed, server.offset_at(elapsed)?);
+                let mut reply = testing::reply(&request, now, server.stratum, u32::from_be_bytes(*b"SIM\0"));
+                reply.root_dispersion = root_dispersion;
+                Some(reply.marshal())
+            });
+            builder = builder.server_via(&name, client.clone(), Box::new(transport.build()));
+        }
+
+        Ok(Simulation {
+            stepper: builder.stepper()?,
+            clock,
+            model,
+            interval: self.interval,
+            samples: Vec::new(),
+        })
+    }
+}
+
+/// The outcome of one poll round of a [`Simulation`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimulationSample {
+    /// Local time since the start of the simulation.
+    pub elapsed: Duration,
+    /// How far the local clock is behind true time, the offset a perfect
+    /// synchronizer would report.
+    pub true_offset_nanos: i64,
+    /// The synchronizer's offset after the round, `None` until a server answered.
+    pub offset_nanos: Option<i64>,
+    /// The server selected in the round, `None` if none answered.
+    pub selected: Option<String>,
+}
+
+impl SimulationSample {
+    /// The synchronizer's offset minus the true one.
+    pub fn error_nanos(&self) -> Option<i64> {
+        self.offset_nanos.map(|offset| offset - self.true_offset_nanos)
+    }
+}
+
+/// A synchronizer polling virtual servers in simulated time.
+///
+/// Example
+/// ```rust
+/// # use std::time::Duration;
+/// # use simple_ntp::simulation::{Simulation, VirtualServer};
+/// # use simple_ntp::testing::Distribution;
+///
+/// fn main() {
+///     let mut sim = Simulation::builder()
+///         .server(VirtualServer::new("near").delay(Distribution::Uniform(Duration::from_millis(1), Duration::from_millis(5))))
+///         .server(VirtualServer::new("far").delay(Distribution::Exponential(Duration::from_millis(40))).loss(0.1))
+///         .offset_nanos(250_000_000)
+///         .drift_ppm(15.0)
+///         .build()
+///         .unwrap();
+///     sim.run(Duration::from_secs(24 * 3600));
+///     assert!(sim.max_error_nanos().unwrap() < 10_000_000);
+/// }
+/// ```
+pub struct Simulation {
+    stepper: Stepper,
+    clock: Arc<MockClock>,
+    model: Model,
+    interval: Duration,
+    samples: Vec<SimulationSample>,
+}
+
+impl Simulation {
+    pub fn builder() -> SimulationBuilder {
+        SimulationBuilder {
+            servers: Vec::new(),
+            interval: Duration::from_secs(64),
+            offset_nanos: 0,
+            drift_ppm: 0.0,
+            max_offset: None,
+            seed: 0,
+        }
+    }
+
+    /// Poll for `duration` of local time, continuing where the last run stopped.
+    pub fn run(&mut self, duration: Duration) {
+        let end = self.elapsed() + duration;
+        while self.elapsed() < end {
+            let started = self.clock.now();
+            self.stepper.step();
+            let now = self.clock.now();
+
+            let handle = self.stepper.handle();
+            self.samples.push(SimulationSample {
+                elapsed: self.elapsed(),
+                true_offset_nanos: self.model.true_offset_nanos(now),
+                offset_nanos: handle.offset_nanos(),
+                selected: handle.sources().into_iter().find(|source| source.selected).map(|source| source.server),
+            });
+            self.clock.set(now.max(started + self.interval));
+        }
+    }
+
+    /// Local time since the start.
+    pub fn elapsed(&self) -> Duration {
+        self.clock.now().saturating_sub(self.model.start)
+    }
+
+    /// Every poll round so far.
+    pub fn samples(&self) -> &[SimulationSample] {
+        &self.samples
+    }
+
+    /// The synchronizer, for its sources and statistics.
+    pub fn handle(&self) -> SyncHandle {
+        self.stepper.handle()
+    }
+
+    /// Largest absolute error of the rounds with an offset.
+    pub fn max_error_nanos(&self) -> Option<i64> {
+        self.samples.iter().filter_map(SimulationSample::error_nanos).map(i64::abs).max()
+    }
+
+    /// RMS error of the rounds with an offset, 0 if there were none.
+    pub fn rms_error_nanos(&self) -> f64 {
+        let errors: Vec<_> = self.samples.iter().filter_map(SimulationSample::error_nanos).collect();
+        if errors.is_empty() {
+            return 0.0;
+        }
+        (errors.iter().map(|error| (*error as f64).powi(2)).sum::<f64>() / errors.len() as f64).sqrt()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::simulation::*;
+
+    #[test]
+    fn test_simulation_drift() {
+        let millis = Duration::from_millis;
+        let mut sim = Simulation::builder()
+            .server(VirtualServer::new("a").delay(Distribution::Fixed(millis(10))))
+            .offset_nanos(-40_000_000)
+            .drift_ppm(10.0)
+            .build()
+            .unwrap();
+        sim.run(Duration::from_secs(3600));
+
+        // A symmetric fixed path measures the truth, as of the middle of the
+        // exchange: 10 ms before the sample, when the clock was 100 ns less ahead.
+        let samples = sim.samples();
+        assert_eq!(samples.len(), 57);
+        assert_eq!(samples[0].elapsed, millis(20));
+        assert!((sim.max_error_nanos().unwrap() - 100).abs() <= 5, "{:?}", sim.max_error_nanos());
+        assert!((samples[0].true_offset_nanos + 40_000_000).abs() < 1_000);
+        assert!((samples[56].true_offset_nanos - samples[0].true_offset_nanos + 35_840_000).abs() < 1_000);
+        let frequency = sim.handle().system_stats().frequency_ppm;
+        assert!((frequency - 10.0).abs() < 0.01, "{}", frequency);
+    }
+
+    #[test]
+    fn test_simulation_failures() {
+        let millis = Duration::from_millis;
+        let minutes = |m: u64| Duration::from_secs(m * 60);
+        let build = |seed| {
+            Simulation::builder()
+                .server(VirtualServer::new("asymmetric")
+                    .delay(Distribution::Fixed(millis(1)))
+                    .return_delay(Distribution::Fixed(millis(21)))
+                    .outage(minutes(10), minutes(10)))
+                .server(VirtualServer::new("lossy")
+                    .offset_nanos(5_000_000)
+                    .delay(Distribution::Uniform(millis(20), millis(40)))
+                    .loss(0.3)
+                    .step(minutes(30), 500_000_000))
+                .seed(seed)
+                .build()
+                .unwrap()
+        };
+        let mut sim = build(1);
+        sim.run(minutes(40));
+
+        let selected = |sample: &SimulationSample| sample.selected.clone().unwrap_or_default();
+        for sample in sim.samples() {
+            match sample.elapsed {
+                // The shorter round trip wins, half the asymmetry shows as offset.
+                elapsed if elapsed < minutes(10) => {
+                    assert_eq!(selected(sample), "asymmetric");
+                    assert!((sample.error_nanos().unwrap() + 10_000_000).abs() <= 5);
+                }
+                elapsed if elapsed > minutes(11) && elapsed < minutes(20) => {
+                    if let Some(error) = sample.error_nanos().filter(|_| selected(sample) == "lossy") {
+                        assert!((error - 5_000_000).abs() < 10_000_000, "{}", error);
+                    }
+                }
+                elapsed if elapsed > minutes(30) => assert_ne!(selected(sample), "lossy"),
+                _ => {}
+            }
+        }
+        assert!(sim.samples().iter().any(|sample| selected(sample) == "lossy"));
+        assert!(sim.samples().iter().any(|sample| sample.selected.is_none()));
+
+        let mut again = build(1);
+        again.run(minutes(40));
+        assert_eq!(sim.samples(), again.samples());
+        let mut other = build(2);
+        other.run(minutes(40));
+        assert_ne!(sim.samples(), other.samples());
+    }
+}
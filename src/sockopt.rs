@@ -0,0 +1,217 @@
+//! Linux socket options the standard library does not expose.
+
+use std::io;
+#[cfg(any(feature = "fwmark", feature = "ttl"))]
+use std::mem;
+#[cfg(any(test, feature = "fwmark", feature = "ttl"))]
+use std::net::UdpSocket;
+use std::os::fd::AsRawFd;
+#[cfg(feature = "netns")]
+use std::path::{Path, PathBuf};
+#[cfg(feature = "netns")]
+use std::thread;
+#[cfg(feature = "ttl")]
+use std::ptr;
+
+/// Tag everything sent on `socket` with the firewall mark `mark`, for policy
+/// routing and nftables rules; needs `CAP_NET_ADMIN`. On failure, says why.
+#[cfg(feature = "fwmark")]
+pub(crate) fn set_mark(socket: &UdpSocket, mark: u32) -> Result<(), String> {
+    // SAFETY: `mark` is a valid u32 for the duration of the call and the length is its size.
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_MARK,
+            &mark as *const u32 as *const libc::c_void,
+            mem::size_of::<u32>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        let err = io::Error::last_os_error();
+        return Err(match err.kind() {
+            io::ErrorKind::PermissionDenied => format!("setting firewall mark {} needs CAP_NET_ADMIN: {}", mark, err),
+            _ => format!("setting firewall mark {}: {}", mark, err),
+        });
+    }
+
+    Ok(())
+}
+
+/// Have the kernel pass the IP TTL (`IP_RECVTTL`), or the IPv6 hop limit
+/// (`IPV6_RECVHOPLIMIT`), of every datagram received on `socket` to [`recv_ttl`].
+#[cfg(feature = "ttl")]
+pub(crate) fn set_recv_ttl(socket: &UdpSocket, ipv4: bool) -> Result<(), String> {
+    let (level, name) = if ipv4 {
+        (libc::IPPROTO_IP, libc::IP_RECVTTL)
+    } else {
+        (libc::IPPROTO_IPV6, libc::IPV6_RECVHOPLIMIT)
+    };
+    let on: libc::c_int = 1;
+    // SAFETY: `on` is a valid c_int for the duration of the call and the length is its size.
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &on as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(format!("receiving the ttl: {}", io::Error::last_os_error()));
+    }
+
+    Ok(())
+}
+
+/// `socket.recv(buf)` with `recvmsg`, plus the TTL or hop limit the datagram
+/// arrived with if [`set_recv_ttl`] asked for it.
+#[cfg(feature = "ttl")]
+pub(crate) fn recv_ttl(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, Option<u8>)> {
+    // Room for one cmsghdr and an int, aligned like a cmsghdr.
+    let mut control = [0u64; 8];
+    let mut iovec = libc::iovec { iov_base: buf.as_mut_ptr().cast(), iov_len: buf.len() };
+    // SAFETY: all zeros is a valid msghdr, null pointers and zero lengths.
+    let mut header: libc::msghdr = unsafe { mem::zeroed() };
+    header.msg_iov = &mut iovec;
+    header.msg_iovlen = 1;
+    header.msg_control = control.as_mut_ptr().cast();
+    header.msg_controllen = mem::size_of_val(&control) as _;
+
+    // SAFETY: `header` points at `iovec`, `buf` and `control`, which outlive the call.
+    let n = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut header, 0) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut ttl = None;
+    // SAFETY: `header` was filled in by recvmsg, its control data is within
+    // `control`, and the CMSG macros stay inside it.
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&header);
+        while !cmsg.is_null() {
+            let (level, kind) = ((*cmsg).cmsg_level, (*cmsg).cmsg_type);
+            if (level, kind) == (libc::IPPROTO_IP, libc::IP_TTL) || (level, kind) == (libc::IPPROTO_IPV6, libc::IPV6_HOPLIMIT) {
+                let value: libc::c_int = ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast());
+                ttl = u8::try_from(value).ok();
+            }
+            cmsg = libc::CMSG_NXTHDR(&header, cmsg);
+        }
+    }
+
+    Ok((n as usize, ttl))
+}
+
+/// The namespace file of `netns`: a path as is, a name as created by `ip netns add`.
+#[cfg(feature = "netns")]
+pub(crate) fn netns_path(netns: &str) -> PathBuf {
+    if netns.contains('/') {
+        PathBuf::from(netns)
+    } else {
+        Path::new("/run/netns").join(netns)
+    }
+}
+
+/// `bind()` run in the network namespace at `netns`, on a thread of its own
+/// so the caller's namespace is left alone; the socket stays in `netns`.
+/// Entering it needs `CAP_SYS_ADMIN`. On failure, says why.
+#[cfg(feature = "netns")]
+pub(crate) fn in_netns<T: Send>(netns: &Path, bind: impl FnOnce() -> Result<T, String> + Send) -> Result<T, String> {
+    let file = std::fs::File::open(netns).map_err(|err| format!("network namespace {}: {}", netns.display(), err))?;
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            // SAFETY: `file` is an open namespace file, setns only affects this thread.
+            if unsafe { libc::setns(file.as_raw_fd(), libc::CLONE_NEWNET) } != 0 {
+                let err = io::Error::last_os_error();
+                return Err(match err.kind() {
+                    io::ErrorKind::PermissionDenied => {
+                        format!("entering network namespace {} needs CAP_SYS_ADMIN: {}", netns.display(), err)
+                    }
+                    _ => format!("entering network namespace {}: {}", netns.display(), err),
+                });
+            }
+            bind()
+        }).join().unwrap()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sockopt::*;
+
+    #[cfg(feature = "netns")]
+    #[test]
+    fn test_in_netns() {
+        assert_eq!(netns_path("blue"), Path::new("/run/netns/blue"));
+        assert_eq!(netns_path("/proc/1/ns/net"), Path::new("/proc/1/ns/net"));
+
+        let err = in_netns(&netns_path("simple-ntp-missing"), || Ok(())).unwrap_err();
+        assert!(err.contains("/run/netns/simple-ntp-missing"), "{}", err);
+        // Our own namespace, if privileged.
+        match in_netns(Path::new("/proc/self/ns/net"), || UdpSocket::bind("127.0.0.1:0").map_err(|err| err.to_string())) {
+            Ok(socket) => assert!(socket.local_addr().unwrap().ip().is_loopback()),
+            Err(err) => assert!(err.contains("CAP_SYS_ADMIN"), "{}", err),
+        }
+    }
+
+    #[cfg(feature = "ttl")]
+    #[test]
+    fn test_recv_ttl() {
+        for (local, ipv4) in [("127.0.0.1:0", true), ("[::1]:0", false)] {
+            let Ok(receiver) = UdpSocket::bind(local) else { continue };
+            let sender = UdpSocket::bind(local).unwrap();
+            sender.connect(receiver.local_addr().unwrap()).unwrap();
+            let mut buf = [0u8; 16];
+
+            sender.send(b"plain").unwrap();
+            assert_eq!(recv_ttl(&receiver, &mut buf).unwrap(), (5, None));
+
+            set_recv_ttl(&receiver, ipv4).unwrap();
+            if ipv4 {
+                sender.set_ttl(42).unwrap();
+            } else {
+                let hops: libc::c_int = 42;
+                // SAFETY: `hops` is a valid c_int for the duration of the call and the length is its size.
+                let ret = unsafe {
+                    libc::setsockopt(
+                        sender.as_raw_fd(),
+                        libc::IPPROTO_IPV6,
+                        libc::IPV6_UNICAST_HOPS,
+                        &hops as *const libc::c_int as *const libc::c_void,
+                        mem::size_of::<libc::c_int>() as libc::socklen_t,
+                    )
+                };
+                assert_eq!(ret, 0);
+            }
+            sender.send(b"marked").unwrap();
+            assert_eq!(recv_ttl(&receiver, &mut buf).unwrap(), (6, Some(42)));
+            assert_eq!(&buf[..6], b"marked");
+        }
+    }
+
+    #[cfg(feature = "fwmark")]
+    #[test]
+    fn test_set_mark() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        match set_mark(&socket, 0x2a) {
+            Ok(()) => {
+                let mut mark = 0u32;
+                let mut len = mem::size_of::<u32>() as libc::socklen_t;
+                // SAFETY: `mark` and `len` are valid and writable, `len` is the size of `mark`.
+                let ret = unsafe {
+                    libc::getsockopt(
+                        socket.as_raw_fd(),
+                        libc::SOL_SOCKET,
+                        libc::SO_MARK,
+                        &mut mark as *mut u32 as *mut libc::c_void,
+                        &mut len,
+                    )
+                };
+                assert_eq!((ret, mark), (0, 0x2a));
+            }
+            // Unprivileged.
+            Err(err) => assert!(err.contains("CAP_NET_ADMIN"), "{}", err),
+        }
+    }
+}
@@ -0,0 +1,212 @@
+//! Measurement log in a SQLite file, for long-term drift analysis without a metrics stack.
+//!
+//! Every answer a [`SntpSynchronizer`](crate::synchronizer::SntpSynchronizer) gets goes
+//! into one table, kept for a retention period:
+//!
+//! ```sql
+//! CREATE TABLE measurements (
+//!     time REAL NOT NULL,         -- end of the poll round, seconds since the unix epoch
+//!     server TEXT NOT NULL,
+//!     addr TEXT NOT NULL,
+//!     offset_ns INTEGER NOT NULL, -- remote timestamp sub local timestamp
+//!     delay_ns INTEGER NOT NULL,
+//!     stratum INTEGER NOT NULL,
+//!     verdict TEXT NOT NULL       -- see synchronizer::Verdict
+//! );
+//! ```
+//!
+//! Uses [`rusqlite`], linking the system's `libsqlite3`, or a copy of SQLite
+//! built in with the `sqlite-bundled` feature.
+
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use rusqlite::{params, Connection};
+
+use crate::synchronizer::LogRecord;
+
+/// Measurements kept by default, see [`SqliteLog::retention`].
+pub const DEFAULT_RETENTION: Duration = Duration::from_secs(90 * 86400);
+
+/// How long a write waits for another process holding the file, e.g. a running analysis.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS measurements (
+    time REAL NOT NULL,
+    server TEXT NOT NULL,
+    addr TEXT NOT NULL,
+    offset_ns INTEGER NOT NULL,
+    delay_ns INTEGER NOT NULL,
+    stratum INTEGER NOT NULL,
+    verdict TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS measurements_time ON measurements (time);";
+
+const INSERT: &str = "INSERT INTO measurements (time, server, addr, offset_ns, delay_ns, stratum, verdict)
+    VALUES (?, ?, ?, ?, ?, ?, ?)";
+
+const PURGE: &str = "DELETE FROM measurements WHERE time < ?";
+
+/// A SQLite file measurements are appended to, see the [module docs](self).
+///
+/// Example
+/// ```rust,no_run
+/// # use std::time::Duration;
+/// # use simple_ntp::sqlite::SqliteLog;
+/// # use simple_ntp::synchronizer::SntpSynchronizer;
+///
+/// fn main() {
+///     let log = SqliteLog::open("/var/lib/sntp/measurements.db")
+///         .unwrap()
+///         .retention(Duration::from_secs(365 * 86400));
+///     let sync = SntpSynchronizer::builder()
+///         .server("ntp.aliyun.com")
+///         .sqlite(log)
+///         .start()
+///         .unwrap();
+/// #   drop(sync);
+/// }
+/// ```
+#[derive(Debug)]
+pub struct SqliteLog {
+    db: Connection,
+    retention: Duration,
+}
+
+impl SqliteLog {
+    /// Open or create the file at `path` and its `measurements` table, keeping
+    /// [`DEFAULT_RETENTION`] of measurements.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let db = Connection::open(path).map_err(|err| error(&format!("opening {}", path.display()), err))?;
+        db.busy_timeout(BUSY_TIMEOUT).map_err(|err| error("busy_timeout", err))?;
+        db.execute_batch(SCHEMA).map_err(|err| error("creating the table", err))?;
+        Ok(SqliteLog { db, retention: DEFAULT_RETENTION })
+    }
+
+    /// Delete measurements older than `retention` on every write.
+    pub fn retention(mut self, retention: Duration) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Append the measurements of the poll round ended at `now` (since unix epoch)
+    /// and purge the expired ones, in one transaction.
+    pub(crate) fn write(&mut self, now: Duration, records: &[LogRecord]) -> io::Result<()> {
+        let time = now.as_secs_f64();
+        // Rolled back on drop if anything below fails.
+        let tx = self.db.transaction().map_err(|err| error("begin", err))?;
+        {
+            let mut insert = tx.prepare_cached(INSERT).map_err(|err| error("prepare", err))?;
+            for record in records {
+                insert
+                    .execute(params![
+                        time,
+                        record.server,
+                        record.addr.to_string(),
+                        record.offset_nanos,
+                        record.delay_nanos,
+                        record.stratum,
+                        record.verdict.as_str(),
+                    ])
+                    .map_err(|err| error("insert", err))?;
+            }
+        }
+        tx.execute(PURGE, params![now.saturating_sub(self.retention).as_secs_f64()])
+            .map_err(|err| error("purge", err))?;
+        tx.commit().map_err(|err| error("commit", err))
+    }
+}
+
+/// `err` of SQLite, after `what` failed.
+fn error(what: &str, err: rusqlite::Error) -> io::Error {
+    io::Error::other(format!("sqlite: {}: {}", what, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sqlite::*;
+    use crate::synchronizer::Verdict;
+
+    fn record(server: &str, offset_nanos: i64, verdict: Verdict) -> LogRecord {
+        LogRecord {
+            server: server.to_string(),
+            addr: "127.0.0.1:123".parse().unwrap(),
+            offset_nanos,
+            delay_nanos: 2_000_000,
+            stratum: 2,
+            verdict,
+        }
+    }
+
+    fn rows(log: &SqliteLog) -> Vec<(i64, String, i64, String)> {
+        let mut select = log.db.prepare("SELECT CAST(time AS INTEGER), server, offset_ns, verdict FROM measurements ORDER BY rowid").unwrap();
+        let rows = select.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))).unwrap();
+        rows.collect::<Result<_, _>>().unwrap()
+    }
+
+    #[test]
+    fn test_sqlite_log() {
+        let path = std::env::temp_dir().join(format!("simple-ntp-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let day = Duration::from_secs(86400);
+
+        let mut log = SqliteLog::open(&path).unwrap().retention(2 * day);
+        log.write(day, &[record("a", 1_500, Verdict::Selected), record("b", -40_000_000, Verdict::Falseticker)]).unwrap();
+        log.write(2 * day, &[record("a", 1_700, Verdict::MaxOffset)]).unwrap();
+        drop(log);
+
+        // Reopening keeps the table; day 1 expires on day 4.
+        let mut log = SqliteLog::open(&path).unwrap().retention(2 * day);
+        assert_eq!(rows(&log), vec![
+            (86400, "a".to_string(), 1_500, "selected".to_string()),
+            (86400, "b".to_string(), -40_000_000, "falseticker".to_string()),
+            (172800, "a".to_string(), 1_700, "max_offset".to_string()),
+        ]);
+        log.write(4 * day, &[record("a", 1_600, Verdict::Candidate)]).unwrap();
+        assert_eq!(rows(&log), vec![
+            (172800, "a".to_string(), 1_700, "max_offset".to_string()),
+            (345600, "a".to_string(), 1_600, "candidate".to_string()),
+        ]);
+        drop(log);
+        std::fs::remove_file(&path).unwrap();
+
+        let err = SqliteLog::open("/nonexistent/measurements.db").unwrap_err();
+        assert!(err.to_string().contains("/nonexistent/measurements.db"), "{}", err);
+    }
+
+    #[test]
+    fn test_synchronizer_log() {
+        use crate::synchronizer::SntpSynchronizer;
+        use crate::testing::MockServer;
+
+        let path = std::env::temp_dir().join(format!("simple-ntp-sync-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let near = MockServer::builder().offset_nanos(1_000_000_000).start().unwrap();
+        let far = MockServer::builder().offset_nanos(100_000_000_000).start().unwrap();
+        let mut stepper = SntpSynchronizer::builder()
+            .server(&near.addr())
+            .server(&far.addr())
+            .server("127.0.0.1:1")
+            .max_offset(Duration::from_secs(10))
+            .sqlite(SqliteLog::open(&path).unwrap())
+            .stepper()
+            .unwrap();
+        stepper.step();
+        stepper.step();
+
+        // Read while the synchronizer keeps the file open.
+        let rows = rows(&SqliteLog::open(&path).unwrap());
+        let verdicts: Vec<_> = rows.iter().map(|(_, server, _, verdict)| (server.clone(), verdict.as_str())).collect();
+        assert_eq!(verdicts, vec![
+            (far.addr(), "max_offset"),
+            (near.addr(), "selected"),
+            (far.addr(), "max_offset"),
+            (near.addr(), "selected"),
+        ]);
+        assert_eq!((rows[1].2 as f64 / 1e9).round(), 1.0);
+        drop(stepper);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
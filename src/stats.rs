@@ -0,0 +1,385 @@
+//! ntpd-compatible statistics files (`loopstats`, `peerstats`), and a CSV measurement log.
+//!
+//! Lines are written in the same layout ntpd uses, so existing scripts that
+//! parse `/var/log/ntpstats` keep working.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::protocol::{civil_from_days, rfc3339, SECONDS_PER_DAY};
+use crate::synchronizer::LogRecord;
+
+/// Days between 1858-11-17 (the MJD epoch) and 1970-01-01.
+const MJD_UNIX_EPOCH: u64 = 40587;
+
+/// How a statistics file is split over time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    /// Always append to `<dir>/<name>`.
+    Never,
+    /// One file per UTC day, `<dir>/<name>.YYYYMMDD`, like ntpd's `filegen ... type day`.
+    Daily,
+}
+
+/// A statistics file generation set, appending one line per record.
+#[derive(Debug)]
+pub struct FileGen {
+    dir: PathBuf,
+    name: String,
+    rotation: Rotation,
+    current: Option<(PathBuf, File)>,
+}
+
+impl FileGen {
+    /// Create a file set named `name` in `dir`, rotated daily.
+    pub fn new(dir: impl Into<PathBuf>, name: &str) -> Self {
+        FileGen {
+            dir: dir.into(),
+            name: name.to_string(),
+            rotation: Rotation::Daily,
+            current: None,
+        }
+    }
+
+    /// Set the rotation policy.
+    pub fn rotation(mut self, rotation: Rotation) -> Self {
+        self.rotation = rotation;
+        self
+    }
+
+    /// Path of the file a record taken at `now` (since unix epoch) goes to.
+    pub fn path_at(&self, now: Duration) -> PathBuf {
+        match self.rotation {
+            Rotation::Never => self.dir.join(&self.name),
+            Rotation::Daily => {
+                let (y, m, d) = civil_from_days((now.as_secs() / SECONDS_PER_DAY) as i64);
+                self.dir.join(format!("{}.{:04}{:02}{:02}", self.name, y, m, d))
+            }
+        }
+    }
+
+    /// Append `line` to the file for `now`, switching files when the period changes.
+    pub fn write_line(&mut self, now: Duration, line: &str) -> io::Result<()> {
+        let path = self.path_at(now);
+        let reopen = match &self.current {
+            Some((current, _)) => *current != path,
+            None => true,
+        };
+        if reopen {
+            fs::create_dir_all(&self.dir)?;
+            let file = OpenOptions::new().create(true).append(true).open(&path)?;
+            self.current = Some((path, file));
+        }
+
+        let (_, file) = self.current.as_mut().unwrap();
+        writeln!(file, "{}", line)
+    }
+}
+
+/// Rotate a [`CsvLog`] at 10 MiB by default.
+pub const DEFAULT_CSV_MAX_SIZE: u64 = 10 << 20;
+
+/// Rotated CSV files kept by default.
+pub const DEFAULT_CSV_KEEP: usize = 3;
+
+const CSV_HEADER: &str = "time,server,offset_ns,delay_ns,stratum,status";
+
+/// A CSV file of every answer, rotated by size: lighter than a SQLite file or a
+/// metrics stack, for embedded and air-gapped systems.
+///
+/// Rows are `time,server,offset_ns,delay_ns,stratum,status`, the time as an
+/// RFC 3339 UTC datetime and the status a
+/// [`Verdict`](crate::synchronizer::Verdict). When a row would take the file past
+/// its size limit it is renamed to `<path>.1`, the older ones shifted to `<path>.2`
+/// and so on, and a new file is started with the header.
+///
+/// Example
+/// ```rust,no_run
+/// # use simple_ntp::stats::CsvLog;
+/// # use simple_ntp::synchronizer::SntpSynchronizer;
+///
+/// fn main() {
+///     let sync = SntpSynchronizer::builder()
+///         .server("ntp.aliyun.com")
+///         .csv(CsvLog::new("/var/log/sntp/measurements.csv").max_size(1 << 20).keep(2))
+///         .start()
+///         .unwrap();
+/// #   drop(sync);
+/// }
+/// ```
+#[derive(Debug)]
+pub struct CsvLog {
+    path: PathBuf,
+    max_size: u64,
+    keep: usize,
+    /// The open file and its size.
+    current: Option<(File, u64)>,
+}
+
+impl CsvLog {
+    /// Append to `path`, rotated at [`DEFAULT_CSV_MAX_SIZE`] keeping [`DEFAULT_CSV_KEEP`] old files.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        CsvLog {
+            path: path.into(),
+            max_size: DEFAULT_CSV_MAX_SIZE,
+            keep: DEFAULT_CSV_KEEP,
+            current: None,
+        }
+    }
+
+    /// Rotate before the file grows past `bytes`.
+    pub fn max_size(mut self, bytes: u64) -> Self {
+        self.max_size = bytes;
+        self
+    }
+
+    /// Keep `files` rotated files, 0 to delete the file instead of rotating it.
+    pub fn keep(mut self, files: usize) -> Self {
+        self.keep = files;
+        self
+    }
+
+    /// Append a row per record, for the poll round ended at `now` (since unix epoch).
+    pub(crate) fn write(&mut self, now: Duration, records: &[LogRecord]) -> io::Result<()> {
+        let time = rfc3339(now);
+        let mut rows = String::new();
+        for record in records {
+            let server = if record.server.contains([',', '"']) {
+                format!("\"{}\"", record.server.replace('"', "\"\""))
+            } else {
+                record.server.clone()
+            };
+            rows += &format!(
+                "{},{},{},{},{},{}\n",
+                time,
+                server,
+                record.offset_nanos,
+                record.delay_nanos,
+                record.stratum,
+                record.verdict.as_str()
+            );
+        }
+
+        if self.current.is_none() {
+            self.open()?;
+        }
+        let (_, size) = self.current.as_ref().unwrap();
+        if *size > CSV_HEADER.len() as u64 + 1 && size + rows.len() as u64 > self.max_size {
+            self.rotate()?;
+            self.open()?;
+        }
+        let (file, size) = self.current.as_mut().unwrap();
+        file.write_all(rows.as_bytes())?;
+        *size += rows.len() as u64;
+
+        Ok(())
+    }
+
+    /// Open the file for appending, writing the header if it is new.
+    fn open(&mut self) -> io::Result<()> {
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        let mut size = file.metadata()?.len();
+        if size == 0 {
+            writeln!(file, "{}", CSV_HEADER)?;
+            size = CSV_HEADER.len() as u64 + 1;
+        }
+        self.current = Some((file, size));
+
+        Ok(())
+    }
+
+    /// Shift `<path>.N` to `<path>.N+1`, dropping the oldest, and the current file to `<path>.1`.
+    fn rotate(&mut self) -> io::Result<()> {
+        self.current = None;
+        let rotated = |n: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        };
+        if self.keep == 0 {
+            return fs::remove_file(&self.path);
+        }
+        for n in (1..self.keep).rev() {
+            match fs::rename(rotated(n), rotated(n + 1)) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        fs::rename(&self.path, rotated(1))
+    }
+}
+
+/// One `loopstats` record: the state of the clock discipline after an update.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoopStats {
+    /// System clock offset in seconds.
+    pub offset: f64,
+    /// Frequency correction in PPM.
+    pub frequency: f64,
+    /// RMS jitter in seconds.
+    pub jitter: f64,
+    /// Frequency wander in PPM.
+    pub wander: f64,
+    /// Poll interval as a log2 seconds exponent.
+    pub poll: i8,
+}
+
+impl LoopStats {
+    /// Format as an ntpd loopstats line for a record taken at `now`.
+    pub fn to_line(&self, now: Duration) -> String {
+        format!(
+            "{} {:.9} {:.3} {:.9} {:.6} {}",
+            mjd_timestamp(now),
+            self.offset,
+            self.frequency,
+            self.jitter,
+            self.wander,
+            self.poll
+        )
+    }
+}
+
+/// One `peerstats` record: a sample accepted from a single server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerStats {
+    /// Server address.
+    pub addr: SocketAddr,
+    /// ntpd peer status word.
+    pub status: u16,
+    /// Clock offset in seconds.
+    pub offset: f64,
+    /// Round-trip delay in seconds.
+    pub delay: f64,
+    /// Dispersion in seconds.
+    pub dispersion: f64,
+    /// RMS jitter in seconds.
+    pub jitter: f64,
+}
+
+impl PeerStats {
+    /// Format as an ntpd peerstats line for a record taken at `now`.
+    pub fn to_line(&self, now: Duration) -> String {
+        format!(
+            "{} {} {:x} {:.9} {:.9} {:.9} {:.9}",
+            mjd_timestamp(now),
+            self.addr.ip(),
+            self.status,
+            self.offset,
+            self.delay,
+            self.dispersion,
+            self.jitter
+        )
+    }
+}
+
+/// `<MJD> <seconds past UTC midnight>` as used by every ntpd statistics file.
+fn mjd_timestamp(now: Duration) -> String {
+    let secs = now.as_secs();
+    format!(
+        "{} {}.{:03}",
+        secs / SECONDS_PER_DAY + MJD_UNIX_EPOCH,
+        secs % SECONDS_PER_DAY,
+        now.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stats::*;
+
+    #[test]
+    fn test_loopstats_line() {
+        let stats = LoopStats {
+            offset: 0.000123456,
+            frequency: 0.0,
+            jitter: 0.000001,
+            wander: 0.0,
+            poll: 6,
+        };
+        let now = Duration::new(1704067200 + 3723, 500_000_000);
+        assert_eq!(stats.to_line(now), "60310 3723.500 0.000123456 0.000 0.000001000 0.000000 6");
+    }
+
+    #[test]
+    fn test_peerstats_line() {
+        let stats = PeerStats {
+            addr: "192.0.2.1:123".parse().unwrap(),
+            status: 0x961a,
+            offset: -0.5,
+            delay: 0.02,
+            dispersion: 0.001,
+            jitter: 0.0,
+        };
+        let now = Duration::from_secs(1704067200);
+        assert_eq!(
+            stats.to_line(now),
+            "60310 0.000 192.0.2.1 961a -0.500000000 0.020000000 0.001000000 0.000000000"
+        );
+    }
+
+    #[test]
+    fn test_filegen_rotation() {
+        let dir = std::env::temp_dir().join(format!("simple-ntp-stats-{}", std::process::id()));
+        let mut gen = FileGen::new(&dir, "loopstats");
+        let day1 = Duration::from_secs(1704067200);
+        let day2 = day1 + Duration::from_secs(SECONDS_PER_DAY);
+        gen.write_line(day1, "a").unwrap();
+        gen.write_line(day2, "b").unwrap();
+
+        assert_eq!(fs::read_to_string(dir.join("loopstats.20240101")).unwrap(), "a\n");
+        assert_eq!(fs::read_to_string(dir.join("loopstats.20240102")).unwrap(), "b\n");
+        assert_eq!(
+            FileGen::new(&dir, "peerstats").rotation(Rotation::Never).path_at(day1),
+            dir.join("peerstats")
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_csv_log() {
+        use crate::synchronizer::Verdict;
+
+        let record = |server: &str, verdict| LogRecord {
+            server: server.to_string(),
+            #[cfg(feature = "sqlite")]
+            addr: "192.0.2.1:123".parse().unwrap(),
+            offset_nanos: -1_500,
+            delay_nanos: 2_000_000,
+            stratum: 2,
+            verdict,
+        };
+        let dir = std::env::temp_dir().join(format!("simple-ntp-csv-{}", std::process::id()));
+        let path = dir.join("measurements.csv");
+        let rotated = |n: usize| dir.join(format!("measurements.csv.{}", n));
+        let now = Duration::new(1704067200, 5);
+        let header = "time,server,offset_ns,delay_ns,stratum,status\n";
+        let row = "2024-01-01T00:00:00.000000005Z,a,-1500,2000000,2,selected\n";
+
+        let mut log = CsvLog::new(&path).max_size((header.len() + 2 * row.len()) as u64).keep(2);
+        log.write(now, &[record("a", Verdict::Selected), record("a", Verdict::Selected)]).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}{}{}", header, row, row));
+        // Full: rotated to .1, then .1 to .2, and the oldest dropped.
+        for _ in 0..3 {
+            log.write(now, &[record("a", Verdict::Selected)]).unwrap();
+            log.write(now, &[record("a", Verdict::Selected)]).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}{}{}", header, row, row));
+        assert!(fs::metadata(rotated(2)).is_ok());
+        assert!(fs::metadata(rotated(3)).is_err());
+
+        // Appends to an existing file without a second header.
+        let mut log = CsvLog::new(&path).keep(0);
+        log.write(now, &[record("b,c", Verdict::MaxOffset)]).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap().lines().last(),
+            Some("2024-01-01T00:00:00.000000005Z,\"b,c\",-1500,2000000,2,max_offset")
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
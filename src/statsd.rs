@@ -0,0 +1,188 @@
+//! statsd / DogStatsD emitter.
+//!
+//! Sends per-poll offset and delay plus error counts over UDP. Plain statsd has
+//! no tags, so the server name becomes part of the metric name there; with
+//! DogStatsD it is sent as a `server` tag next to the configured ones.
+
+use std::net::{ToSocketAddrs, UdpSocket};
+
+use crate::protocol::NtpError;
+
+/// Wire flavour of the emitted lines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatsdFormat {
+    /// `name:value|type`, tags are dropped.
+    Statsd,
+    /// `name:value|type|#tag:value,...`
+    DogStatsd,
+}
+
+/// Emits poll results to a statsd daemon.
+///
+/// Example
+/// ```rust,no_run
+/// # use simple_ntp::statsd::{StatsdEmitter, StatsdFormat};
+/// # use simple_ntp::synchronizer::SntpSynchronizer;
+///
+/// fn main() {
+///     let statsd = StatsdEmitter::new("127.0.0.1:8125")
+///         .unwrap()
+///         .format(StatsdFormat::DogStatsd)
+///         .tag("env", "prod");
+///     let sync = SntpSynchronizer::builder()
+///         .server("ntp.aliyun.com")
+///         .statsd(statsd)
+///         .start()
+///         .unwrap();
+/// #   drop(sync);
+/// }
+/// ```
+#[derive(Debug)]
+pub struct StatsdEmitter {
+    socket: UdpSocket,
+    prefix: String,
+    format: StatsdFormat,
+    tags: Vec<String>,
+}
+
+impl StatsdEmitter {
+    /// Emit to the statsd daemon at `addr`, with prefix `sntp` in plain statsd format.
+    pub fn new(addr: impl ToSocketAddrs) -> Result<Self, NtpError> {
+        let socket = UdpSocket::bind("0.0.0.0:0").map_err(|err| {
+            NtpError::ServiceUnavailable(err.to_string())
+        })?;
+        socket.connect(addr).map_err(|err| {
+            NtpError::BadNtpServerAddr(err.to_string())
+        })?;
+
+        Ok(StatsdEmitter {
+            socket,
+            prefix: "sntp".to_string(),
+            format: StatsdFormat::Statsd,
+            tags: Vec::new(),
+        })
+    }
+
+    /// Set the metric name prefix.
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.to_string();
+        self
+    }
+
+    /// Set the wire format.
+    pub fn format(mut self, format: StatsdFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Add a tag sent with every metric (DogStatsD only).
+    pub fn tag(mut self, key: &str, value: &str) -> Self {
+        self.tags.push(format!("{}:{}", key, value));
+        self
+    }
+
+    /// Emit `offset` (gauge) and `delay` (timer), both in milliseconds.
+    pub(crate) fn record_poll(&self, server: &str, offset_nanos: i64, delay_nanos: i64) {
+        let lines = [
+            self.line(server, "offset", &format!("{}", offset_nanos as f64 / 1e6), "g", None),
+            self.line(server, "delay", &format!("{}", delay_nanos as f64 / 1e6), "ms", None),
+        ];
+        self.send(&lines.join("\n"));
+    }
+
+    /// Count one failed poll, tagged by error kind.
+    pub(crate) fn record_error(&self, server: &str, err: &NtpError) {
+        let line = self.line(server, "errors", "1", "c", Some(error_kind(err)));
+        self.send(&line);
+    }
+
+    fn line(&self, server: &str, name: &str, value: &str, kind: &str, error: Option<&str>) -> String {
+        match self.format {
+            StatsdFormat::Statsd => {
+                let mut line = format!("{}.{}.{}", self.prefix, sanitize(server), name);
+                if let Some(error) = error {
+                    line = line + "." + error;
+                }
+                format!("{}:{}|{}", line, value, kind)
+            }
+            StatsdFormat::DogStatsd => {
+                let mut tags = self.tags.clone();
+                tags.push(format!("server:{}", server));
+                if let Some(error) = error {
+                    tags.push(format!("error:{}", error));
+                }
+                format!("{}.{}:{}|{}|#{}", self.prefix, name, value, kind, tags.join(","))
+            }
+        }
+    }
+
+    fn send(&self, payload: &str) {
+        // Metrics are fire-and-forget, a missing statsd daemon must not fail a poll.
+        if let Err(err) = self.socket.send(payload.as_bytes()) {
+            debug!("statsd send failed: {}", err);
+        }
+    }
+}
+
+fn error_kind(err: &NtpError) -> &'static str {
+    match err {
+        NtpError::ServiceUnavailable(_) => "unavailable",
+        NtpError::BadNtpServerAddr(_) => "bad_addr",
+        NtpError::UnexpectedErr(_) => "unexpected",
+        NtpError::TruncatedNtpMessage => "truncated",
+        NtpError::UntrustedMessage => "untrusted",
+        NtpError::BadConfig(_) => "bad_config",
+        NtpError::ResolveTimeout(_) => "resolve_timeout",
+        NtpError::KissOfDeath(_) => "kiss_of_death",
+    }
+}
+
+/// statsd uses `.` as the hierarchy separator and `:`/`|` as field separators.
+fn sanitize(server: &str) -> String {
+    server.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::statsd::*;
+
+    fn receive(daemon: &UdpSocket) -> String {
+        let mut buf = [0u8; 512];
+        let n = daemon.recv(&mut buf).unwrap();
+        String::from_utf8_lossy(&buf[..n]).to_string()
+    }
+
+    #[test]
+    fn test_statsd() {
+        let daemon = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let emitter = StatsdEmitter::new(daemon.local_addr().unwrap()).unwrap().tag("env", "test");
+
+        emitter.record_poll("ntp.aliyun.com:123", 1_500_000, 20_000_000);
+        assert_eq!(
+            receive(&daemon),
+            "sntp.ntp_aliyun_com_123.offset:1.5|g\nsntp.ntp_aliyun_com_123.delay:20|ms"
+        );
+        emitter.record_error("ntp.aliyun.com", &NtpError::UntrustedMessage);
+        assert_eq!(receive(&daemon), "sntp.ntp_aliyun_com.errors.untrusted:1|c");
+    }
+
+    #[test]
+    fn test_dogstatsd() {
+        let daemon = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let emitter = StatsdEmitter::new(daemon.local_addr().unwrap())
+            .unwrap()
+            .prefix("ntp")
+            .format(StatsdFormat::DogStatsd)
+            .tag("env", "test");
+
+        emitter.record_poll("ntp.aliyun.com", -2_000_000, 10_000_000);
+        assert_eq!(
+            receive(&daemon),
+            "ntp.offset:-2|g|#env:test,server:ntp.aliyun.com\nntp.delay:10|ms|#env:test,server:ntp.aliyun.com"
+        );
+        emitter.record_error("ntp.aliyun.com", &NtpError::TruncatedNtpMessage);
+        assert_eq!(receive(&daemon), "ntp.errors:1|c|#env:test,server:ntp.aliyun.com,error:truncated");
+    }
+}
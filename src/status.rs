@@ -0,0 +1,392 @@
+//! Status of a running synchronizer, for health checks and quick debugging.
+//!
+//! [`serve_http`] answers `GET /status` with the [`json`] document, with a
+//! `200 OK` status while synchronized and `503 Service Unavailable` before the
+//! first server answered, so load balancers can use it as a health check:
+//!
+//! ```text
+//! $ curl -s localhost:8123/status
+//! {"state":"synchronized","offset":0.000052,"selected":{"addr":"162.159.200.1:123",...},"sources":[...]}
+//! ```
+//!
+//! [`tracking`] reports the same fields as `chronyc tracking`, and prints in
+//! its layout so existing parsers keep working.
+
+use std::fmt::{self, Write};
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+
+use crate::http;
+use crate::protocol::{civil_from_days, NtpError, PHI};
+use crate::server::refid_of;
+use crate::synchronizer::{SyncHandle, SyncState};
+
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// A running status endpoint. The listener lives as long as the process.
+#[derive(Debug)]
+pub struct HttpStatus {
+    local_addr: SocketAddr,
+}
+
+impl HttpStatus {
+    /// Address the HTTP endpoint is bound to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+/// Serve the status of `sync` on `addr`.
+///
+/// Example
+/// ```rust,no_run
+/// # use simple_ntp::status;
+/// # use simple_ntp::synchronizer::SntpSynchronizer;
+///
+/// fn main() {
+///     let sync = SntpSynchronizer::builder().server("ntp.aliyun.com").start().unwrap();
+///     let endpoint = status::serve_http("127.0.0.1:8123", sync.handle()).unwrap();
+///     println!("status on http://{}/status", endpoint.local_addr());
+/// }
+/// ```
+pub fn serve_http(addr: impl ToSocketAddrs, sync: SyncHandle) -> Result<HttpStatus, NtpError> {
+    let listener = TcpListener::bind(addr).map_err(|err| {
+        NtpError::ServiceUnavailable(err.to_string())
+    })?;
+    let local_addr = listener.local_addr().map_err(|err| {
+        NtpError::UnexpectedErr(err.to_string())
+    })?;
+
+    thread::Builder::new()
+        .name("sntp-status".to_string())
+        .spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let result = http::respond(stream, |method, path| match (method, path) {
+                    ("GET", "/status") => {
+                        let status = if sync.selected().is_some() { "200 OK" } else { "503 Service Unavailable" };
+                        (status, "application/json", json(&sync))
+                    }
+                    _ => ("404 Not Found", "text/plain", String::new()),
+                });
+                if let Err(err) = result {
+                    debug!("status request failed: {}", err);
+                }
+            }
+        })
+        .map_err(|err| {
+            NtpError::UnexpectedErr(err.to_string())
+        })?;
+
+    Ok(HttpStatus { local_addr })
+}
+
+/// The state, current offset, selected sample and per-server state of `sync` as
+/// a JSON object. Offsets, delays and jitter are in seconds, `last_sync` is the
+/// age of the selected sample in seconds and `consecutive_failures` counts the
+/// poll rounds without an answer since.
+pub fn json(sync: &SyncHandle) -> String {
+    let mut out = String::from("{");
+    match sync.selected() {
+        Some((result, selected_at)) => {
+            let _ = write!(
+                out,
+                r#""state":"{}","offset":{},"selected":{{"addr":"{}","stratum":{},"refid":{},"delay":{},"root_delay":{},"root_dispersion":{},"last_sync":{}}}"#,
+                match sync.state() {
+                    SyncState::Degraded => "degraded",
+                    _ => "synchronized",
+                },
+                result.offset_nanos as f64 / 1e9,
+                result.addr,
+                result.stratum,
+                string(&result.refid()),
+                result.delay_nanos as f64 / 1e9,
+                result.root_delay.as_secs_f64(),
+                result.root_dispersion.as_secs_f64(),
+                sync.local_now().saturating_sub(selected_at).as_secs()
+            );
+        }
+        None => out += r#""state":"unsynchronized","offset":null,"selected":null"#,
+    }
+
+    let _ = write!(out, r#","sources":{},"consecutive_failures":{}}}"#, sources_json(sync), sync.last_sync().consecutive_failures);
+    out
+}
+
+/// The per-server state of [`json`], as a JSON array.
+pub(crate) fn sources_json(sync: &SyncHandle) -> String {
+    let mut out = String::from("[");
+    for (i, source) in sync.sources().iter().enumerate() {
+        let offset = source.offset_nanos.map_or("null".to_string(), |offset| (offset as f64 / 1e9).to_string());
+        let _ = write!(
+            out,
+            r#"{}{{"server":{},"reach":{},"offset":{},"jitter":{},"selected":{},"score":{},"demoted":{}}}"#,
+            if i == 0 { "" } else { "," },
+            string(&source.server),
+            source.reach,
+            offset,
+            source.jitter_nanos / 1e9,
+            source.selected,
+            source.score,
+            source.demoted
+        );
+    }
+    out.push(']');
+    out
+}
+
+/// The fields of `chronyc tracking`, see [`tracking`]. Offsets follow chrony's
+/// sign: positive when the local clock is ahead.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tracking {
+    /// Reference ID we would serve: the selected server's IPv4 address, 0 if unsynchronized.
+    pub reference_id: u32,
+    /// The selected server as configured, empty if unsynchronized.
+    pub reference_name: String,
+    /// Our stratum, one more than the selected server's.
+    pub stratum: u8,
+    /// When the selected sample was taken, since the unix epoch.
+    pub reference_time: Duration,
+    /// Error of the local clock in nano seconds. The clock is not stepped or
+    /// slewed, so this is the last offset.
+    pub system_time_nanos: i64,
+    /// Last selected offset in nano seconds.
+    pub last_offset_nanos: i64,
+    /// RMS of the recent offsets in nano seconds.
+    pub rms_offset_nanos: f64,
+    /// Frequency error of the local clock in ppm, positive when it runs fast.
+    pub frequency_ppm: f64,
+    /// Frequency change not yet applied, always 0 as the clock is not disciplined.
+    pub residual_freq_ppm: f64,
+    /// Error bound of `frequency_ppm`.
+    pub skew_ppm: f64,
+    /// Root delay in seconds.
+    pub root_delay: f64,
+    /// Root dispersion in seconds, growing with the age of the selected sample.
+    pub root_dispersion: f64,
+    /// Mean time between the recent selections.
+    pub update_interval: Duration,
+    pub synchronized: bool,
+}
+
+/// The state of `sync` as `chronyc tracking` reports it.
+///
+/// Example
+/// ```rust,no_run
+/// # use simple_ntp::status;
+/// # use simple_ntp::synchronizer::SntpSynchronizer;
+///
+/// fn main() {
+///     let sync = SntpSynchronizer::builder().server("ntp.aliyun.com").start().unwrap();
+///     print!("{}", status::tracking(&sync.handle()));
+/// }
+/// ```
+pub fn tracking(sync: &SyncHandle) -> Tracking {
+    let stats = sync.system_stats();
+    let mut tracking = Tracking {
+        reference_id: 0,
+        reference_name: String::new(),
+        stratum: 0,
+        reference_time: Duration::ZERO,
+        system_time_nanos: 0,
+        last_offset_nanos: 0,
+        rms_offset_nanos: stats.rms_offset_nanos,
+        frequency_ppm: stats.frequency_ppm,
+        residual_freq_ppm: 0.0,
+        skew_ppm: stats.skew_ppm,
+        root_delay: 0.0,
+        root_dispersion: 0.0,
+        update_interval: stats.update_interval,
+        synchronized: false,
+    };
+    let Some((result, selected_at)) = sync.selected() else {
+        return tracking;
+    };
+
+    let age = sync.local_now().saturating_sub(selected_at).as_secs_f64();
+    tracking.reference_id = refid_of(result.addr.ip());
+    tracking.reference_name = sync.sources()
+        .into_iter()
+        .find(|source| source.selected)
+        .map_or(result.addr.ip().to_string(), |source| source.server);
+    tracking.stratum = result.stratum.saturating_add(1);
+    tracking.reference_time = selected_at;
+    tracking.system_time_nanos = -result.offset_nanos;
+    tracking.last_offset_nanos = -result.offset_nanos;
+    tracking.root_delay = result.root_delay.as_secs_f64() + result.delay_nanos.max(0) as f64 / 1e9;
+    tracking.root_dispersion = result.root_dispersion.as_secs_f64() + PHI * age;
+    tracking.synchronized = true;
+    tracking
+}
+
+impl Tracking {
+    /// The fields as a JSON object, offsets, delays and times in seconds.
+    pub fn json(&self) -> String {
+        format!(
+            r#"{{"reference_id":{},"reference_name":{},"stratum":{},"reference_time":{},"system_time":{},"last_offset":{},"rms_offset":{},"frequency_ppm":{},"residual_freq_ppm":{},"skew_ppm":{},"root_delay":{},"root_dispersion":{},"update_interval":{},"synchronized":{}}}"#,
+            self.reference_id,
+            string(&self.reference_name),
+            self.stratum,
+            self.reference_time.as_secs_f64(),
+            self.system_time_nanos as f64 / 1e9,
+            self.last_offset_nanos as f64 / 1e9,
+            self.rms_offset_nanos / 1e9,
+            self.frequency_ppm,
+            self.residual_freq_ppm,
+            self.skew_ppm,
+            self.root_delay,
+            self.root_dispersion,
+            self.update_interval.as_secs_f64(),
+            self.synchronized
+        )
+    }
+}
+
+impl fmt::Display for Tracking {
+    /// The `chronyc tracking` layout.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.reference_time.as_secs();
+        let days = (secs / 86400) as i64;
+        let (year, month, day) = civil_from_days(days);
+        let slow_or_fast = |value: f64| if value < 0.0 { "slow" } else { "fast" };
+
+        writeln!(f, "Reference ID    : {:08X} ({})", self.reference_id, self.reference_name)?;
+        writeln!(f, "Stratum         : {}", self.stratum)?;
+        writeln!(
+            f,
+            "Ref time (UTC)  : {} {} {:02} {:02}:{:02}:{:02} {}",
+            WEEKDAYS[days.rem_euclid(7) as usize],
+            MONTHS[month as usize - 1],
+            day,
+            secs % 86400 / 3600,
+            secs % 3600 / 60,
+            secs % 60,
+            year
+        )?;
+        let system_time = self.system_time_nanos as f64 / 1e9;
+        writeln!(f, "System time     : {:.9} seconds {} of NTP time", system_time.abs(), slow_or_fast(system_time))?;
+        writeln!(f, "Last offset     : {:+.9} seconds", self.last_offset_nanos as f64 / 1e9)?;
+        writeln!(f, "RMS offset      : {:.9} seconds", self.rms_offset_nanos / 1e9)?;
+        writeln!(f, "Frequency       : {:.3} ppm {}", self.frequency_ppm.abs(), slow_or_fast(self.frequency_ppm))?;
+        writeln!(f, "Residual freq   : {:+.3} ppm", self.residual_freq_ppm)?;
+        writeln!(f, "Skew            : {:.3} ppm", self.skew_ppm)?;
+        writeln!(f, "Root delay      : {:.9} seconds", self.root_delay)?;
+        writeln!(f, "Root dispersion : {:.9} seconds", self.root_dispersion)?;
+        writeln!(f, "Update interval : {:.1} seconds", self.update_interval.as_secs_f64())?;
+        writeln!(f, "Leap status     : {}", if self.synchronized { "Normal" } else { "Not synchronised" })
+    }
+}
+
+/// A JSON string literal.
+fn string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::NtpServer;
+    use crate::status::*;
+    use crate::synchronizer::SntpSynchronizer;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::time::Duration;
+
+    fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_serve_http() {
+        let server = NtpServer::builder().bind("127.0.0.1:0").build().unwrap().spawn().unwrap();
+        let sync = SntpSynchronizer::builder()
+            .server("127.0.0.1:1")
+            .interval(Duration::from_secs(3600))
+            .start()
+            .unwrap();
+        let endpoint = serve_http("127.0.0.1:0", sync.handle()).unwrap();
+
+        let response = get(endpoint.local_addr(), "/status");
+        assert!(response.starts_with("HTTP/1.1 503"), "{}", response);
+        assert!(response.contains(r#"{"state":"unsynchronized","offset":null,"selected":null,"sources":["#));
+        assert!(response.contains(r#"],"consecutive_failures":"#));
+        assert!(get(endpoint.local_addr(), "/").starts_with("HTTP/1.1 404"));
+
+        sync.reconfigure(&[server.local_addr().to_string()], Duration::from_secs(3600)).unwrap();
+        for _ in 0..50 {
+            if sync.offset_nanos().is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        let response = get(endpoint.local_addr(), "/status?verbose");
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.contains(r#""state":"synchronized""#));
+        assert!(response.contains(&format!(r#""addr":"{}","stratum":10"#, server.local_addr())));
+        assert!(response.contains(&format!(r#"{{"server":"{}","reach":1,"#, server.local_addr())));
+        assert!(response.contains(r#""selected":true,"score":1,"demoted":false}"#));
+
+        let tracking = tracking(&sync.handle());
+        assert_eq!((tracking.stratum, tracking.reference_id), (11, 0x7f000001));
+        assert_eq!(tracking.reference_name, server.local_addr().to_string());
+    }
+
+    #[test]
+    fn test_tracking() {
+        let tracking = Tracking {
+            reference_id: 0xC0000201,
+            reference_name: "time.example".to_string(),
+            stratum: 3,
+            reference_time: Duration::from_secs(1_700_000_000),
+            system_time_nanos: -1_234,
+            last_offset_nanos: -1_234,
+            rms_offset_nanos: 5_000.0,
+            frequency_ppm: -8.9174,
+            residual_freq_ppm: 0.0,
+            skew_ppm: 0.0123,
+            root_delay: 0.0107,
+            root_dispersion: 0.000545,
+            update_interval: Duration::from_millis(64_500),
+            synchronized: true,
+        };
+        assert_eq!(
+            tracking.to_string(),
+            "Reference ID    : C0000201 (time.example)\n\
+             Stratum         : 3\n\
+             Ref time (UTC)  : Tue Nov 14 22:13:20 2023\n\
+             System time     : 0.000001234 seconds slow of NTP time\n\
+             Last offset     : -0.000001234 seconds\n\
+             RMS offset      : 0.000005000 seconds\n\
+             Frequency       : 8.917 ppm slow\n\
+             Residual freq   : +0.000 ppm\n\
+             Skew            : 0.012 ppm\n\
+             Root delay      : 0.010700000 seconds\n\
+             Root dispersion : 0.000545000 seconds\n\
+             Update interval : 64.5 seconds\n\
+             Leap status     : Normal\n"
+        );
+        assert!(tracking.json().starts_with(r#"{"reference_id":3221225985,"reference_name":"time.example","stratum":3,"#));
+        assert!(tracking.json().ends_with(r#""update_interval":64.5,"synchronized":true}"#));
+    }
+
+    #[test]
+    fn test_string() {
+        assert_eq!(string("a\"b\\c\n"), r#""a\"b\\c\u000a""#);
+    }
+}
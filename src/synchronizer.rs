@@ -0,0 +1,1758 @@
+//! Background clock synchronization.
+//!
+//! A [`SntpSynchronizer`] polls its configured servers from a worker thread and
+//! keeps the offset of the best (lowest root distance) server of each round.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::client::{shift, AuditRecord, CancelToken, Exchange, NtpResult, SntpClient, Transport, MIN_OUTLIER_DISTANCE};
+use crate::constraint::Constraints;
+use crate::diag;
+use crate::otel;
+use crate::protocol::{NtpError, PollInterval, ShortFormat, PHI};
+#[cfg(feature = "sqlite")]
+use crate::sqlite::SqliteLog;
+use crate::statsd::StatsdEmitter;
+use crate::stats::{CsvLog, FileGen, LoopStats, PeerStats};
+use crate::timesource::{self, TimeSource};
+
+/// Number of offsets kept per server for jitter, like ntpd's clock filter.
+const FILTER_SIZE: usize = 8;
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(64);
+
+/// Measurements kept by default, see [`SynchronizerBuilder::history`].
+pub const DEFAULT_HISTORY: usize = 1024;
+
+/// How often the worker re-reads a manual time source while waiting for the next poll.
+const MANUAL_TICK: Duration = Duration::from_millis(5);
+
+/// Good answers of the last 8 polls below which a server is demoted, see [`Source::demoted`].
+const MIN_SCORE: u32 = 4;
+
+/// Latest polls that must all be good to promote a demoted server back.
+const PROBATION: u8 = 0b1111;
+
+/// Least error of an offset in falseticker detection, like ntpd's MINDISP.
+const MIN_DISPERSION_NANOS: i64 = 10_000_000;
+
+/// ntpd peer status words: configured + reachable, selected as sys.peer or candidate.
+const STATUS_SYS_PEER: u16 = 0x961a;
+const STATUS_CANDIDATE: u16 = 0x9414;
+
+/// Called with the audit record of every exchange.
+type AuditHook = Box<dyn Fn(&AuditRecord) + Send>;
+
+/// Called with every [`SyncEvent`].
+type EventHook = Box<dyn Fn(&SyncEvent) + Send>;
+
+/// Samples near a jumped offset needed to accept it by default, see [`SynchronizerBuilder::panic_threshold`].
+pub const DEFAULT_PANIC_CONFIRMATIONS: usize = 3;
+
+/// Smoothing of the selected offsets by default, see [`SynchronizerBuilder::smoothing`].
+pub const DEFAULT_SMOOTHING: Smoothing = Smoothing::Ema { alpha: 0.25 };
+
+/// Reaches a server instead of a socket to its resolved address.
+type BoxedTransport = Box<dyn Transport + Send>;
+
+/// Configure and start a [`SntpSynchronizer`].
+pub struct SynchronizerBuilder {
+    servers: Vec<(String, SntpClient)>,
+    interval: Duration,
+    max_offset: Option<Duration>,
+    trust_first_sync: bool,
+    panic_threshold: Option<Duration>,
+    panic_confirmations: usize,
+    constraints: Option<Constraints>,
+    smoothing: Smoothing,
+    history: usize,
+    loopstats: Option<FileGen>,
+    peerstats: Option<FileGen>,
+    statsd: Option<StatsdEmitter>,
+    #[cfg(feature = "sqlite")]
+    sqlite: Option<SqliteLog>,
+    csv: Option<CsvLog>,
+    audit: Option<AuditHook>,
+    on_event: Option<EventHook>,
+    time: Arc<dyn TimeSource>,
+    transports: Vec<(String, BoxedTransport)>,
+}
+
+impl SynchronizerBuilder {
+    /// Add a server to poll, `host` or `host:port`.
+    pub fn server(mut self, server: &str) -> Self {
+        self.servers.push((server.to_string(), SntpClient::default()));
+        self
+    }
+
+    /// Add a server polled with `client`, e.g. with a shorter timeout or only over IPv4.
+    pub fn server_with(mut self, server: &str, client: SntpClient) -> Self {
+        self.servers.push((server.to_string(), client));
+        self
+    }
+
+    /// Add a server reached over `transport`, e.g. a simulated one.
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn server_via(mut self, server: &str, client: SntpClient, transport: BoxedTransport) -> Self {
+        self.servers.push((server.to_string(), client));
+        self.transports.push((server.to_string(), transport));
+        self
+    }
+
+    /// Add the servers offered by DHCP, see [`dhcp::ntp_servers`](crate::dhcp::ntp_servers).
+    /// Adds none if no lease has any.
+    #[cfg(target_os = "linux")]
+    pub fn dhcp_servers(mut self) -> Self {
+        for server in crate::dhcp::ntp_servers() {
+            self = self.server(&server);
+        }
+        self
+    }
+
+    /// Set the poll interval, 64 seconds by default.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Ignore samples further than `max_offset` from the local clock, like ntpd's panic
+    /// threshold. Unlimited by default.
+    pub fn max_offset(mut self, max_offset: Duration) -> Self {
+        self.max_offset = Some(max_offset);
+        self
+    }
+
+    /// Accept samples whatever their offset until one is selected, like ntpd's `-g`,
+    /// so a wildly wrong clock at boot can be corrected once; the
+    /// [`max_offset`](Self::max_offset) applies from then on.
+    pub fn trust_first_sync(mut self) -> Self {
+        self.trust_first_sync = true;
+        self
+    }
+
+    /// Hold back a selected offset more than `threshold` away from the current one,
+    /// e.g. from a hijacked or broken server, marking the synchronizer
+    /// [`Degraded`](SyncState::Degraded), until [`panic_confirmations`](Self::panic_confirmations)
+    /// more samples corroborate it. Unlimited by default.
+    pub fn panic_threshold(mut self, threshold: Duration) -> Self {
+        self.panic_threshold = Some(threshold);
+        self
+    }
+
+    /// Samples near a jumped offset, after the jump, needed to accept it,
+    /// [`DEFAULT_PANIC_CONFIRMATIONS`] by default.
+    pub fn panic_confirmations(mut self, samples: usize) -> Self {
+        self.panic_confirmations = samples;
+        self
+    }
+
+    /// Ignore samples outside `constraints`, fetched before the first poll and
+    /// refreshed as configured. Until a constraint is known every sample is ignored.
+    pub fn constraints(mut self, constraints: Constraints) -> Self {
+        self.constraints = Some(constraints);
+        self
+    }
+
+    /// Smooth the selected offsets, reported by
+    /// [`smoothed_offset_nanos`](SntpSynchronizer::smoothed_offset_nanos) next to the raw ones
+    /// and used by [`SyncHandle::now`]. [`DEFAULT_SMOOTHING`] by default; `Ema { alpha: 1.0 }`
+    /// follows the raw offsets. Either way an offset more than 4 times the usual spread, and at
+    /// least 10 ms, from the estimate is skipped, unless 3 in a row are: the clock then moved.
+    pub fn smoothing(mut self, smoothing: Smoothing) -> Self {
+        self.smoothing = smoothing;
+        self
+    }
+
+    /// Keep the latest `capacity` measurements of all servers, see
+    /// [`SyncHandle::history`]. [`DEFAULT_HISTORY`] by default, 0 keeps none.
+    pub fn history(mut self, capacity: usize) -> Self {
+        self.history = capacity;
+        self
+    }
+
+    /// Write an ntpd `loopstats` line after every poll round.
+    pub fn loopstats(mut self, gen: FileGen) -> Self {
+        self.loopstats = Some(gen);
+        self
+    }
+
+    /// Write an ntpd `peerstats` line for every sample received.
+    pub fn peerstats(mut self, gen: FileGen) -> Self {
+        self.peerstats = Some(gen);
+        self
+    }
+
+    /// Emit every poll's offset, delay and errors to statsd.
+    pub fn statsd(mut self, emitter: StatsdEmitter) -> Self {
+        self.statsd = Some(emitter);
+        self
+    }
+
+    /// Log every answer, with its server, offset, delay, stratum and verdict, to a SQLite file.
+    #[cfg(feature = "sqlite")]
+    pub fn sqlite(mut self, log: SqliteLog) -> Self {
+        self.sqlite = Some(log);
+        self
+    }
+
+    /// Append every answer, with its server, offset, delay, stratum and verdict, to a CSV file.
+    pub fn csv(mut self, log: CsvLog) -> Self {
+        self.csv = Some(log);
+        self
+    }
+
+    /// Call `hook` with the audit record of every exchange, successful or not.
+    pub fn audit(mut self, hook: impl Fn(&AuditRecord) + Send + 'static) -> Self {
+        self.audit = Some(Box::new(hook));
+        self
+    }
+
+    /// Call `hook` with every [`SyncEvent`], e.g. to alert on a held back offset jump.
+    pub fn on_event(mut self, hook: impl Fn(&SyncEvent) + Send + 'static) -> Self {
+        self.on_event = Some(Box::new(hook));
+        self
+    }
+
+    /// Time selections and schedule polls by `time` instead of the system
+    /// clock. The servers' clients keep their own, see
+    /// [`SntpClientBuilder::time_source`](crate::client::SntpClientBuilder::time_source).
+    pub fn time_source(mut self, time: Arc<dyn TimeSource>) -> Self {
+        self.time = time;
+        self
+    }
+
+    /// Spawn the worker thread. The first poll round starts immediately.
+    pub fn start(self) -> Result<SntpSynchronizer, NtpError> {
+        let worker = self.worker()?;
+        let shared = worker.shared.clone();
+        let handle = thread::Builder::new()
+            .name("sntp-synchronizer".to_string())
+            .spawn(move || worker.run())
+            .map_err(|err| {
+                NtpError::UnexpectedErr(err.to_string())
+            })?;
+
+        Ok(SntpSynchronizer {
+            shared,
+            worker: Some(handle),
+        })
+    }
+
+    /// Run poll rounds in the caller's thread, one per [`Stepper::step`],
+    /// instead of on a schedule.
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn stepper(self) -> Result<Stepper, NtpError> {
+        Ok(Stepper { worker: self.worker()? })
+    }
+
+    fn worker(self) -> Result<Worker, NtpError> {
+        if self.servers.is_empty() {
+            return Err(NtpError::BadNtpServerAddr("no ntp server configured".to_string()));
+        }
+        self.smoothing.validate()?;
+
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                running: true,
+                offset_nanos: None,
+                smoothed_offset_nanos: None,
+                error_nanos: 0,
+                selected: None,
+                history: VecDeque::new(),
+                history_capacity: self.history,
+                last_sync: None,
+                consecutive_failures: 0,
+                degraded: false,
+                max_offset: self.max_offset,
+                servers: self.servers.clone(),
+                interval: self.interval,
+                changed: false,
+                poll_now: false,
+                sources: Vec::new(),
+                system: SystemStats::default(),
+            }),
+            wakeup: Condvar::new(),
+            time: self.time.clone(),
+            cancel: CancelToken::new(),
+        });
+        let mut transports = self.transports;
+        let peers = self.servers.into_iter()
+            .map(|(server, client)| {
+                let transport = transports.iter()
+                    .position(|(name, _)| *name == server)
+                    .map(|i| transports.swap_remove(i).1);
+                Peer { transport, ..Peer::new(server, client) }
+            })
+            .collect();
+
+        Ok(Worker {
+            peers,
+            interval: self.interval,
+            trust_first_sync: self.trust_first_sync,
+            panic_threshold: self.panic_threshold,
+            panic_confirmations: self.panic_confirmations,
+            jump: None,
+            constraints: self.constraints,
+            smoother: Smoother::new(self.smoothing),
+            loopstats: self.loopstats,
+            peerstats: self.peerstats,
+            statsd: self.statsd,
+            #[cfg(feature = "sqlite")]
+            sqlite: self.sqlite,
+            csv: self.csv,
+            audit: self.audit,
+            on_event: self.on_event,
+            time: self.time,
+            system_offsets: VecDeque::with_capacity(FILTER_SIZE),
+            system_times: VecDeque::with_capacity(FILTER_SIZE),
+            shared,
+        })
+    }
+}
+
+/// A synchronizer without a worker thread, polling when told to.
+#[cfg(any(test, feature = "testing"))]
+pub(crate) struct Stepper {
+    worker: Worker,
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl Stepper {
+    /// Run one poll round.
+    pub(crate) fn step(&mut self) {
+        self.worker.poll();
+    }
+
+    pub(crate) fn handle(&self) -> SyncHandle {
+        SyncHandle { shared: self.worker.shared.clone() }
+    }
+}
+
+/// Keeps the system clock offset up to date in the background.
+///
+/// Example
+/// ```rust,no_run
+/// # use simple_ntp::synchronizer::SntpSynchronizer;
+/// # use simple_ntp::stats::FileGen;
+///
+/// fn main() {
+///     let sync = SntpSynchronizer::builder()
+///         .server("ntp.aliyun.com")
+///         .server("time.cloudflare.com")
+///         .loopstats(FileGen::new("/var/log/ntpstats", "loopstats"))
+///         .peerstats(FileGen::new("/var/log/ntpstats", "peerstats"))
+///         .start()
+///         .unwrap();
+///     println!("{:?}", sync.offset_nanos());
+/// }
+/// ```
+#[derive(Debug)]
+pub struct SntpSynchronizer {
+    shared: Arc<Shared>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl SntpSynchronizer {
+    pub fn builder() -> SynchronizerBuilder {
+        SynchronizerBuilder {
+            servers: Vec::new(),
+            interval: DEFAULT_INTERVAL,
+            max_offset: None,
+            trust_first_sync: false,
+            panic_threshold: None,
+            panic_confirmations: DEFAULT_PANIC_CONFIRMATIONS,
+            constraints: None,
+            smoothing: DEFAULT_SMOOTHING,
+            history: DEFAULT_HISTORY,
+            loopstats: None,
+            peerstats: None,
+            statsd: None,
+            #[cfg(feature = "sqlite")]
+            sqlite: None,
+            csv: None,
+            audit: None,
+            on_event: None,
+            time: timesource::system(),
+            transports: Vec::new(),
+        }
+    }
+
+    /// Latest system clock offset in nano seconds, remote timestamp sub local timestamp.
+    /// `None` until a server has answered.
+    pub fn offset_nanos(&self) -> Option<i64> {
+        self.shared.state.lock().unwrap().offset_nanos
+    }
+
+    /// The offset smoothed as set by [`SynchronizerBuilder::smoothing`], in nano
+    /// seconds. `None` until a server has answered.
+    pub fn smoothed_offset_nanos(&self) -> Option<i64> {
+        self.shared.state.lock().unwrap().smoothed_offset_nanos
+    }
+
+    /// The sample the current offset was taken from, and when (since unix epoch) it was selected.
+    pub fn selected(&self) -> Option<(NtpResult, Duration)> {
+        self.shared.state.lock().unwrap().selected.clone()
+    }
+
+    /// See [`SyncHandle::now`].
+    pub fn now(&self) -> Option<CorrectedTime> {
+        self.handle().now()
+    }
+
+    /// See [`SyncHandle::last_sync`].
+    pub fn last_sync(&self) -> LastSync {
+        self.handle().last_sync()
+    }
+
+    /// A cloneable handle to this synchronizer, usable after it has been moved
+    /// elsewhere, e.g. into an [`NtpServer`](crate::server::NtpServer).
+    pub fn handle(&self) -> SyncHandle {
+        SyncHandle { shared: self.shared.clone() }
+    }
+
+    /// See [`SyncHandle::reconfigure`].
+    pub fn reconfigure(&self, servers: &[String], interval: Duration) -> Result<(), NtpError> {
+        self.handle().reconfigure(servers, interval)
+    }
+
+    /// Stop polling, giving up an exchange in flight, and wait for the worker thread to exit.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.shared.state.lock().unwrap().running = false;
+        self.shared.cancel.cancel();
+        self.shared.wakeup.notify_all();
+        if let Some(handle) = self.worker.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for SntpSynchronizer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Shared handle to a [`SntpSynchronizer`], see [`SntpSynchronizer::handle`].
+///
+/// Only the owner can stop the synchronizer; a handle keeps reporting the last
+/// values after that.
+#[derive(Debug, Clone)]
+pub struct SyncHandle {
+    shared: Arc<Shared>,
+}
+
+impl SyncHandle {
+    /// See [`SntpSynchronizer::offset_nanos`].
+    pub fn offset_nanos(&self) -> Option<i64> {
+        self.shared.state.lock().unwrap().offset_nanos
+    }
+
+    /// See [`SntpSynchronizer::smoothed_offset_nanos`].
+    pub fn smoothed_offset_nanos(&self) -> Option<i64> {
+        self.shared.state.lock().unwrap().smoothed_offset_nanos
+    }
+
+    /// See [`SntpSynchronizer::selected`].
+    pub fn selected(&self) -> Option<(NtpResult, Duration)> {
+        self.shared.state.lock().unwrap().selected.clone()
+    }
+
+    /// Whether an offset is known, and trusted.
+    pub fn state(&self) -> SyncState {
+        let state = self.shared.state.lock().unwrap();
+        match (state.offset_nanos, state.degraded) {
+            (None, _) => SyncState::Unsynchronized,
+            (Some(_), false) => SyncState::Synchronized,
+            (Some(_), true) => SyncState::Degraded,
+        }
+    }
+
+    /// The last successful measurement and the failed poll rounds since, for a
+    /// health endpoint.
+    ///
+    /// Example
+    /// ```rust,no_run
+    /// # use simple_ntp::synchronizer::SntpSynchronizer;
+    ///
+    /// fn main() {
+    ///     let sync = SntpSynchronizer::builder().server("ntp.aliyun.com").start().unwrap();
+    ///     let last = sync.last_sync();
+    ///     let healthy = last.measurement.is_some() && last.consecutive_failures < 3;
+    ///     println!("{} {:?}", healthy, last);
+    /// }
+    /// ```
+    pub fn last_sync(&self) -> LastSync {
+        let state = self.shared.state.lock().unwrap();
+        LastSync {
+            measurement: state.last_sync.clone(),
+            consecutive_failures: state.consecutive_failures,
+        }
+    }
+
+    /// Replace the server list and poll interval, then poll right away.
+    ///
+    /// Servers that stay configured keep their reachability and filter history.
+    pub fn reconfigure(&self, servers: &[String], interval: Duration) -> Result<(), NtpError> {
+        let servers = servers.iter().map(|server| (server.clone(), SntpClient::default())).collect();
+        self.reconfigure_with(servers, interval)
+    }
+
+    /// Like [`reconfigure`](Self::reconfigure), polling each server with its own client.
+    pub fn reconfigure_with(&self, servers: Vec<(String, SntpClient)>, interval: Duration) -> Result<(), NtpError> {
+        if servers.is_empty() {
+            return Err(NtpError::BadNtpServerAddr("no ntp server configured".to_string()));
+        }
+        let mut state = self.shared.state.lock().unwrap();
+        state.servers = servers;
+        state.interval = interval;
+        state.changed = true;
+        self.shared.wakeup.notify_all();
+
+        Ok(())
+    }
+
+    /// Start polling `server` as well, right away.
+    pub fn add_server(&self, server: &str, client: SntpClient) -> Result<(), NtpError> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.servers.iter().any(|(configured, _)| configured == server) {
+            return Err(NtpError::BadNtpServerAddr(format!("{} is already configured", server)));
+        }
+        state.servers.push((server.to_string(), client));
+        state.changed = true;
+        self.shared.wakeup.notify_all();
+
+        Ok(())
+    }
+
+    /// Stop polling `server`. The last server cannot be removed.
+    pub fn remove_server(&self, server: &str) -> Result<(), NtpError> {
+        let mut state = self.shared.state.lock().unwrap();
+        let Some(i) = state.servers.iter().position(|(configured, _)| configured == server) else {
+            return Err(NtpError::BadNtpServerAddr(format!("{} is not configured", server)));
+        };
+        if state.servers.len() == 1 {
+            return Err(NtpError::BadNtpServerAddr("cannot remove the last server".to_string()));
+        }
+        state.servers.remove(i);
+        state.changed = true;
+        self.shared.wakeup.notify_all();
+
+        Ok(())
+    }
+
+    /// Poll all servers now instead of waiting for the poll interval.
+    pub fn poll_now(&self) {
+        self.shared.state.lock().unwrap().poll_now = true;
+        self.shared.wakeup.notify_all();
+    }
+
+    /// State of each server as of the last poll round.
+    pub fn sources(&self) -> Vec<Source> {
+        self.shared.state.lock().unwrap().sources.clone()
+    }
+
+    /// Statistics of the offsets selected in the recent poll rounds.
+    pub fn system_stats(&self) -> SystemStats {
+        self.shared.state.lock().unwrap().system
+    }
+
+    /// The recent measurements of all servers, oldest first, for plotting
+    /// trends or custom statistics.
+    ///
+    /// Example
+    /// ```rust,no_run
+    /// # use simple_ntp::synchronizer::SntpSynchronizer;
+    ///
+    /// fn main() {
+    ///     let sync = SntpSynchronizer::builder().server("ntp.aliyun.com").start().unwrap();
+    ///     for m in sync.handle().history() {
+    ///         println!("{:?} {} {}ns", m.time, m.server, m.offset_nanos);
+    ///     }
+    /// }
+    /// ```
+    pub fn history(&self) -> Vec<Measurement> {
+        self.shared.state.lock().unwrap().history.iter().cloned().collect()
+    }
+
+    /// Current time of the synchronizer's time source, the clock of
+    /// [`SyncHandle::selected`], uncorrected; see [`SyncHandle::now`] for the servers' time.
+    pub fn local_now(&self) -> Duration {
+        self.shared.time.now()
+    }
+
+    /// The time of the servers: [`SyncHandle::local_now`] corrected by the smoothed
+    /// offset, and how far off it may be. `None` until a server has answered.
+    ///
+    /// The error is the root distance of the selected sample plus the jitter of
+    /// the recent offsets, growing by 15 ppm of the time since.
+    ///
+    /// Example
+    /// ```rust,no_run
+    /// # use simple_ntp::synchronizer::SntpSynchronizer;
+    ///
+    /// fn main() {
+    ///     let sync = SntpSynchronizer::builder().server("ntp.aliyun.com").start().unwrap();
+    ///     if let Some(now) = sync.handle().now() {
+    ///         println!("{:?} +/- {:?}", now.time, now.error);
+    ///     }
+    /// }
+    /// ```
+    pub fn now(&self) -> Option<CorrectedTime> {
+        let state = self.shared.state.lock().unwrap();
+        let offset = state.smoothed_offset_nanos?;
+        let (_, selected_at) = state.selected.as_ref()?;
+        let now = self.shared.time.now();
+        // The local clock may have wandered off by PHI since the last selection.
+        let drift = now.saturating_sub(*selected_at).mul_f64(PHI);
+        let error = Duration::from_nanos(state.error_nanos as u64) + drift;
+
+        Some(CorrectedTime { time: shift(now, offset), error })
+    }
+
+    /// Change the limit set by [`SynchronizerBuilder::max_offset`], `None` to remove it.
+    pub fn set_max_offset(&self, max_offset: Option<Duration>) {
+        self.shared.state.lock().unwrap().max_offset = max_offset;
+    }
+}
+
+/// Corrected time and its error bound, see [`SyncHandle::now`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CorrectedTime {
+    /// Since unix epoch.
+    pub time: Duration,
+    /// The true time is within `time` give or take `error`.
+    pub error: Duration,
+}
+
+/// A polled server, see [`SyncHandle::sources`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Source {
+    pub server: String,
+    /// Reachability register of the last 8 polls, bit 0 is the latest.
+    pub reach: u8,
+    /// Offset of the latest answer in nano seconds, `None` if it never answered.
+    pub offset_nanos: Option<i64>,
+    /// RMS jitter of the recent offsets in nano seconds.
+    pub jitter_nanos: f64,
+    /// Whether the current offset was taken from this server.
+    pub selected: bool,
+    /// Good answers, neither ignored nor from a falseticker, of the last 8 polls.
+    pub score: u8,
+    /// Left out of selection after scoring below 4 of 8, and polled on
+    /// probation until the latest 4 answers are good.
+    pub demoted: bool,
+}
+
+/// An answer of a server, see [`SyncHandle::history`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Measurement {
+    pub server: String,
+    pub addr: SocketAddr,
+    /// Offset in nano seconds, remote timestamp sub local timestamp.
+    pub offset_nanos: i64,
+    /// Round-trip delay in nano seconds.
+    pub delay_nanos: i64,
+    /// When the poll round ended, since unix epoch, by the synchronizer's time source.
+    pub time: Duration,
+    /// Whether the round's offset was taken from this measurement.
+    pub selected: bool,
+}
+
+/// What the synchronizer made of an answer, as logged by
+/// [`SynchronizerBuilder::csv`] and `SynchronizerBuilder::sqlite`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// The round's offset was taken from it.
+    Selected,
+    /// Good, but another server had a lower root distance.
+    Candidate,
+    /// Disagreed with the majority of the round.
+    Falseticker,
+    /// Its server is demoted, see [`Source::demoted`](Source::demoted).
+    Demoted,
+    /// Further than [`max_offset`](SynchronizerBuilder::max_offset).
+    MaxOffset,
+    /// Outside the [`constraints`](SynchronizerBuilder::constraints).
+    Constraint,
+    /// Selected, but held back as a jump beyond the
+    /// [`panic_threshold`](SynchronizerBuilder::panic_threshold).
+    Panic,
+}
+
+impl Verdict {
+    /// As logged: `selected`, `candidate`, `falseticker`, `demoted`, `max_offset`, `constraint` or `panic`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Verdict::Selected => "selected",
+            Verdict::Candidate => "candidate",
+            Verdict::Falseticker => "falseticker",
+            Verdict::Demoted => "demoted",
+            Verdict::MaxOffset => "max_offset",
+            Verdict::Constraint => "constraint",
+            Verdict::Panic => "panic",
+        }
+    }
+}
+
+/// An answer and its verdict, a row of the measurement logs.
+#[derive(Debug, Clone)]
+pub(crate) struct LogRecord {
+    pub(crate) server: String,
+    #[cfg(feature = "sqlite")]
+    pub(crate) addr: SocketAddr,
+    pub(crate) offset_nanos: i64,
+    pub(crate) delay_nanos: i64,
+    pub(crate) stratum: u8,
+    pub(crate) verdict: Verdict,
+}
+
+impl LogRecord {
+    fn new(server: &str, exchange: &Exchange, verdict: Verdict) -> Self {
+        LogRecord {
+            server: server.to_string(),
+            #[cfg(feature = "sqlite")]
+            addr: exchange.peer,
+            offset_nanos: exchange.offset_nanos(),
+            delay_nanos: exchange.delay_nanos(),
+            stratum: exchange.msg.stratum,
+            verdict,
+        }
+    }
+}
+
+/// See [`SyncHandle::state`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncState {
+    /// No server has answered yet.
+    Unsynchronized,
+    Synchronized,
+    /// The latest selected offset jumped beyond the
+    /// [`panic_threshold`](SynchronizerBuilder::panic_threshold) and was held back;
+    /// the offset is the last one before the jump.
+    Degraded,
+}
+
+/// Something the synchronizer wants attention for, see [`SynchronizerBuilder::on_event`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncEvent {
+    /// A selected offset jumped beyond the panic threshold from the current one and
+    /// was held back; `remaining` more samples near it are needed to accept it.
+    Panic { server: String, offset_nanos: i64, current_nanos: i64, remaining: usize },
+    /// A held back jump was corroborated and applied.
+    Corroborated { server: String, offset_nanos: i64 },
+}
+
+/// See [`SyncHandle::last_sync`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct LastSync {
+    /// The last selected measurement, `None` if no server has answered yet.
+    pub measurement: Option<Measurement>,
+    /// Poll rounds since then, or since the start, in which no server answered.
+    pub consecutive_failures: u32,
+}
+
+/// Statistics of the selected offsets, see [`SyncHandle::system_stats`].
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct SystemStats {
+    /// RMS of the recent offsets in nano seconds.
+    pub rms_offset_nanos: f64,
+    /// Frequency error of the local clock in ppm, positive when it runs fast.
+    /// Estimated from the slope of the recent offsets, the clock is not disciplined.
+    pub frequency_ppm: f64,
+    /// Standard error of `frequency_ppm`.
+    pub skew_ppm: f64,
+    /// Mean time between the recent selections.
+    pub update_interval: Duration,
+}
+
+/// How [`SynchronizerBuilder::smoothing`] smooths the selected offsets.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Smoothing {
+    /// Exponential moving average, each new offset weighted by `alpha` in (0, 1].
+    Ema { alpha: f64 },
+    /// Kalman filter of a slowly wandering offset. `process_noise_nanos` is how
+    /// far the true offset may move between polls, one standard deviation; each
+    /// offset is trusted by its round-trip delay, as its error is at most half of it.
+    Kalman { process_noise_nanos: f64 },
+}
+
+impl Smoothing {
+    fn validate(&self) -> Result<(), NtpError> {
+        match *self {
+            Smoothing::Ema { alpha } if !(alpha > 0.0 && alpha <= 1.0) => {
+                Err(NtpError::BadConfig(format!("smoothing alpha {} outside (0, 1]", alpha)))
+            }
+            Smoothing::Kalman { process_noise_nanos } if !(process_noise_nanos >= 0.0 && process_noise_nanos.is_finite()) => {
+                Err(NtpError::BadConfig(format!("invalid process noise {}", process_noise_nanos)))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Least measurement noise of a sample, 1 us, so a zero delay does not pin the estimate.
+const MIN_MEASUREMENT_NOISE: f64 = 1e3;
+
+/// Distance from the estimate, in spreads, beyond which an offset is an outlier.
+const OUTLIER_GATE: f64 = 4.0;
+
+/// Outliers in a row taken as a real move of the clock.
+const OUTLIER_HOLD: usize = 3;
+
+/// Weight of each accepted offset in the spread.
+const SPREAD_ALPHA: f64 = 0.25;
+
+/// The running estimate of a [`Smoothing`].
+#[derive(Debug)]
+struct Smoother {
+    smoothing: Smoothing,
+    estimate: Option<f64>,
+    /// Variance of the Kalman estimate, in square nano seconds.
+    variance: f64,
+    /// Moving average of the accepted offsets' distance from the estimate, in nano seconds.
+    spread: f64,
+    /// Outliers skipped in a row.
+    outliers: usize,
+}
+
+impl Smoother {
+    fn new(smoothing: Smoothing) -> Self {
+        Smoother { smoothing, estimate: None, variance: 0.0, spread: 0.0, outliers: 0 }
+    }
+
+    /// Fold in an offset measured with round-trip delay `delay_nanos`, returning the new estimate.
+    fn update(&mut self, offset_nanos: i64, delay_nanos: i64) -> i64 {
+        let offset = offset_nanos as f64;
+        if let Some(estimate) = self.estimate {
+            let gate = (OUTLIER_GATE * self.spread).max(MIN_OUTLIER_DISTANCE.as_nanos() as f64);
+            if (offset - estimate).abs() > gate {
+                self.outliers += 1;
+                if self.outliers < OUTLIER_HOLD {
+                    return estimate.round() as i64;
+                }
+                self.estimate = None;
+                self.spread = 0.0;
+            } else {
+                self.spread += SPREAD_ALPHA * ((offset - estimate).abs() - self.spread);
+            }
+        }
+        self.outliers = 0;
+
+        let noise = (delay_nanos as f64 / 2.0).max(MIN_MEASUREMENT_NOISE).powi(2);
+        let estimate = match (self.estimate, self.smoothing) {
+            (None, _) => {
+                self.variance = noise;
+                offset
+            }
+            (Some(estimate), Smoothing::Ema { alpha }) => estimate + alpha * (offset - estimate),
+            (Some(estimate), Smoothing::Kalman { process_noise_nanos }) => {
+                let predicted = self.variance + process_noise_nanos.powi(2);
+                let gain = predicted / (predicted + noise);
+                self.variance = (1.0 - gain) * predicted;
+                estimate + gain * (offset - estimate)
+            }
+        };
+        self.estimate = Some(estimate);
+        estimate.round() as i64
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    state: Mutex<State>,
+    wakeup: Condvar,
+    time: Arc<dyn TimeSource>,
+    /// Cancelled on stop, so the worker does not wait out an exchange in flight.
+    cancel: CancelToken,
+}
+
+#[derive(Debug)]
+struct State {
+    running: bool,
+    offset_nanos: Option<i64>,
+    smoothed_offset_nanos: Option<i64>,
+    /// Error of the offset when it was selected, see [`SyncHandle::now`].
+    error_nanos: i64,
+    selected: Option<(NtpResult, Duration)>,
+    max_offset: Option<Duration>,
+    /// Servers and poll interval to use, applied by the worker before its next poll round if `changed`.
+    servers: Vec<(String, SntpClient)>,
+    interval: Duration,
+    changed: bool,
+    poll_now: bool,
+    /// Published by the worker after every poll round.
+    sources: Vec<Source>,
+    system: SystemStats,
+    history: VecDeque<Measurement>,
+    history_capacity: usize,
+    last_sync: Option<Measurement>,
+    /// Poll rounds without a selected sample since `last_sync`.
+    consecutive_failures: u32,
+    /// Holding back an offset jump, see [`SyncState::Degraded`].
+    degraded: bool,
+}
+
+struct Peer {
+    server: String,
+    client: SntpClient,
+    offsets: VecDeque<i64>,
+    /// Reachability register, bit 0 is the latest poll.
+    reach: u8,
+    /// Like `reach`, for good answers.
+    good: u8,
+    polls: u32,
+    demoted: bool,
+    transport: Option<BoxedTransport>,
+}
+
+impl Peer {
+    fn new(server: String, client: SntpClient) -> Self {
+        Peer {
+            server,
+            client,
+            offsets: VecDeque::with_capacity(FILTER_SIZE),
+            reach: 0,
+            good: 0,
+            polls: 0,
+            demoted: false,
+            transport: None,
+        }
+    }
+}
+
+struct Worker {
+    peers: Vec<Peer>,
+    interval: Duration,
+    trust_first_sync: bool,
+    panic_threshold: Option<Duration>,
+    panic_confirmations: usize,
+    /// The offset a held back jump went to, and the samples near it so far.
+    jump: Option<(i64, usize)>,
+    constraints: Option<Constraints>,
+    smoother: Smoother,
+    loopstats: Option<FileGen>,
+    peerstats: Option<FileGen>,
+    statsd: Option<StatsdEmitter>,
+    #[cfg(feature = "sqlite")]
+    sqlite: Option<SqliteLog>,
+    csv: Option<CsvLog>,
+    audit: Option<AuditHook>,
+    on_event: Option<EventHook>,
+    time: Arc<dyn TimeSource>,
+    system_offsets: VecDeque<i64>,
+    /// When each of `system_offsets` was selected, seconds since the unix epoch.
+    system_times: VecDeque<f64>,
+    shared: Arc<Shared>,
+}
+
+impl Worker {
+    fn run(mut self) {
+        loop {
+            self.poll();
+
+            let mut state = self.wait();
+            if !state.running {
+                return;
+            }
+            state.poll_now = false;
+            if state.changed {
+                state.changed = false;
+                let (servers, interval) = (state.servers.clone(), state.interval);
+                drop(state);
+                self.reconfigure(servers, interval);
+            }
+        }
+    }
+
+    /// Wait out the poll interval, or until stopped, reconfigured or asked to poll.
+    fn wait(&self) -> MutexGuard<'_, State> {
+        let idle = |state: &mut State| state.running && !state.changed && !state.poll_now;
+        let state = self.shared.state.lock().unwrap();
+        if !self.time.is_manual() {
+            return self.shared.wakeup.wait_timeout_while(state, self.interval, idle).unwrap().0;
+        }
+
+        let deadline = self.time.now() + self.interval;
+        let mut state = state;
+        while self.time.now() < deadline {
+            let (next, timeout) = self.shared.wakeup.wait_timeout_while(state, MANUAL_TICK, idle).unwrap();
+            state = next;
+            if !timeout.timed_out() {
+                break;
+            }
+        }
+        state
+    }
+
+    fn reconfigure(&mut self, servers: Vec<(String, SntpClient)>, interval: Duration) {
+        let names: Vec<_> = servers.iter().map(|(server, _)| server).collect();
+        info!("reconfigured: servers {:?}, interval {:?}", names, interval);
+        let mut peers = std::mem::take(&mut self.peers);
+        self.peers = servers.into_iter()
+            .map(|(server, client)| match peers.iter().position(|peer| peer.server == server) {
+                Some(i) => Peer { client, ..peers.swap_remove(i) },
+                None => Peer::new(server, client),
+            })
+            .collect();
+        self.interval = interval;
+    }
+
+    /// Whether to hold back `offset`, selected from peer `i`, as a jump beyond
+    /// the panic threshold not yet corroborated.
+    fn hold_jump(&mut self, i: usize, offset: i64) -> bool {
+        let Some(threshold) = self.panic_threshold else {
+            return false;
+        };
+        let Some(current) = self.shared.state.lock().unwrap().offset_nanos else {
+            return false;
+        };
+        let threshold = threshold.as_nanos().min(i64::MAX as u128) as i64;
+        let server = self.peers[i].server.clone();
+        if offset.abs_diff(current) <= threshold as u64 {
+            if self.jump.take().is_some() {
+                info!("{} back within the panic threshold, offset {}ns", server, offset);
+            }
+            return false;
+        }
+
+        let near = match self.jump {
+            Some((level, samples)) if offset.abs_diff(level) <= threshold as u64 => samples + 1,
+            _ => 1,
+        };
+        if near > self.panic_confirmations {
+            self.jump = None;
+            info!("{} corroborated the jump to offset {}ns", server, offset);
+            self.emit(SyncEvent::Corroborated { server, offset_nanos: offset });
+            return false;
+        }
+        if near == 1 {
+            self.jump = Some((offset, 1));
+        } else if let Some((_, samples)) = self.jump.as_mut() {
+            *samples = near;
+        }
+        let remaining = self.panic_confirmations + 1 - near;
+        warn!("{}: offset {}ns jumped from {}ns, held back for {} more samples", server, offset, current, remaining);
+        self.shared.state.lock().unwrap().degraded = true;
+        self.emit(SyncEvent::Panic { server, offset_nanos: offset, current_nanos: current, remaining });
+        true
+    }
+
+    fn emit(&self, event: SyncEvent) {
+        if let Some(hook) = &self.on_event {
+            hook(&event);
+        }
+    }
+
+    fn poll(&mut self) {
+        let (max_offset, synced) = {
+            let state = self.shared.state.lock().unwrap();
+            (state.max_offset, state.last_sync.is_some())
+        };
+        let max_offset = max_offset.filter(|_| synced || !self.trust_first_sync);
+        if let Some(constraints) = self.constraints.as_mut() {
+            constraints.refresh_if_stale();
+        }
+        let mut samples: Vec<(usize, Exchange)> = Vec::new();
+        let mut ignored: Vec<LogRecord> = Vec::new();
+        for (i, peer) in self.peers.iter_mut().enumerate() {
+            peer.reach <<= 1;
+            peer.good <<= 1;
+            peer.polls = peer.polls.saturating_add(1);
+            let (result, record) = match peer.transport.as_mut() {
+                Some(transport) => peer.client.exchange_via(transport.as_mut(), &peer.server),
+                None => peer.client.exchange_cancellable(&peer.server, Some(&self.shared.cancel)),
+            };
+            if let Some(audit) = &self.audit {
+                audit(&record);
+            }
+            match result {
+                Ok(exchange) if max_offset.is_some_and(|max| exchange.offset_nanos().unsigned_abs() as u128 > max.as_nanos()) => {
+                    peer.reach |= 1;
+                    warn!("poll {}: offset {}ns exceeds the max offset, ignored", peer.server, exchange.offset_nanos());
+                    ignored.push(LogRecord::new(&peer.server, &exchange, Verdict::MaxOffset));
+                }
+                Ok(exchange) if self.constraints.as_ref().is_some_and(|c| c.check(exchange.offset_nanos()).is_err()) => {
+                    peer.reach |= 1;
+                    warn!("poll {}: offset {}ns violates the constraint, ignored", peer.server, exchange.offset_nanos());
+                    ignored.push(LogRecord::new(&peer.server, &exchange, Verdict::Constraint));
+                }
+                Ok(exchange) => {
+                    peer.reach |= 1;
+                    debug!(
+                        "poll {}: offset {}ns, delay {}ns, stratum {}",
+                        peer.server,
+                        exchange.offset_nanos(),
+                        exchange.delay_nanos(),
+                        exchange.msg.stratum
+                    );
+                    push_bounded(&mut peer.offsets, exchange.offset_nanos());
+                    diag::gauge(diag::JITTER, &peer.server, rms_jitter(&peer.offsets) / 1e9);
+                    if let Some(statsd) = &self.statsd {
+                        statsd.record_poll(&peer.server, exchange.offset_nanos(), exchange.delay_nanos());
+                    }
+                    samples.push((i, exchange));
+                }
+                Err(err) => {
+                    warn!("poll {} failed: {:?}", peer.server, err);
+                    if let Some(statsd) = &self.statsd {
+                        statsd.record_error(&peer.server, &err);
+                    }
+                }
+            }
+            diag::gauge(diag::REACHABILITY, &peer.server, peer.reach as f64);
+        }
+
+        let falsetickers = falsetickers(&samples);
+        for (i, _) in &samples {
+            if !falsetickers.contains(i) {
+                self.peers[*i].good |= 1;
+            }
+        }
+        for peer in &mut self.peers {
+            if peer.demoted && peer.good & PROBATION == PROBATION {
+                info!("promoted {} after probation", peer.server);
+                peer.demoted = false;
+            } else if !peer.demoted && peer.polls >= 8 && peer.good.count_ones() < MIN_SCORE {
+                warn!("demoted {}: {} good answers of the last 8 polls", peer.server, peer.good.count_ones());
+                peer.demoted = true;
+            }
+        }
+
+        let selected = samples.iter()
+            .filter(|(i, _)| !self.peers[*i].demoted)
+            .min_by_key(|(_, exchange)| exchange.root_distance_nanos())
+            .map(|(i, _)| *i);
+        let held = selected.filter(|i| {
+            let (_, exchange) = samples.iter().find(|(j, _)| j == i).unwrap();
+            self.hold_jump(*i, exchange.offset_nanos())
+        });
+        let selected = selected.filter(|_| held.is_none());
+        let now = self.time.now();
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            state.sources = self.peers.iter()
+                .enumerate()
+                .map(|(i, peer)| Source {
+                    server: peer.server.clone(),
+                    reach: peer.reach,
+                    offset_nanos: peer.offsets.back().copied(),
+                    jitter_nanos: rms_jitter(&peer.offsets),
+                    selected: Some(i) == selected,
+                    score: peer.good.count_ones() as u8,
+                    demoted: peer.demoted,
+                })
+                .collect();
+            for (i, exchange) in &samples {
+                if state.history_capacity == 0 {
+                    break;
+                }
+                if state.history.len() == state.history_capacity {
+                    state.history.pop_front();
+                }
+                state.history.push_back(Measurement {
+                    server: self.peers[*i].server.clone(),
+                    addr: exchange.peer,
+                    offset_nanos: exchange.offset_nanos(),
+                    delay_nanos: exchange.delay_nanos(),
+                    time: now,
+                    selected: Some(*i) == selected,
+                });
+            }
+        }
+
+        // Statistics files are best effort, a full disk must not stop synchronization.
+        if let Some(gen) = self.peerstats.as_mut() {
+            for (i, exchange) in &samples {
+                let stats = PeerStats {
+                    addr: exchange.peer,
+                    status: if Some(*i) == selected { STATUS_SYS_PEER } else { STATUS_CANDIDATE },
+                    offset: exchange.offset_nanos() as f64 / 1e9,
+                    delay: exchange.delay_nanos() as f64 / 1e9,
+                    dispersion: ShortFormat(exchange.msg.root_dispersion).as_secs_f64(),
+                    jitter: rms_jitter(&self.peers[*i].offsets) / 1e9,
+                };
+                if let Err(err) = gen.write_line(now, &stats.to_line(now)) {
+                    warn!("failed to write peerstats: {}", err);
+                }
+            }
+        }
+
+        #[cfg(feature = "sqlite")]
+        let logging = self.sqlite.is_some() || self.csv.is_some();
+        #[cfg(not(feature = "sqlite"))]
+        let logging = self.csv.is_some();
+        if logging {
+            let mut records = ignored;
+            records.extend(samples.iter().map(|(i, exchange)| {
+                let verdict = if Some(*i) == selected {
+                    Verdict::Selected
+                } else if Some(*i) == held {
+                    Verdict::Panic
+                } else if falsetickers.contains(i) {
+                    Verdict::Falseticker
+                } else if self.peers[*i].demoted {
+                    Verdict::Demoted
+                } else {
+                    Verdict::Candidate
+                };
+                LogRecord::new(&self.peers[*i].server, exchange, verdict)
+            }));
+            #[cfg(feature = "sqlite")]
+            if let Some(Err(err)) = self.sqlite.as_mut().map(|log| log.write(now, &records)) {
+                warn!("failed to write the sqlite log: {}", err);
+            }
+            if let Some(Err(err)) = self.csv.as_mut().map(|log| log.write(now, &records)) {
+                warn!("failed to write the csv log: {}", err);
+            }
+        }
+
+        let Some((i, exchange)) = selected.and_then(|i| samples.iter().find(|(j, _)| *j == i)) else {
+            if held.is_none() {
+                warn!("no ntp server reachable");
+                self.shared.state.lock().unwrap().consecutive_failures += 1;
+            }
+            return;
+        };
+        let offset = exchange.offset_nanos();
+        info!("selected {}, offset {}ns", self.peers[*i].server, offset);
+        push_bounded(&mut self.system_offsets, offset);
+        push_bounded(&mut self.system_times, now.as_secs_f64());
+        let system = system_stats(&self.system_times, &self.system_offsets);
+        let smoothed = self.smoother.update(offset, exchange.delay_nanos());
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            state.offset_nanos = Some(offset);
+            state.smoothed_offset_nanos = Some(smoothed);
+            state.error_nanos = exchange.root_distance_nanos().max(0) + rms_jitter(&self.system_offsets).round() as i64;
+            state.selected = Some((NtpResult::from(exchange), now));
+            state.system = system;
+            state.last_sync = Some(Measurement {
+                server: self.peers[*i].server.clone(),
+                addr: exchange.peer,
+                offset_nanos: offset,
+                delay_nanos: exchange.delay_nanos(),
+                time: now,
+                selected: true,
+            });
+            state.consecutive_failures = 0;
+            state.degraded = false;
+        }
+        diag::system_gauge(diag::SYSTEM_OFFSET, offset as f64 / 1e9);
+        diag::system_gauge(diag::SYSTEM_JITTER, rms_jitter(&self.system_offsets) / 1e9);
+        otel::record_system_offset(offset);
+
+        if let Some(gen) = self.loopstats.as_mut() {
+            let stats = LoopStats {
+                offset: offset as f64 / 1e9,
+                frequency: system.frequency_ppm,
+                jitter: rms_jitter(&self.system_offsets) / 1e9,
+                wander: 0.0,
+                poll: PollInterval::from(self.interval).exponent().max(0),
+            };
+            if let Err(err) = gen.write_line(now, &stats.to_line(now)) {
+                warn!("failed to write loopstats: {}", err);
+            }
+        }
+    }
+}
+
+/// Servers of `samples` whose offset, give or take its root distance, does not
+/// overlap that of the median offset. Needs 3 samples to tell who is wrong.
+fn falsetickers(samples: &[(usize, Exchange)]) -> Vec<usize> {
+    if samples.len() < 3 {
+        return Vec::new();
+    }
+
+    let mut sorted: Vec<_> = samples.iter().collect();
+    sorted.sort_by_key(|(_, exchange)| exchange.offset_nanos());
+    let median = &sorted[sorted.len() / 2].1;
+    let error = |exchange: &Exchange| exchange.root_distance_nanos().max(0) + MIN_DISPERSION_NANOS;
+    samples.iter()
+        .filter(|(_, exchange)| (exchange.offset_nanos() - median.offset_nanos()).abs() > error(exchange) + error(median))
+        .map(|(i, _)| *i)
+        .collect()
+}
+
+fn push_bounded<T>(samples: &mut VecDeque<T>, value: T) {
+    if samples.len() == FILTER_SIZE {
+        samples.pop_front();
+    }
+    samples.push_back(value);
+}
+
+/// RMS of the differences between successive offsets, in nano seconds.
+fn rms_jitter(offsets: &VecDeque<i64>) -> f64 {
+    if offsets.len() < 2 {
+        return 0.0;
+    }
+
+    let sum: f64 = offsets.iter()
+        .zip(offsets.iter().skip(1))
+        .map(|(a, b)| ((b - a) as f64).powi(2))
+        .sum();
+    (sum / (offsets.len() - 1) as f64).sqrt()
+}
+
+/// Least squares fit of the offsets over time: an offset growing by 1 us per
+/// second means the local clock runs 1 ppm slow.
+fn system_stats(times: &VecDeque<f64>, offsets: &VecDeque<i64>) -> SystemStats {
+    let n = offsets.len() as f64;
+    let rms_offset_nanos = (offsets.iter().map(|offset| (*offset as f64).powi(2)).sum::<f64>() / n).sqrt();
+    if offsets.len() < 2 {
+        return SystemStats { rms_offset_nanos, ..SystemStats::default() };
+    }
+
+    let xs: Vec<f64> = times.iter().copied().collect();
+    let ys: Vec<f64> = offsets.iter().map(|offset| *offset as f64).collect();
+    let Some((slope, skew)) = linear_fit(&xs, &ys) else {
+        return SystemStats { rms_offset_nanos, ..SystemStats::default() };
+    };
+
+    SystemStats {
+        rms_offset_nanos,
+        frequency_ppm: -slope / 1e3,
+        skew_ppm: skew / 1e3,
+        update_interval: Duration::from_secs_f64((times.back().unwrap() - times.front().unwrap()) / (n - 1.0)),
+    }
+}
+
+/// Least squares slope of `ys` over `xs` and its standard error, 0 for two points.
+/// `None` for fewer than two points or all at the same x.
+fn linear_fit(xs: &[f64], ys: &[f64]) -> Option<(f64, f64)> {
+    let n = xs.len() as f64;
+    if xs.len() < 2 {
+        return None;
+    }
+    let mean_x = xs.iter().sum::<f64>() / n;
+    let mean_y = ys.iter().sum::<f64>() / n;
+    let sxx: f64 = xs.iter().map(|x| (x - mean_x).powi(2)).sum();
+    if sxx == 0.0 {
+        return None;
+    }
+    let sxy: f64 = xs.iter().zip(ys).map(|(x, y)| (x - mean_x) * (y - mean_y)).sum();
+    let slope = sxy / sxx;
+    let error = if xs.len() > 2 {
+        let residuals: f64 = xs.iter().zip(ys).map(|(x, y)| (y - mean_y - slope * (x - mean_x)).powi(2)).sum();
+        (residuals / (n - 2.0) / sxx).sqrt()
+    } else {
+        0.0
+    };
+
+    Some((slope, error))
+}
+
+/// Two-sided 95% quantiles of Student's t distribution for 1 to 30 degrees of freedom.
+const T_95: [f64; 30] = [
+    12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228, 2.201, 2.179, 2.160, 2.145, 2.131,
+    2.120, 2.110, 2.101, 2.093, 2.086, 2.080, 2.074, 2.069, 2.064, 2.060, 2.056, 2.052, 2.048, 2.045, 2.042,
+];
+
+/// Frequency error of the local clock estimated by [`drift`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Drift {
+    /// Frequency error in ppm, positive when the local clock runs fast.
+    pub frequency_ppm: f64,
+    /// Standard error of `frequency_ppm`.
+    pub skew_ppm: f64,
+    /// Half width of the 95% confidence interval around `frequency_ppm`.
+    pub confidence_ppm: f64,
+    pub samples: usize,
+    /// Time between the first and the last sample.
+    pub span: Duration,
+}
+
+impl fmt::Display for Drift {
+    /// E.g. `+12.345 ppm ± 0.067 ppm (95%) over 57 samples in 3600s`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:+.3} ppm ± {:.3} ppm (95%) over {} samples in {}s",
+            self.frequency_ppm,
+            self.confidence_ppm,
+            self.samples,
+            self.span.as_secs()
+        )
+    }
+}
+
+/// Estimate the frequency error of the local clock from `samples` of when an
+/// offset was measured (since unix epoch, by the local clock) and the offset
+/// in nano seconds, by least squares over all of them. The clock must not be
+/// stepped or slewed meanwhile. `None` for fewer than three samples or no time
+/// between them.
+///
+/// Example
+/// ```rust,no_run
+/// # use std::thread;
+/// # use std::time::{Duration, SystemTime, UNIX_EPOCH};
+/// # use simple_ntp::client;
+/// # use simple_ntp::synchronizer::drift;
+///
+/// fn main() {
+///     let mut samples = Vec::new();
+///     for _ in 0..16 {
+///         let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+///         if let Ok(result) = client::query("ntp.aliyun.com") {
+///             samples.push((now, result.offset_nanos));
+///         }
+///         thread::sleep(Duration::from_secs(64));
+///     }
+///     println!("{:?}", drift(&samples).map(|drift| drift.to_string()));
+/// }
+/// ```
+pub fn drift(samples: &[(Duration, i64)]) -> Option<Drift> {
+    if samples.len() < 3 {
+        return None;
+    }
+    let first = samples.iter().map(|(time, _)| *time).min()?;
+    let last = samples.iter().map(|(time, _)| *time).max()?;
+    // Relative to the first sample, so seconds since the epoch do not eat the precision.
+    let times: Vec<f64> = samples.iter().map(|(time, _)| (*time - first).as_secs_f64()).collect();
+    let offsets: Vec<f64> = samples.iter().map(|(_, offset)| *offset as f64).collect();
+    let (slope, error) = linear_fit(&times, &offsets)?;
+    let t = T_95.get(samples.len() - 3).copied().unwrap_or(1.96 + 2.4 / (samples.len() - 2) as f64);
+
+    Some(Drift {
+        frequency_ppm: -slope / 1e3,
+        skew_ppm: error / 1e3,
+        confidence_ppm: t * error / 1e3,
+        samples: samples.len(),
+        span: last - first,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::synchronizer::*;
+
+    #[test]
+    fn test_rms_jitter() {
+        assert_eq!(rms_jitter(&VecDeque::from(vec![5])), 0.0);
+        assert_eq!(rms_jitter(&VecDeque::from(vec![0, 3, 0, 3])), 3.0);
+    }
+
+    #[test]
+    fn test_system_stats() {
+        // 2 us more every 64 s: the local clock runs 31.25 ppb slow.
+        let times = VecDeque::from(vec![0.0, 64.0, 128.0, 192.0]);
+        let offsets = VecDeque::from(vec![1000, 3000, 5000, 7000]);
+        let stats = system_stats(&times, &offsets);
+        assert!((stats.frequency_ppm + 2000.0 / 64.0 / 1e3).abs() < 1e-9);
+        assert!(stats.skew_ppm.abs() < 1e-9);
+        assert_eq!(stats.update_interval, Duration::from_secs(64));
+        assert_eq!(stats.rms_offset_nanos, 21_000_000f64.sqrt());
+
+        let single = system_stats(&VecDeque::from(vec![0.0]), &VecDeque::from(vec![-5]));
+        assert_eq!(single, SystemStats { rms_offset_nanos: 5.0, ..SystemStats::default() });
+    }
+
+    #[test]
+    fn test_drift() {
+        // 10 ppm fast: the offset falls 640 us every 64 s, give or take 100 us.
+        let epoch = Duration::from_secs(1_700_000_000);
+        let samples: Vec<_> = (0..57i64)
+            .map(|i| (epoch + Duration::from_secs(64 * i as u64), -640_000 * i + if i % 2 == 0 { 100_000 } else { -100_000 }))
+            .collect();
+        let estimate = drift(&samples).unwrap();
+        assert!((estimate.frequency_ppm - 10.0).abs() < 0.01, "{:?}", estimate);
+        assert!(estimate.confidence_ppm > estimate.skew_ppm && estimate.confidence_ppm < 0.05, "{:?}", estimate);
+        assert!((estimate.frequency_ppm - 10.0).abs() < estimate.confidence_ppm);
+        assert_eq!((estimate.samples, estimate.span), (57, Duration::from_secs(3584)));
+        assert!(estimate.to_string().starts_with("+10.000 ppm ± 0.0"), "{}", estimate);
+
+        // Three exact samples leave no doubt, two are not enough.
+        let exact = drift(&samples.iter().map(|(t, _)| *t).zip([0, -640_000, -1_280_000]).collect::<Vec<_>>()).unwrap();
+        assert!((exact.frequency_ppm - 10.0).abs() < 1e-9 && exact.confidence_ppm < 1e-9);
+        assert_eq!(drift(&samples[..2]), None);
+        assert_eq!(drift(&[(epoch, 0); 3]), None);
+    }
+
+    #[test]
+    fn test_smoothing() {
+        let mut ema = Smoother::new(Smoothing::Ema { alpha: 0.25 });
+        assert_eq!(ema.update(1000, 0), 1000);
+        assert_eq!(ema.update(2000, 0), 1250);
+        assert_eq!(ema.update(2000, 0), 1438);
+
+        // A sample over a path 100 times noisier barely moves the estimate.
+        let mut kalman = Smoother::new(Smoothing::Kalman { process_noise_nanos: 0.0 });
+        assert_eq!(kalman.update(0, 2_000), 0);
+        assert_eq!(kalman.update(10_000, 200_000), 1);
+        assert_eq!(kalman.update(10_000, 2_000), 5000);
+
+        // A lone outlier is skipped, a third one in a row restarts the estimate.
+        let mut ema = Smoother::new(DEFAULT_SMOOTHING);
+        assert_eq!(ema.update(1_000_000, 0), 1_000_000);
+        assert_eq!(ema.update(-500_000_000, 0), 1_000_000);
+        assert_eq!(ema.update(1_400_000, 0), 1_100_000);
+        for _ in 0..2 {
+            assert_eq!(ema.update(-500_000_000, 0), 1_100_000);
+        }
+        assert_eq!(ema.update(-500_000_000, 0), -500_000_000);
+
+        for smoothing in [Smoothing::Ema { alpha: 0.0 }, Smoothing::Ema { alpha: f64::NAN }, Smoothing::Kalman { process_noise_nanos: -1.0 }] {
+            let builder = SntpSynchronizer::builder().server("127.0.0.1:1").smoothing(smoothing);
+            assert!(matches!(builder.start(), Err(NtpError::BadConfig(_))));
+        }
+    }
+
+    #[test]
+    fn test_history() {
+        let server = crate::testing::MockServer::builder().offset_nanos(1_000_000_000).start().unwrap();
+        let mut stepper = SntpSynchronizer::builder()
+            .server(&server.addr())
+            .server("127.0.0.1:1")
+            .history(2)
+            .stepper()
+            .unwrap();
+        for _ in 0..3 {
+            stepper.step();
+        }
+        let history = stepper.handle().history();
+        assert_eq!(history.len(), 2);
+        assert!(history[0].time <= history[1].time);
+        assert_eq!(history[1].server, server.addr());
+        assert_eq!(history[1].addr, server.local_addr());
+        assert_eq!((history[1].offset_nanos as f64 / 1e9).round(), 1.0);
+        assert!(history[1].selected);
+
+        let mut stepper = SntpSynchronizer::builder().server(&server.addr()).history(0).stepper().unwrap();
+        stepper.step();
+        assert!(stepper.handle().history().is_empty());
+    }
+
+    #[test]
+    fn test_now() {
+        let server = crate::testing::MockServer::builder().offset_nanos(2_000_000_000).start().unwrap();
+        let mut stepper = SntpSynchronizer::builder().server(&server.addr()).stepper().unwrap();
+        assert_eq!(stepper.handle().now(), None);
+        stepper.step();
+        let corrected = stepper.handle().now().unwrap();
+        assert_eq!((corrected.time.as_secs_f64() - stepper.handle().local_now().as_secs_f64()).round(), 2.0);
+        let (selected, _) = stepper.handle().selected().unwrap();
+        assert!(corrected.error >= Duration::from_nanos(selected.root_distance_nanos() as u64));
+        assert!(corrected.error < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_last_sync() {
+        let server = crate::testing::MockServer::builder().start().unwrap();
+        let mut stepper = SntpSynchronizer::builder().server(&server.addr()).stepper().unwrap();
+        assert_eq!(stepper.handle().last_sync(), LastSync { measurement: None, consecutive_failures: 0 });
+        stepper.step();
+        let last = stepper.handle().last_sync();
+        assert_eq!(last.measurement.as_ref().unwrap().addr, server.local_addr());
+        assert_eq!(last.consecutive_failures, 0);
+
+        // The port is closed now, so the next rounds fail right away.
+        server.stop();
+        stepper.step();
+        stepper.step();
+        let failed = stepper.handle().last_sync();
+        assert_eq!(failed.measurement, last.measurement);
+        assert_eq!(failed.consecutive_failures, 2);
+    }
+
+    #[test]
+    fn test_trust_first_sync() {
+        let server = crate::testing::MockServer::builder().offset_nanos(3_600_000_000_000).start().unwrap();
+        let builder = || SntpSynchronizer::builder().server(&server.addr()).max_offset(Duration::from_secs(1000));
+        let mut stepper = builder().stepper().unwrap();
+        stepper.step();
+        assert_eq!(stepper.handle().last_sync().consecutive_failures, 1);
+
+        // The first hour is taken, later ones are over the limit again.
+        let mut stepper = builder().trust_first_sync().stepper().unwrap();
+        stepper.step();
+        let first = stepper.handle().last_sync();
+        assert_eq!((first.measurement.unwrap().offset_nanos as f64 / 1e9).round(), 3600.0);
+        stepper.step();
+        assert_eq!(stepper.handle().last_sync().consecutive_failures, 1);
+    }
+
+    #[test]
+    fn test_panic_threshold() {
+        use crate::timesource::{MockClock, SystemClock};
+
+        let clock = Arc::new(MockClock::default());
+        let server = crate::testing::MockServer::builder().time_source(clock.clone()).start().unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = events.clone();
+        let mut stepper = SntpSynchronizer::builder()
+            .server(&server.addr())
+            .panic_threshold(Duration::from_secs(1))
+            .panic_confirmations(2)
+            .on_event(move |event| sink.lock().unwrap().push(event.clone()))
+            .stepper()
+            .unwrap();
+        let mut step = |offset: Duration| {
+            clock.set(SystemClock.now() + offset);
+            stepper.step();
+            let handle = stepper.handle();
+            ((handle.offset_nanos().unwrap() as f64 / 1e9).round() as i64, handle.state())
+        };
+        let (ten, zero) = (Duration::from_secs(10), Duration::ZERO);
+
+        assert_eq!(step(zero), (0, SyncState::Synchronized));
+        // A glitch is held back and forgotten, a lasting jump taken after two more samples.
+        assert_eq!(step(ten), (0, SyncState::Degraded));
+        assert_eq!(step(zero), (0, SyncState::Synchronized));
+        assert_eq!(step(ten), (0, SyncState::Degraded));
+        assert_eq!(step(ten), (0, SyncState::Degraded));
+        assert_eq!(step(ten), (10, SyncState::Synchronized));
+
+        let events = events.lock().unwrap();
+        let remaining: Vec<_> = events.iter()
+            .map(|event| match event {
+                SyncEvent::Panic { remaining, .. } => *remaining,
+                SyncEvent::Corroborated { .. } => 0,
+            })
+            .collect();
+        assert_eq!(remaining, vec![2, 2, 1, 0]);
+        assert!(matches!(&events[0], SyncEvent::Panic { server: s, current_nanos, .. } if *s == server.addr() && current_nanos.abs() < 100_000_000));
+    }
+
+    #[test]
+    fn test_demotion() {
+        use crate::testing::{MockServer, Response};
+
+        let good = [MockServer::builder().start().unwrap(), MockServer::builder().start().unwrap()];
+        let falseticker = MockServer::builder().offset_nanos(10_000_000_000).start().unwrap();
+        let flaky = MockServer::builder().script((0..8).map(|_| Response::Drop)).start().unwrap();
+        let mut stepper = SntpSynchronizer::builder()
+            .server(&good[0].addr())
+            .server(&good[1].addr())
+            .server(&falseticker.addr())
+            .server_with(&flaky.addr(), SntpClient::builder().timeout(Duration::from_millis(50)).build())
+            .stepper()
+            .unwrap();
+        let state = |stepper: &Stepper| stepper.handle().sources().iter().map(|source| (source.score, source.demoted)).collect::<Vec<_>>();
+        for _ in 0..7 {
+            stepper.step();
+        }
+        assert_eq!(state(&stepper), [(7, false), (7, false), (0, false), (0, false)]);
+        stepper.step();
+        assert_eq!(state(&stepper), [(8, false), (8, false), (0, true), (0, true)]);
+
+        // The flaky server answers again and is promoted after 4 good answers.
+        for _ in 0..3 {
+            stepper.step();
+        }
+        assert_eq!(state(&stepper)[3], (3, true));
+        stepper.step();
+        assert_eq!(state(&stepper)[2..], [(0, true), (4, false)]);
+        assert!(!stepper.handle().sources()[2].selected);
+    }
+
+    #[test]
+    fn test_start_without_servers() {
+        assert!(SntpSynchronizer::builder().start().is_err());
+    }
+
+    #[test]
+    fn test_stop_mid_exchange() {
+        // The worker is left waiting for a response that never comes, 5s by default.
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let sync = SntpSynchronizer::builder()
+            .server(&silent.local_addr().unwrap().to_string())
+            .start()
+            .unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let start = std::time::Instant::now();
+        sync.stop();
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn test_reconfigure() {
+        let sync = SntpSynchronizer::builder()
+            .server("127.0.0.1:1")
+            .interval(Duration::from_secs(3600))
+            .start()
+            .unwrap();
+        assert!(sync.reconfigure(&[], Duration::from_secs(1)).is_err());
+
+        // A responding server only shows up if the new list is polled right away.
+        let server = crate::server::NtpServer::builder().bind("127.0.0.1:0").build().unwrap().spawn().unwrap();
+        let servers = ["127.0.0.1:1".to_string(), server.local_addr().to_string()];
+        sync.handle().reconfigure(&servers, Duration::from_secs(3600)).unwrap();
+        for _ in 0..50 {
+            if sync.offset_nanos().is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(sync.selected().unwrap().0.addr, server.local_addr());
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn test_constraints() {
+        // Without a constraint the answering server is reachable but never selected.
+        let server = crate::server::NtpServer::builder().bind("127.0.0.1:0").build().unwrap().spawn().unwrap();
+        let sync = SntpSynchronizer::builder()
+            .server(&server.local_addr().to_string())
+            .interval(Duration::from_secs(3600))
+            .constraints(Constraints::builder().url("http://127.0.0.1:1/").build())
+            .start()
+            .unwrap();
+        for _ in 0..50 {
+            if !sync.handle().sources().is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(sync.handle().sources()[0].reach, 1);
+        assert_eq!(sync.offset_nanos(), None);
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn test_add_remove_server() {
+        let server = crate::server::NtpServer::builder().bind("127.0.0.1:0").build().unwrap().spawn().unwrap();
+        let addr = server.local_addr().to_string();
+        let sync = SntpSynchronizer::builder()
+            .server("127.0.0.1:1")
+            .interval(Duration::from_secs(3600))
+            .start()
+            .unwrap();
+        let handle = sync.handle();
+        assert!(handle.remove_server("127.0.0.1:1").is_err());
+        assert!(handle.add_server("127.0.0.1:1", SntpClient::default()).is_err());
+
+        handle.add_server(&addr, SntpClient::default()).unwrap();
+        handle.remove_server("127.0.0.1:1").unwrap();
+        for _ in 0..50 {
+            if handle.sources().iter().map(|source| &source.server).eq([&addr]) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        let sources = handle.sources();
+        assert_eq!(sources.len(), 1);
+        assert_eq!(sources[0].server, addr);
+        assert_eq!(sources[0].reach & 1, 1);
+        assert!(sources[0].selected);
+
+        // Polling again shifts the reachability register.
+        let reach = (sources[0].reach << 1) | 1;
+        handle.poll_now();
+        for _ in 0..50 {
+            if handle.sources()[0].reach == reach {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(handle.sources()[0].reach, reach);
+    }
+}
@@ -0,0 +1,107 @@
+//! systemd service notifications, see `sd_notify(3)`.
+//!
+//! A `Type=notify` unit is started once the service sends `READY=1`, and a unit
+//! with `WatchdogSec=` is restarted when `WATCHDOG=1` keepalives stop arriving.
+//! Outside systemd `NOTIFY_SOCKET` is unset and notifications are skipped.
+
+use std::env;
+use std::os::unix::net::UnixDatagram;
+use std::process;
+use std::time::Duration;
+
+use crate::protocol::NtpError;
+
+/// Send `state`, newline separated `KEY=value` assignments, to the service manager.
+///
+/// Returns `Ok(false)` when not running under systemd.
+///
+/// Example
+/// ```rust
+/// # use simple_ntp::systemd;
+///
+/// fn main() {
+///     systemd::notify("READY=1\nSTATUS=synchronized").unwrap();
+/// }
+/// ```
+pub fn notify(state: &str) -> Result<bool, NtpError> {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(false);
+    };
+    let socket = UnixDatagram::unbound().map_err(|err| {
+        NtpError::UnexpectedErr(err.to_string())
+    })?;
+
+    let path = path.to_string_lossy();
+    let sent = match path.strip_prefix('@') {
+        Some(name) => send_abstract(&socket, name, state),
+        None => socket.send_to(state.as_bytes(), path.as_ref()),
+    };
+    sent.map_err(|err| {
+        NtpError::ServiceUnavailable(err.to_string())
+    })?;
+
+    Ok(true)
+}
+
+/// Sockets starting with `@` live in the Linux abstract namespace.
+#[cfg(target_os = "linux")]
+fn send_abstract(socket: &UnixDatagram, name: &str, state: &str) -> std::io::Result<usize> {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::SocketAddr;
+
+    let addr = SocketAddr::from_abstract_name(name)?;
+    socket.send_to_addr(state.as_bytes(), &addr)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_abstract(_socket: &UnixDatagram, name: &str, _state: &str) -> std::io::Result<usize> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        format!("abstract socket @{} is only supported on linux", name),
+    ))
+}
+
+/// How often the service manager expects `WATCHDOG=1`, from `WATCHDOG_USEC`.
+///
+/// `None` if the watchdog is disabled or meant for another process. Send
+/// keepalives at half this interval to be safe.
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Some(pid) = env::var_os("WATCHDOG_PID") {
+        if pid.to_str()?.parse::<u32>().ok()? != process::id() {
+            return None;
+        }
+    }
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::systemd::*;
+
+    #[test]
+    fn test_notify() {
+        let dir = env::temp_dir().join(format!("sntp-notify-{}", process::id()));
+        let _ = std::fs::create_dir(&dir);
+        let path = dir.join("notify.sock");
+        let _ = std::fs::remove_file(&path);
+        let manager = UnixDatagram::bind(&path).unwrap();
+
+        // Tests in this module are the only ones touching these variables.
+        env::set_var("NOTIFY_SOCKET", &path);
+        env::set_var("WATCHDOG_USEC", "30000000");
+        env::remove_var("WATCHDOG_PID");
+        assert!(notify("READY=1\nSTATUS=ok").unwrap());
+        assert_eq!(watchdog_interval(), Some(Duration::from_secs(30)));
+        env::set_var("WATCHDOG_PID", "1");
+        assert_eq!(watchdog_interval(), None);
+        env::remove_var("NOTIFY_SOCKET");
+        assert!(!notify("WATCHDOG=1").unwrap());
+
+        let mut buf = [0u8; 64];
+        let n = manager.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1\nSTATUS=ok");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,1088 @@
+//! In-process NTP server and transport for tests.
+//!
+//! A [`MockServer`] binds an ephemeral loopback port and answers like a real
+//! server whose clock is off by a chosen amount behind a link of chosen
+//! latency, or misbehaves as scripted, so time logic can be integration-tested
+//! without the internet. A [`MemoryTransport`] goes without sockets altogether,
+//! simulating loss, duplication, reordering and delay on a [`MockClock`], and
+//! a [`HostilePacket`] makes up the malformed traffic to throw at either side.
+//! Code that only needs answers takes an [`NtpClient`] and gets a [`MockClient`].
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::client::{shift, NtpClient, NtpResult, Transport};
+use crate::protocol::{
+    duration_to_ntp_timestamp, NtpError, NtpMsg, PollInterval, ShortFormat, NTP_MODE_CLIENT, NTP_MODE_SERVER, NTP_VERSION_4,
+};
+use crate::timesource::{self, MockClock, TimeSource};
+
+/// How often the server thread checks whether it was stopped.
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Leap indicator: clock unsynchronized, as sent with a kiss code.
+const LEAP_ALARM: u8 = 3;
+
+/// How the server treats a request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    /// Answer normally.
+    Reply,
+    /// Ignore the request, the client times out.
+    Drop,
+    /// Answer normally, but only after this long on top of the latency.
+    Delay(Duration),
+    /// Answer with an originate timestamp that does not match the request.
+    BadOrigin,
+    /// Answer with a stratum 0 kiss-o'-death, e.g. `*b"RATE"`.
+    KissOfDeath([u8; 4]),
+    /// Answer with this packet, see [`HostilePacket::reply_to`].
+    Packet(HostilePacket),
+}
+
+/// Configure a [`MockServer`].
+#[derive(Debug, Clone)]
+pub struct MockServerBuilder {
+    stratum: u8,
+    reference_id: u32,
+    offset_nanos: i64,
+    latency: Duration,
+    script: VecDeque<Response>,
+    time: Arc<dyn TimeSource>,
+}
+
+impl MockServerBuilder {
+    /// Stratum to answer with, 1 by default.
+    pub fn stratum(mut self, stratum: u8) -> Self {
+        self.stratum = stratum;
+        self
+    }
+
+    /// Reference ID to answer with, `MOCK` by default.
+    pub fn reference_id(mut self, reference_id: u32) -> Self {
+        self.reference_id = reference_id;
+        self
+    }
+
+    /// How far the server's clock is ahead of the local one, in nano seconds.
+    pub fn offset_nanos(mut self, offset_nanos: i64) -> Self {
+        self.offset_nanos = offset_nanos;
+        self
+    }
+
+    /// Round-trip delay the client should measure, split evenly between both
+    /// directions so the offset stays exact. Zero by default. It passes in real
+    /// time, keep it zero with a manual time source.
+    pub fn latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Treat the next requests as given, in order. Requests after the script
+    /// runs out get a [`Response::Reply`].
+    pub fn script(mut self, responses: impl IntoIterator<Item = Response>) -> Self {
+        self.script.extend(responses);
+        self
+    }
+
+    /// Read the local time from `time` instead of the system clock, the server's
+    /// clock is this plus the offset.
+    pub fn time_source(mut self, time: Arc<dyn TimeSource>) -> Self {
+        self.time = time;
+        self
+    }
+
+    /// Bind `127.0.0.1:0` and start answering on a background thread.
+    pub fn start(self) -> Result<MockServer, NtpError> {
+        let socket = UdpSocket::bind("127.0.0.1:0").map_err(|err| {
+            NtpError::ServiceUnavailable(err.to_string())
+        })?;
+        let local_addr = socket.local_addr().map_err(|err| {
+            NtpError::UnexpectedErr(err.to_string())
+        })?;
+        socket.set_read_timeout(Some(STOP_POLL_INTERVAL)).map_err(|err| {
+            NtpError::UnexpectedErr(err.to_string())
+        })?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let requests = Arc::new(AtomicUsize::new(0));
+        let script = Arc::new(Mutex::new(self.script.clone()));
+        let mock = Mock {
+            socket,
+            config: self,
+            script: script.clone(),
+            requests: requests.clone(),
+        };
+        let stopped = stop.clone();
+        let worker = thread::Builder::new()
+            .name("sntp-mock".to_string())
+            .spawn(move || mock.serve(&stopped))
+            .map_err(|err| {
+                NtpError::UnexpectedErr(err.to_string())
+            })?;
+
+        Ok(MockServer {
+            local_addr,
+            script,
+            requests,
+            stop,
+            worker: Some(worker),
+        })
+    }
+}
+
+/// A scripted NTP server on a loopback port, stopped when dropped.
+///
+/// Example
+/// ```rust
+/// # use std::time::Duration;
+/// # use simple_ntp::client;
+/// # use simple_ntp::protocol::NtpError;
+/// # use simple_ntp::testing::{MockServer, Response};
+///
+/// fn main() {
+///     let server = MockServer::builder()
+///         .offset_nanos(1_500_000_000)
+///         .script([Response::KissOfDeath(*b"RATE")])
+///         .start()
+///         .unwrap();
+///     assert!(matches!(client::query(&server.addr()), Err(NtpError::KissOfDeath(_))));
+///     let offset = client::clock_offset_nanos(&server.addr()).unwrap();
+///     assert!((offset - 1_500_000_000).abs() < 50_000_000);
+/// }
+/// ```
+#[derive(Debug)]
+pub struct MockServer {
+    local_addr: SocketAddr,
+    script: Arc<Mutex<VecDeque<Response>>>,
+    requests: Arc<AtomicUsize>,
+    stop: Arc<AtomicBool>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl MockServer {
+    pub fn builder() -> MockServerBuilder {
+        MockServerBuilder {
+            stratum: 1,
+            reference_id: u32::from_be_bytes(*b"MOCK"),
+            offset_nanos: 0,
+            latency: Duration::ZERO,
+            script: VecDeque::new(),
+            time: timesource::system(),
+        }
+    }
+
+    /// Address the server is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Address as a server string for the clients of this crate.
+    pub fn addr(&self) -> String {
+        self.local_addr.to_string()
+    }
+
+    /// Requests received so far, answered or not.
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    /// Append to the script while running.
+    pub fn push(&self, response: Response) {
+        self.script.lock().unwrap().push_back(response);
+    }
+
+    /// Stop serving and wait for the thread to exit.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+struct Mock {
+    socket: UdpSocket,
+    config: MockServerBuilder,
+    script: Arc<Mutex<VecDeque<Response>>>,
+    requests: Arc<AtomicUsize>,
+}
+
+impl Mock {
+    fn serve(&self, stop: &AtomicBool) {
+        let mut buf = [0u8; 1024];
+        while !stop.load(Ordering::Relaxed) {
+            let (n, from) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                // Timeouts to check `stop`, and ICMP errors from earlier replies.
+                Err(_) => continue,
+            };
+            let received = self.config.time.now();
+
+            let mut request = NtpMsg::new();
+            if n != 48 || request.unmarshal(&buf[..n]).is_err() || request.mode != NTP_MODE_CLIENT {
+                continue;
+            }
+            self.requests.fetch_add(1, Ordering::Relaxed);
+
+            let response = self.script.lock().unwrap().pop_front().unwrap_or(Response::Reply);
+            let (reply, wait) = match response {
+                Response::Drop => continue,
+                Response::Reply => (self.reply(&request, received).marshal(), self.config.latency),
+                Response::Delay(delay) => (self.reply(&request, received).marshal(), self.config.latency + delay),
+                Response::BadOrigin => {
+                    let mut reply = self.reply(&request, received);
+                    reply.originate_timestamp ^= 1;
+                    (reply.marshal(), self.config.latency)
+                }
+                Response::KissOfDeath(code) => {
+                    let mut reply = self.reply(&request, received);
+                    reply.leap_indicator = LEAP_ALARM;
+                    reply.stratum = 0;
+                    reply.reference_identifier = u32::from_be_bytes(code);
+                    (reply.marshal(), self.config.latency)
+                }
+                Response::Packet(packet) => {
+                    let now = shift(received + self.config.latency / 2, self.config.offset_nanos);
+                    (packet.build_at(Some(&buf[..n]), now), self.config.latency)
+                }
+            };
+
+            // Loopback takes no time, the whole latency is spent here. Both
+            // timestamps say half of it passed on the way in, so to the client
+            // it looks like a symmetric path and a server that answers at once.
+            thread::sleep(wait);
+            let _ = self.socket.send_to(&reply, from);
+        }
+    }
+
+    fn reply(&self, request: &NtpMsg, received: Duration) -> NtpMsg {
+        let now = shift(received + self.config.latency / 2, self.config.offset_nanos);
+        reply(request, now, self.config.stratum, self.config.reference_id)
+    }
+}
+
+/// A server's answer to `request`, received and sent at `now` by its clock.
+pub(crate) fn reply(request: &NtpMsg, now: Duration, stratum: u8, reference_id: u32) -> NtpMsg {
+    let timestamp = duration_to_ntp_timestamp(&now);
+
+    let mut reply = NtpMsg::new();
+    reply.version_number = request.version_number;
+    reply.mode = NTP_MODE_SERVER;
+    reply.stratum = stratum;
+    reply.poll = request.poll;
+    reply.reference_identifier = reference_id;
+    reply.reference_timestamp = timestamp;
+    reply.originate_timestamp = request.transmit_timestamp;
+    reply.receiver_timestamp = timestamp;
+    reply.transmit_timestamp = timestamp;
+    reply
+}
+
+/// Builds malformed and hostile packets, to test validators against attack
+/// traffic: this crate's client through [`Response::Packet`], or any server by
+/// sending [`HostilePacket::build`] to it.
+///
+/// It starts from a well formed packet, and every method spoils one thing.
+/// Timestamps not set or zeroed are taken from the clock when built, the
+/// origin of a reply from the request.
+///
+/// Example
+/// ```rust
+/// # use simple_ntp::client;
+/// # use simple_ntp::protocol::NtpError;
+/// # use simple_ntp::testing::{HostilePacket, MockServer, Response};
+///
+/// fn main() {
+///     let server = MockServer::builder()
+///         .script([Response::Packet(HostilePacket::server().length(40))])
+///         .start()
+///         .unwrap();
+///     assert!(matches!(client::query(&server.addr()), Err(NtpError::TruncatedNtpMessage)));
+///
+///     let hostile = HostilePacket::client().mode(0).extension(0x0104, 0xfffc, 64).build();
+///     assert_eq!(hostile.len(), 48 + 4 + 64);
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostilePacket {
+    msg: NtpMsg,
+    origin: Option<u64>,
+    reference: Option<u64>,
+    transmit: Option<u64>,
+    zero_timestamps: bool,
+    length: Option<usize>,
+    extensions: Vec<u8>,
+}
+
+impl HostilePacket {
+    /// A well formed version 4 server reply at stratum 1.
+    pub fn server() -> Self {
+        let mut msg = NtpMsg::new();
+        msg.version_number = NTP_VERSION_4;
+        msg.mode = NTP_MODE_SERVER;
+        msg.stratum = 1;
+        msg.reference_identifier = u32::from_be_bytes(*b"MOCK");
+        HostilePacket {
+            msg,
+            origin: None,
+            reference: None,
+            transmit: None,
+            zero_timestamps: false,
+            length: None,
+            extensions: Vec::new(),
+        }
+    }
+
+    /// A well formed version 4 client request.
+    pub fn client() -> Self {
+        let mut packet = HostilePacket::server();
+        packet.msg.mode = NTP_MODE_CLIENT;
+        packet.msg.stratum = 0;
+        packet.msg.reference_identifier = 0;
+        packet
+    }
+
+    /// Version number, only the low 3 bits are sent.
+    pub fn version(mut self, version: u8) -> Self {
+        self.msg.version_number = version;
+        self
+    }
+
+    /// Mode, only the low 3 bits are sent.
+    pub fn mode(mut self, mode: u8) -> Self {
+        self.msg.mode = mode;
+        self
+    }
+
+    /// Leap indicator, only the low 2 bits are sent.
+    pub fn leap(mut self, leap: u8) -> Self {
+        self.msg.leap_indicator = leap;
+        self
+    }
+
+    pub fn stratum(mut self, stratum: u8) -> Self {
+        self.msg.stratum = stratum;
+        self
+    }
+
+    /// Stratum 0 with `code` as reference ID, any 4 bytes, and the leap alarm.
+    pub fn kiss(mut self, code: [u8; 4]) -> Self {
+        self.msg.leap_indicator = LEAP_ALARM;
+        self.msg.stratum = 0;
+        self.msg.reference_identifier = u32::from_be_bytes(code);
+        self
+    }
+
+    pub fn poll(mut self, poll: u8) -> Self {
+        self.msg.poll = poll;
+        self
+    }
+
+    pub fn precision(mut self, precision: u8) -> Self {
+        self.msg.precision = precision;
+        self
+    }
+
+    /// 16.16 fixed point seconds.
+    pub fn root_delay(mut self, root_delay: u32) -> Self {
+        self.msg.root_delay = root_delay;
+        self
+    }
+
+    /// 16.16 fixed point seconds.
+    pub fn root_dispersion(mut self, root_dispersion: u32) -> Self {
+        self.msg.root_dispersion = root_dispersion;
+        self
+    }
+
+    pub fn reference_id(mut self, reference_id: u32) -> Self {
+        self.msg.reference_identifier = reference_id;
+        self
+    }
+
+    /// Originate timestamp, instead of the request's transmit timestamp.
+    pub fn origin(mut self, origin: u64) -> Self {
+        self.origin = Some(origin);
+        self
+    }
+
+    /// Reference timestamp, instead of the clock.
+    pub fn reference(mut self, reference: u64) -> Self {
+        self.reference = Some(reference);
+        self
+    }
+
+    /// Transmit timestamp, instead of the clock.
+    pub fn transmit(mut self, transmit: u64) -> Self {
+        self.transmit = Some(transmit);
+        self
+    }
+
+    /// Zero reference, receive and transmit timestamps. The origin is unaffected.
+    pub fn zero_timestamps(mut self) -> Self {
+        self.zero_timestamps = true;
+        self
+    }
+
+    /// Cut or zero pad the datagram to `length` bytes, after any extension fields.
+    pub fn length(mut self, length: usize) -> Self {
+        self.length = Some(length);
+        self
+    }
+
+    /// Append an extension field whose header claims `declared_length` bytes
+    /// but which carries `body_length` bytes after it, too short, unaligned or huge.
+    pub fn extension(mut self, field_type: u16, declared_length: u16, body_length: usize) -> Self {
+        self.extensions.extend_from_slice(&field_type.to_be_bytes());
+        self.extensions.extend_from_slice(&declared_length.to_be_bytes());
+        self.extensions.resize(self.extensions.len() + body_length, 0xa5);
+        self
+    }
+
+    /// The datagram, timestamped by the system clock.
+    pub fn build(&self) -> Vec<u8> {
+        self.build_at(None, timesource::system().now())
+    }
+
+    /// The datagram answering `request`, its originate timestamp being the
+    /// request's transmit timestamp if there is one.
+    pub fn reply_to(&self, request: &[u8]) -> Vec<u8> {
+        self.build_at(Some(request), timesource::system().now())
+    }
+
+    fn build_at(&self, request: Option<&[u8]>, now: Duration) -> Vec<u8> {
+        let mut msg = self.msg.clone();
+        let now = duration_to_ntp_timestamp(&now);
+        let requested = request
+            .and_then(|request| request.get(40..48))
+            .and_then(|transmit| transmit.try_into().ok())
+            .map(u64::from_be_bytes);
+        msg.originate_timestamp = self.origin.or(requested).unwrap_or(0);
+        if !self.zero_timestamps {
+            if msg.mode != NTP_MODE_CLIENT {
+                msg.reference_timestamp = self.reference.unwrap_or(now);
+                msg.receiver_timestamp = now;
+            }
+            msg.transmit_timestamp = self.transmit.unwrap_or(now);
+        }
+
+        let mut data = msg.marshal();
+        data.extend_from_slice(&self.extensions);
+        if let Some(length) = self.length {
+            data.resize(length, 0);
+        }
+        data
+    }
+}
+
+/// Answers a request that arrived at the given local time, `None` to ignore it.
+type Responder = Box<dyn FnMut(&[u8], Duration) -> Option<Vec<u8>> + Send>;
+
+/// How long a datagram takes one way, see [`MemoryTransportBuilder::delay`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Distribution {
+    Fixed(Duration),
+    /// Uniform between the two bounds.
+    Uniform(Duration, Duration),
+    /// Exponential with this mean, the long tail of a congested path.
+    Exponential(Duration),
+}
+
+/// Configure a [`MemoryTransport`].
+pub struct MemoryTransportBuilder {
+    transport: MemoryTransport,
+    offset_nanos: i64,
+    stratum: u8,
+}
+
+impl MemoryTransportBuilder {
+    /// How far the default responder's clock is ahead of `clock`, in nano seconds.
+    pub fn offset_nanos(mut self, offset_nanos: i64) -> Self {
+        self.offset_nanos = offset_nanos;
+        self
+    }
+
+    /// Stratum of the default responder, 1 by default.
+    pub fn stratum(mut self, stratum: u8) -> Self {
+        self.stratum = stratum;
+        self
+    }
+
+    /// Answer requests with `respond(request, arrival)` instead of a well
+    /// behaved server, `arrival` being the local time the request got there.
+    pub fn respond_with(mut self, respond: impl FnMut(&[u8], Duration) -> Option<Vec<u8>> + Send + 'static) -> Self {
+        self.transport.responder = Some(Box::new(respond));
+        self
+    }
+
+    /// Probability that a datagram is lost, each way. 0 by default.
+    pub fn loss(mut self, probability: f64) -> Self {
+        self.transport.loss = probability;
+        self
+    }
+
+    /// Probability that a response arrives twice. 0 by default.
+    pub fn duplication(mut self, probability: f64) -> Self {
+        self.transport.duplication = probability;
+        self
+    }
+
+    /// Probability that a response is held back until after the next one. 0 by default.
+    pub fn reordering(mut self, probability: f64) -> Self {
+        self.transport.reordering = probability;
+        self
+    }
+
+    /// One-way delay of every datagram, zero by default.
+    pub fn delay(mut self, delay: Distribution) -> Self {
+        self.transport.delay = delay;
+        self.transport.return_delay = delay;
+        self
+    }
+
+    /// One-way delay of the responses only, for an asymmetric path; set it
+    /// after [`delay`](Self::delay).
+    pub fn return_delay(mut self, delay: Distribution) -> Self {
+        self.transport.return_delay = delay;
+        self
+    }
+
+    /// How long `recv` waits by the clock, 5 seconds by default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.transport.timeout = timeout;
+        self
+    }
+
+    /// Seed of the random choices, the same seed gives the same run.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.transport.rng = seed;
+        self
+    }
+
+    /// Address `peer_addr` reports, `192.0.2.1:123` by default.
+    pub fn peer(mut self, peer: SocketAddr) -> Self {
+        self.transport.peer = peer;
+        self
+    }
+
+    pub fn build(mut self) -> MemoryTransport {
+        if self.transport.responder.is_none() {
+            let (offset_nanos, stratum) = (self.offset_nanos, self.stratum);
+            self.transport.responder = Some(Box::new(move |data: &[u8], arrival: Duration| {
+                let mut request = NtpMsg::new();
+                if request.unmarshal(data).is_err() || request.mode != NTP_MODE_CLIENT {
+                    return None;
+                }
+                let now = shift(arrival, offset_nanos);
+                Some(reply(&request, now, stratum, u32::from_be_bytes(*b"MOCK")).marshal())
+            }));
+        }
+        self.transport
+    }
+}
+
+/// A [`Transport`] without sockets: requests go straight to a responder,
+/// through a simulated path that loses, duplicates, reorders and delays
+/// datagrams as configured.
+///
+/// Time is a [`MockClock`] shared with the client, which `recv` moves forward
+/// to when the datagram arrives, or the timeout passes. Exchanges take no real
+/// time and come out the same for the same seed.
+///
+/// Example
+/// ```rust
+/// # use std::sync::Arc;
+/// # use std::time::Duration;
+/// # use simple_ntp::client::SntpClient;
+/// # use simple_ntp::testing::{Distribution, MemoryTransport};
+/// # use simple_ntp::timesource::MockClock;
+///
+/// fn main() {
+///     let clock = Arc::new(MockClock::new(Duration::from_secs(1_700_000_000)));
+///     let mut transport = MemoryTransport::builder(clock.clone())
+///         .offset_nanos(500_000_000)
+///         .delay(Distribution::Fixed(Duration::from_millis(10)))
+///         .build();
+///     let client = SntpClient::builder().time_source(clock).build();
+///     let result = client.query_via(&mut transport).unwrap();
+///     assert!((result.delay_nanos - 20_000_000).abs() < 1_000);
+/// }
+/// ```
+pub struct MemoryTransport {
+    clock: Arc<MockClock>,
+    peer: SocketAddr,
+    responder: Option<Responder>,
+    loss: f64,
+    duplication: f64,
+    reordering: f64,
+    delay: Distribution,
+    return_delay: Distribution,
+    timeout: Duration,
+    rng: u64,
+    /// Datagrams on their way back, with the local time they arrive.
+    in_flight: Vec<(Duration, Vec<u8>)>,
+    held: Option<Vec<u8>>,
+    sent: usize,
+}
+
+impl MemoryTransport {
+    pub fn builder(clock: Arc<MockClock>) -> MemoryTransportBuilder {
+        MemoryTransportBuilder {
+            transport: MemoryTransport {
+                clock,
+                peer: SocketAddr::from(([192, 0, 2, 1], 123)),
+                responder: None,
+                loss: 0.0,
+                duplication: 0.0,
+                reordering: 0.0,
+                delay: Distribution::Fixed(Duration::ZERO),
+                return_delay: Distribution::Fixed(Duration::ZERO),
+                timeout: Duration::from_secs(5),
+                rng: 0,
+                in_flight: Vec::new(),
+                held: None,
+                sent: 0,
+            },
+            offset_nanos: 0,
+            stratum: 1,
+        }
+    }
+
+    /// Datagrams sent so far, lost or not.
+    pub fn sent(&self) -> usize {
+        self.sent
+    }
+
+    /// Responses sent but not received yet, including any held back.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len() + self.held.is_some() as usize
+    }
+
+    /// splitmix64, uniform in [0, 1).
+    fn random(&mut self) -> f64 {
+        self.rng = self.rng.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.random() < probability
+    }
+
+    fn sample_delay(&mut self, delay: Distribution) -> Duration {
+        match delay {
+            Distribution::Fixed(delay) => delay,
+            Distribution::Uniform(low, high) => low + (high.saturating_sub(low)).mul_f64(self.random()),
+            Distribution::Exponential(mean) => mean.mul_f64(-(1.0 - self.random()).ln()),
+        }
+    }
+}
+
+impl fmt::Debug for MemoryTransport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemoryTransport")
+            .field("peer", &self.peer)
+            .field("sent", &self.sent)
+            .field("in_flight", &self.in_flight())
+            .finish()
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sent += 1;
+        if self.chance(self.loss) {
+            return Ok(buf.len());
+        }
+        let arrival = self.clock.now() + self.sample_delay(self.delay);
+        let Some(response) = self.responder.as_mut().and_then(|respond| respond(buf, arrival)) else {
+            return Ok(buf.len());
+        };
+        if self.chance(self.loss) {
+            return Ok(buf.len());
+        }
+
+        let at = arrival + self.sample_delay(self.return_delay);
+        if self.chance(self.duplication) {
+            let again = at + self.sample_delay(self.return_delay);
+            self.in_flight.push((again, response.clone()));
+        }
+        if self.held.is_none() && self.chance(self.reordering) {
+            self.held = Some(response);
+        } else {
+            if let Some(held) = self.held.take() {
+                self.in_flight.push((at + Duration::from_nanos(1), held));
+            }
+            self.in_flight.push((at, response));
+        }
+
+        Ok(buf.len())
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let now = self.clock.now();
+        let deadline = now + self.timeout;
+        let next = self.in_flight.iter()
+            .enumerate()
+            .filter(|(_, (at, _))| *at <= deadline)
+            .min_by_key(|(_, (at, _))| *at)
+            .map(|(i, _)| i);
+        let Some(i) = next else {
+            self.clock.set(deadline);
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "memory transport timed out"));
+        };
+
+        let (at, data) = self.in_flight.remove(i);
+        self.clock.set(at.max(now));
+        let n = data.len().min(buf.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        Ok(n)
+    }
+
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.peer)
+    }
+}
+
+/// A stratum 1 answer from `127.0.0.1:123` with the given offset and delay, for
+/// [`MockClient::push`] and tests that need an [`NtpResult`] to work on.
+///
+/// The timestamps are zero; set any other field with struct update syntax.
+pub fn answer(offset_nanos: i64, delay_nanos: i64) -> NtpResult {
+    NtpResult {
+        addr: SocketAddr::from(([127, 0, 0, 1], 123)),
+        leap_indicator: 0,
+        version: NTP_VERSION_4,
+        mode: NTP_MODE_SERVER,
+        stratum: 1,
+        poll: PollInterval::MIN,
+        precision: -20,
+        reference_id: 0,
+        root_delay: ShortFormat(0),
+        root_dispersion: ShortFormat(0),
+        reference_time: Duration::ZERO,
+        timestamps: (Duration::ZERO, Duration::ZERO, Duration::ZERO, Duration::ZERO),
+        offset_nanos,
+        delay_nanos,
+    }
+}
+
+/// An [`NtpClient`] answering from memory, for unit tests of code that takes one.
+///
+/// Every query succeeds with the configured offset unless a result was
+/// [pushed](MockClient::push), which is returned instead, oldest first.
+///
+/// Example
+/// ```rust
+/// # use std::sync::Arc;
+/// # use std::time::Duration;
+/// # use simple_ntp::client::NtpClient;
+/// # use simple_ntp::protocol::NtpError;
+/// # use simple_ntp::testing::MockClient;
+/// # use simple_ntp::timesource::MockClock;
+///
+/// fn main() {
+///     let clock = Arc::new(MockClock::new(Duration::from_secs(1_700_000_000)));
+///     let client = MockClient::new(-250_000_000).time_source(clock);
+///     client.push(Err(NtpError::ServiceUnavailable("timed out".to_string())));
+///     let client: Box<dyn NtpClient> = Box::new(client);
+///     assert!(client.query("ntp.example.com").is_err());
+///     assert_eq!(client.now("ntp.example.com").unwrap(), Duration::from_millis(1_699_999_999_750));
+/// }
+/// ```
+#[derive(Debug)]
+pub struct MockClient {
+    offset_nanos: i64,
+    delay: Duration,
+    stratum: u8,
+    time: Arc<dyn TimeSource>,
+    script: Mutex<VecDeque<Result<NtpResult, NtpError>>>,
+    queries: Mutex<Vec<String>>,
+}
+
+impl MockClient {
+    /// A client finding the local clock `offset_nanos` behind, remote sub local,
+    /// from a stratum 1 server with no delay.
+    pub fn new(offset_nanos: i64) -> Self {
+        MockClient {
+            offset_nanos,
+            delay: Duration::ZERO,
+            stratum: 1,
+            time: timesource::system(),
+            script: Mutex::new(VecDeque::new()),
+            queries: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Round-trip delay of the answers.
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Stratum of the answers.
+    pub fn stratum(mut self, stratum: u8) -> Self {
+        self.stratum = stratum;
+        self
+    }
+
+    /// The local clock [`NtpClient::now`] corrects, the system clock by default.
+    pub fn time_source(mut self, time: Arc<dyn TimeSource>) -> Self {
+        self.time = time;
+        self
+    }
+
+    /// Return `result` from a coming query instead of the configured answer.
+    pub fn push(&self, result: Result<NtpResult, NtpError>) {
+        self.script.lock().unwrap().push_back(result);
+    }
+
+    /// Servers queried so far, in order.
+    pub fn queries(&self) -> Vec<String> {
+        self.queries.lock().unwrap().clone()
+    }
+}
+
+impl NtpClient for MockClient {
+    fn query(&self, ntp_server: &str) -> Result<NtpResult, NtpError> {
+        self.queries.lock().unwrap().push(ntp_server.to_string());
+        if let Some(result) = self.script.lock().unwrap().pop_front() {
+            return result;
+        }
+
+        let t1 = self.time.now();
+        let t2 = shift(t1 + self.delay / 2, self.offset_nanos);
+        Ok(NtpResult {
+            addr: ntp_server.parse().unwrap_or_else(|_| SocketAddr::from(([0, 0, 0, 0], 123))),
+            stratum: self.stratum,
+            reference_id: u32::from_be_bytes(*b"MOCK"),
+            reference_time: t2,
+            timestamps: (t1, t2, t2, t1 + self.delay),
+            ..answer(self.offset_nanos, self.delay.as_nanos() as i64)
+        })
+    }
+
+    fn now(&self, ntp_server: &str) -> Result<Duration, NtpError> {
+        let offset = self.clock_offset_nanos(ntp_server)?;
+        Ok(shift(self.time.now(), offset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "server")]
+    use crate::server::NtpServer;
+    use crate::client::SntpClient;
+    #[cfg(feature = "server")]
+    use crate::protocol::{NtpPacket, ParseError};
+    use crate::testing::*;
+    use crate::timesource::TimeSource;
+
+    #[test]
+    fn test_mock_server() {
+        let server = MockServer::builder()
+            .stratum(2)
+            .offset_nanos(-3_000_000_000)
+            .latency(Duration::from_millis(40))
+            .script([
+                Response::Drop,
+                Response::BadOrigin,
+                Response::KissOfDeath(*b"DENY"),
+                Response::Delay(Duration::from_millis(300)),
+            ])
+            .start()
+            .unwrap();
+        let client = SntpClient::builder().timeout(Duration::from_millis(200)).build();
+
+        assert!(matches!(client.query(&server.addr()), Err(NtpError::ServiceUnavailable(_))));
+        assert!(matches!(client.query(&server.addr()), Err(NtpError::UntrustedMessage)));
+        assert!(matches!(client.query(&server.addr()), Err(NtpError::KissOfDeath(code)) if code == "DENY"));
+        assert!(matches!(client.query(&server.addr()), Err(NtpError::ServiceUnavailable(_))));
+        assert_eq!(server.requests(), 4);
+
+        // The late answer to the delayed request is still in flight.
+        thread::sleep(Duration::from_millis(200));
+        let result = client.query(&server.addr()).unwrap();
+        assert_eq!(result.stratum, 2);
+        assert!((result.offset_nanos + 3_000_000_000).abs() < 10_000_000, "{}", result.offset_nanos);
+        assert!(result.delay_nanos >= 40_000_000 && result.delay_nanos < 100_000_000, "{}", result.delay_nanos);
+
+        server.push(Response::Drop);
+        assert!(client.query(&server.addr()).is_err());
+        server.stop();
+    }
+
+    /// Some delay, so that the clock moves and no two requests look the same.
+    fn memory(clock: &Arc<MockClock>) -> MemoryTransportBuilder {
+        MemoryTransport::builder(clock.clone())
+            .timeout(Duration::from_secs(1))
+            .delay(Distribution::Fixed(Duration::from_millis(1)))
+    }
+
+    #[test]
+    fn test_memory_transport() {
+        let clock = Arc::new(MockClock::new(Duration::from_secs(1_700_000_000)));
+        let client = SntpClient::builder().time_source(clock.clone()).build();
+
+        let mut transport = memory(&clock)
+            .offset_nanos(2_000_000_000)
+            .delay(Distribution::Fixed(Duration::from_millis(15)))
+            .build();
+        let result = client.query_via(&mut transport).unwrap();
+        assert!((result.offset_nanos - 2_000_000_000).abs() < 1_000, "{}", result.offset_nanos);
+        assert!((result.delay_nanos - 30_000_000).abs() < 1_000, "{}", result.delay_nanos);
+        assert_eq!(clock.now(), Duration::from_secs(1_700_000_000) + Duration::from_millis(30));
+
+        // Lost requests time out by the mock clock, in no real time.
+        let mut transport = memory(&clock).loss(1.0).build();
+        let before = clock.now();
+        assert!(matches!(client.query_via(&mut transport), Err(NtpError::ServiceUnavailable(_))));
+        assert_eq!(clock.now() - before, Duration::from_secs(1));
+
+        // A duplicate is the next exchange's first datagram, and fails its origin check.
+        let mut transport = memory(&clock).duplication(1.0).build();
+        assert!(client.query_via(&mut transport).is_ok());
+        assert!(matches!(client.query_via(&mut transport), Err(NtpError::UntrustedMessage)));
+
+        // A held back response arrives after the next one.
+        let mut transport = memory(&clock).reordering(1.0).build();
+        assert!(client.query_via(&mut transport).is_err());
+        assert!(client.query_via(&mut transport).is_ok());
+        assert_eq!(transport.in_flight(), 1);
+        assert!(matches!(client.query_via(&mut transport), Err(NtpError::UntrustedMessage)));
+        assert_eq!(transport.sent(), 3);
+    }
+
+    #[test]
+    fn test_memory_transport_distribution() {
+        let delays = |seed| {
+            let clock = Arc::new(MockClock::new(Duration::from_secs(1_700_000_000)));
+            let client = SntpClient::builder().time_source(clock.clone()).build();
+            let mut transport = memory(&clock)
+                .delay(Distribution::Uniform(Duration::from_millis(5), Duration::from_millis(50)))
+                .loss(0.2)
+                .seed(seed)
+                .build();
+            (0..50)
+                .map(|_| client.query_via(&mut transport).map(|result| result.delay_nanos).ok())
+                .collect::<Vec<_>>()
+        };
+
+        let run = delays(7);
+        assert_eq!(run, delays(7));
+        assert_ne!(run, delays(8));
+        let answered: Vec<_> = run.iter().flatten().collect();
+        assert!(answered.len() > 20 && answered.len() < 45, "{}", answered.len());
+        assert!(answered.iter().all(|delay| (9_999_000..=100_001_000).contains(*delay)));
+
+        let mut calls = 0;
+        let clock = Arc::new(MockClock::new(Duration::from_secs(1_700_000_000)));
+        let mut transport = memory(&clock)
+            .respond_with(move |request, _| {
+                calls += 1;
+                (calls > 1).then(|| request.to_vec())
+            })
+            .build();
+        let client = SntpClient::builder().time_source(clock).build();
+        assert!(matches!(client.query_via(&mut transport), Err(NtpError::ServiceUnavailable(_))));
+        assert!(matches!(client.query_via(&mut transport), Err(NtpError::UntrustedMessage)));
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn test_hostile_packet() {
+        let request = HostilePacket::client().transmit(42).build();
+        let reply = NtpMsg::parse(&HostilePacket::server().reply_to(&request)).unwrap();
+        assert_eq!((reply.mode, reply.stratum, reply.originate_timestamp), (4, 1, 42));
+        let kiss = NtpMsg::parse(&HostilePacket::server().kiss(*b"\0\xff\n ").zero_timestamps().build()).unwrap();
+        assert_eq!((kiss.leap_indicator, kiss.stratum, kiss.reference_identifier), (3, 0, 0x00ff_0a20));
+        assert_eq!((kiss.receiver_timestamp, kiss.transmit_timestamp), (0, 0));
+        let oversized = HostilePacket::server().extension(0x0104, 0xfffc, 32).build();
+        assert_eq!(NtpPacket::parse(&oversized), Err(ParseError::BadExtension(48)));
+
+        // The client rejects what it can tell is wrong.
+        let server = MockServer::builder()
+            .script([
+                Response::Packet(HostilePacket::server().length(47)),
+                Response::Packet(HostilePacket::server().origin(0)),
+                Response::Packet(HostilePacket::server().zero_timestamps().origin(0)),
+                Response::Packet(HostilePacket::server().mode(0)),
+                Response::Packet(HostilePacket::server().kiss(*b"RATE")),
+            ])
+            .start()
+            .unwrap();
+        assert!(matches!(crate::client::query(&server.addr()), Err(NtpError::TruncatedNtpMessage)));
+        assert!(matches!(crate::client::query(&server.addr()), Err(NtpError::UntrustedMessage)));
+        assert!(matches!(crate::client::query(&server.addr()), Err(NtpError::UntrustedMessage)));
+        assert!(matches!(crate::client::query(&server.addr()), Err(NtpError::UntrustedMessage)));
+        assert!(matches!(crate::client::query(&server.addr()), Err(NtpError::KissOfDeath(code)) if code == "RATE"));
+
+        // A server shrugs off attack traffic and keeps answering.
+        let ntpd = NtpServer::builder().bind("127.0.0.1:0").build().unwrap().spawn().unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        let hostile = [
+            HostilePacket::client().length(0),
+            HostilePacket::client().length(47),
+            HostilePacket::client().mode(0),
+            HostilePacket::client().version(7),
+            HostilePacket::client().mode(4),
+            HostilePacket::client().extension(0x0104, 0xfffc, 1400),
+            HostilePacket::client().kiss(*b"DENY").zero_timestamps(),
+        ];
+        let mut buf = [0u8; 1500];
+        for packet in &hostile {
+            socket.send_to(&packet.build(), ntpd.local_addr()).unwrap();
+        }
+        let mut answered = 0;
+        while socket.recv_from(&mut buf).is_ok() {
+            answered += 1;
+        }
+        assert!(answered <= 2, "{}", answered);
+        assert!(ntpd.is_running());
+        assert!(crate::client::query(&ntpd.local_addr().to_string()).is_ok());
+        ntpd.stop();
+    }
+
+    #[test]
+    fn test_ntp_client() {
+        fn offsets(client: &dyn NtpClient, server: &str) -> Vec<i64> {
+            (0..3).filter_map(|_| client.clock_offset_nanos(server).ok()).collect()
+        }
+
+        let clock = Arc::new(MockClock::new(Duration::from_secs(1_700_000_000)));
+        let mock = MockClient::new(1_500).delay(Duration::from_millis(2)).stratum(3).time_source(clock.clone());
+        mock.push(Err(NtpError::ServiceUnavailable("timed out".to_string())));
+        assert_eq!(offsets(&mock, "192.0.2.1:123"), vec![1_500, 1_500]);
+        assert_eq!(mock.queries(), vec!["192.0.2.1:123"; 3]);
+        let result = mock.query("192.0.2.1:123").unwrap();
+        assert_eq!((result.addr.to_string(), result.stratum, result.delay_nanos), ("192.0.2.1:123".to_string(), 3, 2_000_000));
+        assert_eq!(mock.now("a").unwrap(), Duration::new(1_700_000_000, 1_500));
+
+        // The real client, boxed the same way.
+        let server = MockServer::builder().offset_nanos(-2_000_000_000).time_source(clock.clone()).start().unwrap();
+        let client: Box<dyn NtpClient> = Box::new(SntpClient::builder().time_source(clock.clone()).build());
+        assert_eq!(offsets(client.as_ref(), &server.addr()), vec![-2_000_000_000; 3]);
+        assert_eq!(client.now(&server.addr()).unwrap(), Duration::from_secs(1_699_999_998));
+    }
+}
@@ -0,0 +1,387 @@
+//! Where the local time comes from.
+//!
+//! Clients, synchronizers and the mock server read the local clock through a
+//! [`TimeSource`], the system clock by default. Tests can hand them a
+//! [`MockClock`] instead, which stands still until advanced, so offsets and
+//! poll schedules come out exact and no test has to sleep through an interval.
+//!
+//! The same goes for production: a host whose best clock is not the system
+//! clock, e.g. a PTP hardware clock disciplined by `ptp4l`, or a virtual
+//! machine with a paravirtualized clock, hands that to the builders and gets
+//! offsets relative to it. On Linux [`PhcClock`] reads a PTP hardware clock
+//! and [`TaiClock`] the kernel's `CLOCK_TAI` (feature `clock`); anything else
+//! implements [`TimeSource`].
+//!
+//! NTP timestamps are UTC, so sources keeping TAI convert with the leap
+//! seconds of [`tai_offset`] before handing out the time.
+//!
+//! Example
+//! ```rust,no_run
+//! # use std::sync::Arc;
+//! # use std::time::Duration;
+//! # #[cfg(feature = "client")]
+//! # use simple_ntp::client::SntpClient;
+//! # use simple_ntp::timesource::{SystemClock, TimeSource};
+//!
+//! /// The system clock, as corrected by a previous measurement.
+//! #[derive(Debug)]
+//! struct Corrected {
+//!     ahead: Duration,
+//! }
+//!
+//! impl TimeSource for Corrected {
+//!     fn now(&self) -> Duration {
+//!         SystemClock.now() + self.ahead
+//!     }
+//! }
+//!
+//! fn main() {
+//!     let time = Arc::new(Corrected { ahead: Duration::from_millis(250) });
+//!     # #[cfg(feature = "client")]
+//!     println!("{:?}", SntpClient::builder().time_source(time).build().query("ntp.aliyun.com"));
+//! }
+//! ```
+
+use std::fmt;
+use std::sync::Mutex;
+#[cfg(feature = "client")]
+use std::sync::Arc;
+use std::time::{self, Duration};
+
+/// The local clock, as seen by this crate.
+///
+/// `now` is called right before sending and after receiving each request, so
+/// it should be cheap and must not block; a clock that cannot be read returns
+/// its last reading rather than failing.
+pub trait TimeSource: fmt::Debug + Send + Sync {
+    /// Time since the Unix epoch.
+    fn now(&self) -> Duration;
+
+    /// Whether the time only moves when told to, like a [`MockClock`]. Waits
+    /// on such a source re-read it every few milliseconds instead of blocking
+    /// for the whole duration.
+    fn is_manual(&self) -> bool {
+        false
+    }
+}
+
+/// The system's real time clock.
+///
+/// On Windows this is `GetSystemTimePreciseAsFileTime`, 100ns ticks instead of
+/// the timer interrupt's 1-16ms of `GetSystemTimeAsFileTime`, which the
+/// standard library only falls back to on systems older than Windows 8.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl TimeSource for SystemClock {
+    fn now(&self) -> Duration {
+        time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap()
+    }
+}
+
+/// A clock that stands still until [advanced](MockClock::advance) or [set](MockClock::set).
+///
+/// Example
+/// ```rust
+/// # use std::sync::Arc;
+/// # use std::time::Duration;
+/// # use simple_ntp::timesource::{MockClock, TimeSource};
+///
+/// fn main() {
+///     let clock = Arc::new(MockClock::new(Duration::from_secs(1_700_000_000)));
+///     clock.advance(Duration::from_millis(1500));
+///     assert_eq!(clock.now(), Duration::from_millis(1_700_000_001_500));
+/// }
+/// ```
+#[derive(Debug, Default)]
+pub struct MockClock {
+    now: Mutex<Duration>,
+}
+
+impl MockClock {
+    /// A clock reading `now` since the Unix epoch.
+    pub fn new(now: Duration) -> Self {
+        MockClock { now: Mutex::new(now) }
+    }
+
+    /// Move the clock forward.
+    pub fn advance(&self, d: Duration) {
+        *self.now.lock().unwrap() += d;
+    }
+
+    /// Move the clock to `now`, backwards too.
+    pub fn set(&self, now: Duration) {
+        *self.now.lock().unwrap() = now;
+    }
+}
+
+impl TimeSource for MockClock {
+    fn now(&self) -> Duration {
+        *self.now.lock().unwrap()
+    }
+
+    fn is_manual(&self) -> bool {
+        true
+    }
+}
+
+/// A PTP hardware clock, e.g. `/dev/ptp0` of a NIC with hardware timestamping.
+///
+/// The clock is read as UTC unless marked [`tai`](PhcClock::tai): one
+/// disciplined by `ptp4l` usually keeps TAI, 37 seconds ahead of UTC.
+///
+/// Example
+/// ```rust,no_run
+/// # use std::sync::Arc;
+/// # use simple_ntp::client::SntpClient;
+/// # use simple_ntp::timesource::PhcClock;
+///
+/// fn main() {
+///     let phc = Arc::new(PhcClock::open("/dev/ptp0").unwrap().tai());
+///     let client = SntpClient::builder().time_source(phc).build();
+///     println!("{:?}", client.query("ntp.aliyun.com"));
+/// }
+/// ```
+#[cfg(all(feature = "clock", target_os = "linux"))]
+#[derive(Debug)]
+pub struct PhcClock {
+    device: std::fs::File,
+    tai: bool,
+    last: Mutex<Duration>,
+}
+
+#[cfg(all(feature = "clock", target_os = "linux"))]
+impl PhcClock {
+    /// Open the clock device at `path`; reading it needs read access only.
+    pub fn open(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        let clock = PhcClock { device: std::fs::File::open(path)?, tai: false, last: Mutex::default() };
+        clock.read()?;
+        Ok(clock)
+    }
+
+    /// The clock keeps TAI, convert it to UTC.
+    pub fn tai(mut self) -> Self {
+        self.tai = true;
+        self
+    }
+
+    fn read(&self) -> std::io::Result<Duration> {
+        use std::os::fd::AsRawFd;
+
+        // FD_TO_CLOCKID of linux/posix-timers.h, a dynamic clock is addressed by its file.
+        const CLOCKFD: libc::clockid_t = 3;
+        let now = clock_gettime((!(self.device.as_raw_fd() as libc::clockid_t) << 3) | CLOCKFD)?;
+        Ok(if self.tai { utc_from_tai(now) } else { now })
+    }
+}
+
+#[cfg(all(feature = "clock", target_os = "linux"))]
+impl TimeSource for PhcClock {
+    fn now(&self) -> Duration {
+        let mut last = self.last.lock().unwrap();
+        if let Ok(now) = self.read() {
+            *last = now;
+        }
+        *last
+    }
+}
+
+/// Start of each TAI - UTC offset in Unix seconds, with the offset, as in the
+/// IERS leap second list. The last leap second was inserted at the end of 2016.
+const LEAP_SECONDS: [(u64, u64); 28] = [
+    (63072000, 10), (78796800, 11), (94694400, 12), (126230400, 13), (157766400, 14),
+    (189302400, 15), (220924800, 16), (252460800, 17), (283996800, 18), (315532800, 19),
+    (362793600, 20), (394329600, 21), (425865600, 22), (489024000, 23), (567993600, 24),
+    (631152000, 25), (662688000, 26), (709948800, 27), (741484800, 28), (773020800, 29),
+    (820454400, 30), (867715200, 31), (915148800, 32), (1136073600, 33), (1230768000, 34),
+    (1341100800, 35), (1435708800, 36), (1483228800, 37),
+];
+
+/// TAI - UTC at `utc` since the Unix epoch: 37 seconds since 2017, zero
+/// before 1972, when leap seconds began.
+///
+/// Example
+/// ```rust
+/// # use std::time::Duration;
+/// # use simple_ntp::timesource::tai_offset;
+///
+/// fn main() {
+///     assert_eq!(tai_offset(Duration::from_secs(1_700_000_000)), Duration::from_secs(37));
+/// }
+/// ```
+pub fn tai_offset(utc: Duration) -> Duration {
+    let at = LEAP_SECONDS.partition_point(|&(start, _)| start <= utc.as_secs());
+    Duration::from_secs(at.checked_sub(1).map_or(0, |at| LEAP_SECONDS[at].1))
+}
+
+/// UTC at `tai` since the Unix epoch, the inverse of [`tai_offset`].
+pub fn utc_from_tai(tai: Duration) -> Duration {
+    let at = LEAP_SECONDS.partition_point(|&(start, offset)| start + offset <= tai.as_secs());
+    let offset = at.checked_sub(1).map_or(0, |at| LEAP_SECONDS[at].1);
+    tai.saturating_sub(Duration::from_secs(offset))
+}
+
+/// Read the Linux clock `id`.
+#[cfg(all(feature = "clock", target_os = "linux"))]
+fn clock_gettime(id: libc::clockid_t) -> std::io::Result<Duration> {
+    let mut now = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    // SAFETY: `now` is a valid, writable timespec.
+    if unsafe { libc::clock_gettime(id, &mut now) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(Duration::new(now.tv_sec.max(0) as u64, now.tv_nsec as u32))
+}
+
+/// The kernel's `CLOCK_TAI`, for systems that discipline TAI and treat UTC as
+/// derived from it.
+///
+/// The reading is turned into UTC with the leap seconds of [`tai_offset`],
+/// not the kernel's own TAI offset, so offsets are those of `CLOCK_TAI`
+/// itself: if nothing set the kernel's offset, `CLOCK_TAI` equals UTC and is
+/// reported 37 seconds behind.
+///
+/// Example
+/// ```rust,no_run
+/// # use std::sync::Arc;
+/// # use simple_ntp::client::SntpClient;
+/// # use simple_ntp::timesource::TaiClock;
+///
+/// fn main() {
+///     let client = SntpClient::builder().time_source(Arc::new(TaiClock)).build();
+///     println!("CLOCK_TAI is {:?}ns off", client.query("ntp.aliyun.com").map(|r| r.offset_nanos));
+/// }
+/// ```
+#[cfg(all(feature = "clock", target_os = "linux"))]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TaiClock;
+
+#[cfg(all(feature = "clock", target_os = "linux"))]
+impl TaiClock {
+    /// TAI - UTC as set in the kernel, zero unless e.g. chronyd or ptp4l set it.
+    pub fn kernel_offset() -> std::io::Result<Duration> {
+        // SAFETY: timex is plain data, all zero is a valid read-only request.
+        let mut timex: libc::timex = unsafe { std::mem::zeroed() };
+        // SAFETY: `timex` is a valid, writable timex and modes is zero, nothing is set.
+        if unsafe { libc::adjtimex(&mut timex) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(Duration::from_secs(timex.tai.max(0) as u64))
+    }
+}
+
+#[cfg(all(feature = "clock", target_os = "linux"))]
+impl TimeSource for TaiClock {
+    fn now(&self) -> Duration {
+        let tai = clock_gettime(libc::CLOCK_TAI).unwrap_or_else(|_| SystemClock.now() + tai_offset(SystemClock.now()));
+        utc_from_tai(tai)
+    }
+}
+
+/// The default source of clients and synchronizers.
+#[cfg(feature = "client")]
+pub(crate) fn system() -> Arc<dyn TimeSource> {
+    Arc::new(SystemClock)
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "client")]
+    use crate::client::SntpClient;
+    #[cfg(feature = "client")]
+    use crate::synchronizer::SntpSynchronizer;
+    #[cfg(feature = "client")]
+    use crate::testing::MockServer;
+    use crate::timesource::*;
+    #[cfg(feature = "client")]
+    use std::thread;
+
+    #[cfg(feature = "client")]
+    #[test]
+    fn test_mock_clock_exchange() {
+        let clock = Arc::new(MockClock::new(Duration::from_secs(1_700_000_000)));
+        let server = MockServer::builder().offset_nanos(-1_250_000_000).time_source(clock.clone()).start().unwrap();
+        let client = SntpClient::builder().time_source(clock.clone()).build();
+
+        // Exact as long as it fits NTP's 2^-32 s resolution.
+        let result = client.query(&server.addr()).unwrap();
+        assert_eq!((result.offset_nanos, result.delay_nanos), (-1_250_000_000, 0));
+    }
+
+    #[test]
+    fn test_system_clock_resolution() {
+        // Sub-millisecond offsets need a clock ticking finer than that.
+        let first = SystemClock.now();
+        let mut next = SystemClock.now();
+        while next == first {
+            next = SystemClock.now();
+        }
+        assert!(next - first < Duration::from_millis(1), "{:?}", next - first);
+    }
+
+    #[test]
+    fn test_tai_offset() {
+        let secs = Duration::from_secs;
+        assert_eq!(tai_offset(secs(0)), secs(0));
+        assert_eq!(tai_offset(secs(63072000)), secs(10));
+        assert_eq!(tai_offset(secs(1483228799)), secs(36));
+        assert_eq!(tai_offset(secs(1483228800)), secs(37));
+        assert_eq!(utc_from_tai(secs(1483228800 + 36)), secs(1483228800));
+        assert_eq!(utc_from_tai(secs(1483228800 + 37)), secs(1483228800));
+        assert_eq!(utc_from_tai(secs(1483228800 + 38)), secs(1483228801));
+        for utc in [secs(100_000_000), secs(1_700_000_000)] {
+            assert_eq!(utc_from_tai(utc + tai_offset(utc)), utc);
+        }
+    }
+
+    #[cfg(all(feature = "clock", target_os = "linux"))]
+    #[test]
+    fn test_tai_clock() {
+        // Within a second of UTC, whatever the kernel's TAI offset.
+        let kernel = TaiClock::kernel_offset().unwrap();
+        let utc = TaiClock.now() + tai_offset(SystemClock.now()) - kernel;
+        assert!(utc.abs_diff(SystemClock.now()) < Duration::from_secs(1));
+    }
+
+    #[cfg(all(feature = "clock", target_os = "linux"))]
+    #[test]
+    fn test_phc_clock() {
+        assert!(PhcClock::open("/nonexistent/ptp0").is_err());
+        // Not a clock device.
+        assert!(PhcClock::open("/dev/null").is_err());
+    }
+
+    #[cfg(feature = "client")]
+    #[test]
+    fn test_mock_clock_schedule() {
+        let clock = Arc::new(MockClock::new(Duration::from_secs(1_700_000_000)));
+        let server = MockServer::builder().time_source(clock.clone()).start().unwrap();
+        let sync = SntpSynchronizer::builder()
+            .server_with(&server.addr(), SntpClient::builder().time_source(clock.clone()).build())
+            .interval(Duration::from_secs(64))
+            .time_source(clock.clone())
+            .start()
+            .unwrap();
+
+        let wait_for = |requests| {
+            for _ in 0..100 {
+                if server.requests() >= requests && sync.offset_nanos().is_some() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+        };
+        wait_for(1);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(server.requests(), 1);
+        assert_eq!(sync.selected().unwrap().1, Duration::from_secs(1_700_000_000));
+
+        clock.advance(Duration::from_secs(63));
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(server.requests(), 1);
+        clock.advance(Duration::from_secs(1));
+        wait_for(2);
+        assert_eq!(server.requests(), 2);
+        assert_eq!(sync.handle().system_stats().update_interval, Duration::from_secs(64));
+        sync.stop();
+    }
+}
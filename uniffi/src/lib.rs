@@ -0,0 +1,163 @@
+//! Kotlin and Swift bindings of simple-ntp, generated by
+//! [uniffi](https://mozilla.github.io/uniffi-rs/), for apps that cannot trust
+//! the device clock.
+//!
+//! ```shell
+//! cargo build --release
+//! cargo run --bin uniffi-bindgen generate --library target/release/libsimple_ntp_uniffi.so --language kotlin --out-dir out
+//! cargo run --bin uniffi-bindgen generate --library target/release/libsimple_ntp_uniffi.a --language swift --out-dir out
+//! ```
+
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use simple_ntp::client::{NtpResult, SntpClient};
+use simple_ntp::protocol;
+use simple_ntp::synchronizer::{SntpSynchronizer, SyncHandle};
+
+uniffi::setup_scaffolding!();
+
+/// Why a query failed, see `simple_ntp::protocol::NtpError`.
+#[derive(Debug, uniffi::Error)]
+pub enum NtpError {
+    ServiceUnavailable { reason: String },
+    BadServerAddr { reason: String },
+    Unexpected { reason: String },
+    Truncated,
+    Untrusted,
+    BadConfig { reason: String },
+    ResolveTimeout { reason: String },
+    KissOfDeath { code: String },
+}
+
+impl fmt::Display for NtpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NtpError::ServiceUnavailable { reason } => write!(f, "service unavailable: {}", reason),
+            NtpError::BadServerAddr { reason } => write!(f, "bad server address: {}", reason),
+            NtpError::Unexpected { reason } => write!(f, "unexpected error: {}", reason),
+            NtpError::Truncated => write!(f, "truncated response"),
+            NtpError::Untrusted => write!(f, "untrusted response"),
+            NtpError::BadConfig { reason } => write!(f, "bad configuration: {}", reason),
+            NtpError::ResolveTimeout { reason } => write!(f, "resolve timeout: {}", reason),
+            NtpError::KissOfDeath { code } => write!(f, "kiss-o'-death: {}", code),
+        }
+    }
+}
+
+impl std::error::Error for NtpError {}
+
+impl From<protocol::NtpError> for NtpError {
+    fn from(err: protocol::NtpError) -> Self {
+        match err {
+            protocol::NtpError::ServiceUnavailable(reason) => NtpError::ServiceUnavailable { reason },
+            protocol::NtpError::BadNtpServerAddr(reason) => NtpError::BadServerAddr { reason },
+            protocol::NtpError::UnexpectedErr(reason) => NtpError::Unexpected { reason },
+            protocol::NtpError::TruncatedNtpMessage => NtpError::Truncated,
+            protocol::NtpError::UntrustedMessage => NtpError::Untrusted,
+            protocol::NtpError::BadConfig(reason) => NtpError::BadConfig { reason },
+            protocol::NtpError::ResolveTimeout(reason) => NtpError::ResolveTimeout { reason },
+            protocol::NtpError::KissOfDeath(code) => NtpError::KissOfDeath { code },
+        }
+    }
+}
+
+/// The answer of a server, see `simple_ntp::client::NtpResult`.
+#[derive(Debug, uniffi::Record)]
+pub struct NtpAnswer {
+    pub addr: String,
+    pub stratum: u8,
+    /// Reference ID as text, a kiss code or an IPv4 address.
+    pub refid: String,
+    pub root_delay_seconds: f64,
+    pub root_dispersion_seconds: f64,
+    pub offset_nanos: i64,
+    pub delay_nanos: i64,
+}
+
+impl From<NtpResult> for NtpAnswer {
+    fn from(result: NtpResult) -> Self {
+        NtpAnswer {
+            addr: result.addr.to_string(),
+            stratum: result.stratum,
+            refid: result.refid(),
+            root_delay_seconds: result.root_delay.as_secs_f64(),
+            root_dispersion_seconds: result.root_dispersion.as_secs_f64(),
+            offset_nanos: result.offset_nanos,
+            delay_nanos: result.delay_nanos,
+        }
+    }
+}
+
+/// One-off queries, see `simple_ntp::client::SntpClient`.
+#[derive(Debug, uniffi::Object)]
+pub struct NtpClient {
+    client: SntpClient,
+}
+
+#[uniffi::export]
+impl NtpClient {
+    /// A client waiting up to `timeout_millis` for each response.
+    #[uniffi::constructor]
+    pub fn new(timeout_millis: u64) -> Self {
+        NtpClient {
+            client: SntpClient::builder().timeout(Duration::from_millis(timeout_millis)).build(),
+        }
+    }
+
+    /// Query `server`, `host` or `host:port`.
+    pub fn query(&self, server: String) -> Result<NtpAnswer, NtpError> {
+        Ok(self.client.query(&server)?.into())
+    }
+}
+
+/// The device clock corrected by servers polled in the background, see
+/// `simple_ntp::synchronizer::SntpSynchronizer`.
+#[derive(uniffi::Object)]
+pub struct SyncedClock {
+    sync: Mutex<Option<SntpSynchronizer>>,
+    handle: SyncHandle,
+}
+
+#[uniffi::export]
+impl SyncedClock {
+    /// Start polling `servers` every `poll_interval_seconds`.
+    #[uniffi::constructor]
+    pub fn new(servers: Vec<String>, poll_interval_seconds: u64) -> Result<Self, NtpError> {
+        let mut builder = SntpSynchronizer::builder().interval(Duration::from_secs(poll_interval_seconds));
+        for server in &servers {
+            builder = builder.server(server);
+        }
+        let sync = builder.start()?;
+
+        Ok(SyncedClock {
+            handle: sync.handle(),
+            sync: Mutex::new(Some(sync)),
+        })
+    }
+
+    /// Offset of the device clock from the selected server, `None` until one answers.
+    pub fn offset_nanos(&self) -> Option<i64> {
+        self.handle.offset_nanos()
+    }
+
+    pub fn is_synchronized(&self) -> bool {
+        self.handle.offset_nanos().is_some()
+    }
+
+    /// Corrected unix time in milliseconds, `None` until synchronized.
+    pub fn now_unix_millis(&self) -> Option<i64> {
+        let offset_nanos = self.handle.offset_nanos()?;
+        let local = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+
+        Some(((local.as_nanos() as i128 + offset_nanos as i128) / 1_000_000) as i64)
+    }
+
+    /// Stop polling; the last offset stays available.
+    pub fn stop(&self) {
+        if let Some(sync) = self.sync.lock().unwrap().take() {
+            sync.stop();
+        }
+    }
+}